@@ -3,6 +3,7 @@ use anyhow::{anyhow, Error, Result};
 use chrono::offset::Local;
 use dialoguer::console::style;
 use envfile::EnvFile;
+use hyper::header::RANGE;
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
@@ -13,8 +14,8 @@ use lagon_runtime_http::{
 };
 use lagon_runtime_isolate::{options::IsolateOptions, Isolate};
 use lagon_runtime_isolate::{IsolateEvent, IsolateRequest};
-use lagon_runtime_utils::assets::{find_asset, handle_asset};
-use lagon_runtime_utils::response::{handle_response, ResponseEvent, FAVICON_URL};
+use lagon_runtime_utils::assets::{find_asset, handle_asset, RangeRequest};
+use lagon_runtime_utils::response::{handle_response, ResponseEvent, ResponseOptions, FAVICON_URL};
 use lagon_runtime_utils::Deployment;
 use notify::event::ModifyKind;
 use notify::{Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
@@ -81,7 +82,13 @@ async fn handle_request(
             style("(asset)").black().bright()
         );
 
-        let run_result = match handle_asset(public_dir.unwrap(), asset) {
+        let range = req
+            .headers()
+            .get(RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(RangeRequest::parse);
+
+        let run_result = match handle_asset(public_dir.unwrap(), asset, range) {
             Ok(response) => RunResult::Response(response, None),
             Err(error) => RunResult::Error(format!("Could not retrieve asset ({asset}): {error}")),
         };
@@ -119,6 +126,7 @@ async fn handle_request(
             .send_async(IsolateEvent::Request(IsolateRequest {
                 request,
                 sender: tx,
+                total_timeout: None,
             }))
             .await
             .unwrap_or(());
@@ -129,7 +137,10 @@ async fn handle_request(
         ..Deployment::default()
     });
 
-    handle_response(rx, deployment, |event| async move {
+    // Errors are always answered with an HTML page in development
+    let options = ResponseOptions::default();
+
+    handle_response(rx, deployment, options, |event| async move {
         match event {
             ResponseEvent::StreamDoneNoDataError => {
                 println!(
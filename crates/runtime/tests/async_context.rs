@@ -213,6 +213,6 @@ export async function handler() {
 
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "2".into(), None)
+        ("log".into(), "2".into(), None, None)
     );
 }
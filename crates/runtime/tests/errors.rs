@@ -55,6 +55,25 @@ async fn handler_reject() {
     .await;
 }
 
+#[tokio::test]
+async fn top_level_await_reject() {
+    utils::setup();
+    let (send, receiver) = utils::create_isolate(IsolateOptions::new(
+        "await Promise.reject(new Error('Rejected'));
+
+export function handler() {
+    return new Response('Hello world');
+}"
+        .into(),
+    ));
+    send(Request::default());
+
+    match receiver.recv_async().await.unwrap() {
+        RunResult::Error(error) => assert!(error.starts_with("Uncaught Error: Rejected")),
+        _ => panic!("Expected an error"),
+    }
+}
+
 #[tokio::test]
 async fn compilation_error() {
     utils::setup();
@@ -2,6 +2,7 @@ use httptest::{matchers::*, responders::*, Expectation, Server};
 use hyper::{header::CONTENT_TYPE, Request, Response};
 use lagon_runtime_http::RunResult;
 use lagon_runtime_isolate::options::IsolateOptions;
+use std::time::Duration;
 
 mod utils;
 
@@ -627,3 +628,33 @@ async fn fetch_set_content_length() {
     )
     .await;
 }
+
+#[tokio::test]
+async fn fetch_memory_reached() {
+    utils::setup();
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/"))
+            .times(1..)
+            .respond_with(status_code(200).body(vec![1; 1024 * 1024])),
+    );
+    let url = server.url("/");
+
+    let (send, receiver) = utils::create_isolate(
+        IsolateOptions::new(format!(
+            "export async function handler() {{
+    const buffers = [];
+    while (true) {{
+        buffers.push(await fetch('{url}').then(res => res.arrayBuffer()));
+    }}
+    return new Response('Should not be reached');
+}}"
+        ))
+        // Increase timeout for CI
+        .total_timeout(Duration::from_secs(5))
+        .memory(2),
+    );
+    send(Request::default());
+
+    utils::assert_run_result(&receiver, RunResult::MemoryLimit).await;
+}
@@ -52,7 +52,11 @@ pub fn create_isolate(options: IsolateOptions) -> (SendRequest, flume::Receiver<
             let request = (parts, body);
 
             request_tx
-                .send(IsolateEvent::Request(IsolateRequest { request, sender }))
+                .send(IsolateEvent::Request(IsolateRequest {
+                    request,
+                    sender,
+                    total_timeout: None,
+                }))
                 .unwrap();
         });
     });
@@ -86,7 +90,11 @@ pub fn create_isolate_without_snapshot(
             let request = (parts, body);
 
             request_tx
-                .send(IsolateEvent::Request(IsolateRequest { request, sender }))
+                .send(IsolateEvent::Request(IsolateRequest {
+                    request,
+                    sender,
+                    total_timeout: None,
+                }))
                 .unwrap();
         });
     });
@@ -5,3 +5,5 @@ pub const X_REAL_IP: &str = "x-real-ip";
 
 pub const X_LAGON_REGION: &str = "x-lagon-region";
 pub const X_LAGON_ID: &str = "x-lagon-id";
+pub const X_LAGON_SIGNATURE: &str = "x-lagon-signature";
+pub const X_LAGON_TOTAL_TIMEOUT: &str = "x-lagon-total-timeout";
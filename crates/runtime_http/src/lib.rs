@@ -9,20 +9,29 @@ pub use headers::*;
 pub use request::*;
 pub use response::*;
 
+// Resources used by an isolate to handle a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestStats {
+    pub cpu_time: Duration,
+    // Highest heap usage sampled while handling the request, in bytes. The
+    // heap is shared by the requests handled concurrently by the isolate.
+    pub peak_memory: usize,
+}
+
 #[derive(Debug)]
 pub enum StreamResult {
     Start(Builder),
     Data(Vec<u8>),
-    // Stream responses always have a duration
+    // Stream responses always have stats
     // since they are always from the isolate
-    Done(Duration),
+    Done(RequestStats),
 }
 
 #[derive(Debug)]
 pub enum RunResult {
-    // Isolate responses have a duration (cpu time)
+    // Isolate responses have stats (e.g cpu time)
     // Assets responses don't
-    Response(Response<Body>, Option<Duration>),
+    Response(Response<Body>, Option<RequestStats>),
     Stream(StreamResult),
     Timeout,
     MemoryLimit,
@@ -9,11 +9,18 @@ pub fn console_binding(
 ) {
     let level = args.get(0).to_rust_string_lossy(scope);
     let message = args.get(1).to_rust_string_lossy(scope);
+    // JSON object of the custom fields of structured logs
+    let fields = match args.get(2) {
+        fields if fields.is_undefined() => None,
+        fields => Some(fields.to_rust_string_lossy(scope)),
+    };
     let state = Isolate::state(scope);
     let state = state.borrow();
 
     if let Some(log_sender) = state.log_sender.as_ref() {
-        if let Err(error) = log_sender.send((level, message, state.metadata.as_ref().clone())) {
+        if let Err(error) =
+            log_sender.send((level, message, state.metadata.as_ref().clone(), fields))
+        {
             error!("Failed to send log message: {}", error)
         }
     }
@@ -8,7 +8,7 @@ use fetch::{fetch_binding, fetch_init};
 use hyper::{body::Bytes, HeaderMap};
 use lagon_runtime_http::response_to_v8;
 use lagon_runtime_v8_utils::{v8_boolean, v8_string, v8_uint8array};
-use pull_stream::pull_stream_binding;
+use pull_stream::{pull_stream_binding, stream_ready_binding, stream_ready_init};
 use queue_microtask::queue_microtask_binding;
 use sleep::{sleep_binding, sleep_init};
 
@@ -159,6 +159,13 @@ pub fn bind<'a>(
             decrypt_binding
         );
         async_binding!(scope, lagon_object, "sleep", sleep_init, sleep_binding);
+        async_binding!(
+            scope,
+            lagon_object,
+            "streamReady",
+            stream_ready_init,
+            stream_ready_binding
+        );
         async_binding!(
             scope,
             lagon_object,
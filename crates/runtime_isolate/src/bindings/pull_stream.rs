@@ -1,14 +1,23 @@
 use std::time::Duration;
 
-use lagon_runtime_http::StreamResult;
-use lagon_runtime_v8_utils::{extract_v8_uint8array, v8_exception};
+use anyhow::{anyhow, Result};
+use lagon_runtime_http::{RequestStats, RunResult, StreamResult};
+use lagon_runtime_v8_utils::{extract_v8_uint8array, v8_boolean, v8_exception};
 
-use crate::Isolate;
+use crate::{
+    bindings::{BindingResult, PromiseResult},
+    Isolate,
+};
+
+// Number of chunks waiting to be consumed by the receiver of a request
+// after which the stream is paused, until the receiver catches up
+const STREAM_HIGH_WATER_MARK: usize = 16;
+const STREAM_READY_INTERVAL: Duration = Duration::from_millis(1);
 
 pub fn pull_stream_binding(
     scope: &mut v8::HandleScope,
     args: v8::FunctionCallbackArguments,
-    mut _retval: v8::ReturnValue,
+    mut retval: v8::ReturnValue,
 ) {
     let isolate_state = Isolate::state(scope);
     let state = isolate_state.borrow();
@@ -19,7 +28,7 @@ pub fn pull_stream_binding(
     if done.is_true() {
         state
             .stream_sender
-            .send((id, StreamResult::Done(Duration::from_secs(0))))
+            .send((id, StreamResult::Done(RequestStats::default())))
             .unwrap_or(());
     } else {
         match extract_v8_uint8array(args.get(2)) {
@@ -28,6 +37,16 @@ pub fn pull_stream_binding(
                     .stream_sender
                     .send((id, StreamResult::Data(buf)))
                     .unwrap_or(());
+
+                // Tell the stream to wait for `streamReady` before
+                // reading the next chunk if the receiver is lagging
+                let should_wait = state
+                    .handler_results
+                    .get(&id)
+                    .map(|handler_result| handler_result.sender.len() >= STREAM_HIGH_WATER_MARK)
+                    .unwrap_or(false);
+
+                retval.set(v8_boolean(scope, should_wait).into());
             }
             Err(error) => {
                 let exception = v8_exception(scope, error.to_string().as_str());
@@ -36,3 +55,38 @@ pub fn pull_stream_binding(
         }
     }
 }
+
+type Arg = Option<flume::Sender<RunResult>>;
+
+pub fn stream_ready_init(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+) -> Result<Arg> {
+    let id = match args.get(0).uint32_value(scope) {
+        Some(id) => id,
+        None => return Err(anyhow!("Invalid stream id")),
+    };
+
+    let isolate_state = Isolate::state(scope);
+    let state = isolate_state.borrow();
+
+    Ok(state
+        .handler_results
+        .get(&id)
+        .map(|handler_result| handler_result.sender.clone()))
+}
+
+pub async fn stream_ready_binding(id: usize, arg: Arg) -> BindingResult {
+    if let Some(sender) = arg {
+        // Polling the length of the channel avoids blocking the isolate
+        // thread, which would stall every other request it handles
+        while !sender.is_disconnected() && sender.len() >= STREAM_HIGH_WATER_MARK {
+            tokio::time::sleep(STREAM_READY_INTERVAL).await;
+        }
+    }
+
+    BindingResult {
+        id,
+        result: PromiseResult::Undefined,
+    }
+}
@@ -3,24 +3,24 @@ use hyper::{
     body::Bytes,
     http::{request::Parts, response::Builder},
 };
-use lagon_runtime_http::{request_to_v8, response_from_v8, RunResult, StreamResult};
+use lagon_runtime_http::{request_to_v8, response_from_v8, RequestStats, RunResult, StreamResult};
 use lagon_runtime_v8_utils::v8_string;
 use linked_hash_map::LinkedHashMap;
 use std::{
-    cell::{RefCell, RefMut},
+    cell::{Cell, RefCell, RefMut},
     collections::HashMap,
     pin::Pin,
     rc::Rc,
     sync::{Arc, RwLock},
     task::{Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 use v8::MapFnTo;
 
 use self::{
     bindings::{BindingResult, PromiseResult},
     callbacks::{heap_limit_callback, promise_reject_callback, resolve_module_callback},
-    options::{IsolateOptions, Metadata},
+    options::{IsolateCode, IsolateOptions, Metadata},
 };
 
 mod bindings;
@@ -30,6 +30,7 @@ pub mod options;
 const RUNTIME_ONLY_SCRIPT_NAME: &str = "runtime.js";
 const CODE_ONLY_SCRIPT_NAME: &str = "code.js";
 const ISOLATE_SCRIPT_NAME: &str = "isolate.js";
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_millis(10);
 
 #[derive(Debug, Default)]
 pub struct RequestContext {
@@ -39,10 +40,29 @@ pub struct RequestContext {
 pub struct IsolateRequest {
     pub request: (Parts, Bytes),
     pub sender: flume::Sender<RunResult>,
+    // Overrides the isolate's total timeout for this request only
+    pub total_timeout: Option<Duration>,
+}
+
+// Event delivered to the function's handler instead of an HTTP request (e.g from
+// a queue), when set in the extensions of the request. The handler receives a
+// `{ name, payload }` object, whose payload is parsed from JSON.
+#[derive(Debug, Clone)]
+pub struct FunctionEvent {
+    pub name: String,
+    pub payload: String,
+}
+
+// Replace the code of the isolate without re-creating it. The sender
+// receives the compilation error if the new code couldn't be evaluated
+pub struct IsolateReload {
+    pub code: IsolateCode,
+    pub sender: flume::Sender<Result<(), String>>,
 }
 
 pub enum IsolateEvent {
     Request(IsolateRequest),
+    ReloadCode(IsolateReload),
     Terminate(String),
 }
 
@@ -51,9 +71,12 @@ pub struct HandlerResult {
     promise: Option<v8::Global<v8::Promise>>,
     sender: flume::Sender<RunResult>,
     start_time: Instant,
+    total_timeout: Duration,
     stream_response_sent: RefCell<bool>,
     stream_status: RefCell<StreamStatus>,
     context: RequestContext,
+    // Highest heap usage sampled since the request started, see `track_peak_memory`
+    peak_memory: Cell<usize>,
 }
 
 #[derive(Debug, Clone)]
@@ -69,7 +92,7 @@ pub struct IsolateState {
     rejected_promises: LinkedHashMap<v8::Global<v8::Promise>, String>,
     lines: usize,
     requests_count: u32,
-    log_sender: Option<flume::Sender<(String, String, Metadata)>>,
+    log_sender: Option<flume::Sender<(String, String, Metadata, Option<String>)>>,
 }
 
 #[derive(Debug)]
@@ -106,6 +129,8 @@ pub struct Isolate {
     options: IsolateOptions,
     isolate: Option<v8::OwnedIsolate>,
     master_handler: Option<v8::Global<v8::Function>>,
+    // Not defined by older runtime versions
+    master_event_handler: Option<v8::Global<v8::Function>>,
     handler: Option<v8::Global<v8::Value>>,
     compilation_error: Option<String>,
     stream_receiver: flume::Receiver<(u32, StreamResult)>,
@@ -114,6 +139,8 @@ pub struct Isolate {
     rx: flume::Receiver<IsolateEvent>,
     near_heap_limit_callback_data: Option<Box<RefCell<dyn std::any::Any>>>,
     last_statistic_sent: Instant,
+    last_memory_check: Instant,
+    heartbeat_started: bool,
 }
 
 unsafe impl Send for Isolate {}
@@ -206,6 +233,7 @@ impl Isolate {
             options,
             isolate: Some(isolate),
             master_handler: None,
+            master_event_handler: None,
             handler: None,
             compilation_error: None,
             stream_receiver,
@@ -214,6 +242,8 @@ impl Isolate {
             rx,
             near_heap_limit_callback_data: None,
             last_statistic_sent: Instant::now(),
+            last_memory_check: Instant::now(),
+            heartbeat_started: false,
         };
 
         let thread_safe_handle = this.isolate.as_ref().unwrap().thread_safe_handle();
@@ -255,6 +285,10 @@ impl Isolate {
         Rc::clone(&self.options.metadata)
     }
 
+    pub fn get_compilation_error(&self) -> Option<&str> {
+        self.compilation_error.as_deref()
+    }
+
     fn terminate(&mut self, run_result: RunResult) {
         self.termination_result.write().unwrap().replace(run_result);
 
@@ -265,53 +299,44 @@ impl Isolate {
         }
     }
 
-    pub(self) fn state(isolate: &v8::Isolate) -> Rc<RefCell<IsolateState>> {
-        let s = isolate.get_slot::<Rc<RefCell<IsolateState>>>().unwrap();
-        s.clone()
+    // The heap limit only bounds the V8 heap, so we also need to check
+    // the memory allocated outside of it, e.g ArrayBuffers' backing stores
+    fn check_memory_usage(&mut self) {
+        if self.last_memory_check.elapsed() < MEMORY_CHECK_INTERVAL {
+            return;
+        }
+
+        self.last_memory_check = Instant::now();
+
+        if get_memory_usage(self.isolate.as_mut().unwrap()) > self.options.memory * 1024 * 1024 {
+            self.terminate(RunResult::MemoryLimit);
+        }
     }
 
-    pub fn evaluate(&mut self) {
-        let isolate_state = Isolate::state(self.isolate.as_ref().unwrap());
-        let global = {
-            let state = isolate_state.borrow();
-            state.global.as_ref().unwrap().0.clone()
-        };
+    // Sample the heap usage after each turn of the event loop, to
+    // know the highest usage while each request is being handled
+    fn track_peak_memory(&mut self, state: &Rc<RefCell<IsolateState>>) {
+        let state = state.borrow();
 
-        let scope =
-            &mut v8::HandleScope::with_context(self.isolate.as_mut().unwrap(), global.clone());
-        let try_catch = &mut v8::TryCatch::new(scope);
+        if state.handler_results.is_empty() {
+            return;
+        }
 
-        let (code, lines) = self.options.get_runtime_code(try_catch);
-        let resource_name = v8_string(
-            try_catch,
-            if self.options.snapshot {
-                RUNTIME_ONLY_SCRIPT_NAME
-            } else if self.options.snapshot_blob.is_some() {
-                CODE_ONLY_SCRIPT_NAME
-            } else {
-                ISOLATE_SCRIPT_NAME
-            },
-        );
-        let source_map_url = v8_string(try_catch, "");
-        isolate_state.borrow_mut().lines = lines;
+        let memory_usage = get_memory_usage(self.isolate.as_mut().unwrap());
 
-        let source = v8::script_compiler::Source::new(
-            code,
-            Some(&v8::ScriptOrigin::new(
-                try_catch,
-                resource_name.into(),
-                0,
-                0,
-                false,
-                i32::from(self.options.snapshot_blob.is_some()),
-                source_map_url.into(),
-                false,
-                false,
-                true,
-            )),
-        );
+        for handler_result in state.handler_results.values() {
+            handler_result
+                .peak_memory
+                .set(handler_result.peak_memory.get().max(memory_usage));
+        }
+    }
 
-        let thread_safe_handle = try_catch.thread_safe_handle();
+    pub(self) fn state(isolate: &v8::Isolate) -> Rc<RefCell<IsolateState>> {
+        let s = isolate.get_slot::<Rc<RefCell<IsolateState>>>().unwrap();
+        s.clone()
+    }
+
+    fn start_heartbeat(&self, thread_safe_handle: v8::IsolateHandle) {
         let termination_result = Arc::clone(&self.termination_result);
         let tick_timeout = self.options.tick_timeout;
         let heartbeat = Arc::clone(&self.heartbeat);
@@ -356,6 +381,55 @@ impl Isolate {
                 }
             }
         });
+    }
+
+    pub fn evaluate(&mut self) {
+        // The code can be evaluated again when reloaded, but
+        // we only need a single heartbeat thread
+        if !self.heartbeat_started {
+            self.heartbeat_started = true;
+            self.start_heartbeat(self.isolate.as_ref().unwrap().thread_safe_handle());
+        }
+
+        let isolate_state = Isolate::state(self.isolate.as_ref().unwrap());
+        let global = {
+            let state = isolate_state.borrow();
+            state.global.as_ref().unwrap().0.clone()
+        };
+
+        let scope =
+            &mut v8::HandleScope::with_context(self.isolate.as_mut().unwrap(), global.clone());
+        let try_catch = &mut v8::TryCatch::new(scope);
+
+        let (code, lines) = self.options.get_runtime_code(try_catch);
+        let resource_name = v8_string(
+            try_catch,
+            if self.options.snapshot {
+                RUNTIME_ONLY_SCRIPT_NAME
+            } else if self.options.snapshot_blob.is_some() {
+                CODE_ONLY_SCRIPT_NAME
+            } else {
+                ISOLATE_SCRIPT_NAME
+            },
+        );
+        let source_map_url = v8_string(try_catch, "");
+        isolate_state.borrow_mut().lines = lines;
+
+        let source = v8::script_compiler::Source::new(
+            code,
+            Some(&v8::ScriptOrigin::new(
+                try_catch,
+                resource_name.into(),
+                0,
+                0,
+                false,
+                i32::from(self.options.snapshot_blob.is_some()),
+                source_map_url.into(),
+                false,
+                false,
+                true,
+            )),
+        );
 
         match v8::script_compiler::compile_module(try_catch, source) {
             Some(module) => {
@@ -367,9 +441,25 @@ impl Isolate {
                     return;
                 }
 
-                if module.evaluate(try_catch).is_none() {
-                    self.compilation_error = Some(handle_error(try_catch, lines).as_error());
-                    return;
+                let result = match module.evaluate(try_catch) {
+                    Some(result) => result,
+                    None => {
+                        self.compilation_error = Some(handle_error(try_catch, lines).as_error());
+                        return;
+                    }
+                };
+
+                // Modules evaluate to a promise, which is rejected
+                // if the code throws after a top-level await
+                if let Ok(promise) = v8::Local::<v8::Promise>::try_from(result) {
+                    try_catch.perform_microtask_checkpoint();
+
+                    if promise.state() == v8::PromiseState::Rejected {
+                        let exception = promise.result(try_catch);
+                        self.compilation_error =
+                            Some(get_exception_message(try_catch, exception, lines));
+                        return;
+                    }
                 }
 
                 if !self.options.snapshot {
@@ -388,6 +478,13 @@ impl Isolate {
                     let handler = v8::Global::new(try_catch, handler);
 
                     self.master_handler = Some(handler);
+
+                    let handler_key = v8_string(try_catch, "masterEventHandler");
+                    let handler = global.get(try_catch, handler_key.into()).unwrap();
+
+                    self.master_event_handler = v8::Local::<v8::Function>::try_from(handler)
+                        .ok()
+                        .map(|handler| v8::Global::new(try_catch, handler));
                 }
             }
             None => {
@@ -398,7 +495,37 @@ impl Isolate {
 
     pub fn handle_event(&mut self, event: IsolateEvent, state: &Rc<RefCell<IsolateState>>) {
         match event {
-            IsolateEvent::Request(IsolateRequest { request, sender }) => {
+            IsolateEvent::Request(IsolateRequest {
+                request,
+                sender,
+                total_timeout,
+            }) => {
+                // Nobody is waiting for the response anymore, e.g the request
+                // was abandoned after waiting too long in the queue
+                if sender.is_disconnected() {
+                    return;
+                }
+
+                let event = request.0.extensions.get::<FunctionEvent>().cloned();
+
+                // Events aren't HTTP requests
+                if let (None, Some(on_request)) = (&event, &self.options.on_request) {
+                    on_request(Rc::clone(&self.options.metadata), &request.0);
+                }
+
+                let master_handler = match (&event, &self.master_event_handler) {
+                    (None, _) => self.master_handler.clone().unwrap(),
+                    (Some(_), Some(master_event_handler)) => master_event_handler.clone(),
+                    (Some(_), None) => {
+                        sender
+                            .send(RunResult::Error(String::from(
+                                "Events are not supported by this runtime version",
+                            )))
+                            .unwrap_or(());
+                        return;
+                    }
+                };
+
                 let (global, requests_count) = {
                     let mut isolate_state = state.borrow_mut();
                     let global = isolate_state.global.as_ref().unwrap().0.clone();
@@ -413,7 +540,6 @@ impl Isolate {
                 );
                 let try_catch = &mut v8::TryCatch::new(scope);
 
-                let master_handler = self.master_handler.as_ref().unwrap();
                 let master_handler = master_handler.open(try_catch);
 
                 let handler = self.handler.as_ref().unwrap();
@@ -422,7 +548,10 @@ impl Isolate {
                 let global = global.open(try_catch);
                 let global = global.global(try_catch);
 
-                let request = request_to_v8(request, try_catch);
+                let request = match event {
+                    Some(event) => event_to_v8(event, try_catch),
+                    None => request_to_v8(request, try_catch).into(),
+                };
                 let id = v8::Integer::new(try_catch, requests_count as i32);
                 try_catch.set_continuation_preserved_embedder_data(id.into());
 
@@ -432,9 +561,11 @@ impl Isolate {
                         promise: None,
                         sender,
                         start_time: Instant::now(),
+                        total_timeout: total_timeout.unwrap_or(self.options.total_timeout),
                         stream_response_sent: RefCell::new(false),
                         stream_status: RefCell::new(StreamStatus::None),
                         context: RequestContext::default(),
+                        peak_memory: Cell::new(get_memory_usage(try_catch)),
                     },
                 );
 
@@ -464,6 +595,23 @@ impl Isolate {
                     }
                 };
             }
+            IsolateEvent::ReloadCode(IsolateReload { code, sender }) => {
+                self.options.code = code;
+                self.handler = None;
+                self.master_handler = None;
+                self.master_event_handler = None;
+                self.compilation_error = None;
+
+                // Requests being processed keep using the previous handler
+                self.evaluate();
+
+                let result = match &self.compilation_error {
+                    Some(error) => Err(error.clone()),
+                    None => Ok(()),
+                };
+
+                sender.send(result).unwrap_or(());
+            }
             IsolateEvent::Terminate(reason) => {
                 self.terminate(RunResult::Error(reason));
             }
@@ -536,9 +684,10 @@ impl Isolate {
 
                     handler_result
                         .sender
-                        .send(RunResult::Stream(StreamResult::Done(
-                            handler_result.start_time.elapsed(),
-                        )))
+                        .send(RunResult::Stream(StreamResult::Done(RequestStats {
+                            cpu_time: handler_result.start_time.elapsed(),
+                            peak_memory: handler_result.peak_memory.get(),
+                        })))
                         .unwrap_or(());
                 } else {
                     handler_result
@@ -592,6 +741,8 @@ impl Isolate {
 
         self.poll_v8(&global);
         self.resolve_promises(cx, &global, &state);
+        self.check_memory_usage();
+        self.track_peak_memory(&state);
 
         let mut state = state.borrow_mut();
         self.poll_stream(&state);
@@ -641,7 +792,7 @@ impl Isolate {
                     return false;
                 }
 
-                if handler_result.start_time.elapsed() >= options.total_timeout {
+                if handler_result.start_time.elapsed() >= handler_result.total_timeout {
                     handler_result.sender.send(RunResult::Timeout).unwrap_or(());
                     return false;
                 }
@@ -659,7 +810,10 @@ impl Isolate {
                         Ok((response, is_streaming)) => (
                             RunResult::Response(
                                 response,
-                                Some(handler_result.start_time.elapsed()),
+                                Some(RequestStats {
+                                    cpu_time: handler_result.start_time.elapsed(),
+                                    peak_memory: handler_result.peak_memory.get(),
+                                }),
                             ),
                             is_streaming,
                         ),
@@ -710,7 +864,7 @@ impl Isolate {
                     false
                 }
                 v8::PromiseState::Pending => {
-                    if handler_result.start_time.elapsed() >= options.total_timeout {
+                    if handler_result.start_time.elapsed() >= handler_result.total_timeout {
                         handler_result.sender.send(RunResult::Timeout).unwrap_or(());
                         return false;
                     }
@@ -756,12 +910,35 @@ impl Drop for Isolate {
     }
 }
 
+fn event_to_v8<'a>(
+    event: FunctionEvent,
+    scope: &mut v8::HandleScope<'a>,
+) -> v8::Local<'a, v8::Value> {
+    let object = v8::Object::new(scope);
+
+    let name_key = v8_string(scope, "name");
+    let name = v8_string(scope, &event.name);
+    object.set(scope, name_key.into(), name.into());
+
+    let payload_key = v8_string(scope, "payload");
+    let payload = v8_string(scope, &event.payload);
+    let payload = v8::json::parse(scope, payload).unwrap_or_else(|| v8::null(scope).into());
+    object.set(scope, payload_key.into(), payload);
+
+    object.into()
+}
+
+// Memory used by the V8 heap and by native allocations tracked by V8
+fn get_memory_usage(isolate: &mut v8::Isolate) -> usize {
+    let mut statistics = v8::HeapStatistics::default();
+    isolate.get_heap_statistics(&mut statistics);
+
+    statistics.used_heap_size() + statistics.external_memory()
+}
+
 pub fn send_statistics(options: &IsolateOptions, isolate: &mut v8::Isolate) {
     if let Some(on_statistics) = &options.on_statistics {
-        let mut statistics = v8::HeapStatistics::default();
-        isolate.get_heap_statistics(&mut statistics);
-
-        on_statistics(Rc::clone(&options.metadata), statistics.used_heap_size())
+        on_statistics(Rc::clone(&options.metadata), get_memory_usage(isolate))
     }
 }
 
@@ -1,3 +1,4 @@
+use hyper::http::request::Parts;
 use lagon_runtime_v8_utils::v8_string;
 use std::{collections::HashMap, rc::Rc, time::Duration};
 
@@ -6,9 +7,11 @@ const JS_RUNTIME: &str = include_str!("../runtime.js");
 pub type Metadata = Option<(String, String)>;
 type OnIsolateDropCallback = Box<dyn Fn(Rc<Metadata>)>;
 type OnIsolateStatisticsCallback = Box<dyn Fn(Rc<Metadata>, usize)>;
+type OnIsolateRequestCallback = Box<dyn Fn(Rc<Metadata>, &Parts)>;
+pub type IsolateCode = Box<dyn AsRef<str> + Send>;
 
 pub struct IsolateOptions {
-    pub code: String,
+    pub code: IsolateCode,
     pub environment_variables: Option<HashMap<String, String>>,
     pub memory: usize, // in MB (MegaBytes)
     pub tick_timeout: Duration,
@@ -17,7 +20,9 @@ pub struct IsolateOptions {
     pub metadata: Rc<Metadata>,
     pub on_drop: Option<OnIsolateDropCallback>,
     pub on_statistics: Option<OnIsolateStatisticsCallback>,
-    pub log_sender: Option<flume::Sender<(String, String, Metadata)>>,
+    pub on_request: Option<OnIsolateRequestCallback>,
+    // Level, message, metadata and JSON fields (for structured logs) of the logs
+    pub log_sender: Option<flume::Sender<(String, String, Metadata, Option<String>)>>,
     pub snapshot: bool,
     pub snapshot_blob: Option<&'static [u8]>,
 }
@@ -26,8 +31,14 @@ unsafe impl Send for IsolateOptions {}
 
 impl IsolateOptions {
     pub fn new(code: String) -> Self {
+        Self::with_code(code)
+    }
+
+    // Allows passing code that isn't owned by the isolate,
+    // e.g memory-mapped from a file
+    pub fn with_code(code: impl AsRef<str> + Send + 'static) -> Self {
         Self {
-            code,
+            code: Box::new(code),
             environment_variables: None,
             tick_timeout: Duration::from_millis(200),
             total_timeout: Duration::from_secs(1),
@@ -36,6 +47,7 @@ impl IsolateOptions {
             metadata: Rc::new(None),
             on_drop: None,
             on_statistics: None,
+            on_request: None,
             snapshot: false,
             snapshot_blob: None,
             log_sender: None,
@@ -77,7 +89,16 @@ impl IsolateOptions {
         self
     }
 
-    pub fn log_sender(mut self, log_sender: flume::Sender<(String, String, Metadata)>) -> Self {
+    // Called when the isolate picks up a request, before running the handler
+    pub fn on_request_callback(mut self, on_request: OnIsolateRequestCallback) -> Self {
+        self.on_request = Some(on_request);
+        self
+    }
+
+    pub fn log_sender(
+        mut self,
+        log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+    ) -> Self {
         self.log_sender = Some(log_sender);
         self
     }
@@ -118,6 +139,7 @@ impl IsolateOptions {
                 .join("\n"),
             None => "".to_string(),
         };
+        let code = (**code).as_ref();
 
         if snapshot_blob.is_some() {
             // If we have a snapshot, only return the isolate's code
@@ -0,0 +1,90 @@
+// Compares the anonymous memory (RssAnon) used to keep the code of many idle
+// deployments around, when each isolate owns a copy of the code versus when
+// the code is memory-mapped with `Deployment::get_code`.
+//
+// cargo bench -p lagon-runtime-utils --bench code_memory
+use lagon_runtime_utils::{Deployment, DEPLOYMENTS_DIR};
+use std::{env, fs, hint::black_box};
+
+const DEPLOYMENTS: usize = 200;
+const ISOLATES_PER_DEPLOYMENT: usize = 2;
+const CODE_SIZE: usize = 512 * 1024;
+
+fn rss_anon_kb() -> usize {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|line| line.starts_with("RssAnon:"))
+                .and_then(|line| line.split_whitespace().nth(1))
+                .and_then(|value| value.parse().ok())
+        })
+        .unwrap_or_default()
+}
+
+// Read one byte per page so every page is actually loaded
+fn touch(code: &str) -> usize {
+    code.bytes().step_by(4096).map(usize::from).sum()
+}
+
+fn main() {
+    let dir = env::temp_dir().join("lagon-code-memory-bench");
+    let deployments_dir = dir.join(DEPLOYMENTS_DIR);
+    fs::create_dir_all(&deployments_dir).unwrap();
+
+    let code = format!(
+        "export function handler() {{ return new Response('{}') }}",
+        "a".repeat(CODE_SIZE)
+    );
+    let deployments = (0..DEPLOYMENTS)
+        .map(|index| {
+            let deployment = Deployment {
+                id: format!("deployment-{index}"),
+                ..Deployment::default()
+            };
+            deployment
+                .write_code(&deployments_dir, code.as_bytes())
+                .unwrap();
+            deployment
+        })
+        .collect::<Vec<_>>();
+
+    let before = rss_anon_kb();
+    let mapped = deployments
+        .iter()
+        .flat_map(|deployment| {
+            (0..ISOLATES_PER_DEPLOYMENT).map(|_| deployment.get_code(&deployments_dir).unwrap())
+        })
+        .collect::<Vec<_>>();
+    black_box(
+        mapped
+            .iter()
+            .map(|code| touch(code.as_ref()))
+            .sum::<usize>(),
+    );
+    let mapped_kb = rss_anon_kb().saturating_sub(before);
+    drop(mapped);
+
+    let before = rss_anon_kb();
+    let owned = deployments
+        .iter()
+        .flat_map(|deployment| {
+            (0..ISOLATES_PER_DEPLOYMENT).map(|_| {
+                fs::read_to_string(deployments_dir.join(deployment.id.clone() + ".js")).unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+    black_box(owned.iter().map(|code| touch(code)).sum::<usize>());
+    let owned_kb = rss_anon_kb().saturating_sub(before);
+    drop(owned);
+
+    println!(
+        "{DEPLOYMENTS} deployments, {ISOLATES_PER_DEPLOYMENT} isolates each, {} KiB of code",
+        code.len() / 1024
+    );
+    println!("owned:  {owned_kb} KiB");
+    println!("mapped: {mapped_kb} KiB");
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -1,14 +1,110 @@
 use anyhow::Result;
-use hyper::{body::Bytes, header::CONTENT_TYPE, Body, Response};
+use hyper::{
+    body::Bytes,
+    header::{ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
 use std::{
     collections::HashSet,
     fs,
+    ops::Range,
     path::{Path, PathBuf},
 };
 
-pub fn find_asset<'a>(url: &'a str, assets: &'a HashSet<String>) -> Option<&'a String> {
+// A single byte range requested with the `Range` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeRequest {
+    // `bytes=start-end`, both inclusive
+    Bounded(u64, u64),
+    // `bytes=start-`
+    From(u64),
+    // `bytes=-length`, the last `length` bytes
+    Suffix(u64),
+}
+
+impl RangeRequest {
+    // Multiple ranges, other units and invalid values are ignored,
+    // in which case the whole asset is served
+    pub fn parse(value: &str) -> Option<Self> {
+        let range = value.trim().strip_prefix("bytes=")?;
+
+        if range.contains(',') {
+            return None;
+        }
+
+        let (start, end) = range.split_once('-')?;
+
+        match (start.trim(), end.trim()) {
+            ("", "") => None,
+            ("", length) => length.parse().ok().map(Self::Suffix),
+            (start, "") => start.parse().ok().map(Self::From),
+            (start, end) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+
+                (start <= end).then_some(Self::Bounded(start, end))
+            }
+        }
+    }
+
+    // The bytes to send from an asset of `size` bytes,
+    // or `None` if the range can't be satisfied
+    pub fn resolve(&self, size: u64) -> Option<Range<u64>> {
+        let range = match *self {
+            Self::Bounded(start, end) => start..end.saturating_add(1).min(size),
+            Self::From(start) => start..size,
+            Self::Suffix(length) => size.saturating_sub(length)..size,
+        };
+
+        (range.start < range.end).then_some(range)
+    }
+}
+
+// Value of the `Content-Range` header of a partial response
+pub fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, size)
+}
+
+// Value of the `Content-Range` header of a 416 response
+pub fn unsatisfied_content_range(size: u64) -> String {
+    format!("bytes */{size}")
+}
+
+// Decode `%XX` sequences, or return `None` if one of them is
+// invalid or the result isn't valid UTF-8
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut chars = value.bytes();
+
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+// Decode the url's path and reject the ones that could point outside
+// of the deployment's directory once joined to it
+fn normalize_asset_path(url: &str) -> Option<String> {
+    let path = percent_decode(url)?;
+
+    let is_safe = path
+        .split('/')
+        .all(|segment| segment != ".." && segment != ".")
+        && !path.contains(['\\', '\0']);
+
+    is_safe.then_some(path)
+}
+
+pub fn find_asset<'a>(url: &str, assets: &'a HashSet<String>) -> Option<&'a String> {
     // Remove the leading '/' from the url
-    let url = &url[1..];
+    let url = normalize_asset_path(&url[1..])?;
 
     assets.iter().find(|asset| {
         **asset == url
@@ -18,29 +114,94 @@ pub fn find_asset<'a>(url: &'a str, assets: &'a HashSet<String>) -> Option<&'a S
     })
 }
 
-pub fn handle_asset(root: PathBuf, asset: &String) -> Result<Response<Body>> {
-    let path = root.join(asset);
-    let body = fs::read(path)?;
-
-    let content_type = Path::new(asset).extension().map_or(
-        "application/octet-stream",
-        |extension| match extension.to_str().unwrap_or("") {
-            "js" => "application/javascript",
-            "css" => "text/css",
-            "html" => "text/html",
-            "png" => "image/png",
-            "jpg" => "image/jpeg",
-            "jpeg" => "image/jpeg",
-            "svg" => "image/svg+xml",
-            "json" => "application/json",
-            "txt" => "text/plain",
-            _ => "application/octet-stream",
-        },
+pub fn get_content_type(asset: &str) -> &'static str {
+    Path::new(asset)
+        .extension()
+        .map_or("application/octet-stream", |extension| {
+            match extension.to_str().unwrap_or("") {
+                "js" => "application/javascript",
+                "css" => "text/css",
+                "html" => "text/html",
+                "png" => "image/png",
+                "jpg" => "image/jpeg",
+                "jpeg" => "image/jpeg",
+                "svg" => "image/svg+xml",
+                "json" => "application/json",
+                "txt" => "text/plain",
+                "ico" => "image/x-icon",
+                _ => "application/octet-stream",
+            }
+        })
+}
+
+// Link preloading the asset, e.g `</index.css>; rel=preload; as=style`
+pub fn preload_link(asset: &str) -> String {
+    let destination = Path::new(asset)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map_or("fetch", |extension| match extension {
+            "css" => "style",
+            "js" => "script",
+            "woff" | "woff2" | "ttf" | "otf" => "font",
+            "png" | "jpg" | "jpeg" | "svg" | "ico" | "gif" | "webp" => "image",
+            _ => "fetch",
+        });
+
+    let link = format!(
+        "</{}>; rel=preload; as={}",
+        asset.trim_start_matches('/'),
+        destination
     );
 
-    Ok(Response::builder()
-        .header(CONTENT_TYPE, content_type)
-        .body(Body::from(Bytes::from(body)))?)
+    // Fonts and fetches are always requested in CORS mode
+    match destination {
+        "font" | "fetch" => link + "; crossorigin",
+        _ => link,
+    }
+}
+
+// Path of the asset in `root`, or `None` if it doesn't exist or resolves
+// outside of `root` (e.g with `..` segments or symbolic links)
+pub fn resolve_asset_path(root: &Path, asset: &str) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+    let path = root.join(asset).canonicalize().ok()?;
+
+    path.starts_with(&root).then_some(path)
+}
+
+pub fn handle_asset(
+    root: PathBuf,
+    asset: &String,
+    range: Option<RangeRequest>,
+) -> Result<Response<Body>> {
+    let path = match resolve_asset_path(&root, asset) {
+        Some(path) => path,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())?)
+        }
+    };
+    let body = Bytes::from(fs::read(path)?);
+    let size = body.len() as u64;
+
+    let response = Response::builder()
+        .header(CONTENT_TYPE, get_content_type(asset))
+        .header(ACCEPT_RANGES, "bytes");
+
+    Ok(match range.map(|range| range.resolve(size)) {
+        Some(Some(range)) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_RANGE, content_range(&range, size))
+            .body(Body::from(
+                body.slice(range.start as usize..range.end as usize),
+            ))?,
+        Some(None) => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, unsatisfied_content_range(size))
+            .body(Body::empty())?,
+        None => response.body(Body::from(body))?,
+    })
 }
 
 #[cfg(test)]
@@ -99,6 +260,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn range_parse() {
+        assert_eq!(
+            RangeRequest::parse("bytes=0-99"),
+            Some(RangeRequest::Bounded(0, 99))
+        );
+        assert_eq!(
+            RangeRequest::parse("bytes=100-"),
+            Some(RangeRequest::From(100))
+        );
+        assert_eq!(
+            RangeRequest::parse("bytes=-100"),
+            Some(RangeRequest::Suffix(100))
+        );
+        assert_eq!(RangeRequest::parse("bytes=0-1, 5-6"), None);
+        assert_eq!(RangeRequest::parse("bytes=10-5"), None);
+        assert_eq!(RangeRequest::parse("items=0-1"), None);
+        assert_eq!(RangeRequest::parse("bytes=-"), None);
+    }
+
+    #[test]
+    fn range_resolve() {
+        assert_eq!(RangeRequest::Bounded(0, 9).resolve(100), Some(0..10));
+        assert_eq!(RangeRequest::Bounded(90, 200).resolve(100), Some(90..100));
+        assert_eq!(RangeRequest::From(50).resolve(100), Some(50..100));
+        assert_eq!(RangeRequest::Suffix(10).resolve(100), Some(90..100));
+        assert_eq!(RangeRequest::Suffix(200).resolve(100), Some(0..100));
+
+        assert_eq!(RangeRequest::Bounded(100, 200).resolve(100), None);
+        assert_eq!(RangeRequest::From(100).resolve(100), None);
+        assert_eq!(RangeRequest::Suffix(0).resolve(100), None);
+    }
+
     #[test]
     fn find_asset_none() {
         let assets = vec![
@@ -116,4 +310,71 @@ mod tests {
         assert_eq!(find_asset("/hello/none", &assets), None);
         assert_eq!(find_asset("/hello/world/none", &assets), None);
     }
+
+    #[test]
+    fn preload_links() {
+        assert_eq!(
+            preload_link("index.css"),
+            "</index.css>; rel=preload; as=style"
+        );
+        assert_eq!(
+            preload_link("/static/app.js"),
+            "</static/app.js>; rel=preload; as=script"
+        );
+        assert_eq!(
+            preload_link("fonts/inter.woff2"),
+            "</fonts/inter.woff2>; rel=preload; as=font; crossorigin"
+        );
+        assert_eq!(
+            preload_link("data"),
+            "</data>; rel=preload; as=fetch; crossorigin"
+        );
+    }
+
+    #[test]
+    fn find_asset_traversal() {
+        let assets = vec![
+            "../../etc/passwd".into(),
+            "../secret".into(),
+            "hello/world.html".into(),
+        ]
+        .into_iter()
+        .collect::<HashSet<String>>();
+
+        assert_eq!(find_asset("/../../etc/passwd", &assets), None);
+        assert_eq!(find_asset("/%2e%2e/secret", &assets), None);
+        assert_eq!(find_asset("/..%2fsecret", &assets), None);
+        assert_eq!(find_asset("/%zz", &assets), None);
+        assert_eq!(
+            find_asset("/hello/world", &assets),
+            Some(&"hello/world.html".into())
+        );
+        assert_eq!(
+            find_asset("/hello%2Fworld.html", &assets),
+            Some(&"hello/world.html".into())
+        );
+    }
+
+    #[test]
+    fn resolve_asset_path_traversal() {
+        let dir = std::env::temp_dir().join("lagon-assets-test");
+        let root = dir.join("deployment");
+        fs::create_dir_all(root.join("hello")).unwrap();
+        fs::write(root.join("hello/world.html"), "Hello").unwrap();
+        fs::write(dir.join("secret"), "Secret").unwrap();
+
+        assert_eq!(
+            resolve_asset_path(&root, "hello/world.html"),
+            Some(root.join("hello/world.html").canonicalize().unwrap())
+        );
+        assert_eq!(resolve_asset_path(&root, "../secret"), None);
+        assert_eq!(resolve_asset_path(&root, "hello/../../secret"), None);
+        assert_eq!(resolve_asset_path(&root, "/etc/passwd"), None);
+        assert_eq!(resolve_asset_path(&root, "hello/none.html"), None);
+
+        let response = handle_asset(root.clone(), &"../secret".into(), None).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
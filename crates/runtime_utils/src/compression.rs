@@ -0,0 +1,237 @@
+use anyhow::Result;
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use std::io::{Read, Write};
+
+// Smaller bodies don't get much smaller, and the
+// compression isn't worth its CPU time
+pub const MIN_COMPRESSION_SIZE: usize = 1024;
+
+// Maximum size of decompressed request bodies, when
+// the deployment doesn't limit the size of bodies
+pub const MAX_DECOMPRESSED_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Brotli,
+    Gzip,
+    // Only used to decompress request bodies
+    Deflate,
+}
+
+impl ContentEncoding {
+    // Pick the encoding with the highest quality value from an
+    // `Accept-Encoding` header, preferring Brotli on ties
+    pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let mut brotli = 0.0;
+        let mut gzip = 0.0;
+
+        for entry in accept_encoding.split(',') {
+            let mut params = entry.split(';');
+            let coding = params.next().unwrap_or_default().trim();
+
+            // Encodings without a quality value have a quality of 1, and
+            // invalid ones are ignored like a zero quality value
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(0.0);
+
+            match coding.to_ascii_lowercase().as_str() {
+                "br" => brotli = quality,
+                "gzip" => gzip = quality,
+                _ => {}
+            }
+        }
+
+        // Encodings with a zero quality value are explicitly refused
+        if brotli > 0.0 && brotli >= gzip {
+            Some(Self::Brotli)
+        } else if gzip > 0.0 {
+            Some(Self::Gzip)
+        } else {
+            None
+        }
+    }
+
+    // Parse a `Content-Encoding` header with a single encoding
+    pub fn from_header(content_encoding: &str) -> Option<Self> {
+        match content_encoding.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Self::Brotli),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut compressed = Vec::new();
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                encoder.write_all(bytes)?;
+                drop(encoder);
+
+                Ok(compressed)
+            }
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(bytes)?;
+
+                Ok(encoder.finish()?)
+            }
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(bytes)?;
+
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+
+    // Return `None` if the decompressed bytes are larger than `max_size`, without
+    // decompressing further (e.g a few KB expanding to GBs, also called zip bombs)
+    pub fn decompress(&self, bytes: &[u8], max_size: usize) -> Result<Option<Vec<u8>>> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Self::Brotli => Box::new(brotli::Decompressor::new(bytes, 4096)),
+            Self::Gzip => Box::new(GzDecoder::new(bytes)),
+            Self::Deflate => Box::new(ZlibDecoder::new(bytes)),
+        };
+
+        let mut decompressed = Vec::new();
+        decoder
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+
+        if decompressed.len() > max_size {
+            return Ok(None);
+        }
+
+        Ok(Some(decompressed))
+    }
+}
+
+// Text formats compress well, while most binary formats
+// (images, videos, archives) are already compressed
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(
+            ContentEncoding::negotiate("gzip, deflate, br"),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(
+            ContentEncoding::negotiate("gzip"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(
+            ContentEncoding::negotiate("br;q=0, gzip;q=0.5"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(
+            ContentEncoding::negotiate("gzip;q=1, br;q=0.5"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(
+            ContentEncoding::negotiate("gzip;q=0.5, BR;q=0.5"),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(ContentEncoding::negotiate("br;q=0"), None);
+        assert_eq!(
+            ContentEncoding::negotiate("br;q=invalid, gzip"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(ContentEncoding::negotiate("identity"), None);
+        assert_eq!(ContentEncoding::negotiate(""), None);
+    }
+
+    #[test]
+    fn compressible() {
+        assert!(is_compressible("application/json; charset=utf-8"));
+        assert!(is_compressible("text/html"));
+        assert!(is_compressible("application/ld+json"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/octet-stream"));
+    }
+
+    #[test]
+    fn compress_gzip() {
+        let body = "Hello world".repeat(100);
+        let compressed = ContentEncoding::Gzip.compress(body.as_bytes()).unwrap();
+        assert!(compressed.len() < body.len());
+
+        let mut decompressed = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn decompress() {
+        let body = "Hello world".repeat(100);
+
+        for encoding in ["gzip", "deflate", "BR"] {
+            let encoding = ContentEncoding::from_header(encoding).unwrap();
+            let compressed = encoding.compress(body.as_bytes()).unwrap();
+
+            assert_eq!(
+                encoding.decompress(&compressed, body.len()).unwrap(),
+                Some(body.clone().into_bytes())
+            );
+        }
+
+        assert_eq!(ContentEncoding::from_header("gzip, br"), None);
+        assert!(ContentEncoding::Gzip.decompress(b"invalid", 1024).is_err());
+    }
+
+    #[test]
+    fn decompress_bomb() {
+        let compressed = ContentEncoding::Gzip
+            .compress(&vec![0; 1024 * 1024])
+            .unwrap();
+        assert!(compressed.len() < 10 * 1024);
+
+        assert_eq!(
+            ContentEncoding::Gzip
+                .decompress(&compressed, 64 * 1024)
+                .unwrap(),
+            None
+        );
+    }
+}
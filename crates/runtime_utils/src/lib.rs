@@ -1,14 +1,19 @@
 use anyhow::{anyhow, Result};
-
+use hyper::Method;
+use ipnet::IpNet;
+use memmap2::Mmap;
 use std::{
     collections::{HashMap, HashSet},
-    env,
+    env, fmt,
     fs::{self, File},
     io::Write,
     path::Path,
+    sync::Arc,
+    time::Duration,
 };
 
 pub mod assets;
+pub mod compression;
 pub mod response;
 
 #[cfg(not(feature = "test"))]
@@ -17,6 +22,11 @@ pub const DEPLOYMENTS_DIR: &str = "deployments";
 #[cfg(feature = "test")]
 pub const DEPLOYMENTS_DIR: &str = "deployments_test";
 
+// Linux truncates thread names longer than 15 bytes
+pub const MAX_THREAD_NAME_LEN: usize = 15;
+// Characters of the deployment id kept in isolate thread names
+const THREAD_NAME_ID_LEN: usize = 4;
+
 #[derive(Debug, Clone, Default)]
 pub struct Deployment {
     pub id: String,
@@ -30,6 +40,108 @@ pub struct Deployment {
     pub total_timeout: usize, // in ms (MilliSeconds)
     pub is_production: bool,
     pub cron: Option<String>,
+    // IANA name of the timezone the cron is evaluated in, UTC if not set
+    pub cron_timezone: Option<String>,
+    // Asset served for every path that doesn't match another asset,
+    // e.g `index.html` for single-page applications
+    pub spa_fallback: Option<String>,
+    // Requests are answered with a 503 without invoking the isolate
+    pub maintenance: bool,
+    // Send requests to `/favicon.ico` to the function when there's
+    // no favicon asset, instead of answering with a 404
+    pub function_favicon: bool,
+    // Expected hex-encoded SHA-256 of the code, checked after downloading it
+    pub code_hash: Option<String>,
+    // Client addresses allowed or denied to call the deployment,
+    // every address is allowed if both lists are empty
+    pub ip_allow_list: Vec<IpNet>,
+    pub ip_deny_list: Vec<IpNet>,
+    // Injected in the environment like environment variables,
+    // but never printed in logs
+    pub secrets: Secrets,
+    // Terminate the isolate once its requests are done instead of
+    // keeping it warm, e.g for one-shot jobs triggered over HTTP
+    pub ephemeral: bool,
+    // Suspended by the control plane (e.g billing or abuse), requests
+    // are answered with a 403 without invoking the isolate
+    pub suspended: bool,
+    // Compress responses of the function (e.g JSON or HTML) when
+    // the client supports it and they aren't already compressed
+    pub compress: bool,
+    // Version of the runtime the deployment is pinned to, which
+    // selects the snapshot its isolates are created from
+    pub runtime_version: Option<String>,
+    // New deployment taking a share of this deployment's
+    // traffic before being promoted
+    pub canary: Option<Canary>,
+    // Answer errors with a JSON body to clients accepting it,
+    // see `ServerConfig::json_errors` to enable it globally
+    pub json_errors: bool,
+    // Added to every response of the deployment (e.g security headers
+    // like CSP or HSTS), unless the function already set them
+    pub response_headers: HashMap<String, String>,
+    // Maximum size of request bodies in bytes, unlimited if not set
+    pub max_body_size: Option<usize>,
+    // CPU time the deployment can use per window, requests are
+    // rejected once it's exceeded until the window is over
+    pub cpu_budget: Option<CpuBudget>,
+    // Keep the isolate warm instead of evicting it when idle or
+    // when the maximum number of isolates is reached
+    pub pinned: bool,
+    // Placeholder registered while the deployment is downloaded, whose
+    // requests are answered with a 503 until it's ready
+    pub provisioning: bool,
+    // Deployment receiving a copy of every request in the background,
+    // whose responses are discarded, e.g to try a candidate on real traffic
+    pub shadow_deployment_id: Option<String>,
+    // Only serve the paths under this prefix (e.g `/api`) on the deployment's
+    // domains, so a domain can be shared by multiple deployments
+    pub path_prefix: Option<String>,
+    // Send every request to the function, including `/favicon.ico`,
+    // without looking for a matching asset, e.g for API-only deployments
+    pub disable_assets: bool,
+    // Decompress request bodies sent with a `Content-Encoding` (gzip,
+    // deflate or br) before passing them to the function
+    pub decompress_body: bool,
+    // Methods handled by the function (e.g `GET` and `POST`), other methods are
+    // answered with a 405 without invoking the isolate. Every method is allowed if empty.
+    pub allowed_methods: Vec<Method>,
+    // Share of the requests written to ClickHouse, overriding
+    // `ServerConfig::request_sample_rate` if set
+    pub request_sample_rate: Option<f64>,
+    // Bodies served (with a 200) for paths like `/robots.txt` or
+    // `/.well-known/security.txt` when there's no matching asset
+    pub static_responses: HashMap<String, String>,
+    // Answer `/robots.txt` (allowing every crawler) and `/.well-known/*` (with a 404)
+    // when there's no matching asset or static response, instead of sending them
+    // to the function
+    pub builtin_static_routes: bool,
+    // Critical assets (e.g `index.css`) preloaded with a `Link` header on the
+    // function's responses, see `ServerConfig::preload_links`
+    pub preload_assets: Vec<String>,
+    // Deployment serving the request instead when the function throws or
+    // times out, e.g a stable version of the function
+    pub fallback_deployment_id: Option<String>,
+    // Content types (e.g `application/json`) of the request bodies handled by the
+    // function, other bodies are answered with a 415 without invoking the isolate.
+    // Every content type is allowed if empty.
+    pub allowed_content_types: Vec<String>,
+    // Serve the cacheable responses of the function (per their `Cache-Control`)
+    // from memory until they expire, see `ServerConfig::response_cache`
+    pub cache_responses: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuBudget {
+    pub cpu_time: Duration,
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Canary {
+    pub deployment: Arc<Deployment>,
+    // Share of the clients (from 0 to 100) routed to the canary
+    pub percentage: u8,
 }
 
 impl Deployment {
@@ -53,55 +165,170 @@ impl Deployment {
             domains.extend(self.domains.clone());
         }
 
+        // Hostnames are matched lowercased, so domains only differing
+        // by their case would register the same deployment twice
+        let mut seen = HashSet::new();
+
+        // Deployments with a path prefix are registered under `<domain><prefix>`,
+        // see `get_deployment`. Paths are case-sensitive, unlike hostnames.
         domains
+            .into_iter()
+            .map(|domain| domain.trim().to_lowercase())
+            .filter(|domain| seen.insert(domain.clone()))
+            .map(|domain| match &self.path_prefix {
+                Some(path_prefix) => format!("{domain}{path_prefix}"),
+                None => domain,
+            })
+            .collect()
+    }
+
+    // Name of the thread running the deployment's isolate, e.g `i-hello-x7k2`
+    // with the end of the deployment id (its beginning is a timestamp). The
+    // function name is shortened so the name fits in `MAX_THREAD_NAME_LEN`,
+    // which keeps it legible in `top -H` or thread dumps.
+    pub fn get_isolate_thread_name(&self) -> String {
+        let id_start = self
+            .id
+            .char_indices()
+            .rev()
+            .nth(THREAD_NAME_ID_LEN - 1)
+            .map_or(0, |(index, _)| index);
+        let short_id = &self.id[id_start..];
+
+        let max_function_len = MAX_THREAD_NAME_LEN.saturating_sub(short_id.len() + 3);
+        let function_name = self
+            .function_name
+            .char_indices()
+            .take_while(|(index, char)| index + char.len_utf8() <= max_function_len)
+            .map(|(_, char)| char)
+            .collect::<String>();
+        let function_name = function_name.trim_end_matches('-');
+
+        match function_name.is_empty() {
+            true => format!("i-{}", short_id),
+            false => format!("i-{}-{}", function_name, short_id),
+        }
     }
 
     pub fn should_run_cron(&self) -> bool {
         self.is_production && self.cron.is_some()
     }
 
-    pub fn get_code(&self) -> Result<String> {
-        let path = Path::new(env::current_dir()?.as_path())
-            .join(DEPLOYMENTS_DIR)
-            .join(self.id.clone() + ".js");
-        let code = fs::read_to_string(path)?;
+    // Environment variables of the isolate, secrets included
+    pub fn get_environment_variables(&self) -> HashMap<String, String> {
+        let mut environment_variables = self.environment_variables.clone();
+        environment_variables.extend(self.secrets.0.clone());
 
-        Ok(code)
+        environment_variables
     }
 
-    pub fn has_code(&self) -> bool {
-        let path = Path::new(DEPLOYMENTS_DIR).join(self.id.clone() + ".js");
+    // Parameters (e.g `; charset=utf-8`) and case are ignored, so
+    // `Application/JSON; charset=utf-8` is `application/json`
+    pub fn is_content_type_allowed(&self, content_type: Option<&str>) -> bool {
+        if self.allowed_content_types.is_empty() {
+            return true;
+        }
+
+        let content_type = match content_type {
+            Some(content_type) => content_type.split(';').next().unwrap_or_default().trim(),
+            None => return false,
+        };
+
+        self.allowed_content_types
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(content_type))
+    }
+
+    pub fn get_code(&self, deployments_dir: &Path) -> Result<DeploymentCode> {
+        let file = File::open(deployments_dir.join(self.id.clone() + ".js"))?;
+
+        DeploymentCode::map(&file)
+    }
+
+    pub fn has_code(&self, deployments_dir: &Path) -> bool {
+        let path = deployments_dir.join(self.id.clone() + ".js");
 
         path.exists()
     }
 
-    pub fn write_code(&self, code: &[u8]) -> Result<()> {
-        let mut file = File::create(Path::new(DEPLOYMENTS_DIR).join(self.id.clone() + ".js"))?;
+    pub fn write_code(&self, deployments_dir: &Path, code: &[u8]) -> Result<()> {
+        // The code might currently be memory-mapped by an isolate, so we
+        // never write to the existing file in place but replace it instead
+        let path = deployments_dir.join(self.id.clone() + ".js");
+        let tmp_path = deployments_dir.join(self.id.clone() + ".js.tmp");
 
+        let mut file = File::create(&tmp_path)?;
         file.write_all(code)?;
+        fs::rename(tmp_path, path)?;
 
         Ok(())
     }
 
-    pub fn write_asset(&self, asset: &str, content: &[u8]) -> Result<()> {
+    pub fn write_asset(&self, deployments_dir: &Path, asset: &str, content: &[u8]) -> Result<()> {
         let asset = asset.replace("public/", "");
         let asset = asset.as_str();
 
-        let dir = Path::new(DEPLOYMENTS_DIR).join(self.id.clone()).join(
+        let dir = deployments_dir.join(self.id.clone()).join(
             Path::new(asset)
                 .parent()
                 .ok_or_else(|| anyhow!("Could not get parent of {}", asset))?,
         );
         fs::create_dir_all(dir)?;
 
-        let mut file =
-            File::create(Path::new(DEPLOYMENTS_DIR).join(self.id.clone() + "/" + asset))?;
+        // Same as the code, the asset might currently be served
+        let path = deployments_dir.join(self.id.clone() + "/" + asset);
+        let tmp_path = deployments_dir.join(self.id.clone() + "/" + asset + ".tmp");
+
+        let mut file = File::create(&tmp_path)?;
         file.write_all(content)?;
+        fs::rename(tmp_path, path)?;
 
         Ok(())
     }
 }
 
+/// Secrets of a deployment, whose values are redacted when debug-printed.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secrets(pub HashMap<String, String>);
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.keys().map(|key| (key, "[redacted]")))
+            .finish()
+    }
+}
+
+/// The code of a deployment, memory-mapped from its file on disk. Isolates
+/// don't keep their own copy of the code on the heap, and isolates of the
+/// same deployment share the same pages.
+#[derive(Default)]
+pub struct DeploymentCode(Option<Mmap>);
+
+impl DeploymentCode {
+    fn map(file: &File) -> Result<Self> {
+        if file.metadata()?.len() == 0 {
+            return Ok(Self::default());
+        }
+
+        // SAFETY: the file is never modified in place, see `Deployment::write_code`
+        let mmap = unsafe { Mmap::map(file)? };
+        std::str::from_utf8(&mmap)?;
+
+        Ok(Self(Some(mmap)))
+    }
+}
+
+impl AsRef<str> for DeploymentCode {
+    fn as_ref(&self) -> &str {
+        match &self.0 {
+            // SAFETY: the content has been validated as UTF-8 in `DeploymentCode::map`
+            Some(mmap) => unsafe { std::str::from_utf8_unchecked(mmap) },
+            None => "",
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,11 +349,41 @@ mod tests {
             total_timeout: 1000,
             is_production: false,
             cron: None,
+            ..Deployment::default()
         };
 
         assert_eq!(deployment.get_domains(), vec!["123.lagon.test".to_owned()]);
     }
 
+    #[test]
+    fn isolate_thread_name() {
+        let deployment = Deployment {
+            id: "clh2x1w0a0000x7k2".into(),
+            function_name: "hello".into(),
+            ..Deployment::default()
+        };
+
+        assert_eq!(deployment.get_isolate_thread_name(), "i-hello-x7k2");
+
+        let deployment = Deployment {
+            id: "clh2x1w0a0000x7k2".into(),
+            function_name: "my-very-long-function".into(),
+            ..Deployment::default()
+        };
+
+        assert_eq!(deployment.get_isolate_thread_name(), "i-my-very-x7k2");
+        assert!(deployment.get_isolate_thread_name().len() <= MAX_THREAD_NAME_LEN);
+
+        // Multi-byte characters aren't split
+        let deployment = Deployment {
+            id: "abc".into(),
+            function_name: "héllo-wörld-fn".into(),
+            ..Deployment::default()
+        };
+
+        assert_eq!(deployment.get_isolate_thread_name(), "i-héllo-w-abc");
+    }
+
     #[test]
     fn deployment_domains() {
         env::set_var("LAGON_ROOT_DOMAIN", "lagon.test");
@@ -143,6 +400,7 @@ mod tests {
             total_timeout: 1000,
             is_production: false,
             cron: None,
+            ..Deployment::default()
         };
 
         assert_eq!(deployment.get_domains(), vec!["123.lagon.test".to_owned(),]);
@@ -164,6 +422,7 @@ mod tests {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         };
 
         assert_eq!(
@@ -175,4 +434,89 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn deployment_domains_normalized() {
+        env::set_var("LAGON_ROOT_DOMAIN", "lagon.test");
+
+        let deployment = Deployment {
+            id: "123".into(),
+            function_id: "456".into(),
+            function_name: "Hello".into(),
+            domains: HashSet::from_iter(vec![
+                "lagon.app".to_owned(),
+                " Lagon.App".to_owned(),
+                "hello.LAGON.test".to_owned(),
+            ]),
+            is_production: true,
+            ..Deployment::default()
+        };
+
+        assert_eq!(
+            deployment.get_domains(),
+            vec![
+                "123.lagon.test".to_owned(),
+                "hello.lagon.test".to_owned(),
+                "lagon.app".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn deployment_domains_path_prefix() {
+        env::set_var("LAGON_ROOT_DOMAIN", "lagon.test");
+
+        let deployment = Deployment {
+            id: "123".into(),
+            function_id: "456".into(),
+            function_name: "hello".into(),
+            domains: HashSet::from_iter(vec!["Lagon.app".to_owned()]),
+            is_production: true,
+            path_prefix: Some("/API".into()),
+            ..Deployment::default()
+        };
+
+        assert_eq!(
+            deployment.get_domains(),
+            vec![
+                "123.lagon.test/API".to_owned(),
+                "hello.lagon.test/API".to_owned(),
+                "lagon.app/API".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn deployment_secrets_redacted() {
+        let deployment = Deployment {
+            id: "123".into(),
+            environment_variables: HashMap::from([("PUBLIC".into(), "public value".into())]),
+            secrets: Secrets(HashMap::from([("TOKEN".into(), "secret value".into())])),
+            ..Deployment::default()
+        };
+
+        let debug = format!("{:?}", deployment);
+        assert!(debug.contains("TOKEN"));
+        assert!(!debug.contains("secret value"));
+
+        let environment_variables = deployment.get_environment_variables();
+        assert_eq!(environment_variables["PUBLIC"], "public value");
+        assert_eq!(environment_variables["TOKEN"], "secret value");
+    }
+
+    #[test]
+    fn deployment_allowed_content_types() {
+        let deployment = Deployment::default();
+        assert!(deployment.is_content_type_allowed(None));
+        assert!(deployment.is_content_type_allowed(Some("text/plain")));
+
+        let deployment = Deployment {
+            allowed_content_types: vec!["application/json".into()],
+            ..Deployment::default()
+        };
+        assert!(deployment.is_content_type_allowed(Some("application/json")));
+        assert!(deployment.is_content_type_allowed(Some("Application/JSON; charset=utf-8")));
+        assert!(!deployment.is_content_type_allowed(Some("text/plain")));
+        assert!(!deployment.is_content_type_allowed(None));
+    }
 }
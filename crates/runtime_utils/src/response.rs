@@ -1,29 +1,115 @@
-use crate::Deployment;
+use crate::{
+    compression::{is_compressible, ContentEncoding, MIN_COMPRESSION_SIZE},
+    Deployment,
+};
 use anyhow::Result;
-use flume::Receiver;
+use flume::{Receiver, Sender};
 use hyper::{
-    body::{Bytes, HttpBody},
-    Body, Response,
+    body::{self, Bytes, HttpBody},
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+    http::{
+        response::{Builder, Parts},
+        HeaderName, HeaderValue,
+    },
+    Body, Response, StatusCode,
 };
 use lagon_runtime_http::{RunResult, StreamResult};
+use serde_json::json;
 use std::{future::Future, sync::Arc};
 
 pub const PAGE_404: &str = include_str!("../public/404.html");
 pub const PAGE_403: &str = include_str!("../public/403.html");
 pub const PAGE_502: &str = include_str!("../public/502.html");
+pub const PAGE_503: &str = include_str!("../public/503.html");
 pub const PAGE_500: &str = include_str!("../public/500.html");
 pub const FAVICON_URL: &str = "/favicon.ico";
+pub const ROBOTS_URL: &str = "/robots.txt";
+pub const WELL_KNOWN_PREFIX: &str = "/.well-known/";
+// Allow every crawler, like a missing robots.txt
+const DEFAULT_ROBOTS: &str = "User-agent: *\nAllow: /\n";
+
+// Response to a path browsers and crawlers request on every domain,
+// answered without invoking the isolate
+#[derive(Debug, PartialEq, Eq)]
+pub enum StaticResponse<'a> {
+    // Served with a 200
+    Body(&'a str),
+    NotFound,
+}
+
+// Static response of the path if there's no matching asset: the deployment's
+// static responses come first, then `/favicon.ico` is answered with a 404.
+// With `Deployment::builtin_static_routes`, `/.well-known/*` is also answered
+// with a 404 and `/robots.txt` allows every crawler.
+pub fn find_static_response<'a>(
+    url: &str,
+    deployment: &'a Deployment,
+) -> Option<StaticResponse<'a>> {
+    if let Some(body) = deployment.static_responses.get(url) {
+        return Some(StaticResponse::Body(body));
+    }
+
+    if deployment.disable_assets {
+        return None;
+    }
+
+    if url == FAVICON_URL {
+        return (!deployment.function_favicon).then(|| StaticResponse::NotFound);
+    }
+
+    if !deployment.builtin_static_routes {
+        return None;
+    }
+
+    if url == ROBOTS_URL {
+        Some(StaticResponse::Body(DEFAULT_ROBOTS))
+    } else if url.starts_with(WELL_KNOWN_PREFIX) {
+        Some(StaticResponse::NotFound)
+    } else {
+        None
+    }
+}
 
 #[derive(Debug)]
 pub enum ResponseEvent {
-    Bytes(usize, Option<u128>),
+    // Bytes sent (summed across all chunks for streams), cpu
+    // time, status code and peak memory of the response
+    Bytes(usize, Option<u128>, u16, Option<usize>),
     StreamDoneNoDataError,
     UnexpectedStreamResult(RunResult),
     LimitsReached(RunResult),
     Error(RunResult),
 }
 
+// How the response is sent back, based on the client's request
+#[derive(Debug, Clone, Default)]
+pub struct ResponseOptions {
+    // Encoding accepted by the client, see `ContentEncoding::negotiate`
+    pub encoding: Option<ContentEncoding>,
+    // Answer errors with a JSON body (containing `request_id`)
+    // instead of an HTML page
+    pub json_errors: bool,
+    pub request_id: String,
+}
+
 const X_ROBOTS_TAGS: &str = "x-robots-tag";
+// Maximum number of chunks buffered for a streaming response
+// before we stop reading more from the isolate
+const STREAM_BUFFER_CHUNKS: usize = 16;
+
+// Headers set by the function (or the asset) take precedence over the
+// deployment's response headers, which are only added when missing.
+// Invalid header names or values are ignored.
+pub fn inject_response_headers(response: &mut Response<Body>, deployment: &Deployment) {
+    for (name, value) in &deployment.response_headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().entry(name).or_insert(value);
+        }
+    }
+}
 
 fn enrich_response(response: &mut Response<Body>, deployment: &Deployment) {
     // We automatically add a X-Robots-Tag: noindex header to
@@ -36,9 +122,116 @@ fn enrich_response(response: &mut Response<Body>, deployment: &Deployment) {
     }
 }
 
+// Compress the body of deployments with compression enabled, if it's fully
+// buffered (streams and assets aren't), not already compressed, and its
+// content type is worth compressing
+async fn compress_response(
+    response: Response<Body>,
+    deployment: &Deployment,
+    encoding: Option<ContentEncoding>,
+) -> Result<Response<Body>> {
+    let compressible = deployment.compress
+        && !response.headers().contains_key(CONTENT_ENCODING)
+        && response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(false, is_compressible);
+
+    if !compressible {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+
+    // The response depends on the client's Accept-Encoding even
+    // when it isn't compressed, e.g for caches in front of us.
+    // Cached responses already vary on it.
+    let varies = parts
+        .headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case(ACCEPT_ENCODING.as_str()));
+
+    if !varies {
+        parts.headers.append(VARY, ACCEPT_ENCODING.into());
+    }
+
+    let size = body.size_hint().exact().unwrap_or(0) as usize;
+
+    match encoding {
+        Some(encoding) if size >= MIN_COMPRESSION_SIZE => {
+            let body = body::to_bytes(body).await?;
+            let compressed = encoding.compress(&body)?;
+
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, encoding.as_str().parse()?);
+
+            Ok(Response::from_parts(parts, compressed.into()))
+        }
+        _ => Ok(Response::from_parts(parts, body)),
+    }
+}
+
+pub fn accepts_json(accept: &str) -> bool {
+    accept.split(',').any(|media_range| {
+        media_range
+            .split(';')
+            .next()
+            .map_or(false, |mime| mime.trim() == "application/json")
+    })
+}
+
+fn error_response(
+    status: u16,
+    page: &'static str,
+    error: &str,
+    options: &ResponseOptions,
+) -> Result<Response<Body>> {
+    if !options.json_errors {
+        return Ok(Response::builder().status(status).body(page.into())?);
+    }
+
+    let body = json!({
+        "error": error,
+        "request_id": options.request_id,
+    });
+
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string().into())?)
+}
+
+// Stream responses only give us a builder, from which we can't read the status
+fn response_parts(response: Builder, status: &mut StatusCode) -> Result<Parts> {
+    let (parts, _) = response.body(())?.into_parts();
+    *status = parts.status;
+
+    Ok(parts)
+}
+
+// Chunks received before the response parts can't be sent to the body yet,
+// since hyper only polls it once we returned the response
+async fn forward_chunk(
+    stream_tx: &Sender<Result<Bytes, std::io::Error>>,
+    pending_chunks: &mut Option<Vec<Bytes>>,
+    bytes: Bytes,
+) {
+    match pending_chunks {
+        Some(chunks) => chunks.push(bytes),
+        None => stream_tx.send_async(Ok(bytes)).await.unwrap_or(()),
+    }
+}
+
 pub async fn handle_response<F>(
     rx: Receiver<RunResult>,
     deployment: Arc<Deployment>,
+    options: ResponseOptions,
     on_event: impl Fn(ResponseEvent) -> F + Send + Sync + 'static,
 ) -> Result<Response<Body>>
 where
@@ -48,27 +241,34 @@ where
 
     match result {
         RunResult::Stream(stream_result) => {
-            let (stream_tx, stream_rx) = flume::unbounded::<Result<Bytes, std::io::Error>>();
+            // The body channel is bounded so a slow client stops us from reading
+            // more chunks, which in turn makes the isolate pause the stream
+            let (stream_tx, stream_rx) =
+                flume::bounded::<Result<Bytes, std::io::Error>>(STREAM_BUFFER_CHUNKS);
             let body = Body::wrap_stream(stream_rx.into_stream());
 
-            let (response_builder_tx, response_builder_rx) = flume::bounded(1);
+            let (response_parts_tx, response_parts_rx) = flume::bounded(1);
+            let mut pending_chunks = Some(Vec::new());
             let mut total_bytes = 0;
+            let mut status = StatusCode::OK;
 
             match stream_result {
                 StreamResult::Start(response) => {
-                    response_builder_tx.send_async(response).await.unwrap_or(());
+                    let parts = response_parts(response, &mut status);
+                    response_parts_tx.send_async(parts).await.unwrap_or(());
+                    pending_chunks = None;
                 }
                 StreamResult::Data(bytes) => {
                     total_bytes += bytes.len();
 
                     let bytes = Bytes::from(bytes);
-                    stream_tx.send_async(Ok(bytes)).await.unwrap_or(());
+                    forward_chunk(&stream_tx, &mut pending_chunks, bytes).await;
                 }
                 StreamResult::Done(_) => {
                     on_event(ResponseEvent::StreamDoneNoDataError).await?;
 
                     // Close the stream by sending empty bytes
-                    stream_tx.send_async(Ok(Bytes::new())).await.unwrap_or(());
+                    forward_chunk(&stream_tx, &mut pending_chunks, Bytes::new()).await;
                 }
             }
 
@@ -76,21 +276,33 @@ where
                 while let Ok(result) = rx.recv_async().await {
                     match result {
                         RunResult::Stream(StreamResult::Start(response)) => {
-                            response_builder_tx.send_async(response).await.unwrap_or(());
+                            let parts = response_parts(response, &mut status);
+                            response_parts_tx.send_async(parts).await.unwrap_or(());
+
+                            if let Some(chunks) = pending_chunks.take() {
+                                for bytes in chunks {
+                                    stream_tx.send_async(Ok(bytes)).await.unwrap_or(());
+                                }
+                            }
                         }
                         RunResult::Stream(StreamResult::Data(bytes)) => {
                             total_bytes += bytes.len();
 
                             let bytes = Bytes::from(bytes);
-                            stream_tx.send_async(Ok(bytes)).await.unwrap_or(());
+                            forward_chunk(&stream_tx, &mut pending_chunks, bytes).await;
                         }
-                        RunResult::Stream(StreamResult::Done(elapsed)) => {
-                            on_event(ResponseEvent::Bytes(total_bytes, Some(elapsed.as_micros())))
-                                .await
-                                .unwrap_or(());
+                        RunResult::Stream(StreamResult::Done(stats)) => {
+                            on_event(ResponseEvent::Bytes(
+                                total_bytes,
+                                Some(stats.cpu_time.as_micros()),
+                                status.as_u16(),
+                                Some(stats.peak_memory),
+                            ))
+                            .await
+                            .unwrap_or(());
 
                             // Close the stream by sending empty bytes
-                            stream_tx.send_async(Ok(Bytes::new())).await.unwrap_or(());
+                            forward_chunk(&stream_tx, &mut pending_chunks, Bytes::new()).await;
                         }
                         _ => {
                             on_event(ResponseEvent::UnexpectedStreamResult(result))
@@ -105,34 +317,53 @@ where
                 }
             });
 
-            let response_builder = response_builder_rx.recv_async().await?;
-            let mut response = response_builder.body(body)?;
+            let parts = response_parts_rx.recv_async().await??;
+            let mut response = Response::from_parts(parts, body);
 
             enrich_response(&mut response, &deployment);
 
             Ok(response)
         }
-        RunResult::Response(mut response, elapsed) => {
+        RunResult::Response(response, stats) => {
+            let mut response = compress_response(response, &deployment, options.encoding).await?;
             enrich_response(&mut response, &deployment);
 
-            let bytes = response.body().size_hint().exact().unwrap_or(0);
-            let event =
-                ResponseEvent::Bytes(bytes as usize, elapsed.map(|duration| duration.as_micros()));
+            // Streamed bodies (e.g assets) don't know their size, but
+            // usually have a Content-Length header
+            let bytes = response.body().size_hint().exact().unwrap_or_else(|| {
+                response
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(0)
+            });
+            let event = ResponseEvent::Bytes(
+                bytes as usize,
+                stats.map(|stats| stats.cpu_time.as_micros()),
+                response.status().as_u16(),
+                stats.map(|stats| stats.peak_memory),
+            );
             on_event(event).await?;
 
             Ok(response)
         }
         RunResult::Timeout | RunResult::MemoryLimit => {
+            let error = match result {
+                RunResult::Timeout => "timeout",
+                _ => "memory_limit",
+            };
+
             let event = ResponseEvent::LimitsReached(result);
             on_event(event).await?;
 
-            Ok(Response::builder().status(502).body(PAGE_502.into())?)
+            error_response(502, PAGE_502, error, &options)
         }
         RunResult::Error(_) => {
             let event = ResponseEvent::Error(result);
             on_event(event).await?;
 
-            Ok(Response::builder().status(500).body(PAGE_500.into())?)
+            error_response(500, PAGE_500, "error", &options)
         }
     }
 }
@@ -141,7 +372,8 @@ where
 mod tests {
     use super::*;
     use hyper::{body::to_bytes, Response};
-    use std::time::Duration;
+    use lagon_runtime_http::RequestStats;
+    use std::{collections::HashMap, time::Duration};
 
     #[tokio::test]
     async fn sequential() {
@@ -149,11 +381,16 @@ mod tests {
 
         let handle = tokio::spawn(async move {
             let deployment = Arc::new(Deployment::default());
-            let mut response = handle_response(rx, deployment, |event| async move {
-                assert!(matches!(event, ResponseEvent::Bytes(11, None)));
-
-                Ok(())
-            })
+            let mut response = handle_response(
+                rx,
+                deployment,
+                ResponseOptions::default(),
+                |event| async move {
+                    assert!(matches!(event, ResponseEvent::Bytes(11, None, 200, None)));
+
+                    Ok(())
+                },
+            )
             .await
             .unwrap();
 
@@ -185,11 +422,16 @@ mod tests {
                 ..Deployment::default()
             });
 
-            let mut response = handle_response(rx, deployment, |event| async move {
-                assert!(matches!(event, ResponseEvent::Bytes(11, None)));
+            let mut response = handle_response(
+                rx,
+                deployment,
+                ResponseOptions::default(),
+                |event| async move {
+                    assert!(matches!(event, ResponseEvent::Bytes(11, None, 200, None)));
 
-                Ok(())
-            })
+                    Ok(())
+                },
+            )
             .await
             .unwrap();
 
@@ -217,11 +459,19 @@ mod tests {
 
         let handle = tokio::spawn(async move {
             let deployment = Arc::new(Deployment::default());
-            let mut response = handle_response(rx, deployment, |event| async move {
-                assert!(matches!(event, ResponseEvent::Bytes(11, Some(0))));
-
-                Ok(())
-            })
+            let mut response = handle_response(
+                rx,
+                deployment,
+                ResponseOptions::default(),
+                |event| async move {
+                    assert!(matches!(
+                        event,
+                        ResponseEvent::Bytes(11, Some(0), 200, Some(0))
+                    ));
+
+                    Ok(())
+                },
+            )
             .await
             .unwrap();
 
@@ -245,9 +495,9 @@ mod tests {
             .await
             .unwrap();
 
-        tx.send_async(RunResult::Stream(StreamResult::Done(Duration::from_secs(
-            0,
-        ))))
+        tx.send_async(RunResult::Stream(StreamResult::Done(
+            RequestStats::default(),
+        )))
         .await
         .unwrap();
 
@@ -266,11 +516,19 @@ mod tests {
                 ..Deployment::default()
             });
 
-            let mut response = handle_response(rx, deployment, |event| async move {
-                assert!(matches!(event, ResponseEvent::Bytes(11, Some(0))));
-
-                Ok(())
-            })
+            let mut response = handle_response(
+                rx,
+                deployment,
+                ResponseOptions::default(),
+                |event| async move {
+                    assert!(matches!(
+                        event,
+                        ResponseEvent::Bytes(11, Some(0), 200, Some(0))
+                    ));
+
+                    Ok(())
+                },
+            )
             .await
             .unwrap();
 
@@ -294,9 +552,9 @@ mod tests {
             .await
             .unwrap();
 
-        tx.send_async(RunResult::Stream(StreamResult::Done(Duration::from_secs(
-            0,
-        ))))
+        tx.send_async(RunResult::Stream(StreamResult::Done(
+            RequestStats::default(),
+        )))
         .await
         .unwrap();
 
@@ -311,11 +569,19 @@ mod tests {
 
         let handle = tokio::spawn(async move {
             let deployment = Arc::new(Deployment::default());
-            let mut response = handle_response(rx, deployment, |event| async move {
-                assert!(matches!(event, ResponseEvent::Bytes(11, Some(0))));
-
-                Ok(())
-            })
+            let mut response = handle_response(
+                rx,
+                deployment,
+                ResponseOptions::default(),
+                |event| async move {
+                    assert!(matches!(
+                        event,
+                        ResponseEvent::Bytes(11, Some(0), 200, Some(0))
+                    ));
+
+                    Ok(())
+                },
+            )
             .await
             .unwrap();
 
@@ -339,14 +605,122 @@ mod tests {
             .await
             .unwrap();
 
-        tx.send_async(RunResult::Stream(StreamResult::Done(Duration::from_secs(
-            0,
-        ))))
+        tx.send_async(RunResult::Stream(StreamResult::Done(
+            RequestStats::default(),
+        )))
+        .await
+        .unwrap();
+
+        drop(tx);
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stream_backpressure() {
+        let (tx, rx) = flume::unbounded::<RunResult>();
+
+        tx.send_async(RunResult::Stream(StreamResult::Start(Response::builder())))
+            .await
+            .unwrap();
+
+        for _ in 0..STREAM_BUFFER_CHUNKS * 4 {
+            tx.send_async(RunResult::Stream(StreamResult::Data(b"a".to_vec())))
+                .await
+                .unwrap();
+        }
+
+        let deployment = Arc::new(Deployment::default());
+        let mut response = handle_response(
+            rx,
+            deployment,
+            ResponseOptions::default(),
+            |event| async move {
+                assert!(matches!(
+                    event,
+                    ResponseEvent::Bytes(bytes, Some(0), 200, Some(0)) if bytes == STREAM_BUFFER_CHUNKS * 4
+                ));
+
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The body isn't consumed, so chunks should stay in the channel
+        assert!(!tx.is_empty());
+
+        tx.send_async(RunResult::Stream(StreamResult::Done(
+            RequestStats::default(),
+        )))
         .await
         .unwrap();
 
         drop(tx);
 
+        assert_eq!(
+            to_bytes(response.body_mut()).await.unwrap(),
+            Bytes::from("a".repeat(STREAM_BUFFER_CHUNKS * 4))
+        );
+    }
+
+    #[test]
+    fn accept_json() {
+        assert!(accepts_json("application/json"));
+        assert!(accepts_json("text/html, application/json;q=0.9"));
+        assert!(!accepts_json("text/html"));
+        assert!(!accepts_json("*/*"));
+    }
+
+    #[tokio::test]
+    async fn json_error() {
+        let (tx, rx) = flume::unbounded::<RunResult>();
+
+        let handle = tokio::spawn(async move {
+            let deployment = Arc::new(Deployment::default());
+            let options = ResponseOptions {
+                json_errors: true,
+                request_id: String::from("request"),
+                ..ResponseOptions::default()
+            };
+
+            let mut response = handle_response(rx, deployment, options, |_| async move { Ok(()) })
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), 502);
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+            assert_eq!(
+                to_bytes(response.body_mut()).await.unwrap(),
+                Bytes::from(r#"{"error":"memory_limit","request_id":"request"}"#)
+            );
+        });
+
+        tx.send_async(RunResult::MemoryLimit).await.unwrap();
+
         handle.await.unwrap();
     }
+
+    #[test]
+    fn response_headers() {
+        let deployment = Deployment {
+            response_headers: HashMap::from([
+                (String::from("x-frame-options"), String::from("DENY")),
+                (String::from("x-custom"), String::from("deployment")),
+            ]),
+            ..Deployment::default()
+        };
+
+        let mut response = Response::builder()
+            .header("x-custom", "function")
+            .body("Hello World".into())
+            .unwrap();
+        inject_response_headers(&mut response, &deployment);
+
+        assert_eq!(response.headers()["x-frame-options"], "DENY");
+        // The function's header takes precedence
+        assert_eq!(response.headers()["x-custom"], "function");
+    }
 }
@@ -0,0 +1,7 @@
+// Kept alive so the allocations can't be garbage collected
+const buffers = [];
+
+export function handler() {
+  buffers.push(new Uint8Array(16 * 1024 * 1024));
+  return new Response('Allocated');
+}
@@ -0,0 +1,8 @@
+export function handler(request) {
+  if (new URL(request.url).pathname === '/busy') {
+    const start = Date.now();
+    while (Date.now() - start < 500) {}
+  }
+
+  return new Response('Hello world');
+}
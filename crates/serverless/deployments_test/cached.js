@@ -0,0 +1,18 @@
+let count = 0;
+
+const CACHE_CONTROL = {
+  '/private': 'private, max-age=60',
+  '/short': 'public, max-age=1',
+};
+
+export function handler(request) {
+  count += 1;
+
+  const { pathname } = new URL(request.url);
+
+  return new Response(count.toString(), {
+    headers: {
+      'cache-control': CACHE_CONTROL[pathname] ?? 'public, max-age=60',
+    },
+  });
+}
@@ -0,0 +1,7 @@
+export async function handler(request) {
+  return new Response(await request.text(), {
+    headers: {
+      'x-content-encoding': request.headers.get('content-encoding') ?? '',
+    },
+  });
+}
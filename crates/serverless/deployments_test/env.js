@@ -0,0 +1,3 @@
+export function handler() {
+  return new Response(process.env.MESSAGE);
+}
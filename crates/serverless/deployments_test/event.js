@@ -0,0 +1,11 @@
+let lastEvent = null;
+
+export function handler(request) {
+  if (request instanceof Request) {
+    return new Response(JSON.stringify(lastEvent));
+  }
+
+  lastEvent = request;
+
+  return { received: true };
+}
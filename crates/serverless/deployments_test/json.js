@@ -0,0 +1,9 @@
+export function handler() {
+  const items = Array.from({ length: 100 }, (_, id) => ({ id, name: `Item ${id}` }));
+
+  return new Response(JSON.stringify(items), {
+    headers: {
+      'content-type': 'application/json',
+    },
+  });
+}
@@ -0,0 +1,5 @@
+await Promise.reject(new Error('Init error'));
+
+export function handler() {
+  return new Response('Hello world');
+}
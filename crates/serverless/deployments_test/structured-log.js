@@ -0,0 +1,4 @@
+export function handler() {
+  console.log('Signed in', { userId: 42, admin: false });
+  return new Response('Logged');
+}
@@ -0,0 +1,5 @@
+throw new Error('Init error');
+
+export function handler() {
+  return new Response('Hello world');
+}
@@ -0,0 +1,5 @@
+const start = Date.now();
+
+export function handler() {
+  return new Response((Date.now() - start).toString());
+}
@@ -0,0 +1,95 @@
+use crate::{
+    cronjob::Cronjob,
+    deployments::{
+        cache::{clear_isolate, get_isolate},
+        get_deployments_summary,
+        handoff::export_state,
+        Deployments,
+    },
+    serverless::{ServerConfig, Workers},
+};
+use anyhow::Result;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{error, info};
+use serde::Serialize;
+use serde_json::json;
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
+use tokio::sync::Mutex;
+
+fn json_response<T: Serialize>(value: &T) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(value)?.into())?)
+}
+
+async fn handle_admin_request(
+    req: Request<Body>,
+    deployments: Deployments,
+    cronjob: Arc<Mutex<Cronjob>>,
+    config: Arc<ServerConfig>,
+    workers: Workers,
+) -> Result<Response<Body>> {
+    if let Some(deployment_id) = req.uri().path().strip_prefix("/isolates/") {
+        return match *req.method() {
+            Method::GET => json_response(&get_isolate(&workers, deployment_id)),
+            Method::DELETE => json_response(&json!({
+                "deploymentId": deployment_id,
+                "cleared": clear_isolate(&workers, deployment_id).await,
+            })),
+            _ => Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())?),
+        };
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/deployments") => json_response(&get_deployments_summary(&deployments)),
+        (&Method::GET, "/crons") => json_response(&cronjob.lock().await.jobs()),
+        (&Method::GET, "/latency") => json_response(&config.latency.summary()),
+        (&Method::GET, "/handoff") => json_response(&export_state(&deployments, &workers)),
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())?),
+    }
+}
+
+// The admin server exposes information about this node and lets operators
+// clear isolates, so it should only be reachable from the internal network
+pub fn start_admin(
+    addr: SocketAddr,
+    deployments: Deployments,
+    cronjob: Arc<Mutex<Cronjob>>,
+    config: Arc<ServerConfig>,
+    workers: Workers,
+) -> Result<impl Future<Output = ()> + Send> {
+    let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let deployments = Arc::clone(&deployments);
+        let cronjob = Arc::clone(&cronjob);
+        let config = Arc::clone(&config);
+        let workers = Arc::clone(&workers);
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle_admin_request(
+                    req,
+                    Arc::clone(&deployments),
+                    Arc::clone(&cronjob),
+                    Arc::clone(&config),
+                    Arc::clone(&workers),
+                )
+            }))
+        }
+    }));
+
+    info!("Admin server listening on {}", addr);
+
+    Ok(async move {
+        if let Err(error) = server.await {
+            error!("Admin server error: {}", error);
+        }
+    })
+}
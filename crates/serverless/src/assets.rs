@@ -0,0 +1,416 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use dashmap::{mapref::entry::Entry, DashMap};
+use hyper::{
+    body::{Bytes, HttpBody},
+    Body,
+};
+use lagon_runtime_utils::assets::{resolve_asset_path, RangeRequest};
+use std::{fmt, io::SeekFrom, ops::Range, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_util::io::ReaderStream;
+
+pub struct AssetStream {
+    // Total size of the asset, in bytes
+    pub size: u64,
+    // Bytes of the asset contained in `body`, the whole asset if not set
+    pub range: Option<Range<u64>>,
+    pub body: Body,
+}
+
+// Returned by stores when the requested range is outside of the asset
+#[derive(Debug)]
+pub struct RangeNotSatisfiable {
+    pub size: u64,
+}
+
+impl fmt::Display for RangeNotSatisfiable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Range not satisfiable for an asset of {} bytes",
+            self.size
+        )
+    }
+}
+
+impl std::error::Error for RangeNotSatisfiable {}
+
+// Returned by stores when the asset doesn't exist, or
+// its path resolves outside of the deployment's assets
+#[derive(Debug)]
+pub struct AssetNotFound;
+
+impl fmt::Display for AssetNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Asset not found")
+    }
+}
+
+impl std::error::Error for AssetNotFound {}
+
+// Where the assets of deployments are read from when serving them,
+// e.g the local filesystem or an object storage. When a range is
+// requested, only these bytes should be returned.
+#[async_trait]
+pub trait AssetStore: Send + Sync {
+    async fn fetch(
+        &self,
+        deployment_id: &str,
+        path: &str,
+        range: Option<RangeRequest>,
+    ) -> Result<AssetStream>;
+}
+
+// Read assets from the deployments directory, where they are
+// written when downloading deployments
+pub struct FilesystemAssetStore {
+    root: PathBuf,
+}
+
+impl FilesystemAssetStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+#[async_trait]
+impl AssetStore for FilesystemAssetStore {
+    async fn fetch(
+        &self,
+        deployment_id: &str,
+        path: &str,
+        range: Option<RangeRequest>,
+    ) -> Result<AssetStream> {
+        let path = resolve_asset_path(&self.root.join(deployment_id), path).ok_or(AssetNotFound)?;
+        let mut file = File::open(path).await?;
+        let size = file.metadata().await?.len();
+
+        let range = match range {
+            Some(range) => Some(range.resolve(size).ok_or(RangeNotSatisfiable { size })?),
+            None => None,
+        };
+
+        let body = match &range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+
+                Body::wrap_stream(ReaderStream::new(file.take(range.end - range.start)))
+            }
+            None => Body::wrap_stream(ReaderStream::new(file)),
+        };
+
+        Ok(AssetStream { size, range, body })
+    }
+}
+
+// Maximum number of chunks of a coalesced read not received yet by all of
+// its waiters, after which the read waits for the slowest one
+const COALESCED_READ_CHUNKS: usize = 16;
+// How often a paused coalesced read checks if it can send more chunks
+const COALESCED_READ_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// What a coalesced read sends to its waiters: the size of the asset
+// first, then its chunks, or an error at any point
+#[derive(Clone)]
+enum ReadEvent {
+    Start(u64),
+    Chunk(Bytes),
+    Error(Arc<anyhow::Error>),
+}
+
+// Coalesce concurrent fetches of the same asset into a single fetch of the
+// underlying store, whose chunks are broadcasted to the waiters as they are
+// read. Only fetches of whole assets are coalesced, range requests go to
+// the store directly.
+pub struct CoalescingAssetStore {
+    inner: Arc<dyn AssetStore>,
+    in_flight: Arc<DashMap<(String, String), broadcast::Sender<ReadEvent>>>,
+}
+
+impl CoalescingAssetStore {
+    pub fn new(inner: Box<dyn AssetStore>) -> Self {
+        Self {
+            inner: Arc::from(inner),
+            in_flight: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+async fn coalesced_read(
+    inner: Arc<dyn AssetStore>,
+    in_flight: Arc<DashMap<(String, String), broadcast::Sender<ReadEvent>>>,
+    key: (String, String),
+    sender: broadcast::Sender<ReadEvent>,
+) {
+    let asset = inner.fetch(&key.0, &key.1, None).await;
+
+    // Waiters can't join once chunks are sent since they would miss them,
+    // the next fetches will hit the store again and see updates of the asset
+    in_flight.remove(&key);
+
+    let mut body = match asset {
+        Ok(asset) => {
+            sender.send(ReadEvent::Start(asset.size)).unwrap_or(0);
+            asset.body
+        }
+        Err(error) => {
+            sender.send(ReadEvent::Error(Arc::new(error))).unwrap_or(0);
+            return;
+        }
+    };
+
+    while let Some(chunk) = body.data().await {
+        while sender.len() >= COALESCED_READ_CHUNKS {
+            tokio::time::sleep(COALESCED_READ_POLL_INTERVAL).await;
+        }
+
+        let event = match chunk {
+            Ok(chunk) => ReadEvent::Chunk(chunk),
+            Err(error) => ReadEvent::Error(Arc::new(error.into())),
+        };
+        let is_error = matches!(event, ReadEvent::Error(_));
+
+        // Every waiter is gone, no need to read the rest
+        if sender.send(event).is_err() || is_error {
+            return;
+        }
+    }
+}
+
+fn read_error(error: &anyhow::Error) -> anyhow::Error {
+    match error.is::<AssetNotFound>() {
+        true => anyhow::Error::new(AssetNotFound),
+        false => anyhow!("{}", error),
+    }
+}
+
+#[async_trait]
+impl AssetStore for CoalescingAssetStore {
+    async fn fetch(
+        &self,
+        deployment_id: &str,
+        path: &str,
+        range: Option<RangeRequest>,
+    ) -> Result<AssetStream> {
+        if range.is_some() {
+            return self.inner.fetch(deployment_id, path, range).await;
+        }
+
+        let key = (deployment_id.to_string(), path.to_string());
+        let mut receiver = match self.in_flight.entry(key.clone()) {
+            Entry::Occupied(entry) => entry.get().subscribe(),
+            Entry::Vacant(entry) => {
+                let (sender, receiver) = broadcast::channel(COALESCED_READ_CHUNKS);
+                entry.insert(sender.clone());
+
+                tokio::spawn(coalesced_read(
+                    Arc::clone(&self.inner),
+                    Arc::clone(&self.in_flight),
+                    key,
+                    sender,
+                ));
+
+                receiver
+            }
+        };
+
+        let size = match receiver.recv().await {
+            Ok(ReadEvent::Start(size)) => size,
+            Ok(ReadEvent::Error(error)) => return Err(read_error(&error)),
+            _ => return Err(anyhow!("Asset read ended before it started")),
+        };
+
+        let chunks = futures::stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(ReadEvent::Chunk(chunk)) => Some((Ok(chunk), receiver)),
+                Ok(ReadEvent::Error(error)) => Some((Err(read_error(&error)), receiver)),
+                Ok(ReadEvent::Start(_)) | Err(RecvError::Closed) => None,
+                Err(RecvError::Lagged(_)) => {
+                    Some((Err(anyhow!("Asset read is too far ahead")), receiver))
+                }
+            }
+        });
+
+        Ok(AssetStream {
+            size,
+            range: None,
+            body: Body::wrap_stream(chunks),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lagon_runtime_utils::DEPLOYMENTS_DIR;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingAssetStore {
+        reads: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl AssetStore for CountingAssetStore {
+        async fn fetch(
+            &self,
+            deployment_id: &str,
+            path: &str,
+            range: Option<RangeRequest>,
+        ) -> Result<AssetStream> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            FilesystemAssetStore::new(PathBuf::from(DEPLOYMENTS_DIR))
+                .fetch(deployment_id, path, range)
+                .await
+        }
+    }
+
+    // Slowly returns an asset made of many chunks
+    struct ChunkedAssetStore;
+
+    #[async_trait]
+    impl AssetStore for ChunkedAssetStore {
+        async fn fetch(
+            &self,
+            _deployment_id: &str,
+            _path: &str,
+            _range: Option<RangeRequest>,
+        ) -> Result<AssetStream> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let chunks = (0..100).map(|_| Ok::<_, std::io::Error>(Bytes::from("chunk")));
+
+            Ok(AssetStream {
+                size: 500,
+                range: None,
+                body: Body::wrap_stream(futures::stream::iter(chunks)),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn filesystem_fetch() {
+        let asset_store = FilesystemAssetStore::new(PathBuf::from(DEPLOYMENTS_DIR));
+        let asset = asset_store
+            .fetch("assets", "index.css", None)
+            .await
+            .unwrap();
+
+        assert_eq!(asset.size, 28);
+        assert_eq!(asset.range, None);
+        assert_eq!(
+            hyper::body::to_bytes(asset.body).await.unwrap(),
+            "body {\n    display: flex;\n}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn filesystem_fetch_range() {
+        let asset_store = FilesystemAssetStore::new(PathBuf::from(DEPLOYMENTS_DIR));
+        let asset = asset_store
+            .fetch("assets", "index.css", Some(RangeRequest::Bounded(7, 19)))
+            .await
+            .unwrap();
+
+        assert_eq!(asset.size, 28);
+        assert_eq!(asset.range, Some(7..20));
+        assert_eq!(
+            hyper::body::to_bytes(asset.body).await.unwrap(),
+            "    display: "
+        );
+
+        let error = asset_store
+            .fetch("assets", "index.css", Some(RangeRequest::From(28)))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.downcast_ref::<RangeNotSatisfiable>().unwrap().size,
+            28
+        );
+    }
+
+    #[tokio::test]
+    async fn filesystem_fetch_missing() {
+        let asset_store = FilesystemAssetStore::new(PathBuf::from(DEPLOYMENTS_DIR));
+
+        assert!(asset_store
+            .fetch("assets", "missing.css", None)
+            .await
+            .err()
+            .unwrap()
+            .is::<AssetNotFound>());
+    }
+
+    #[tokio::test]
+    async fn filesystem_fetch_traversal() {
+        let asset_store = FilesystemAssetStore::new(PathBuf::from(DEPLOYMENTS_DIR));
+
+        assert!(asset_store
+            .fetch("assets", "../simple.js", None)
+            .await
+            .err()
+            .unwrap()
+            .is::<AssetNotFound>());
+    }
+
+    #[tokio::test]
+    async fn coalesce_concurrent_reads() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let asset_store = CoalescingAssetStore::new(Box::new(CountingAssetStore {
+            reads: Arc::clone(&reads),
+        }));
+
+        let assets = futures::future::join_all(
+            (0..10).map(|_| asset_store.fetch("assets", "index.css", None)),
+        )
+        .await;
+
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        for asset in assets {
+            let asset = asset.unwrap();
+
+            assert_eq!(asset.size, 28);
+            assert_eq!(
+                hyper::body::to_bytes(asset.body).await.unwrap(),
+                "body {\n    display: flex;\n}\n"
+            );
+        }
+
+        // Reads are only coalesced while they are in flight
+        asset_store
+            .fetch("assets", "index.css", None)
+            .await
+            .unwrap();
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn coalesce_streams_chunks() {
+        let asset_store = CoalescingAssetStore::new(Box::new(ChunkedAssetStore));
+
+        let (first, second) = futures::future::join(
+            asset_store.fetch("assets", "index.css", None),
+            asset_store.fetch("assets", "index.css", None),
+        )
+        .await;
+
+        // The slowest waiter still gets every chunk
+        let (first, second) =
+            futures::future::join(hyper::body::to_bytes(first.unwrap().body), async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                hyper::body::to_bytes(second.unwrap().body).await
+            })
+            .await;
+
+        assert_eq!(first.unwrap(), "chunk".repeat(100));
+        assert_eq!(second.unwrap(), "chunk".repeat(100));
+    }
+}
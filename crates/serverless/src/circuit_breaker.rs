@@ -0,0 +1,143 @@
+use dashmap::DashMap;
+use metrics::increment_counter;
+use std::time::{Duration, Instant};
+
+struct Circuit {
+    // Consecutive failures since `first_failure`
+    failures: usize,
+    first_failure: Instant,
+    opened_at: Option<Instant>,
+}
+
+// Stop invoking deployments whose requests keep failing (e.g their code throws
+// when evaluated), answering them with a 503 instead. The circuit of a deployment
+// opens after `threshold` consecutive failures within `window`. Once `cooldown` is
+// over, requests are invoked again: the circuit closes on the first success, and
+// opens again on the first failure.
+pub struct CircuitBreaker {
+    threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    circuits: DashMap<String, Circuit>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            window,
+            cooldown,
+            circuits: DashMap::new(),
+        }
+    }
+
+    // Return how long clients should wait before retrying,
+    // if the deployment's circuit is open
+    pub fn check(&self, deployment_id: &str) -> Result<(), Duration> {
+        let opened_at = self
+            .circuits
+            .get(deployment_id)
+            .and_then(|circuit| circuit.opened_at);
+
+        match opened_at.map(|opened_at| opened_at.elapsed()) {
+            Some(elapsed) if elapsed < self.cooldown => Err(self.cooldown - elapsed),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_success(&self, deployment_id: &str) {
+        self.circuits.remove(deployment_id);
+    }
+
+    pub fn record_failure(&self, deployment_id: &str, function_id: &str) {
+        let now = Instant::now();
+        let mut circuit = self
+            .circuits
+            .entry(deployment_id.to_owned())
+            .or_insert_with(|| Circuit {
+                failures: 0,
+                first_failure: now,
+                opened_at: None,
+            });
+
+        // The trial request after the cooldown failed
+        let half_open = circuit.opened_at.is_some();
+
+        if !half_open && now.duration_since(circuit.first_failure) > self.window {
+            circuit.failures = 0;
+            circuit.first_failure = now;
+        }
+
+        circuit.failures += 1;
+
+        if half_open || circuit.failures >= self.threshold {
+            circuit.opened_at = Some(now);
+
+            increment_counter!(
+                "lagon_circuit_open",
+                "deployment" => deployment_id.to_owned(),
+                "function" => function_id.to_owned(),
+            );
+        }
+    }
+
+    // Forget the deployment, e.g once it's undeployed
+    pub fn remove(&self, deployment_id: &str) {
+        self.circuits.remove(deployment_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_after_failures() {
+        let circuit_breaker =
+            CircuitBreaker::new(3, Duration::from_secs(60), Duration::from_secs(60));
+
+        circuit_breaker.record_failure("deployment", "function");
+        circuit_breaker.record_failure("deployment", "function");
+        assert!(circuit_breaker.check("deployment").is_ok());
+
+        circuit_breaker.record_failure("deployment", "function");
+        let retry_after = circuit_breaker.check("deployment").unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(60));
+
+        // Other deployments have their own circuit
+        assert!(circuit_breaker.check("other").is_ok());
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let circuit_breaker =
+            CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_secs(60));
+
+        circuit_breaker.record_failure("deployment", "function");
+        circuit_breaker.record_success("deployment");
+        circuit_breaker.record_failure("deployment", "function");
+
+        assert!(circuit_breaker.check("deployment").is_ok());
+    }
+
+    #[test]
+    fn recover_after_cooldown() {
+        let circuit_breaker =
+            CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_millis(20));
+
+        circuit_breaker.record_failure("deployment", "function");
+        circuit_breaker.record_failure("deployment", "function");
+        assert!(circuit_breaker.check("deployment").is_err());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(circuit_breaker.check("deployment").is_ok());
+
+        // A failure after the cooldown opens the circuit right away
+        circuit_breaker.record_failure("deployment", "function");
+        assert!(circuit_breaker.check("deployment").is_err());
+
+        std::thread::sleep(Duration::from_millis(30));
+        circuit_breaker.record_success("deployment");
+        assert!(circuit_breaker.check("deployment").is_ok());
+    }
+}
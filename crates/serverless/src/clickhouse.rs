@@ -1,9 +1,19 @@
-use std::env;
+use std::{
+    collections::VecDeque,
+    env, mem,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use anyhow::Result;
-use clickhouse::{Client, Row};
+use clickhouse::{inserter::Inserter, Client, Row};
+use futures::lock::Mutex;
+use metrics::{counter, increment_counter};
 use serde::{Deserialize, Serialize};
 
+// Maximum number of rows of each table waiting to be committed by default
+pub const DEFAULT_MAX_PENDING_ROWS: usize = 100_000;
+
 #[derive(Row, Serialize, Deserialize)]
 pub struct LogRow {
     pub function_id: String,
@@ -12,8 +22,12 @@ pub struct LogRow {
     pub message: String,
     pub region: String,
     pub timestamp: u32,
+    // JSON object of the custom fields of structured logs
+    pub fields: Option<String>,
 }
 
+// Columns added after the table creation need an `ALTER TABLE`
+// migration in `run_migrations`, e.g `status_code`
 #[derive(Row, Serialize, Deserialize)]
 pub struct RequestRow {
     pub function_id: String,
@@ -22,7 +36,146 @@ pub struct RequestRow {
     pub bytes_in: u32,
     pub bytes_out: u32,
     pub cpu_time_micros: Option<u128>,
+    pub status_code: u16,
+    pub method: String,
+    pub path: String,
+    pub ip: String,
     pub timestamp: u32,
+    // Highest heap usage of the isolate while handling the request,
+    // see `RequestStats::peak_memory`
+    pub peak_memory_bytes: Option<u64>,
+    // Number of requests the row stands for when requests are
+    // sampled, see `sample_weight`
+    pub sample_weight: f64,
+}
+
+// Weight of a request row written with the given sample rate (from 0 to 1,
+// where 1 writes every row), or `None` if the row is sampled out
+pub fn sample_weight(rate: f64) -> Option<f64> {
+    if rate >= 1.0 {
+        return Some(1.0);
+    }
+
+    (rate > 0.0 && rand::random::<f64>() < rate).then(|| 1.0 / rate)
+}
+
+// Keep the rows written between two commits in a bounded queue: while ClickHouse
+// is slow or down, the oldest rows are dropped (counted by `lagon_clickhouse_drops`)
+// instead of growing the memory forever. Writing a row never waits for ClickHouse,
+// only commits do.
+pub struct BoundedInserter<T> {
+    table: &'static str,
+    max_pending_rows: usize,
+    pending: std::sync::Mutex<VecDeque<T>>,
+    inserter: Mutex<Inserter<T>>,
+}
+
+impl<T: Row + Serialize> BoundedInserter<T> {
+    pub fn new(inserter: Inserter<T>, table: &'static str, max_pending_rows: usize) -> Self {
+        Self {
+            table,
+            max_pending_rows: max_pending_rows.max(1),
+            pending: std::sync::Mutex::new(VecDeque::new()),
+            inserter: Mutex::new(inserter),
+        }
+    }
+
+    pub fn write(&self, row: T) {
+        let mut pending = self.pending.lock().unwrap();
+
+        if pending.len() >= self.max_pending_rows {
+            pending.pop_front();
+            increment_counter!("lagon_clickhouse_drops", "table" => self.table);
+        }
+
+        pending.push_back(row);
+    }
+
+    // Number of rows waiting for the next commit
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    // Commit the pending rows if the insertion period is over,
+    // otherwise they're sent with the next commit
+    pub async fn commit(&self) -> Result<()> {
+        self.send(false).await
+    }
+
+    pub async fn force_commit(&self) -> Result<()> {
+        self.send(true).await
+    }
+
+    async fn send(&self, force: bool) -> Result<()> {
+        let mut inserter = self.inserter.lock().await;
+        // Rows written while sending wait for the next commit
+        let rows = mem::take(&mut *self.pending.lock().unwrap());
+
+        for (index, row) in rows.iter().enumerate() {
+            if let Err(error) = inserter.write(row).await {
+                counter!("lagon_clickhouse_drops", (rows.len() - index) as u64, "table" => self.table);
+
+                return Err(error.into());
+            }
+        }
+
+        if force {
+            inserter.force_commit().await?;
+        } else {
+            inserter.commit().await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub type Inserters = Arc<(BoundedInserter<RequestRow>, BoundedInserter<LogRow>)>;
+
+pub fn create_inserters(
+    client: &Client,
+    period: Option<Duration>,
+    max_pending_rows: usize,
+) -> Result<Inserters> {
+    Ok(Arc::new((
+        BoundedInserter::new(
+            client
+                .inserter::<RequestRow>("serverless.requests")?
+                .with_period(period),
+            "requests",
+            max_pending_rows,
+        ),
+        BoundedInserter::new(
+            client
+                .inserter::<LogRow>("serverless.logs")?
+                .with_period(period),
+            "logs",
+            max_pending_rows,
+        ),
+    )))
+}
+
+// Commit the pending requests and logs immediately instead of waiting for the
+// insertion interval, e.g before shutting down or in tests. A flusher is bound to
+// the inserters of the server it's passed to, see `ServerConfig::flusher`.
+#[derive(Clone, Default)]
+pub struct Flusher {
+    inserters: Arc<OnceLock<Inserters>>,
+}
+
+impl Flusher {
+    pub(crate) fn bind(&self, inserters: Inserters) {
+        self.inserters.set(inserters).unwrap_or(());
+    }
+
+    // Does nothing if the server isn't started yet
+    pub async fn flush(&self) -> Result<()> {
+        if let Some(inserters) = self.inserters.get() {
+            inserters.0.force_commit().await?;
+            inserters.1.force_commit().await?;
+        }
+
+        Ok(())
+    }
 }
 
 pub fn create_client() -> Client {
@@ -75,6 +228,10 @@ TTL timestamp + INTERVAL 1 WEEK",
     bytes_in UInt32,
     bytes_out UInt32,
     cpu_time_micros Nullable(UInt128),
+    status_code UInt16,
+    method String,
+    path String,
+    ip String,
     timestamp DateTime,
 )
 ENGINE = MergeTree()
@@ -83,5 +240,84 @@ PRIMARY KEY (function_id, timestamp)",
         .execute()
         .await?;
 
+    client
+        .query(
+            "ALTER TABLE serverless.requests
+ADD COLUMN IF NOT EXISTS status_code UInt16 AFTER cpu_time_micros,
+ADD COLUMN IF NOT EXISTS method String AFTER status_code,
+ADD COLUMN IF NOT EXISTS path String AFTER method,
+ADD COLUMN IF NOT EXISTS ip String AFTER path,
+ADD COLUMN IF NOT EXISTS peak_memory_bytes Nullable(UInt64) AFTER timestamp,
+ADD COLUMN IF NOT EXISTS sample_weight Float64 DEFAULT 1 AFTER peak_memory_bytes",
+        )
+        .execute()
+        .await?;
+
+    client
+        .query(
+            "ALTER TABLE serverless.logs
+ADD COLUMN IF NOT EXISTS fields Nullable(String) AFTER timestamp",
+        )
+        .execute()
+        .await?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_weights() {
+        assert_eq!(sample_weight(1.0), Some(1.0));
+        assert_eq!(sample_weight(0.0), None);
+        assert_eq!(sample_weight(-1.0), None);
+
+        let weights = (0..1000)
+            .filter_map(|_| sample_weight(0.5))
+            .collect::<Vec<_>>();
+        assert!(!weights.is_empty() && weights.len() < 1000);
+        assert!(weights.iter().all(|weight| *weight == 2.0));
+    }
+
+    fn log_row(message: usize) -> LogRow {
+        LogRow {
+            function_id: String::from("function"),
+            deployment_id: String::from("deployment"),
+            level: String::from("info"),
+            message: message.to_string(),
+            region: String::from("local"),
+            timestamp: 0,
+            fields: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded_pending_rows() {
+        // Nothing listens on this port, so every commit fails
+        let client = Client::default().with_url("http://127.0.0.1:1");
+        let inserter = BoundedInserter::new(
+            client.inserter::<LogRow>("serverless.logs").unwrap(),
+            "logs",
+            10,
+        );
+
+        for round in 0..5 {
+            for message in 0..100 {
+                inserter.write(log_row(round * 100 + message));
+            }
+
+            assert_eq!(inserter.pending(), 10);
+
+            // The oldest rows are dropped
+            assert_eq!(
+                inserter.pending.lock().unwrap().front().unwrap().message,
+                (round * 100 + 90).to_string()
+            );
+
+            assert!(inserter.force_commit().await.is_err());
+            assert_eq!(inserter.pending(), 0);
+        }
+    }
+}
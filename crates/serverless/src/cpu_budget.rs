@@ -0,0 +1,185 @@
+use dashmap::DashMap;
+use lagon_runtime_utils::CpuBudget;
+use std::time::{Duration, Instant};
+
+struct DeploymentUsage {
+    window: Duration,
+    window_start: Instant,
+    cpu_time: Duration,
+    previous_cpu_time: Duration,
+}
+
+impl DeploymentUsage {
+    // Move to the window `now` is in, the CPU time of the previous
+    // window being forgotten once a whole window went by
+    fn roll(&mut self, window: Duration, now: Instant) {
+        self.window = window;
+        let elapsed = now.duration_since(self.window_start);
+
+        if elapsed >= window * 2 {
+            self.window_start = now;
+            self.previous_cpu_time = Duration::ZERO;
+            self.cpu_time = Duration::ZERO;
+        } else if elapsed >= window {
+            self.window_start += window;
+            self.previous_cpu_time = self.cpu_time;
+            self.cpu_time = Duration::ZERO;
+        }
+    }
+}
+
+// CPU time used by each deployment over a sliding window, estimated from
+// the current window and the share of the previous one still covered by the
+// sliding window. Once a deployment used more than its budget, its requests
+// are rejected until enough of this CPU time is out of the sliding window.
+#[derive(Default)]
+pub struct CpuBudgets {
+    usages: DashMap<String, DeploymentUsage>,
+}
+
+impl CpuBudgets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, deployment_id: &str, budget: &CpuBudget, cpu_time: Duration) {
+        let now = Instant::now();
+        let mut usage = self
+            .usages
+            .entry(deployment_id.to_owned())
+            .or_insert_with(|| DeploymentUsage {
+                window: budget.window,
+                window_start: now,
+                cpu_time: Duration::ZERO,
+                previous_cpu_time: Duration::ZERO,
+            });
+
+        usage.roll(budget.window, now);
+        usage.cpu_time += cpu_time;
+    }
+
+    // Return how long the deployment has to wait for its
+    // usage to be back under its budget, if it exceeded it
+    pub fn check(&self, deployment_id: &str, budget: &CpuBudget) -> Result<(), Duration> {
+        if budget.window.is_zero() {
+            return Ok(());
+        }
+
+        let mut usage = match self.usages.get_mut(deployment_id) {
+            Some(usage) => usage,
+            None => return Ok(()),
+        };
+
+        usage.roll(budget.window, Instant::now());
+
+        let window = budget.window.as_secs_f64();
+        let elapsed = usage.window_start.elapsed().as_secs_f64().min(window);
+        let previous_cpu_time = usage.previous_cpu_time.as_secs_f64();
+        let cpu_time = usage.cpu_time.as_secs_f64();
+        let budget_cpu_time = budget.cpu_time.as_secs_f64();
+        let estimated = previous_cpu_time * (window - elapsed) / window + cpu_time;
+        let over_budget = estimated - budget_cpu_time;
+
+        if over_budget <= 0.0 {
+            return Ok(());
+        }
+
+        // The previous window's share decreases linearly until the current
+        // window is over, after which the current window becomes the previous one
+        let retry_after = match previous_cpu_time > 0.0 && cpu_time <= budget_cpu_time {
+            true => (over_budget * window / previous_cpu_time).min(window - elapsed),
+            false => window - elapsed,
+        };
+
+        Err(Duration::from_secs_f64(retry_after))
+    }
+
+    // Usages without CPU time recorded for two windows are empty,
+    // so removing them doesn't change the budgets and bounds the memory
+    pub fn cleanup(&self) {
+        self.usages
+            .retain(|_, usage| usage.window_start.elapsed() < usage.window * 2);
+    }
+
+    pub fn len(&self) -> usize {
+        self.usages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.usages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn over_budget() {
+        let budgets = CpuBudgets::new();
+        let budget = CpuBudget {
+            cpu_time: Duration::from_millis(10),
+            window: Duration::from_secs(60),
+        };
+
+        budgets.record("deployment", &budget, Duration::from_millis(5));
+        assert!(budgets.check("deployment", &budget).is_ok());
+
+        budgets.record("deployment", &budget, Duration::from_millis(10));
+        let retry_after = budgets.check("deployment", &budget).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= budget.window);
+        assert!(budgets.check("other", &budget).is_ok());
+    }
+
+    #[test]
+    fn window_reset() {
+        let budgets = CpuBudgets::new();
+        let budget = CpuBudget {
+            cpu_time: Duration::from_millis(10),
+            window: Duration::from_millis(10),
+        };
+
+        budgets.record("deployment", &budget, Duration::from_millis(20));
+        assert!(budgets.check("deployment", &budget).is_err());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(budgets.check("deployment", &budget).is_ok());
+    }
+
+    #[test]
+    fn sliding_window() {
+        let budgets = CpuBudgets::new();
+        let budget = CpuBudget {
+            cpu_time: Duration::from_millis(10),
+            window: Duration::from_millis(100),
+        };
+
+        budgets.record("deployment", &budget, Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(110));
+
+        // Most of the previous window is still covered by the sliding window,
+        // so the usage isn't reset when a new window starts
+        let retry_after = budgets.check("deployment", &budget).unwrap_err();
+        assert!(retry_after < Duration::from_millis(90));
+
+        std::thread::sleep(retry_after + Duration::from_millis(5));
+        assert!(budgets.check("deployment", &budget).is_ok());
+    }
+
+    #[test]
+    fn cleanup() {
+        let budgets = CpuBudgets::new();
+        let budget = CpuBudget {
+            cpu_time: Duration::from_millis(10),
+            window: Duration::from_millis(10),
+        };
+
+        budgets.record("deployment", &budget, Duration::from_millis(5));
+        budgets.cleanup();
+        assert_eq!(budgets.len(), 1);
+
+        std::thread::sleep(Duration::from_millis(25));
+        budgets.cleanup();
+        assert!(budgets.is_empty());
+    }
+}
@@ -1,7 +1,9 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bytes::Bytes;
-use clickhouse::inserter::Inserter;
-use futures::lock::Mutex;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use dashmap::DashMap;
 use hyper::{body, Request};
 use lagon_runtime_http::RunResult;
 use lagon_runtime_isolate::{
@@ -10,9 +12,11 @@ use lagon_runtime_isolate::{
 };
 use lagon_runtime_utils::Deployment;
 use log::{error, info, warn};
-use metrics::{decrement_gauge, histogram, increment_gauge};
+use metrics::{decrement_gauge, histogram, increment_counter, increment_gauge};
+use serde::Serialize;
 use std::{
     collections::HashMap,
+    str::FromStr,
     sync::Arc,
     time::{Duration, UNIX_EPOCH},
 };
@@ -21,248 +25,479 @@ use tokio_cron_scheduler::{Job, JobScheduler};
 use uuid::Uuid;
 
 use crate::{
-    clickhouse::{LogRow, RequestRow},
-    get_region, SNAPSHOT_BLOB,
+    clickhouse::{Inserters, RequestRow},
+    serverless::ServerConfig,
 };
 
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CronRun {
+    // Unix timestamp (in seconds) of the execution
+    pub timestamp: u64,
+    pub success: bool,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CronJobStatus {
+    pub deployment_id: String,
+    pub schedule: String,
+    pub timezone: String,
+    pub last_run: Option<CronRun>,
+    // Unix timestamp (in seconds) of the next execution
+    pub next_run: Option<u64>,
+}
+
+struct CronJob {
+    uuid: Uuid,
+    deployment: Arc<Deployment>,
+    schedule: String,
+    timezone: Tz,
+}
+
+// Adding a 0 at the beginning because tokio-cron-scheduler's
+// cron format include seconds at the start
+fn with_seconds(cron: &str) -> String {
+    format!("0 {cron}")
+}
+
+fn parse_timezone(timezone: Option<&str>) -> Result<Tz> {
+    match timezone {
+        Some(timezone) => timezone
+            .parse()
+            .map_err(|error| anyhow!("Invalid cron timezone {}: {}", timezone, error)),
+        None => Ok(Tz::UTC),
+    }
+}
+
+// Schedules are evaluated in the local time of their timezone,
+// so runs follow its DST transitions
+fn next_run(cron: &str, timezone: Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    Schedule::from_str(&with_seconds(cron))
+        .ok()?
+        .after(&after.with_timezone(&timezone))
+        .next()
+        .map(|next_run| next_run.with_timezone(&Utc))
+}
+
+// Run the cron function once in a new isolate, and record its outcome
+async fn execute(
+    deployment: Arc<Deployment>,
+    runs: Arc<DashMap<String, CronRun>>,
+    inserters: Inserters,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+    config: Arc<ServerConfig>,
+) {
+    let labels = [
+        ("deployment", deployment.id.clone()),
+        ("function", deployment.function_id.clone()),
+    ];
+
+    let code = match deployment.get_code(&config.deployments_dir) {
+        Ok(code) => code,
+        Err(error) => {
+            increment_counter!("lagon_isolate_code_load_failures", &labels);
+            error!(deployment = deployment.id; "Error while getting deployment code: {}", error);
+
+            // Running an empty code would only report a confusing error
+            record_run(
+                &deployment,
+                &runs,
+                &log_sender,
+                String::from("error"),
+                format!("Cron execution error: {}", error),
+            )
+            .await;
+
+            return;
+        }
+    };
+    let snapshot_blob = config.snapshots.get(deployment.runtime_version.as_deref());
+
+    let handle = Handle::current();
+    let (isolate_sender, isolate_receiver) = flume::unbounded();
+    let log_sender_handle = log_sender.clone();
+    let deployment_handle = Arc::clone(&deployment);
+
+    std::thread::Builder::new().name(String::from("cron-") + deployment.id.as_str()).spawn(move || {
+        handle.block_on(async move {
+            let deployment  = deployment_handle;
+
+            increment_gauge!("lagon_isolates", 1.0, &labels);
+            info!(deployment = deployment.id.clone(), function = deployment.function_id.clone(); "Creating new cron isolate");
+
+            let options = IsolateOptions::with_code(code)
+                .environment_variables(deployment.get_environment_variables())
+                .memory(deployment.memory)
+                .tick_timeout(Duration::from_millis(deployment.tick_timeout as u64))
+                .total_timeout(Duration::from_millis(
+                    deployment.total_timeout as u64,
+                ))
+                .metadata(Some((
+                    deployment.id.clone(),
+                    deployment.function_id.clone(),
+                )))
+                .on_drop_callback(Box::new(|metadata| {
+                    if let Some(metadata) = metadata.as_ref().as_ref() {
+                        let labels = [
+                            ("deployment", metadata.0.clone()),
+                            ("function", metadata.1.clone()),
+                        ];
+
+                        decrement_gauge!("lagon_isolates", 1.0, &labels);
+                        info!(deployment = metadata.0, function = metadata.1; "Dropping cron isolate");
+                    }
+                }))
+                .on_statistics_callback(Box::new(|metadata, statistics| {
+                    if let Some(metadata) = metadata.as_ref().as_ref() {
+                        let labels = [
+                            ("deployment", metadata.0.clone()),
+                            ("function", metadata.1.clone()),
+                        ];
+
+                        histogram!(
+                            "lagon_isolate_memory_usage",
+                            statistics as f64,
+                            &labels
+                        );
+                    }
+                }))
+                .log_sender(log_sender_handle)
+                .snapshot_blob(snapshot_blob);
+
+            let mut isolate = Isolate::new(options, isolate_receiver);
+            isolate.evaluate();
+            isolate.run_event_loop().await;
+        });
+    }).unwrap();
+
+    let (sender, receiver) = flume::unbounded();
+    let request = Request::new(Bytes::new()).into_parts();
+    let method = request.0.method.to_string();
+    let path = request.0.uri.path().to_string();
+
+    isolate_sender
+        .send_async(IsolateEvent::Request(IsolateRequest {
+            sender,
+            request,
+            total_timeout: None,
+        }))
+        .await
+        .unwrap_or(());
+
+    let run_result = receiver
+        .recv_async()
+        .await
+        .expect("Isolate didn't send a response");
+
+    isolate_sender
+        .send_async(IsolateEvent::Terminate(String::from("Cron completed")))
+        .await
+        .unwrap_or(());
+
+    let (level, message) = match run_result {
+        RunResult::Stream(_) => {
+            warn!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron Functions can't return a stream",
+            );
+
+            (
+                String::from("warn"),
+                String::from("Cron Functions can't return a stream"),
+            )
+        }
+        RunResult::Response(response, stats) => {
+            let status = response.status();
+            let body = body::to_bytes(response.into_body())
+                .await
+                .unwrap_or_else(|error| {
+                    error!(
+                        deployment = deployment.id,
+                        function = deployment.function_id;
+                        "Error while reading response body: {}", error,
+                    );
+
+                    Bytes::new()
+                });
+
+            let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs() as u32;
+
+            inserters.0.write(RequestRow {
+                function_id: deployment.function_id.clone(),
+                deployment_id: deployment.id.clone(),
+                region: config.region().to_owned(),
+                bytes_in: 0,
+                bytes_out: 0,
+                cpu_time_micros: stats.map(|stats| stats.cpu_time.as_micros()),
+                status_code: status.as_u16(),
+                method,
+                path,
+                ip: String::new(),
+                timestamp,
+                peak_memory_bytes: stats.map(|stats| stats.peak_memory as u64),
+                sample_weight: 1.0,
+            });
+
+            let body = String::from_utf8_lossy(&body);
+            let maybe_body = if body == "" {
+                String::from("")
+            } else {
+                format!(": {body}")
+            };
+
+            if status == 200 {
+                info!(
+                    deployment = deployment.id,
+                    function = deployment.function_id;
+                    "Cron execution successful{}",
+                    maybe_body,
+                );
+
+                (
+                    String::from("info"),
+                    format!("Cron execution successful{}", maybe_body),
+                )
+            } else {
+                error!(
+                    deployment = deployment.id,
+                    function = deployment.function_id;
+                    "Cron execution failed with status {}{}",
+                    status,
+                    maybe_body,
+                );
+
+                (
+                    String::from("error"),
+                    format!("Cron execution failed with status {}{}", status, maybe_body),
+                )
+            }
+        }
+        RunResult::Timeout => {
+            warn!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron execution timed out",
+            );
+
+            (
+                String::from("warn"),
+                String::from("Cron execution timed out"),
+            )
+        }
+        RunResult::MemoryLimit => {
+            warn!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron execution memory limit reached",
+            );
+
+            (
+                String::from("warn"),
+                String::from("Cron execution memory limit reached"),
+            )
+        }
+        RunResult::Error(error) => {
+            error!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron execution error: {}",
+                error,
+            );
+
+            (
+                String::from("error"),
+                format!("Cron execution error: {}", error),
+            )
+        }
+    };
+
+    record_run(&deployment, &runs, &log_sender, level, message).await;
+}
+
+// Store the outcome of an execution and send it to the deployment's logs
+async fn record_run(
+    deployment: &Deployment,
+    runs: &DashMap<String, CronRun>,
+    log_sender: &flume::Sender<(String, String, Metadata, Option<String>)>,
+    level: String,
+    message: String,
+) {
+    runs.insert(
+        deployment.id.clone(),
+        CronRun {
+            timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs(),
+            success: level == "info",
+            message: message.clone(),
+        },
+    );
+
+    log_sender
+        .send_async((
+            level,
+            message,
+            Some((deployment.id.clone(), deployment.function_id.clone())),
+            None,
+        ))
+        .await
+        .unwrap_or(());
+}
+
 pub struct Cronjob {
-    jobs: HashMap<String, Uuid>,
+    jobs: HashMap<String, CronJob>,
+    // Outcome of the last execution of each deployment's cron
+    runs: Arc<DashMap<String, CronRun>>,
     scheduler: JobScheduler,
-    log_sender: flume::Sender<(String, String, Metadata)>,
-    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+    inserters: Inserters,
+    config: Arc<ServerConfig>,
 }
 
 impl Cronjob {
     pub async fn new(
-        log_sender: flume::Sender<(String, String, Metadata)>,
-        inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
+        log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+        inserters: Inserters,
+        config: Arc<ServerConfig>,
     ) -> Self {
         let scheduler = JobScheduler::new().await.unwrap();
         scheduler.start().await.unwrap();
 
         Self {
             jobs: HashMap::new(),
+            runs: Arc::new(DashMap::new()),
             scheduler,
             log_sender,
             inserters,
+            config,
         }
     }
 
     pub async fn add(&mut self, deployment: Arc<Deployment>) -> Result<()> {
         if let Some(cron) = &deployment.cron {
-            // Adding a 0 at the beginning because tokio-cron-scheduler's
-            // cron format include seconds at the start
-            let cron = format!("0 {cron}");
+            let schedule = cron.clone();
+            let timezone = parse_timezone(deployment.cron_timezone.as_deref())?;
+            let cron = with_seconds(cron);
 
-            info!("Registering cron {} for deployment {}", cron, deployment.id);
+            info!(
+                "Registering cron {} ({}) for deployment {}",
+                cron, timezone, deployment.id
+            );
 
             let id = deployment.id.clone();
+            let job_deployment = Arc::clone(&deployment);
+            let runs = Arc::clone(&self.runs);
             let inserters = self.inserters.clone();
             let log_sender = self.log_sender.clone();
+            let config = Arc::clone(&self.config);
 
             let uuid = self
                 .scheduler
-                .add(Job::new_async(cron.as_str(), move |_, _| {
-                    let labels = [
-                        ("deployment", deployment.id.clone()),
-                        ("function", deployment.function_id.clone()),
-                    ];
-
-                    let deployment = Arc::clone(&deployment);
-                    let inserters = Arc::clone(&inserters);
-                    let log_sender = log_sender.clone();
-                    let code = deployment.get_code().unwrap_or_else(|error| {
-                        error!(deployment = deployment.id; "Error while getting deployment code: {}", error);
-
-                        "".into()
-                    });
-
-                    Box::pin(async move {
-                        let handle = Handle::current();
-                        let (isolate_sender, isolate_receiver) = flume::unbounded();
-                        let log_sender_handle = log_sender.clone();
-                        let deployment_handle = Arc::clone(&deployment);
-
-                        std::thread::Builder::new().name(String::from("cron-") + deployment.id.as_str()).spawn(move || {
-                            handle.block_on(async move {
-                                let deployment  = deployment_handle;
-
-                                increment_gauge!("lagon_isolates", 1.0, &labels);
-                                info!(deployment = deployment.id.clone(), function = deployment.function_id.clone(); "Creating new cron isolate");
-
-                                let options = IsolateOptions::new(code)
-                                    .environment_variables(deployment.environment_variables.clone())
-                                    .memory(deployment.memory)
-                                    .tick_timeout(Duration::from_millis(deployment.tick_timeout as u64))
-                                    .total_timeout(Duration::from_millis(
-                                        deployment.total_timeout as u64,
-                                    ))
-                                    .metadata(Some((
-                                        deployment.id.clone(),
-                                        deployment.function_id.clone(),
-                                    )))
-                                    .on_drop_callback(Box::new(|metadata| {
-                                        if let Some(metadata) = metadata.as_ref().as_ref() {
-                                            let labels = [
-                                                ("deployment", metadata.0.clone()),
-                                                ("function", metadata.1.clone()),
-                                            ];
-
-                                            decrement_gauge!("lagon_isolates", 1.0, &labels);
-                                            info!(deployment = metadata.0, function = metadata.1; "Dropping cron isolate");
-                                        }
-                                    }))
-                                    .on_statistics_callback(Box::new(|metadata, statistics| {
-                                        if let Some(metadata) = metadata.as_ref().as_ref() {
-                                            let labels = [
-                                                ("deployment", metadata.0.clone()),
-                                                ("function", metadata.1.clone()),
-                                            ];
-
-                                            histogram!(
-                                                "lagon_isolate_memory_usage",
-                                                statistics as f64,
-                                                &labels
-                                            );
-                                        }
-                                    }))
-                                    .log_sender(log_sender_handle)
-                                    .snapshot_blob(SNAPSHOT_BLOB);
-
-                                let mut isolate = Isolate::new(options, isolate_receiver);
-                                isolate.evaluate();
-                                isolate.run_event_loop().await;
-                            });
-                        }).unwrap();
-
-                        let (sender, receiver) = flume::unbounded();
-                        let request = Request::new(Bytes::new()).into_parts();
-
-                        isolate_sender.send_async(IsolateEvent::Request(IsolateRequest {
-                            sender,
-                            request,
-                        })).await.unwrap_or(());
-
-                        let run_result = receiver.recv_async().await.expect("Isolate didn't send a response");
-
-                        isolate_sender.send_async(IsolateEvent::Terminate(String::from("Cron completed"))).await.unwrap_or(());
-
-                        let (level, message) = match run_result {
-                            RunResult::Stream(_) => {
-                                warn!(
-                                    deployment = deployment.id,
-                                    function = deployment.function_id;
-                                    "Cron Functions can't return a stream",
-                                );
-
-                                (String::from("warn"), String::from("Cron Functions can't return a stream"))
-                            }
-                            RunResult::Response(response, elapsed) => {
-                                let status = response.status();
-                                let body = body::to_bytes(response.into_body()).await.unwrap_or_else(|error| {
-                                    error!(
-                                        deployment = deployment.id,
-                                        function = deployment.function_id;
-                                        "Error while reading response body: {}", error,
-                                    );
-
-                                    Bytes::new()
-                                });
-
-                                let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs() as u32;
-
-                                inserters
-                                    .lock()
-                                    .await
-                                    .0
-                                    .write(&RequestRow {
-                                        function_id: deployment.function_id.clone(),
-                                        deployment_id: deployment.id.clone(),
-                                        region: get_region().clone(),
-                                        bytes_in: 0,
-                                        bytes_out: 0,
-                                        cpu_time_micros: elapsed.map(|duration| duration.as_micros()),
-                                        timestamp,
-                                    })
-                                    .await
-                                    .unwrap_or(());
-
-                                let body = String::from_utf8_lossy(&body);
-                                let maybe_body = if body == "" { String::from("") } else { format!(": {body}") };
-
-                                if status == 200 {
-                                    info!(
-                                        deployment = deployment.id,
-                                        function = deployment.function_id;
-                                        "Cron execution successful{}",
-                                        maybe_body,
-                                    );
-
-                                    (String::from("info"), format!("Cron execution successful{}", maybe_body))
-                                } else {
-
-                                    error!(
-                                        deployment = deployment.id,
-                                        function = deployment.function_id;
-                                        "Cron execution failed with status {}{}",
-                                        status,
-                                        maybe_body,
-                                    );
-
-                                    (String::from("error"), format!("Cron execution failed with status {}{}", status, maybe_body))
-                                }
-                            }
-                            RunResult::Timeout => {
-                                warn!(
-                                    deployment = deployment.id,
-                                    function = deployment.function_id;
-                                    "Cron execution timed out",
-                                );
-
-                                (String::from("warn"), String::from("Cron execution timed out"))
-                            }
-                            RunResult::MemoryLimit => {
-                                warn!(
-                                    deployment = deployment.id,
-                                    function = deployment.function_id;
-                                    "Cron execution memory limit reached",
-                                );
-
-                                (String::from("warn"), String::from("Cron execution memory limit reached"))
-                            }
-                            RunResult::Error(error) => {
-                                error!(
-                                    deployment = deployment.id,
-                                    function = deployment.function_id;
-                                    "Cron execution error: {}",
-                                    error,
-                                );
-
-                                (String::from("error"), format!("Cron execution error: {}", error))
-                            }
-                        };
-
-                        log_sender.send_async((level, message, Some((
-                            deployment.id.clone(),
-                            deployment.function_id.clone(),
-                        )))).await.unwrap_or(());
-                    })
+                .add(Job::new_async_tz(cron.as_str(), timezone, move |_, _| {
+                    Box::pin(execute(
+                        Arc::clone(&job_deployment),
+                        Arc::clone(&runs),
+                        Arc::clone(&inserters),
+                        log_sender.clone(),
+                        Arc::clone(&config),
+                    ))
                 })?)
                 .await?;
 
-            self.jobs.insert(id, uuid);
+            self.jobs.insert(
+                id,
+                CronJob {
+                    uuid,
+                    deployment,
+                    schedule,
+                    timezone,
+                },
+            );
         }
 
         Ok(())
     }
 
     pub async fn remove(&mut self, deployment_id: &String) -> Result<()> {
-        if let Some(uuid) = self.jobs.remove(deployment_id) {
+        if let Some(job) = self.jobs.remove(deployment_id) {
             info!("Unregistering cron for deployment {}", deployment_id);
 
-            self.scheduler.remove(&uuid).await?;
+            self.runs.remove(deployment_id);
+            self.scheduler.remove(&job.uuid).await?;
         }
 
         Ok(())
     }
+
+    // Run a registered cron right away, independently of its schedule.
+    // Returns false if the deployment doesn't have a registered cron.
+    pub fn trigger(&self, deployment_id: &str) -> bool {
+        match self.jobs.get(deployment_id) {
+            Some(job) => {
+                tokio::spawn(execute(
+                    Arc::clone(&job.deployment),
+                    Arc::clone(&self.runs),
+                    Arc::clone(&self.inserters),
+                    self.log_sender.clone(),
+                    Arc::clone(&self.config),
+                ));
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Registered crons sorted by deployment id, with the outcome
+    // of their last execution and when they will run next
+    pub fn jobs(&self) -> Vec<CronJobStatus> {
+        let mut jobs = self
+            .jobs
+            .iter()
+            .map(|(deployment_id, job)| CronJobStatus {
+                deployment_id: deployment_id.clone(),
+                schedule: job.schedule.clone(),
+                timezone: job.timezone.name().to_string(),
+                last_run: self.runs.get(deployment_id).map(|run| run.clone()),
+                next_run: next_run(&job.schedule, job.timezone, Utc::now())
+                    .map(|next_run| next_run.timestamp() as u64),
+            })
+            .collect::<Vec<_>>();
+
+        jobs.sort_by(|a, b| a.deployment_id.cmp(&b.deployment_id));
+        jobs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timezone() {
+        assert_eq!(parse_timezone(None).unwrap(), Tz::UTC);
+        assert_eq!(
+            parse_timezone(Some("Europe/Paris")).unwrap(),
+            Tz::Europe__Paris
+        );
+        assert!(parse_timezone(Some("Europe/Nowhere")).is_err());
+    }
+
+    #[test]
+    fn next_run_dst() {
+        let timezone = Tz::Europe__Paris;
+        let run = |after: &str| {
+            next_run("0 9 * * *", timezone, after.parse().unwrap())
+                .unwrap()
+                .to_rfc3339()
+        };
+
+        // Paris is UTC+1 before the last Sunday of March, and UTC+2 after
+        assert_eq!(run("2023-03-24T12:00:00Z"), "2023-03-25T08:00:00+00:00");
+        assert_eq!(run("2023-03-25T12:00:00Z"), "2023-03-26T07:00:00+00:00");
+
+        // And back to UTC+1 after the last Sunday of October
+        assert_eq!(run("2023-10-28T12:00:00Z"), "2023-10-29T08:00:00+00:00");
+    }
 }
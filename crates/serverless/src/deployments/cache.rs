@@ -1,14 +1,77 @@
 use super::pubsub::clear_deployment_cache;
 use crate::serverless::Workers;
 use dashmap::DashMap;
+use log::info;
+use serde::Serialize;
 use std::{
     env,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     time::{Duration, Instant},
 };
 
 const CACHE_TASK_INTERVAL: Duration = Duration::from_secs(5);
 
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IsolateSummary {
+    pub deployment_id: String,
+    pub running: bool,
+    pub in_flight: usize,
+    pub pinned: bool,
+}
+
+pub fn get_isolate(workers: &Workers, deployment_id: &str) -> IsolateSummary {
+    let worker = workers.get(deployment_id);
+
+    IsolateSummary {
+        deployment_id: deployment_id.to_owned(),
+        running: worker.is_some(),
+        in_flight: worker
+            .as_ref()
+            .map_or(0, |worker| worker.in_flight.load(Ordering::SeqCst)),
+        pinned: worker.as_ref().map_or(false, |worker| worker.pinned),
+    }
+}
+
+// Terminate the deployment's isolate (e.g while debugging a misbehaving
+// function) so its next request creates a fresh one. Its in-flight requests
+// are aborted. Return whether the deployment had an isolate.
+pub async fn clear_isolate(workers: &Workers, deployment_id: &str) -> bool {
+    let cleared = clear_deployment_cache(
+        deployment_id.to_owned(),
+        Arc::clone(workers),
+        String::from("admin"),
+    )
+    .await;
+
+    if cleared {
+        info!(deployment = deployment_id; "Cleared isolate");
+    }
+
+    cleared
+}
+
+// Deployments that didn't receive requests for longer than the cache
+// duration, except the pinned ones which always stay warm
+fn get_expired_deployments(
+    last_requests: &DashMap<String, Instant>,
+    workers: &Workers,
+    isolates_cache_seconds: Duration,
+) -> Vec<String> {
+    let now = Instant::now();
+
+    last_requests
+        .iter()
+        .filter(|last_request| now.duration_since(*last_request.value()) > isolates_cache_seconds)
+        .map(|last_request| last_request.key().clone())
+        .filter(|deployment_id| {
+            !workers
+                .get(deployment_id)
+                .map_or(false, |worker| worker.pinned)
+        })
+        .collect()
+}
+
 pub fn run_cache_clear_task(last_requests: Arc<DashMap<String, Instant>>, workers: Workers) {
     let isolates_cache_seconds = Duration::from_secs(
         env::var("LAGON_ISOLATES_CACHE_SECONDS")
@@ -18,24 +81,11 @@ pub fn run_cache_clear_task(last_requests: Arc<DashMap<String, Instant>>, worker
     );
 
     tokio::spawn(async move {
-        let mut deployments_to_clear = Vec::new();
-
         loop {
             tokio::time::sleep(CACHE_TASK_INTERVAL).await;
 
-            let now = Instant::now();
-
-            for last_request in last_requests.iter() {
-                let (deployment_id, last_request) = last_request.pair();
-
-                if now.duration_since(*last_request) > isolates_cache_seconds {
-                    deployments_to_clear.push(deployment_id.clone());
-                }
-            }
-
-            if deployments_to_clear.is_empty() {
-                continue;
-            }
+            let deployments_to_clear =
+                get_expired_deployments(&last_requests, &workers, isolates_cache_seconds);
 
             for deployment_id in &deployments_to_clear {
                 last_requests.remove(deployment_id);
@@ -47,8 +97,74 @@ pub fn run_cache_clear_task(last_requests: Arc<DashMap<String, Instant>>, worker
                 )
                 .await;
             }
-
-            deployments_to_clear.clear();
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serverless::Worker;
+    use lagon_runtime_isolate::IsolateEvent;
+    use std::sync::atomic::AtomicUsize;
+
+    fn worker(pinned: bool) -> Worker {
+        let (sender, _) = flume::unbounded();
+
+        Worker {
+            sender,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            pinned,
+        }
+    }
+
+    #[tokio::test]
+    async fn clear_single_isolate() {
+        let workers: Workers = Arc::new(DashMap::new());
+        let (sender, receiver) = flume::unbounded();
+
+        workers.insert(
+            String::from("cleared"),
+            Worker {
+                sender,
+                in_flight: Arc::new(AtomicUsize::new(1)),
+                pinned: false,
+            },
+        );
+        workers.insert(String::from("other"), worker(false));
+
+        let isolate = get_isolate(&workers, "cleared");
+        assert!(isolate.running);
+        assert_eq!(isolate.in_flight, 1);
+
+        assert!(clear_isolate(&workers, "cleared").await);
+        assert!(!workers.contains_key("cleared"));
+        assert!(workers.contains_key("other"));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(IsolateEvent::Terminate(reason)) if reason == "admin"
+        ));
+
+        assert!(!get_isolate(&workers, "cleared").running);
+        assert!(!clear_isolate(&workers, "cleared").await);
+    }
+
+    #[test]
+    fn pinned_deployments_not_expired() {
+        let last_requests = DashMap::new();
+        let workers: Workers = Arc::new(DashMap::new());
+        let idle = Instant::now() - Duration::from_secs(10);
+
+        last_requests.insert(String::from("idle"), idle);
+        workers.insert(String::from("idle"), worker(false));
+        last_requests.insert(String::from("pinned"), idle);
+        workers.insert(String::from("pinned"), worker(true));
+        last_requests.insert(String::from("recent"), Instant::now());
+        workers.insert(String::from("recent"), worker(false));
+
+        assert_eq!(
+            get_expired_deployments(&last_requests, &workers, Duration::from_secs(5)),
+            vec![String::from("idle")]
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use log::warn;
+use metrics::increment_counter;
+use std::sync::Arc;
+
+// Maximum number of events waiting to be passed to the callback,
+// newer events are dropped when it's reached
+const EVENTS_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeploymentEventKind {
+    Deployed,
+    Undeployed,
+    Promoted,
+    // The deployment couldn't be downloaded or didn't pass the readiness check
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeploymentEvent {
+    pub deployment_id: String,
+    pub kind: DeploymentEventKind,
+}
+
+pub type DeploymentEventCallback = Arc<dyn Fn(DeploymentEvent) + Send + Sync>;
+
+// Pass deployment events to the callback from a dedicated thread,
+// so a slow callback can't stall the processing of pub/sub messages
+#[derive(Clone)]
+pub struct DeploymentEvents {
+    sender: flume::Sender<DeploymentEvent>,
+}
+
+impl DeploymentEvents {
+    pub fn new(callback: DeploymentEventCallback) -> Self {
+        let (sender, receiver) = flume::bounded::<DeploymentEvent>(EVENTS_CAPACITY);
+
+        std::thread::Builder::new()
+            .name(String::from("deployment-events"))
+            .spawn(move || {
+                // Stops once the sender is dropped
+                while let Ok(event) = receiver.recv() {
+                    callback(event);
+                }
+            })
+            .unwrap();
+
+        Self { sender }
+    }
+
+    pub fn emit(&self, deployment_id: &str, kind: DeploymentEventKind) {
+        let event = DeploymentEvent {
+            deployment_id: deployment_id.to_string(),
+            kind,
+        };
+
+        if self.sender.try_send(event).is_err() {
+            increment_counter!("lagon_deployment_events_dropped", "deployment" => deployment_id.to_string());
+            warn!(deployment = deployment_id; "Deployment event dropped, the callback is too slow");
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use super::{get_deployment_by_id, pubsub::deployment_to_value, Deployments};
+use crate::serverless::Workers;
+use lagon_runtime_utils::Deployment;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, sync::Arc};
+
+// State exported by a node being replaced, so the node replacing it warms up
+// the same deployments before serving traffic, instead of cold starting all
+// of them at once. The isolates themselves aren't handed over.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HandoffState {
+    // Deployments in the same format as deploy messages (see `deployment_from_value`),
+    // without their environment variables and secrets
+    pub deployments: Vec<Value>,
+    // Ids of the deployments with a live isolate
+    pub warm_deployments: Vec<String>,
+}
+
+// The map is keyed by domain, so deployments are only exported once. The state
+// can be written to a file or read from the admin API, so it never contains
+// environment variables or secrets: the new node loads them from the database.
+pub fn export_state(deployments: &Deployments, workers: &Workers) -> HandoffState {
+    let deployments = deployments
+        .iter()
+        .filter(|entry| !entry.value().provisioning)
+        .map(|entry| (entry.value().id.clone(), Arc::clone(entry.value())))
+        .collect::<BTreeMap<_, _>>();
+
+    let warm_deployments = deployments
+        .keys()
+        .filter(|deployment_id| workers.contains_key(*deployment_id))
+        .cloned()
+        .collect();
+
+    HandoffState {
+        deployments: deployments
+            .values()
+            .map(|deployment| {
+                let mut value = deployment_to_value(deployment);
+
+                if let Some(value) = value.as_object_mut() {
+                    value.remove("env");
+                    value.remove("secrets");
+                }
+
+                value
+            })
+            .collect(),
+        warm_deployments,
+    }
+}
+
+// Return the warm deployments of the state, to be warmed up on this node too.
+// Deployments are loaded from the database (with their environment variables
+// and secrets) beforehand, the ones of the state that weren't are skipped.
+pub fn import_state(state: &HandoffState, deployments: &Deployments) -> Vec<Arc<Deployment>> {
+    let skipped = state
+        .deployments
+        .iter()
+        .filter_map(|value| value["deploymentId"].as_str())
+        .filter(|deployment_id| get_deployment_by_id(deployments, deployment_id).is_none())
+        .count();
+
+    if skipped > 0 {
+        warn!(
+            "Skipped {} deployment(s) of the handoff state not found in the database",
+            skipped
+        );
+    }
+
+    let warm_deployments = state
+        .warm_deployments
+        .iter()
+        .filter_map(|deployment_id| get_deployment_by_id(deployments, deployment_id))
+        .collect::<Vec<_>>();
+
+    info!(
+        "Imported {} warm deployment(s) from handoff state",
+        warm_deployments.len()
+    );
+
+    warm_deployments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deployments::register_deployment, serverless::Worker};
+    use dashmap::DashMap;
+    use lagon_runtime_utils::{CpuBudget, Secrets};
+    use std::{
+        collections::{HashMap, HashSet},
+        time::Duration,
+    };
+    use tokio::sync::watch;
+
+    #[test]
+    fn round_trip() {
+        std::env::set_var("LAGON_ROOT_DOMAIN", "lagon.test");
+
+        let deployments = Deployments::default();
+        let simple = Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "simple".into(),
+            domains: HashSet::from(["example.com".into()]),
+            environment_variables: HashMap::from([("KEY".into(), "value".into())]),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            secrets: Secrets(HashMap::from([("SECRET".into(), "value".into())])),
+            cpu_budget: Some(CpuBudget {
+                cpu_time: Duration::from_millis(100),
+                window: Duration::from_secs(60),
+            }),
+            path_prefix: Some("/api".into()),
+            ..Deployment::default()
+        });
+        register_deployment(&deployments, &simple);
+        register_deployment(
+            &deployments,
+            &Arc::new(Deployment {
+                id: "counter".into(),
+                function_id: "function_id".into(),
+                function_name: "counter".into(),
+                memory: 128,
+                tick_timeout: 1000,
+                total_timeout: 1000,
+                ..Deployment::default()
+            }),
+        );
+
+        let workers = Arc::new(DashMap::new());
+        workers.insert(
+            "simple".into(),
+            Worker {
+                sender: flume::unbounded().0,
+                in_flight: Default::default(),
+                pinned: false,
+                ready: watch::channel(true).1,
+            },
+        );
+
+        let state = export_state(&deployments, &workers);
+        assert_eq!(state.warm_deployments, vec![String::from("simple")]);
+
+        // Neither environment variables nor secrets leave the node
+        let exported = serde_json::to_string(&state).unwrap();
+        assert!(!exported.contains("KEY"));
+        assert!(!exported.contains("SECRET"));
+
+        // The new node loaded the deployments from the database first,
+        // except `counter` which isn't there anymore
+        let state = serde_json::from_str::<HandoffState>(&exported).unwrap();
+        let loaded = Deployments::default();
+        register_deployment(&loaded, &simple);
+
+        let warm = import_state(&state, &loaded);
+        assert_eq!(warm.len(), 1);
+        assert_eq!(warm[0].id, "simple");
+        assert_eq!(warm[0].environment_variables["KEY"], "value");
+        assert_eq!(
+            export_state(&loaded, &workers).deployments,
+            state.deployments[1..]
+        );
+    }
+}
@@ -1,89 +1,728 @@
-use crate::get_region;
+use crate::{get_region, secrets::SecretsKey};
 use anyhow::{anyhow, Result};
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
 use futures::{stream::FuturesUnordered, StreamExt};
-use lagon_runtime_utils::{Deployment, DEPLOYMENTS_DIR};
+use lagon_runtime_utils::{Canary, CpuBudget, Deployment, Secrets};
 use lagon_serverless_downloader::Downloader;
 use log::{error, info, warn};
-use mysql::{prelude::Queryable, PooledConn};
-use serde::Deserialize;
+use metrics::{counter, histogram, increment_counter};
+use mysql::{prelude::Queryable, PooledConn, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fs,
+    ops::Deref,
     path::Path,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use self::filesystem::{create_deployments_folder, rm_deployment};
+use self::{
+    filesystem::{create_deployments_folder, rm_deployment},
+    pubsub::{deployment_from_value, get_str_map, parse_ip_list, parse_methods, parse_path_prefix},
+};
 
 pub mod cache;
+pub mod events;
 pub mod filesystem;
+pub mod handoff;
 pub mod pubsub;
+pub mod queue;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// Deployments by domain (with their path prefix if any). Writes go through the
+// methods below and the `register_*` functions, which keep the index of domains
+// by deployment id up to date, while reads use the map directly.
+#[derive(Default)]
+pub struct DeploymentsMap {
+    domains: DashMap<String, Arc<Deployment>>,
+    // Domains each deployment is registered on, either directly or as the
+    // canary of another deployment, to find deployments by id in O(1)
+    ids: DashMap<String, HashSet<String>>,
+}
+
+pub type Deployments = Arc<DeploymentsMap>;
+
+impl Deref for DeploymentsMap {
+    type Target = DashMap<String, Arc<Deployment>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.domains
+    }
+}
+
+impl DeploymentsMap {
+    pub fn insert(&self, domain: String, deployment: Arc<Deployment>) -> Option<Arc<Deployment>> {
+        match self.domains.entry(domain.clone()) {
+            Entry::Occupied(mut entry) => {
+                let previous = entry.insert(Arc::clone(&deployment));
+                self.reindex(&domain, Some(&previous), Some(&deployment));
+
+                Some(previous)
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::clone(&deployment));
+                self.reindex(&domain, None, Some(&deployment));
+
+                None
+            }
+        }
+    }
+
+    pub fn remove(&self, domain: &str) -> Option<(String, Arc<Deployment>)> {
+        self.remove_if(domain, |_| true)
+    }
+
+    fn remove_if(
+        &self,
+        domain: &str,
+        f: impl Fn(&Arc<Deployment>) -> bool,
+    ) -> Option<(String, Arc<Deployment>)> {
+        match self.domains.entry(domain.to_string()) {
+            Entry::Occupied(entry) if f(entry.get()) => {
+                self.reindex(domain, Some(entry.get()), None);
+
+                Some(entry.remove_entry())
+            }
+            _ => None,
+        }
+    }
+
+    // Update the index once the deployment registered on the domain changed. The
+    // new ids are added before the old ones are removed, so a deployment staying
+    // on the domain is never missing from the index in between.
+    fn reindex(
+        &self,
+        domain: &str,
+        previous: Option<&Arc<Deployment>>,
+        deployment: Option<&Arc<Deployment>>,
+    ) {
+        let previous_ids = previous.map_or_else(Vec::new, |previous| deployment_ids(previous));
+        let ids = deployment.map_or_else(Vec::new, |deployment| deployment_ids(deployment));
+
+        for id in &ids {
+            self.ids
+                .entry(id.to_string())
+                .or_default()
+                .insert(domain.to_string());
+        }
+
+        for id in previous_ids {
+            if ids.contains(&id) {
+                continue;
+            }
+
+            if let Entry::Occupied(mut entry) = self.ids.entry(id.to_string()) {
+                entry.get_mut().remove(domain);
+
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    // Domains the deployment is registered on, directly or as a canary
+    fn get_deployment_domains(&self, deployment_id: &str) -> HashSet<String> {
+        self.ids
+            .get(deployment_id)
+            .map(|domains| domains.clone())
+            .unwrap_or_default()
+    }
+}
+
+// The id of the deployment and of its canary if any
+fn deployment_ids(deployment: &Deployment) -> Vec<&str> {
+    let mut ids = vec![deployment.id.as_str()];
+
+    if let Some(canary) = &deployment.canary {
+        ids.push(canary.deployment.id.as_str());
+    }
+
+    ids
+}
+
+// Wildcard domains are only allowed as the leftmost label,
+// e.g `*.example.com` but not `a.*.example.com`
+fn is_valid_domain(domain: &str) -> bool {
+    match domain.strip_prefix("*.") {
+        Some(rest) => !rest.is_empty() && !rest.contains('*'),
+        None => !domain.contains('*'),
+    }
+}
 
-pub type Deployments = Arc<DashMap<String, Arc<Deployment>>>;
+// Domains already served by another deployment get the canary attached to
+// their deployment instead of being replaced, the other domains (e.g the
+// canary's own domain) are registered as usual
+pub fn register_canary(deployments: &Deployments, canary: &Arc<Deployment>, percentage: u8) {
+    for domain in canary.get_domains() {
+        if !is_valid_domain(&domain) {
+            warn!(deployment = canary.id, domain = domain; "Skipping invalid domain");
+            continue;
+        }
 
-pub async fn download_deployment<D>(deployment: &Deployment, downloader: Arc<D>) -> Result<()>
+        match deployments.entry(domain.clone()) {
+            Entry::Occupied(mut entry) if entry.get().id != canary.id => {
+                let mut deployment = entry.get().as_ref().clone();
+                deployment.canary = Some(Canary {
+                    deployment: Arc::clone(canary),
+                    percentage,
+                });
+
+                let previous = entry.insert(Arc::new(deployment));
+                deployments.reindex(&domain, Some(&previous), Some(entry.get()));
+            }
+            Entry::Occupied(mut entry) => {
+                let previous = entry.insert(Arc::clone(canary));
+                deployments.reindex(&domain, Some(&previous), Some(canary));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::clone(canary));
+                deployments.reindex(&domain, None, Some(canary));
+            }
+        }
+    }
+}
+
+// Remove the domains still pointing to the deployment, and
+// detach it from the deployments it's a canary of
+pub fn unregister_deployment(deployments: &Deployments, deployment: &Deployment) {
+    for domain in deployment.get_domains() {
+        deployments.remove_if(&domain, |registered| registered.id == deployment.id);
+    }
+
+    for domain in deployments.get_deployment_domains(&deployment.id) {
+        if let Some(mut entry) = deployments.get_mut(&domain) {
+            let is_canary = entry
+                .value()
+                .canary
+                .as_ref()
+                .map_or(false, |canary| canary.deployment.id == deployment.id);
+
+            if is_canary {
+                let mut registered = entry.value().as_ref().clone();
+                registered.canary = None;
+
+                let registered = Arc::new(registered);
+                deployments.reindex(&domain, Some(entry.value()), Some(&registered));
+                *entry.value_mut() = registered;
+            }
+        }
+    }
+}
+
+// Pick the canary for a share of the clients, identified by `key` (e.g their
+// IP address). A given client is always routed to the same deployment.
+pub fn get_canary(deployment: &Deployment, key: &str) -> Option<Arc<Deployment>> {
+    let canary = deployment.canary.as_ref()?;
+
+    let hash = stable_hash(&[key, &canary.deployment.id]);
+
+    ((hash % 100) < canary.percentage as u64).then(|| Arc::clone(&canary.deployment))
+}
+
+// FNV-1a of the parts, which unlike `DefaultHasher` gives the same hash
+// across processes and Rust releases, so clients stick to a deployment
+fn stable_hash(parts: &[&str]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+
+    for part in parts {
+        // Separate the parts, so ("ab", "c") and ("a", "bc") differ
+        for byte in part.bytes().chain(std::iter::once(0)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    hash
+}
+
+// Keep the canary attached to the deployment previously registered on a
+// domain when replacing it, unless the new deployment is that canary
+fn with_previous_canary(deployment: &Arc<Deployment>, previous: &Deployment) -> Arc<Deployment> {
+    match &previous.canary {
+        Some(canary) if deployment.canary.is_none() && canary.deployment.id != deployment.id => {
+            let mut deployment = deployment.as_ref().clone();
+            deployment.canary = Some(canary.clone());
+
+            Arc::new(deployment)
+        }
+        _ => Arc::clone(deployment),
+    }
+}
+
+pub fn register_deployment(deployments: &Deployments, deployment: &Arc<Deployment>) {
+    for domain in deployment.get_domains() {
+        if !is_valid_domain(&domain) {
+            warn!(deployment = deployment.id, domain = domain; "Skipping invalid domain");
+            continue;
+        }
+
+        match deployments.entry(domain.clone()) {
+            Entry::Occupied(mut entry) => {
+                let deployment = with_previous_canary(deployment, entry.get());
+                let previous = entry.insert(Arc::clone(&deployment));
+                deployments.reindex(&domain, Some(&previous), Some(&deployment));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::clone(deployment));
+                deployments.reindex(&domain, None, Some(deployment));
+            }
+        }
+    }
+}
+
+// Register a placeholder of a deployment being downloaded on its domains that
+// aren't served yet, so their requests are answered with a 503 instead of a 404
+// until it's ready. Return the placeholder to unregister it afterwards.
+pub fn register_provisioning(
+    deployments: &Deployments,
+    deployment: &Deployment,
+) -> Arc<Deployment> {
+    let mut placeholder = deployment.clone();
+    placeholder.provisioning = true;
+    let placeholder = Arc::new(placeholder);
+
+    for domain in placeholder.get_domains() {
+        if is_valid_domain(&domain) {
+            if let Entry::Vacant(entry) = deployments.entry(domain.clone()) {
+                entry.insert(Arc::clone(&placeholder));
+                deployments.reindex(&domain, None, Some(&placeholder));
+            }
+        }
+    }
+
+    placeholder
+}
+
+// Remove the entries still pointing to the placeholder, e.g once the
+// deployment claimed its domains or when it failed to deploy
+pub fn unregister_provisioning(deployments: &Deployments, placeholder: &Arc<Deployment>) {
+    for domain in placeholder.get_domains() {
+        deployments.remove_if(&domain, |registered| Arc::ptr_eq(registered, placeholder));
+    }
+}
+
+// Register the deployment, detecting the domains already served by a deployment of
+// another function, which usually means the control plane assigned a domain twice.
+// These domains are kept by their current deployment when `keep_existing` is set,
+// and reassigned otherwise. Return the conflicting domains.
+pub fn claim_domains(
+    deployments: &Deployments,
+    deployment: &Arc<Deployment>,
+    keep_existing: bool,
+) -> Vec<String> {
+    let mut conflicts = Vec::new();
+
+    for domain in deployment.get_domains() {
+        if !is_valid_domain(&domain) {
+            warn!(deployment = deployment.id, domain = domain; "Skipping invalid domain");
+            continue;
+        }
+
+        match deployments.entry(domain.clone()) {
+            Entry::Occupied(mut entry) if entry.get().function_id != deployment.function_id => {
+                increment_counter!("lagon_domain_conflicts", "domain" => domain.clone());
+                warn!(
+                    deployment = deployment.id,
+                    domain = domain,
+                    previous_deployment = entry.get().id;
+                    "Domain already served by a deployment of another function"
+                );
+
+                if !keep_existing {
+                    let previous = entry.insert(Arc::clone(deployment));
+                    deployments.reindex(&domain, Some(&previous), Some(deployment));
+                }
+
+                conflicts.push(domain);
+            }
+            Entry::Occupied(mut entry) => {
+                let deployment = with_previous_canary(deployment, entry.get());
+                let previous = entry.insert(Arc::clone(&deployment));
+                deployments.reindex(&domain, Some(&previous), Some(&deployment));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::clone(deployment));
+                deployments.reindex(&domain, None, Some(deployment));
+            }
+        }
+    }
+
+    conflicts
+}
+
+// Lowercase the hostname and strip its port if any, taking care of
+// IPv6 literals which contain colons, e.g `[::1]:8080` becomes `[::1]`
+pub fn normalize_hostname(hostname: &str) -> String {
+    let hostname = hostname.trim().to_lowercase();
+
+    if hostname.starts_with('[') {
+        return match hostname.find(']') {
+            Some(end) => hostname[..=end].to_string(),
+            None => hostname,
+        };
+    }
+
+    match hostname.rsplit_once(':') {
+        Some((host, _)) => host.to_string(),
+        None => hostname,
+    }
+}
+
+// Find the deployment of the domain with the longest path prefix matching the
+// path, falling back to the deployment of the whole domain. Prefixes match whole
+// segments, so `/api/users` tries `/api/users`, `/api` and then no prefix.
+fn get_deployment_by_path(
+    deployments: &Deployments,
+    domain: &str,
+    path: &str,
+) -> Option<Arc<Deployment>> {
+    let mut prefix = path.trim_end_matches('/');
+
+    while !prefix.is_empty() {
+        if let Some(entry) = deployments.get(&format!("{domain}{prefix}")) {
+            return Some(Arc::clone(entry.value()));
+        }
+
+        prefix = prefix.rsplit_once('/').map_or("", |(parent, _)| parent);
+    }
+
+    deployments
+        .get(domain)
+        .map(|entry| Arc::clone(entry.value()))
+}
+
+// Exact matches always take precedence over wildcards. If there's none,
+// we strip the leftmost label until we find a wildcard domain, so
+// `a.b.example.com` tries `*.b.example.com` and then `*.example.com`
+pub fn get_deployment(
+    deployments: &Deployments,
+    hostname: &str,
+    path: &str,
+) -> Option<Arc<Deployment>> {
+    if let Some(deployment) = get_deployment_by_path(deployments, hostname, path) {
+        return Some(deployment);
+    }
+
+    let mut rest = hostname;
+
+    while let Some((_, parent)) = rest.split_once('.') {
+        if let Some(deployment) = get_deployment_by_path(deployments, &format!("*.{parent}"), path)
+        {
+            return Some(deployment);
+        }
+
+        rest = parent;
+    }
+
+    None
+}
+
+pub fn get_deployment_by_id(
+    deployments: &Deployments,
+    deployment_id: &str,
+) -> Option<Arc<Deployment>> {
+    deployments
+        .get_deployment_domains(deployment_id)
+        .iter()
+        .filter_map(|domain| deployments.get(domain))
+        .find(|entry| entry.value().id == deployment_id)
+        .map(|entry| Arc::clone(entry.value()))
+}
+
+// Whether requests can still be routed to the deployment, either
+// directly or as the canary of another deployment
+pub fn is_deployment_registered(deployments: &Deployments, deployment_id: &str) -> bool {
+    deployments.ids.contains_key(deployment_id)
+}
+
+// Deployments are immutable once registered, so we replace
+// every entry (one per domain) with an updated copy
+fn update_deployment(
+    deployments: &Deployments,
+    deployment_id: &str,
+    update: impl Fn(&mut Deployment),
+) -> bool {
+    let mut found = false;
+
+    // The ids of the deployment and its canary don't change, so the index stays valid
+    for domain in deployments.get_deployment_domains(deployment_id) {
+        let mut entry = match deployments.get_mut(&domain) {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        if entry.value().id == deployment_id {
+            let mut deployment = entry.value().as_ref().clone();
+            update(&mut deployment);
+
+            *entry.value_mut() = Arc::new(deployment);
+            found = true;
+        }
+
+        // Canaries are also stored in the deployment they take traffic from
+        let is_canary = entry
+            .value()
+            .canary
+            .as_ref()
+            .map_or(false, |canary| canary.deployment.id == deployment_id);
+
+        if is_canary {
+            let mut deployment = entry.value().as_ref().clone();
+
+            if let Some(canary) = &mut deployment.canary {
+                let mut canary_deployment = canary.deployment.as_ref().clone();
+                update(&mut canary_deployment);
+
+                canary.deployment = Arc::new(canary_deployment);
+            }
+
+            *entry.value_mut() = Arc::new(deployment);
+            found = true;
+        }
+    }
+
+    found
+}
+
+pub fn set_maintenance(deployments: &Deployments, deployment_id: &str, enabled: bool) -> bool {
+    update_deployment(deployments, deployment_id, |deployment| {
+        deployment.maintenance = enabled
+    })
+}
+
+pub fn set_suspended(deployments: &Deployments, deployment_id: &str, enabled: bool) -> bool {
+    update_deployment(deployments, deployment_id, |deployment| {
+        deployment.suspended = enabled
+    })
+}
+
+pub fn set_environment_variables(
+    deployments: &Deployments,
+    deployment_id: &str,
+    environment_variables: HashMap<String, String>,
+) -> bool {
+    update_deployment(deployments, deployment_id, |deployment| {
+        deployment.environment_variables = environment_variables.clone()
+    })
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentSummary {
+    pub deployment_id: String,
+    pub function_id: String,
+    pub function_name: String,
+    pub domains: Vec<String>,
+    pub is_production: bool,
+    pub has_cron: bool,
+}
+
+// The map is keyed by domain, so we group its entries by deployment id. We only
+// clone the entries while iterating to hold each shard's lock as little as possible
+pub fn get_deployments_summary(deployments: &Deployments) -> Vec<DeploymentSummary> {
+    let entries = deployments
+        .iter()
+        .map(|entry| (entry.key().clone(), Arc::clone(entry.value())))
+        .collect::<Vec<_>>();
+
+    let mut summaries = BTreeMap::<String, DeploymentSummary>::new();
+
+    for (domain, deployment) in entries {
+        summaries
+            .entry(deployment.id.clone())
+            .or_insert_with(|| DeploymentSummary {
+                deployment_id: deployment.id.clone(),
+                function_id: deployment.function_id.clone(),
+                function_name: deployment.function_name.clone(),
+                domains: Vec::new(),
+                is_production: deployment.is_production,
+                has_cron: deployment.cron.is_some(),
+            })
+            .domains
+            .push(domain);
+    }
+
+    summaries
+        .into_values()
+        .map(|mut summary| {
+            summary.domains.sort();
+            summary
+        })
+        .collect()
+}
+
+fn verify_code_hash(code: &[u8], code_hash: &str) -> Result<()> {
+    let hash = hex::encode(Sha256::digest(code));
+
+    match hash.eq_ignore_ascii_case(code_hash) {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "Code hash mismatch: expected {}, got {}",
+            code_hash,
+            hash
+        )),
+    }
+}
+
+async fn download_code<D>(deployment: &Deployment, downloader: &D) -> Result<Vec<u8>>
 where
     D: Downloader,
 {
-    match downloader.download(deployment.id.clone() + ".js").await {
-        Ok(object) => {
-            deployment.write_code(&object)?;
-            info!(deployment = deployment.id; "Wrote deployment");
-
-            if !deployment.assets.is_empty() {
-                let mut futures = FuturesUnordered::new();
-
-                for asset in &deployment.assets {
-                    futures.push(async {
-                        let future =
-                            downloader.download(deployment.id.clone() + "/" + asset.as_str());
-                        (future.await, asset.clone())
-                    });
-                }
+    let code = downloader.download(deployment.id.clone() + ".js").await?;
 
-                while let Some((result, asset)) = futures.next().await {
-                    match result {
-                        Ok(object) => {
-                            deployment.write_asset(&asset, &object)?;
-                        }
-                        Err(error) => {
-                            warn!(deployment = deployment.id, asset = asset; "Failed to download deployment asset: {}", error)
-                        }
-                    };
-                }
+    // Never write a corrupted or tampered code
+    if let Some(code_hash) = &deployment.code_hash {
+        verify_code_hash(&code, code_hash)?;
+    }
+
+    Ok(code)
+}
+
+// Everything is downloaded before writing the files, so a deployment
+// whose new code can't be downloaded keeps serving its previous code
+pub async fn download_deployment<D>(
+    deployment: &Deployment,
+    downloader: Arc<D>,
+    deployments_dir: &Path,
+) -> Result<()>
+where
+    D: Downloader,
+{
+    let start = Instant::now();
+
+    // Checked before downloading, which could fill the cache
+    let cache_hits = std::iter::once(deployment.id.clone() + ".js")
+        .chain(
+            deployment
+                .assets
+                .iter()
+                .map(|asset| deployment.id.clone() + "/" + asset.as_str()),
+        )
+        .filter(|path| downloader.is_cached(path))
+        .count();
+
+    if cache_hits > 0 {
+        counter!("lagon_download_cache_hits", cache_hits as u64, "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+    }
+
+    let code = match download_code(deployment, downloader.as_ref()).await {
+        Ok(code) => code,
+        Err(error) => {
+            if deployment.has_code(deployments_dir) {
+                increment_counter!(
+                    "lagon_download_fallback_stale",
+                    "deployment" => deployment.id.clone(),
+                    "function" => deployment.function_id.clone(),
+                );
+                warn!(deployment = deployment.id; "Failed to download deployment, keeping its previous code");
             }
 
-            Ok(())
+            return Err(error);
+        }
+    };
+
+    let mut bytes = code.len();
+    let mut assets = Vec::new();
+
+    if !deployment.assets.is_empty() {
+        let mut futures = FuturesUnordered::new();
+
+        for asset in &deployment.assets {
+            futures.push(async {
+                let future = downloader.download(deployment.id.clone() + "/" + asset.as_str());
+                (future.await, asset.clone())
+            });
+        }
+
+        while let Some((result, asset)) = futures.next().await {
+            match result {
+                Ok(object) => {
+                    bytes += object.len();
+                    assets.push((asset, object));
+                }
+                Err(error) => {
+                    warn!(deployment = deployment.id, asset = asset; "Failed to download deployment asset: {}", error)
+                }
+            };
         }
-        Err(error) => Err(anyhow!(error)),
     }
+
+    for (asset, object) in assets {
+        deployment.write_asset(deployments_dir, &asset, &object)?;
+    }
+
+    // Write the code last, once its assets are in place
+    deployment.write_code(deployments_dir, &code)?;
+    info!(deployment = deployment.id; "Wrote deployment");
+
+    histogram!("lagon_download_bytes", bytes as f64, "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+    histogram!("lagon_download_duration", start.elapsed(), "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+
+    Ok(())
 }
 
 #[derive(Deserialize)]
 struct AssetObj(Vec<String>);
 
-type QueryResult = (
-    String,
-    bool,
-    String,
-    String,
-    String,
-    usize,
-    usize,
-    usize,
-    Option<String>,
-    Option<String>,
-    Option<String>,
-    Option<String>,
-);
-
-pub async fn get_deployments<D>(mut conn: PooledConn, downloader: Arc<D>) -> Result<Deployments>
+// JSON columns are read as strings, and missing or
+// invalid values are treated as `null`
+fn take_json(row: &mut Row, column: &str) -> Value {
+    row.take::<Option<String>, _>(column)
+        .flatten()
+        .and_then(|value| serde_json::from_str(&value).ok())
+        .unwrap_or_default()
+}
+
+// Secrets are stored encrypted (see `SecretsKey`), and skipped if they
+// can't be decrypted, e.g because no key is set
+fn add_secret(
+    deployment: &mut Deployment,
+    key: Option<String>,
+    value: Option<String>,
+    secrets_key: Option<&SecretsKey>,
+) {
+    let (key, value) = match (key, value) {
+        (Some(key), Some(value)) => (key, value),
+        _ => return,
+    };
+
+    if deployment.secrets.0.contains_key(&key) {
+        return;
+    }
+
+    let value = match secrets_key.map(|secrets_key| secrets_key.decrypt(&value)) {
+        Some(Ok(value)) => value,
+        Some(Err(error)) => {
+            error!(deployment = deployment.id, secret = key; "Could not decrypt secret: {}", error);
+            return;
+        }
+        None => {
+            warn!(deployment = deployment.id, secret = key; "Skipping secret, LAGON_SECRETS_KEY isn't set");
+            return;
+        }
+    };
+
+    deployment.secrets.0.insert(key, value);
+}
+
+pub async fn get_deployments<D>(
+    mut conn: PooledConn,
+    downloader: Arc<D>,
+    deployments_dir: &Path,
+    secrets_key: Option<&SecretsKey>,
+) -> Result<Deployments>
 where
     D: Downloader,
 {
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     let mut deployments_list: HashMap<String, Deployment> = HashMap::new();
+    let mut canary_percentages: HashMap<String, u8> = HashMap::new();
 
     conn.query_map(
         format!(
@@ -92,15 +731,45 @@ SELECT
     Deployment.id,
     Deployment.isProduction,
     Deployment.assets,
-    Function.id,
-    Function.name,
+    Deployment.runtimeVersion,
+    Deployment.canaryPercentage,
+    Function.id AS functionId,
+    Function.name AS functionName,
     Function.memory,
     Function.tickTimeout,
     Function.totalTimeout,
     Function.cron,
+    Function.ipAllowList,
+    Function.ipDenyList,
+    Function.suspended,
+    Function.responseHeaders,
+    Function.maxBodySize,
+    Function.cpuBudgetMs,
+    Function.cpuBudgetWindowMs,
+    Function.pinned,
+    Function.shadowDeploymentId,
+    Function.pathPrefix,
+    Function.disableAssets,
+    Function.decompressBody,
+    Function.allowedMethods,
+    Function.requestSampleRate,
+    Function.fallbackDeploymentId,
+    Function.allowedContentTypes,
+    Function.cacheResponses,
+    Function.preloadAssets,
+    Function.cronTimezone,
+    Function.spaFallback,
+    Function.functionFavicon,
+    Function.ephemeral,
+    Function.compress,
+    Function.jsonErrors,
+    Function.staticResponses,
+    Function.builtinStaticRoutes,
     Domain.domain,
-    EnvVariable.key,
-    EnvVariable.value
+    EnvVariable.key AS envKey,
+    EnvVariable.value AS envValue,
+    Secret.key AS secretKey,
+    Secret.value AS secretValue
 FROM
     Deployment
 INNER JOIN Function
@@ -109,6 +778,8 @@ LEFT JOIN Domain
     ON Function.id = Domain.functionId
 LEFT JOIN EnvVariable 
     ON Function.id = EnvVariable.functionId
+LEFT JOIN Secret
+    ON Function.id = Secret.functionId
 WHERE
     Function.cron IS NULL
 OR
@@ -116,25 +787,25 @@ OR
 ",
             get_region()
         ),
-        |(
-            id,
-            is_production,
-            assets,
-            function_id,
-            function_name,
-            memory,
-            tick_timeout,
-            total_timeout,
-            cron,
-            domain,
-            env_key,
-            env_value,
-        ): QueryResult| {
+        // Columns are read by name since there are too many for a tuple
+        |mut row: Row| {
+            let id: String = row.take("id").unwrap_or_default();
+            let domain: Option<String> = row.take("domain").flatten();
+            let env_key: Option<String> = row.take("envKey").flatten();
+            let env_value: Option<String> = row.take("envValue").flatten();
+            let secret_key: Option<String> = row.take("secretKey").flatten();
+            let secret_value: Option<String> = row.take("secretValue").flatten();
+            let assets: String = row.take("assets").unwrap_or_default();
+
+            if let Some(percentage) = row.take::<Option<u32>, _>("canaryPercentage").flatten() {
+                canary_percentages.insert(id.clone(), percentage.min(100) as u8);
+            }
+
             let assets = serde_json::from_str::<AssetObj>(&assets)
                 .map(|asset_obj| asset_obj.0)
                 .unwrap_or_default();
 
-            deployments_list
+            let deployment = deployments_list
                 .entry(id.clone())
                 .and_modify(|deployment| {
                     if let Some(domain) = domain.clone() {
@@ -149,10 +820,10 @@ OR
                             .insert(env_key, env_value.clone().unwrap_or_default());
                     }
                 })
-                .or_insert(Deployment {
+                .or_insert_with(|| Deployment {
                     id,
-                    function_id,
-                    function_name,
+                    function_id: row.take("functionId").unwrap_or_default(),
+                    function_name: row.take("functionName").unwrap_or_default(),
                     domains: domain
                         .map(|domain| {
                             let mut domains = HashSet::new();
@@ -168,12 +839,61 @@ OR
                             environment_variables
                         })
                         .unwrap_or_default(),
-                    memory,
-                    tick_timeout,
-                    total_timeout,
-                    is_production,
-                    cron,
+                    memory: row.take("memory").unwrap_or_default(),
+                    tick_timeout: row.take("tickTimeout").unwrap_or_default(),
+                    total_timeout: row.take("totalTimeout").unwrap_or_default(),
+                    is_production: row.take("isProduction").unwrap_or_default(),
+                    cron: row.take("cron").flatten(),
+                    cron_timezone: row.take("cronTimezone").flatten(),
+                    spa_fallback: row.take("spaFallback").flatten(),
+                    maintenance: false,
+                    function_favicon: row.take("functionFavicon").unwrap_or_default(),
+                    code_hash: None,
+                    ip_allow_list: parse_ip_list(&take_json(&mut row, "ipAllowList")),
+                    ip_deny_list: parse_ip_list(&take_json(&mut row, "ipDenyList")),
+                    secrets: Secrets::default(),
+                    ephemeral: row.take("ephemeral").unwrap_or_default(),
+                    suspended: row.take("suspended").unwrap_or_default(),
+                    compress: row.take("compress").unwrap_or_default(),
+                    runtime_version: row.take("runtimeVersion").flatten(),
+                    canary: None,
+                    json_errors: row.take("jsonErrors").unwrap_or_default(),
+                    response_headers: get_str_map(&take_json(&mut row, "responseHeaders")),
+                    max_body_size: row.take("maxBodySize").flatten(),
+                    cpu_budget: match (
+                        row.take("cpuBudgetMs").flatten(),
+                        row.take("cpuBudgetWindowMs").flatten(),
+                    ) {
+                        (Some(cpu_time), Some(window)) => Some(CpuBudget {
+                            cpu_time: Duration::from_millis(cpu_time),
+                            window: Duration::from_millis(window),
+                        }),
+                        _ => None,
+                    },
+                    pinned: row.take("pinned").unwrap_or_default(),
+                    provisioning: false,
+                    shadow_deployment_id: row.take("shadowDeploymentId").flatten(),
+                    path_prefix: parse_path_prefix(&Value::from(
+                        row.take::<Option<String>, _>("pathPrefix").flatten(),
+                    )),
+                    disable_assets: row.take("disableAssets").unwrap_or_default(),
+                    decompress_body: row.take("decompressBody").unwrap_or_default(),
+                    allowed_methods: parse_methods(&take_json(&mut row, "allowedMethods")),
+                    request_sample_rate: row.take("requestSampleRate").flatten(),
+                    static_responses: get_str_map(&take_json(&mut row, "staticResponses")),
+                    builtin_static_routes: row.take("builtinStaticRoutes").unwrap_or_default(),
+                    preload_assets: serde_json::from_value(take_json(&mut row, "preloadAssets"))
+                        .unwrap_or_default(),
+                    fallback_deployment_id: row.take("fallbackDeploymentId").flatten(),
+                    allowed_content_types: serde_json::from_value(take_json(
+                        &mut row,
+                        "allowedContentTypes",
+                    ))
+                    .unwrap_or_default(),
+                    cache_responses: row.take("cacheResponses").unwrap_or_default(),
                 });
+
+            add_secret(deployment, secret_key, secret_value, secrets_key);
         },
     )?;
 
@@ -181,36 +901,101 @@ OR
 
     info!("Found {} deployment(s) to deploy", deployments_list.len());
 
-    if let Err(error) = create_deployments_folder() {
+    if let Err(error) = create_deployments_folder(deployments_dir) {
         error!("Could not create deployments folder: {}", error);
     }
 
-    if let Err(error) = delete_old_deployments(&deployments_list).await {
+    if let Err(error) = delete_old_deployments(deployments_dir, &deployments_list).await {
         error!("Failed to delete old deployments: {:?}", error);
     }
 
-    futures::future::join_all(deployments_list.into_iter().map(|deployment| async {
-        if !deployment.has_code() {
-            if let Err(error) = download_deployment(&deployment, Arc::clone(&downloader)).await {
+    let loaded = futures::future::join_all(deployments_list.into_iter().map(|deployment| async {
+        if !deployment.has_code(deployments_dir) {
+            if let Err(error) =
+                download_deployment(&deployment, Arc::clone(&downloader), deployments_dir).await
+            {
                 error!("Failed to download deployment {}: {}", deployment.id, error);
-                return;
+                return None;
             }
         }
 
-        let deployment = Arc::new(deployment);
-
-        for domain in deployment.get_domains() {
-            deployments.insert(domain, Arc::clone(&deployment));
-        }
+        Some(Arc::new(deployment))
     }))
     .await;
 
+    register_loaded_deployments(
+        &deployments,
+        loaded.into_iter().flatten().collect(),
+        &canary_percentages,
+    );
+
+    Ok(deployments)
+}
+
+// Canaries (deployments with a canary percentage) are registered once the
+// deployments they take a share of the traffic of are, like when they're
+// deployed after them, so they're attached to them instead of replacing them
+fn register_loaded_deployments(
+    deployments: &Deployments,
+    loaded: Vec<Arc<Deployment>>,
+    canary_percentages: &HashMap<String, u8>,
+) {
+    let (canaries, loaded): (Vec<_>, Vec<_>) = loaded
+        .into_iter()
+        .partition(|deployment| canary_percentages.contains_key(&deployment.id));
+
+    for deployment in loaded {
+        register_deployment(deployments, &deployment);
+    }
+
+    for canary in canaries {
+        register_canary(deployments, &canary, canary_percentages[&canary.id]);
+    }
+}
+
+// Load the deployments described by the `<id>.json` manifests of the deployments
+// directory, whose code and assets are already next to them (e.g `<id>.js`), to run
+// without a control plane. Manifests have the same format as deploy messages.
+pub fn get_local_deployments(deployments_dir: &Path) -> Result<Deployments> {
+    let deployments = Deployments::default();
+
+    for entry in fs::read_dir(deployments_dir)? {
+        let path = entry?.path();
+
+        if path
+            .extension()
+            .map_or(true, |extension| extension != "json")
+        {
+            continue;
+        }
+
+        let manifest = serde_json::from_slice(&fs::read(&path)?)
+            .map_err(|error| anyhow!("Invalid manifest {}: {}", path.display(), error))?;
+        let deployment = deployment_from_value(&manifest)
+            .map_err(|error| anyhow!("Invalid manifest {}: {}", path.display(), error))?;
+
+        if !deployment.has_code(deployments_dir) {
+            return Err(anyhow!(
+                "Missing code of deployment {} in {}",
+                deployment.id,
+                deployments_dir.display()
+            ));
+        }
+
+        register_deployment(&deployments, &Arc::new(deployment));
+    }
+
+    info!(
+        "Found {} local deployment(s)",
+        get_deployments_summary(&deployments).len()
+    );
+
     Ok(deployments)
 }
 
-async fn delete_old_deployments(deployments: &[Deployment]) -> Result<()> {
+async fn delete_old_deployments(deployments_dir: &Path, deployments: &[Deployment]) -> Result<()> {
     info!("Deleting old deployments");
-    let local_deployments_files = fs::read_dir(Path::new(DEPLOYMENTS_DIR))?;
+    let local_deployments_files = fs::read_dir(deployments_dir)?;
 
     for local_deployment_file in local_deployments_files {
         let local_deployment_file_name = local_deployment_file?
@@ -229,10 +1014,416 @@ async fn delete_old_deployments(deployments: &[Deployment]) -> Result<()> {
             .iter()
             .any(|deployment| deployment.id == local_deployment_id)
         {
-            rm_deployment(&local_deployment_id)?;
+            rm_deployment(deployments_dir, &local_deployment_id)?;
         }
     }
     info!("Old deployments deleted");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use metrics::{Counter, Gauge, Histogram, Key, KeyName, Recorder, SharedString, Unit};
+    use std::sync::Mutex;
+
+    // Names of the counters and histograms recorded
+    static METRICS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct TestRecorder;
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key) -> Counter {
+            METRICS.lock().unwrap().push(key.name().to_owned());
+            Counter::noop()
+        }
+
+        fn register_gauge(&self, _: &Key) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key) -> Histogram {
+            METRICS.lock().unwrap().push(key.name().to_owned());
+            Histogram::noop()
+        }
+    }
+
+    struct CachedDownloader;
+
+    #[async_trait]
+    impl Downloader for CachedDownloader {
+        async fn download(&self, _path: String) -> Result<Vec<u8>> {
+            Ok(b"export function handler() {}".to_vec())
+        }
+
+        fn is_cached(&self, _path: &str) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn download_metrics() {
+        metrics::set_recorder(&TestRecorder).unwrap();
+
+        let deployments_dir = std::env::temp_dir().join("lagon-download-metrics-test");
+        fs::create_dir_all(&deployments_dir).unwrap();
+
+        let deployment = Deployment {
+            id: "download-metrics".into(),
+            ..Deployment::default()
+        };
+        download_deployment(&deployment, Arc::new(CachedDownloader), &deployments_dir)
+            .await
+            .unwrap();
+
+        let metrics = METRICS.lock().unwrap();
+        for name in [
+            "lagon_download_duration",
+            "lagon_download_bytes",
+            "lagon_download_cache_hits",
+        ] {
+            assert!(metrics.iter().any(|metric| metric == name));
+        }
+    }
+
+    #[test]
+    fn code_hash() {
+        let code = b"export function handler() {}";
+        let hash = hex::encode(Sha256::digest(code));
+
+        assert!(verify_code_hash(code, &hash).is_ok());
+        assert!(verify_code_hash(code, &hash.to_uppercase()).is_ok());
+        assert!(verify_code_hash(b"tampered", &hash).is_err());
+    }
+
+    #[test]
+    fn deployments_summary() {
+        let deployments = Deployments::default();
+        let deployment = Arc::new(Deployment {
+            id: "123".into(),
+            function_id: "456".into(),
+            function_name: "hello".into(),
+            is_production: true,
+            ..Deployment::default()
+        });
+        deployments.insert("hello.lagon.app".into(), Arc::clone(&deployment));
+        deployments.insert("lagon.app".into(), deployment);
+
+        assert_eq!(
+            get_deployments_summary(&deployments),
+            vec![DeploymentSummary {
+                deployment_id: "123".into(),
+                function_id: "456".into(),
+                function_name: "hello".into(),
+                domains: vec!["hello.lagon.app".into(), "lagon.app".into()],
+                is_production: true,
+                has_cron: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn domain_conflicts() {
+        std::env::set_var("LAGON_ROOT_DOMAIN", "lagon.test");
+
+        let deployments = Deployments::default();
+        let deployment = Arc::new(Deployment {
+            id: "123".into(),
+            function_id: "456".into(),
+            function_name: "hello".into(),
+            domains: HashSet::from(["lagon.app".into()]),
+            is_production: true,
+            ..Deployment::default()
+        });
+        let conflicting = Arc::new(Deployment {
+            id: "789".into(),
+            function_id: "012".into(),
+            function_name: "world".into(),
+            domains: HashSet::from(["lagon.app".into()]),
+            is_production: true,
+            ..Deployment::default()
+        });
+
+        assert!(claim_domains(&deployments, &deployment, true).is_empty());
+        assert_eq!(
+            claim_domains(&deployments, &conflicting, true),
+            vec![String::from("lagon.app")]
+        );
+        assert_eq!(deployments.get("lagon.app").unwrap().id, "123");
+        assert_eq!(deployments.get("world.lagon.test").unwrap().id, "789");
+
+        assert_eq!(
+            claim_domains(&deployments, &conflicting, false),
+            vec![String::from("lagon.app")]
+        );
+        assert_eq!(deployments.get("lagon.app").unwrap().id, "789");
+
+        // New deployments of the same function take over its domains
+        let redeployment = Arc::new(Deployment {
+            id: "345".into(),
+            ..deployment.as_ref().clone()
+        });
+        assert!(claim_domains(&deployments, &redeployment, true).is_empty());
+        assert_eq!(deployments.get("hello.lagon.test").unwrap().id, "345");
+    }
+
+    #[test]
+    fn path_prefixes() {
+        let deployments = Deployments::default();
+
+        for (domain, id) in [
+            ("example.com", "root"),
+            ("example.com/api", "api"),
+            ("example.com/api/v2", "v2"),
+            ("*.example.com/api", "wildcard-api"),
+        ] {
+            deployments.insert(
+                domain.into(),
+                Arc::new(Deployment {
+                    id: id.into(),
+                    ..Deployment::default()
+                }),
+            );
+        }
+
+        let get = |hostname: &str, path: &str| {
+            get_deployment(&deployments, hostname, path).map(|deployment| deployment.id.clone())
+        };
+
+        // The longest prefix takes precedence
+        assert_eq!(get("example.com", "/api/v2/users").unwrap(), "v2");
+        assert_eq!(get("example.com", "/api/users").unwrap(), "api");
+        assert_eq!(get("example.com", "/api/").unwrap(), "api");
+        assert_eq!(get("example.com", "/api").unwrap(), "api");
+
+        // Prefixes only match whole segments and case-sensitively
+        assert_eq!(get("example.com", "/apis").unwrap(), "root");
+        assert_eq!(get("example.com", "/API").unwrap(), "root");
+        assert_eq!(get("example.com", "/").unwrap(), "root");
+
+        // Other paths of wildcard domains don't fall through to another domain
+        assert_eq!(
+            get("www.example.com", "/api/users").unwrap(),
+            "wildcard-api"
+        );
+        assert!(get("www.example.com", "/users").is_none());
+    }
+
+    #[test]
+    fn canary_percentage() {
+        let canary = Arc::new(Deployment {
+            id: "canary".into(),
+            ..Deployment::default()
+        });
+        let mut deployment = Deployment {
+            id: "stable".into(),
+            canary: Some(Canary {
+                deployment: Arc::clone(&canary),
+                percentage: 0,
+            }),
+            ..Deployment::default()
+        };
+
+        let keys = (0..100).map(|i| format!("10.0.0.{i}")).collect::<Vec<_>>();
+
+        assert!(keys
+            .iter()
+            .all(|key| get_canary(&deployment, key).is_none()));
+
+        deployment.canary.as_mut().unwrap().percentage = 100;
+        assert!(keys
+            .iter()
+            .all(|key| get_canary(&deployment, key).unwrap().id == "canary"));
+
+        deployment.canary.as_mut().unwrap().percentage = 50;
+        let routed = keys
+            .iter()
+            .filter(|key| get_canary(&deployment, key).is_some())
+            .count();
+        assert!(routed > 0 && routed < 100);
+
+        // Clients always get the same deployment, even after a restart
+        let selected = |deployment: &Deployment, key: &str| {
+            get_canary(deployment, key)
+                .map_or_else(|| deployment.id.clone(), |canary| canary.id.clone())
+        };
+        let restarted = deployment.clone();
+        assert!(keys
+            .iter()
+            .all(|key| selected(&deployment, key) == selected(&restarted, key)));
+
+        deployment.canary.as_mut().unwrap().percentage = 45;
+        assert_eq!(selected(&deployment, "10.0.0.1"), "stable");
+        deployment.canary.as_mut().unwrap().percentage = 46;
+        assert_eq!(selected(&deployment, "10.0.0.1"), "canary");
+    }
+
+    #[test]
+    fn redeploy_keeps_canary() {
+        std::env::set_var("LAGON_ROOT_DOMAIN", "lagon.test");
+
+        let deployments = Deployments::default();
+        let deployment = Arc::new(Deployment {
+            id: "stable".into(),
+            function_id: "function".into(),
+            function_name: "hello".into(),
+            is_production: true,
+            ..Deployment::default()
+        });
+        let canary = Arc::new(Deployment {
+            id: "canary".into(),
+            ..deployment.as_ref().clone()
+        });
+
+        register_deployment(&deployments, &deployment);
+        register_canary(&deployments, &canary, 10);
+
+        let redeployment = Arc::new(Deployment {
+            id: "redeployed".into(),
+            ..deployment.as_ref().clone()
+        });
+        register_deployment(&deployments, &redeployment);
+
+        let registered = deployments.get("hello.lagon.test").unwrap();
+        assert_eq!(registered.id, "redeployed");
+        assert_eq!(registered.canary.as_ref().unwrap().deployment.id, "canary");
+        drop(registered);
+
+        // Promoting the canary replaces the deployment it was attached to
+        register_deployment(&deployments, &canary);
+        let registered = deployments.get("hello.lagon.test").unwrap();
+        assert_eq!(registered.id, "canary");
+        assert!(registered.canary.is_none());
+    }
+
+    #[test]
+    fn registered_deployments() {
+        std::env::set_var("LAGON_ROOT_DOMAIN", "lagon.test");
+
+        let deployments = Deployments::default();
+        let deployment = Arc::new(Deployment {
+            id: "stable".into(),
+            function_id: "function".into(),
+            function_name: "hello".into(),
+            is_production: true,
+            ..Deployment::default()
+        });
+        let canary = Arc::new(Deployment {
+            id: "canary".into(),
+            ..deployment.as_ref().clone()
+        });
+
+        register_deployment(&deployments, &deployment);
+        register_canary(&deployments, &canary, 10);
+
+        assert!(is_deployment_registered(&deployments, "stable"));
+        assert!(is_deployment_registered(&deployments, "canary"));
+        assert_eq!(
+            get_deployment_by_id(&deployments, "canary").unwrap().id,
+            "canary"
+        );
+
+        assert!(set_maintenance(&deployments, "stable", true));
+        assert!(
+            get_deployment_by_id(&deployments, "stable")
+                .unwrap()
+                .maintenance
+        );
+
+        unregister_deployment(&deployments, &canary);
+        assert!(!is_deployment_registered(&deployments, "canary"));
+        assert!(get_deployment_by_id(&deployments, "canary").is_none());
+        assert!(get_deployment_by_id(&deployments, "stable")
+            .unwrap()
+            .canary
+            .is_none());
+
+        unregister_deployment(&deployments, &deployment);
+        assert!(!is_deployment_registered(&deployments, "stable"));
+        assert!(deployments.is_empty());
+    }
+
+    #[test]
+    fn loaded_secrets() {
+        let secrets_key = SecretsKey::from_hex(
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        )
+        .unwrap();
+        let mut deployment = Deployment {
+            id: "deployment".into(),
+            environment_variables: HashMap::from([("PUBLIC".into(), "public value".into())]),
+            ..Deployment::default()
+        };
+
+        // One row per secret, joined with the other tables
+        for _ in 0..2 {
+            add_secret(
+                &mut deployment,
+                Some("TOKEN".into()),
+                Some(secrets_key.encrypt("secret value").unwrap()),
+                Some(&secrets_key),
+            );
+        }
+        add_secret(&mut deployment, None, None, Some(&secrets_key));
+        add_secret(
+            &mut deployment,
+            Some("INVALID".into()),
+            Some("0001".into()),
+            Some(&secrets_key),
+        );
+        add_secret(
+            &mut deployment,
+            Some("WITHOUT_KEY".into()),
+            Some(secrets_key.encrypt("secret value").unwrap()),
+            None,
+        );
+
+        assert_eq!(
+            deployment.secrets.0,
+            HashMap::from([("TOKEN".into(), "secret value".into())])
+        );
+
+        let environment_variables = deployment.get_environment_variables();
+        assert_eq!(environment_variables["PUBLIC"], "public value");
+        assert_eq!(environment_variables["TOKEN"], "secret value");
+    }
+
+    #[test]
+    fn loaded_canaries() {
+        std::env::set_var("LAGON_ROOT_DOMAIN", "lagon.test");
+
+        let deployments = Deployments::default();
+        let deployment = |id: &str| {
+            Arc::new(Deployment {
+                id: id.into(),
+                function_name: "function".into(),
+                domains: HashSet::from(["example.com".into()]),
+                is_production: true,
+                ..Deployment::default()
+            })
+        };
+
+        // The canary is loaded before the deployment it's a canary of
+        register_loaded_deployments(
+            &deployments,
+            vec![deployment("canary"), deployment("stable")],
+            &HashMap::from([("canary".into(), 20)]),
+        );
+
+        let registered = deployments.get("example.com").unwrap();
+        assert_eq!(registered.id, "stable");
+
+        let canary = registered.canary.as_ref().unwrap();
+        assert_eq!(canary.deployment.id, "canary");
+        assert_eq!(canary.percentage, 20);
+        assert_eq!(deployments.get("canary.lagon.test").unwrap().id, "canary");
+    }
+}
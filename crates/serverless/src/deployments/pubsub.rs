@@ -1,242 +1,1082 @@
-use super::{download_deployment, filesystem::rm_deployment, Deployment, Deployments};
-use crate::{cronjob::Cronjob, get_region, serverless::Workers};
-use anyhow::Result;
+use super::{
+    claim_domains, download_deployment,
+    events::{DeploymentEventKind, DeploymentEvents},
+    filesystem::rm_deployment,
+    get_deployment_by_id, register_canary, register_deployment, register_provisioning,
+    set_environment_variables, set_maintenance, set_suspended, unregister_deployment,
+    unregister_provisioning, Deployment, Deployments,
+};
+use crate::{
+    cronjob::Cronjob,
+    ip::parse_ip_net,
+    serverless::{dispatch_event, ServerConfig, Worker, Workers},
+};
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
 use futures::StreamExt;
-use lagon_runtime_isolate::IsolateEvent;
+use hyper::Method;
+use ipnet::IpNet;
+use lagon_runtime_http::RunResult;
+use lagon_runtime_isolate::{
+    options::{IsolateOptions, Metadata},
+    FunctionEvent, Isolate, IsolateEvent, IsolateReload,
+};
+use lagon_runtime_utils::{CpuBudget, Secrets};
 use lagon_serverless_downloader::Downloader;
-use lagon_serverless_pubsub::{PubSubListener, PubSubMessage, PubSubMessageKind};
-use log::{error, warn};
+use lagon_serverless_pubsub::{PubSubEncoding, PubSubListener, PubSubMessage, PubSubMessageKind};
+use log::{error, info, warn};
 use metrics::increment_counter;
-use serde_json::Value;
-use std::{collections::HashMap, sync::Arc};
-use tokio::{runtime::Handle, sync::Mutex};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime::Handle,
+    sync::{
+        oneshot::{self, error::TryRecvError},
+        Mutex, Semaphore,
+    },
+};
 
-pub async fn clear_deployment_cache(deployment_id: String, workers: Workers, reason: String) {
-    if let Some((_, tx)) = workers.remove(&deployment_id) {
-        tx.send_async(IsolateEvent::Terminate(reason))
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Return whether the deployment had a worker
+pub async fn clear_deployment_cache(
+    deployment_id: String,
+    workers: Workers,
+    reason: String,
+) -> bool {
+    match workers.remove(&deployment_id) {
+        Some((_, worker)) => {
+            worker
+                .sender
+                .send_async(IsolateEvent::Terminate(reason))
+                .await
+                .unwrap_or(());
+
+            true
+        }
+        None => false,
+    }
+}
+
+// Removing the worker stops routing new requests to its isolate (a new one
+// will be created if needed), but we wait for the in-flight requests to
+// complete (up to the timeout) before terminating it
+pub fn drain_deployment_cache(
+    deployment_id: String,
+    workers: Workers,
+    reason: String,
+    timeout: Duration,
+) {
+    if let Some((_, worker)) = workers.remove(&deployment_id) {
+        drain_worker(worker, reason, Some(timeout));
+    }
+}
+
+// Terminate a worker already removed from the workers map once its in-flight
+// requests are completed, or anyway once the timeout (if any) is elapsed
+pub fn drain_worker(worker: Worker, reason: String, timeout: Option<Duration>) {
+    tokio::spawn(async move {
+        match timeout {
+            Some(timeout) => {
+                if tokio::time::timeout(timeout, wait_in_flight_requests(&worker))
+                    .await
+                    .is_err()
+                {
+                    warn!(
+                        reason = reason;
+                        "{} request(s) still in flight after {:?}, terminating isolate",
+                        worker.in_flight.load(Ordering::SeqCst),
+                        timeout,
+                    );
+                    increment_counter!("lagon_drain_timeouts", "reason" => reason.clone());
+                }
+            }
+            None => wait_in_flight_requests(&worker).await,
+        }
+
+        worker
+            .sender
+            .send_async(IsolateEvent::Terminate(reason))
             .await
             .unwrap_or(());
+    });
+}
+
+async fn wait_in_flight_requests(worker: &Worker) {
+    while worker.in_flight.load(Ordering::SeqCst) > 0 {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
     }
 }
 
-async fn run<D, P>(
+fn evaluate_deployment(deployment: &Deployment, config: &ServerConfig) -> Result<()> {
+    let code = deployment.get_code(&config.deployments_dir)?;
+    let options = IsolateOptions::with_code(code)
+        .environment_variables(deployment.get_environment_variables())
+        .memory(deployment.memory)
+        .tick_timeout(Duration::from_millis(deployment.tick_timeout as u64))
+        .total_timeout(Duration::from_millis(deployment.total_timeout as u64))
+        .snapshot_blob(config.snapshots.get(deployment.runtime_version.as_deref()));
+
+    // The isolate never receives any request
+    let (_, receiver) = flume::unbounded();
+    let mut isolate = Isolate::new(options, receiver);
+    isolate.evaluate();
+
+    match isolate.get_compilation_error() {
+        Some(error) => Err(anyhow!(error.to_string())),
+        None => Ok(()),
+    }
+}
+
+// Evaluate the deployment's code in a throwaway isolate, without registering
+// the deployment. The isolate is dropped as soon as the evaluation is done
+async fn validate_deployment(deployment: Arc<Deployment>, config: Arc<ServerConfig>) -> Result<()> {
+    let (sender, receiver) = flume::bounded(1);
+
+    std::thread::Builder::new()
+        .name(String::from("validate-") + deployment.id.as_str())
+        .spawn(move || {
+            let result = evaluate_deployment(&deployment, &config);
+            sender.send(result).unwrap_or(());
+        })?;
+
+    receiver.recv_async().await?
+}
+
+pub(crate) fn parse_ip_list(value: &Value) -> Vec<IpNet> {
+    value
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .filter_map(|v| v.as_str())
+                .filter_map(|v| {
+                    let net = parse_ip_net(v);
+
+                    if net.is_none() {
+                        warn!("Ignoring invalid IP or CIDR: {}", v);
+                    }
+
+                    net
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) fn parse_methods(value: &Value) -> Vec<Method> {
+    value
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .filter_map(|v| v.as_str())
+                .filter_map(|v| {
+                    let method = Method::from_bytes(v.to_ascii_uppercase().as_bytes()).ok();
+
+                    if method.is_none() {
+                        warn!("Ignoring invalid HTTP method: {}", v);
+                    }
+
+                    method
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Only the code can be swapped in a live isolate, the other
+// options are set when creating it
+fn has_same_isolate_options(deployment: &Deployment, previous: &Deployment) -> bool {
+    deployment.environment_variables == previous.environment_variables
+        && deployment.secrets == previous.secrets
+        && deployment.memory == previous.memory
+        && deployment.tick_timeout == previous.tick_timeout
+        && deployment.total_timeout == previous.total_timeout
+}
+
+// Send the new code to the deployment's live isolate, keeping it warm. Returns
+// false if there is no live isolate, since the next one will use the new code
+async fn reload_isolate(
+    deployment: &Deployment,
+    previous: Option<Arc<Deployment>>,
+    workers: &Workers,
+    deployments_dir: &Path,
+) -> Result<bool> {
+    if let Some(previous) = previous {
+        if !has_same_isolate_options(deployment, &previous) {
+            return Err(anyhow!("Isolate options changed"));
+        }
+    }
+
+    let sender = match workers.get(&deployment.id) {
+        Some(worker) => worker.sender.clone(),
+        None => return Ok(false),
+    };
+
+    let code = deployment.get_code(deployments_dir)?;
+    let (reload_sender, reload_receiver) = flume::bounded(1);
+
+    sender
+        .send_async(IsolateEvent::ReloadCode(IsolateReload {
+            code: Box::new(code),
+            sender: reload_sender,
+        }))
+        .await?;
+
+    reload_receiver
+        .recv_async()
+        .await?
+        .map_err(|error| anyhow!(error))?;
+
+    Ok(true)
+}
+
+fn parse_payload(payload: &[u8], encoding: PubSubEncoding) -> Result<Value> {
+    Ok(match encoding {
+        PubSubEncoding::Json => serde_json::from_slice(payload)?,
+        PubSubEncoding::MessagePack => rmp_serde::from_slice(payload)?,
+    })
+}
+
+fn get_str(value: &Value, key: &str) -> Result<String> {
+    value[key]
+        .as_str()
+        .map(|v| v.to_string())
+        .ok_or_else(|| anyhow!("Invalid or missing {} in payload", key))
+}
+
+fn get_u64(value: &Value, key: &str) -> Result<u64> {
+    value[key]
+        .as_u64()
+        .ok_or_else(|| anyhow!("Invalid or missing {} in payload", key))
+}
+
+fn get_str_list(value: &Value, key: &str) -> Result<Vec<String>> {
+    value[key]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid or missing {} in payload", key))?
+        .iter()
+        .map(|v| {
+            v.as_str()
+                .map(|v| v.to_string())
+                .ok_or_else(|| anyhow!("Invalid value in {} in payload", key))
+        })
+        .collect()
+}
+
+pub(crate) fn get_str_map(value: &Value) -> HashMap<String, String> {
+    value
+        .as_object()
+        .map(|object| {
+            object
+                .iter()
+                .filter_map(|(k, v)| v.as_str().map(|v| (k.to_owned(), v.to_owned())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Both the budget and its window have to be set
+fn parse_cpu_budget(value: &Value) -> Option<CpuBudget> {
+    Some(CpuBudget {
+        cpu_time: Duration::from_millis(value["cpuBudgetMs"].as_u64()?),
+        window: Duration::from_millis(value["cpuBudgetWindowMs"].as_u64()?),
+    })
+}
+
+// Prefixes match whole segments, so `api`, `/api` and `/api/` are all `/api`
+pub(crate) fn parse_path_prefix(value: &Value) -> Option<String> {
+    let path_prefix = value.as_str()?.trim_matches('/');
+
+    (!path_prefix.is_empty()).then(|| format!("/{path_prefix}"))
+}
+
+// Parse a deployment from the payload of a message, whatever its encoding
+pub fn deployment_from_value(value: &Value) -> Result<Deployment> {
+    Ok(Deployment {
+        id: get_str(value, "deploymentId")?,
+        function_id: get_str(value, "functionId")?,
+        function_name: get_str(value, "functionName")?,
+        assets: get_str_list(value, "assets")?.into_iter().collect(),
+        domains: get_str_list(value, "domains")?.into_iter().collect(),
+        environment_variables: get_str_map(&value["env"]),
+        memory: get_u64(value, "memory")? as usize,
+        tick_timeout: get_u64(value, "tickTimeout")? as usize,
+        total_timeout: get_u64(value, "totalTimeout")? as usize,
+        is_production: value["isProduction"]
+            .as_bool()
+            .ok_or_else(|| anyhow!("Invalid or missing isProduction in payload"))?,
+        cron: value["cron"].as_str().map(|v| v.to_string()),
+        cron_timezone: value["cronTimezone"].as_str().map(|v| v.to_string()),
+        spa_fallback: value["spaFallback"].as_str().map(|v| v.to_string()),
+        maintenance: false,
+        function_favicon: value["functionFavicon"].as_bool().unwrap_or(false),
+        code_hash: value["codeHash"].as_str().map(|v| v.to_string()),
+        ip_allow_list: parse_ip_list(&value["ipAllowList"]),
+        ip_deny_list: parse_ip_list(&value["ipDenyList"]),
+        secrets: Secrets(get_str_map(&value["secrets"])),
+        ephemeral: value["ephemeral"].as_bool().unwrap_or(false),
+        suspended: value["suspended"].as_bool().unwrap_or(false),
+        compress: value["compress"].as_bool().unwrap_or(false),
+        runtime_version: value["runtimeVersion"].as_str().map(|v| v.to_string()),
+        canary: None,
+        json_errors: value["jsonErrors"].as_bool().unwrap_or(false),
+        response_headers: get_str_map(&value["responseHeaders"]),
+        max_body_size: value["maxBodySize"].as_u64().map(|v| v as usize),
+        cpu_budget: parse_cpu_budget(value),
+        pinned: value["pinned"].as_bool().unwrap_or(false),
+        provisioning: false,
+        shadow_deployment_id: value["shadowDeploymentId"].as_str().map(|v| v.to_string()),
+        path_prefix: parse_path_prefix(&value["pathPrefix"]),
+        disable_assets: value["disableAssets"].as_bool().unwrap_or(false),
+        decompress_body: value["decompressBody"].as_bool().unwrap_or(false),
+        allowed_methods: parse_methods(&value["allowedMethods"]),
+        request_sample_rate: value["requestSampleRate"].as_f64(),
+        static_responses: get_str_map(&value["staticResponses"]),
+        builtin_static_routes: value["builtinStaticRoutes"].as_bool().unwrap_or(false),
+        preload_assets: get_str_list(value, "preloadAssets").unwrap_or_default(),
+        fallback_deployment_id: value["fallbackDeploymentId"]
+            .as_str()
+            .map(|v| v.to_string()),
+        allowed_content_types: get_str_list(value, "allowedContentTypes").unwrap_or_default(),
+        cache_responses: value["cacheResponses"].as_bool().unwrap_or(false),
+    })
+}
+
+// Inverse of `deployment_from_value`. The state set on this node only (e.g the
+// maintenance mode or the canaries attached to the deployment) isn't included.
+pub fn deployment_to_value(deployment: &Deployment) -> Value {
+    let ip_list = |list: &[IpNet]| list.iter().map(IpNet::to_string).collect::<Vec<_>>();
+    // Sorted to always serialize a deployment the same way
+    let sorted = |set: &HashSet<String>| set.iter().collect::<BTreeSet<_>>();
+    let cpu_budget = deployment.cpu_budget.as_ref();
+
+    json!({
+        "deploymentId": deployment.id,
+        "functionId": deployment.function_id,
+        "functionName": deployment.function_name,
+        "assets": sorted(&deployment.assets),
+        "domains": sorted(&deployment.domains),
+        "env": deployment.environment_variables,
+        "memory": deployment.memory,
+        "tickTimeout": deployment.tick_timeout,
+        "totalTimeout": deployment.total_timeout,
+        "isProduction": deployment.is_production,
+        "cron": deployment.cron,
+        "cronTimezone": deployment.cron_timezone,
+        "spaFallback": deployment.spa_fallback,
+        "functionFavicon": deployment.function_favicon,
+        "codeHash": deployment.code_hash,
+        "ipAllowList": ip_list(&deployment.ip_allow_list),
+        "ipDenyList": ip_list(&deployment.ip_deny_list),
+        "secrets": deployment.secrets.0,
+        "ephemeral": deployment.ephemeral,
+        "suspended": deployment.suspended,
+        "compress": deployment.compress,
+        "runtimeVersion": deployment.runtime_version,
+        "jsonErrors": deployment.json_errors,
+        "responseHeaders": deployment.response_headers,
+        "maxBodySize": deployment.max_body_size,
+        "cpuBudgetMs": cpu_budget.map(|budget| budget.cpu_time.as_millis() as u64),
+        "cpuBudgetWindowMs": cpu_budget.map(|budget| budget.window.as_millis() as u64),
+        "pinned": deployment.pinned,
+        "shadowDeploymentId": deployment.shadow_deployment_id,
+        "pathPrefix": deployment.path_prefix,
+        "disableAssets": deployment.disable_assets,
+        "decompressBody": deployment.decompress_body,
+        "allowedMethods": deployment
+            .allowed_methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>(),
+        "requestSampleRate": deployment.request_sample_rate,
+        "staticResponses": deployment.static_responses,
+        "builtinStaticRoutes": deployment.builtin_static_routes,
+        "preloadAssets": deployment.preload_assets,
+        "fallbackDeploymentId": deployment.fallback_deployment_id,
+        "allowedContentTypes": deployment.allowed_content_types,
+        "cacheResponses": deployment.cache_responses,
+    })
+}
+
+// A stalled download (e.g a connection to the bucket that hangs) would
+// otherwise block the handling of all the next messages
+async fn download_with_timeout<D>(
+    deployment: &Deployment,
+    downloader: Arc<D>,
+    config: &ServerConfig,
+) -> Result<()>
+where
+    D: Downloader,
+{
+    let download = download_deployment(deployment, downloader, &config.deployments_dir);
+
+    let timeout = match config.download_timeout {
+        Some(timeout) => timeout,
+        None => return download.await,
+    };
+
+    match tokio::time::timeout(timeout, download).await {
+        Ok(result) => result,
+        Err(_) => {
+            increment_counter!(
+                "lagon_download_timeouts",
+                "deployment" => deployment.id.clone(),
+                "function" => deployment.function_id.clone(),
+            );
+
+            Err(anyhow!(
+                "Download timed out after {}ms",
+                timeout.as_millis()
+            ))
+        }
+    }
+}
+
+struct MessageContext<D> {
+    config: Arc<ServerConfig>,
     downloader: Arc<D>,
     deployments: Deployments,
     workers: Workers,
     cronjob: Arc<Mutex<Cronjob>>,
-    pubsub: Arc<Mutex<P>>,
+    events: DeploymentEvents,
+    last_requests: Arc<DashMap<String, Instant>>,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+}
+
+async fn handle_message<D>(
+    kind: PubSubMessageKind,
+    value: Value,
+    context: &MessageContext<D>,
 ) -> Result<()>
 where
     D: Downloader,
-    P: PubSubListener,
 {
-    let mut pubsub = pubsub.lock().await;
-    let mut stream = pubsub.get_stream()?;
+    let MessageContext {
+        config,
+        downloader,
+        deployments,
+        workers,
+        cronjob,
+        events,
+        ..
+    } = context;
 
-    while let Some(Ok(PubSubMessage { kind, payload })) = stream.next().await {
-        let value: Value = serde_json::from_str(&payload)?;
+    // Maintenance messages only contain the deployment id
+    if let PubSubMessageKind::SetMaintenance { enabled } = kind {
+        let deployment_id = get_str(&value, "deploymentId")?;
 
-        let cron = value["cron"].as_str();
-        let cron_region = value["cronRegion"].as_str().unwrap().to_string();
+        match set_maintenance(&deployments, &deployment_id, enabled) {
+            true => {
+                info!(deployment = deployment_id, enabled = enabled; "Maintenance mode updated")
+            }
+            false => {
+                warn!(deployment = deployment_id; "Maintenance mode updated for an unknown deployment")
+            }
+        }
 
-        // Ignore deployments that have a cron set but where
-        // the region isn't this node' region, except for undeploys
-        // because we might remove the cron from the old region
-        if cron.is_some() && &cron_region != get_region() && kind != PubSubMessageKind::Undeploy {
-            continue;
+        return Ok(());
+    }
+
+    if let PubSubMessageKind::SetSuspended { enabled } = kind {
+        let deployment_id = get_str(&value, "deploymentId")?;
+
+        match set_suspended(&deployments, &deployment_id, enabled) {
+            true => {
+                info!(deployment = deployment_id, enabled = enabled; "Suspension updated");
+
+                // Suspended deployments must stop running right away
+                if enabled {
+                    clear_deployment_cache(
+                        deployment_id.clone(),
+                        Arc::clone(&workers),
+                        String::from("suspension"),
+                    )
+                    .await;
+                }
+            }
+            false => {
+                warn!(deployment = deployment_id; "Suspension updated for an unknown deployment")
+            }
         }
 
-        let cron = cron.map(|cron| cron.to_string());
+        return Ok(());
+    }
 
-        let deployment = Deployment {
-            id: value["deploymentId"].as_str().unwrap().to_string(),
-            function_id: value["functionId"].as_str().unwrap().to_string(),
-            function_name: value["functionName"].as_str().unwrap().to_string(),
-            assets: value["assets"]
-                .as_array()
-                .unwrap()
-                .iter()
-                .map(|v| v.as_str().unwrap().to_string())
-                .collect(),
-            domains: value["domains"]
-                .as_array()
-                .unwrap()
-                .iter()
-                .map(|v| v.as_str().unwrap().to_string())
-                .collect(),
-            environment_variables: value["env"]
-                .as_object()
-                .unwrap()
-                .iter()
-                .map(|(k, v)| (k.to_owned(), v.as_str().unwrap().to_string()))
-                .collect::<HashMap<_, _>>(),
-            memory: value["memory"].as_u64().unwrap() as usize,
-            tick_timeout: value["tickTimeout"].as_u64().unwrap() as usize,
-            total_timeout: value["totalTimeout"].as_u64().unwrap() as usize,
-            is_production: value["isProduction"].as_bool().unwrap(),
-            cron,
-        };
+    // Only the environment variables change, so the code isn't downloaded again
+    if kind == PubSubMessageKind::UpdateEnv {
+        let deployment_id = get_str(&value, "deploymentId")?;
 
-        let workers = Arc::clone(&workers);
-
-        match kind {
-            PubSubMessageKind::Deploy => {
-                match download_deployment(&deployment, Arc::clone(&downloader)).await {
-                    Ok(_) => {
-                        increment_counter!(
-                            "lagon_deployments",
-                            "status" => "success",
-                            "deployment" => deployment.id.clone(),
-                            "function" => deployment.function_id.clone(),
-                        );
+        if !value["env"].is_object() {
+            return Err(anyhow!("Invalid or missing env in payload"));
+        }
 
-                        let domains = deployment.get_domains();
-                        let deployment = Arc::new(deployment);
+        match set_environment_variables(&deployments, &deployment_id, get_str_map(&value["env"])) {
+            true => {
+                info!(deployment = deployment_id; "Environment variables updated");
 
-                        for domain in &domains {
-                            deployments.insert(domain.clone(), Arc::clone(&deployment));
-                        }
+                // Environment variables are set when creating the isolate,
+                // so the next request creates a new one
+                drain_deployment_cache(
+                    deployment_id,
+                    Arc::clone(&workers),
+                    String::from("env"),
+                    config.undeploy_drain_timeout,
+                );
+            }
+            false => {
+                warn!(deployment = deployment_id; "Environment variables updated for an unknown deployment")
+            }
+        }
+
+        return Ok(());
+    }
+
+    let cron = value["cron"].as_str();
+    let cron_region = value["cronRegion"].as_str().unwrap().to_string();
+
+    // Ignore deployments that have a cron set but where
+    // the region isn't this node' region, except for undeploys
+    // because we might remove the cron from the old region
+    if cron.is_some() && cron_region != config.region() && kind != PubSubMessageKind::Undeploy {
+        return Ok(());
+    }
+
+    let deployment = deployment_from_value(&value)?;
+
+    let workers = Arc::clone(&workers);
+
+    match kind {
+        PubSubMessageKind::Deploy => {
+            // Canaries don't serve their domains by themselves
+            let placeholder = (config.provisioning_placeholders
+                && value["canaryPercentage"].is_null())
+            .then(|| register_provisioning(&deployments, &deployment));
 
-                        if deployment.should_run_cron() {
-                            let mut cronjob = cronjob.lock().await;
-                            let id = deployment.id.clone();
+            match download_with_timeout(&deployment, Arc::clone(&downloader), &config).await {
+                Ok(_) => {
+                    let deployment = Arc::new(deployment);
 
-                            if let Err(error) = cronjob.add(deployment).await {
-                                error!(deployment = id; "Failed to register cron: {}", error);
+                    // Production deployments are only routable once we
+                    // know their code can be evaluated
+                    if config.readiness_check && deployment.is_production {
+                        if let Err(error) =
+                            validate_deployment(Arc::clone(&deployment), Arc::clone(&config)).await
+                        {
+                            increment_counter!(
+                                "lagon_deploy_readiness_failures",
+                                "deployment" => deployment.id.clone(),
+                                "function" => deployment.function_id.clone(),
+                            );
+                            increment_counter!(
+                                "lagon_deployments",
+                                "status" => "error",
+                                "deployment" => deployment.id.clone(),
+                                "function" => deployment.function_id.clone(),
+                            );
+                            error!(deployment = deployment.id; "Deployment failed readiness check: {}", error);
+                            events.emit(&deployment.id, DeploymentEventKind::Failed);
+
+                            if let Some(placeholder) = &placeholder {
+                                unregister_provisioning(&deployments, placeholder);
+                            }
+
+                            if get_deployment_by_id(&deployments, &deployment.id).is_none() {
+                                if let Err(error) =
+                                    rm_deployment(&config.deployments_dir, &deployment.id)
+                                {
+                                    warn!(deployment = deployment.id; "Failed to delete deployment: {}", error);
+                                }
                             }
+
+                            return Ok(());
                         }
                     }
-                    Err(error) => {
-                        increment_counter!(
-                            "lagon_deployments",
-                            "status" => "error",
-                            "deployment" => deployment.id.clone(),
-                            "function" => deployment.function_id.clone(),
-                        );
-                        error!(
-                            deployment = deployment.id;
-                            "Failed to download deployment: {}", error
-                        );
-                    }
-                };
-            }
-            PubSubMessageKind::Undeploy => {
-                match rm_deployment(&deployment.id) {
-                    Ok(_) => {
-                        increment_counter!(
-                            "lagon_undeployments",
-                            "status" => "success",
-                            "deployment" => deployment.id.clone(),
-                            "function" => deployment.function_id.clone(),
-                        );
 
-                        let domains = deployment.get_domains();
+                    increment_counter!(
+                        "lagon_deployments",
+                        "status" => "success",
+                        "deployment" => deployment.id.clone(),
+                        "function" => deployment.function_id.clone(),
+                    );
 
-                        for domain in &domains {
-                            deployments.remove(domain);
+                    // Canaries only take a share of the traffic of
+                    // the deployment currently serving their domains
+                    match value["canaryPercentage"].as_u64() {
+                        Some(percentage) => {
+                            register_canary(&deployments, &deployment, percentage.min(100) as u8)
                         }
+                        None => {
+                            claim_domains(
+                                &deployments,
+                                &deployment,
+                                config.keep_conflicting_domains,
+                            );
+                        }
+                    }
 
-                        clear_deployment_cache(
-                            deployment.id.clone(),
-                            workers,
-                            String::from("undeployment"),
-                        )
-                        .await;
+                    // Domains still provisioning weren't claimed, e.g because
+                    // another function kept them
+                    if let Some(placeholder) = &placeholder {
+                        unregister_provisioning(&deployments, placeholder);
+                    }
 
-                        if deployment.should_run_cron() {
-                            let mut cronjob = cronjob.lock().await;
+                    events.emit(&deployment.id, DeploymentEventKind::Deployed);
 
-                            if let Err(error) = cronjob.remove(&deployment.id).await {
-                                error!(deployment = deployment.id; "Failed to remove cron: {}", error);
-                            }
+                    if deployment.should_run_cron() {
+                        let mut cronjob = cronjob.lock().await;
+                        let id = deployment.id.clone();
+
+                        if let Err(error) = cronjob.add(deployment).await {
+                            error!(deployment = id; "Failed to register cron: {}", error);
                         }
                     }
-                    Err(error) => {
-                        increment_counter!(
-                            "lagon_undeployments",
-                            "status" => "error",
-                            "deployment" => deployment.id.clone(),
-                            "function" => deployment.function_id.clone(),
+                }
+                Err(error) => {
+                    increment_counter!(
+                        "lagon_deployments",
+                        "status" => "error",
+                        "deployment" => deployment.id.clone(),
+                        "function" => deployment.function_id.clone(),
+                    );
+                    error!(
+                        deployment = deployment.id;
+                        "Failed to download deployment: {}", error
+                    );
+                    events.emit(&deployment.id, DeploymentEventKind::Failed);
+
+                    if let Some(placeholder) = &placeholder {
+                        unregister_provisioning(&deployments, placeholder);
+                    }
+                }
+            };
+        }
+        PubSubMessageKind::Undeploy => {
+            match rm_deployment(&config.deployments_dir, &deployment.id) {
+                Ok(_) => {
+                    increment_counter!(
+                        "lagon_undeployments",
+                        "status" => "success",
+                        "deployment" => deployment.id.clone(),
+                        "function" => deployment.function_id.clone(),
+                    );
+
+                    unregister_deployment(&deployments, &deployment);
+                    config.latency.remove(&deployment.id);
+                    config.request_rates.remove(&deployment.id);
+                    config.response_cache.remove(&deployment.id);
+
+                    if let Some(circuit_breaker) = &config.circuit_breaker {
+                        circuit_breaker.remove(&deployment.id);
+                    }
+
+                    // The deployment isn't routable anymore, so new requests can't reach
+                    // the isolate while its in-flight requests complete
+                    if let Some((_, worker)) = workers.remove(&deployment.id) {
+                        drain_worker(
+                            worker,
+                            String::from("undeployment"),
+                            Some(config.undeploy_drain_timeout),
                         );
-                        error!(deployment = deployment.id; "Failed to delete deployment: {}", error);
                     }
-                };
+
+                    events.emit(&deployment.id, DeploymentEventKind::Undeployed);
+
+                    if deployment.should_run_cron() {
+                        let mut cronjob = cronjob.lock().await;
+
+                        if let Err(error) = cronjob.remove(&deployment.id).await {
+                            error!(deployment = deployment.id; "Failed to remove cron: {}", error);
+                        }
+                    }
+                }
+                Err(error) => {
+                    increment_counter!(
+                        "lagon_undeployments",
+                        "status" => "error",
+                        "deployment" => deployment.id.clone(),
+                        "function" => deployment.function_id.clone(),
+                    );
+                    error!(deployment = deployment.id; "Failed to delete deployment: {}", error);
+                }
+            };
+        }
+        PubSubMessageKind::Promote => {
+            increment_counter!(
+                "lagon_promotion",
+                "deployment" => deployment.id.clone(),
+                "function" => deployment.function_id.clone(),
+            );
+
+            let previous_id = value["previousDeploymentId"].as_str().unwrap();
+
+            if let Some(deployment) = deployments.get(previous_id) {
+                let mut unpromoted_deployment = deployment.as_ref().clone();
+                unpromoted_deployment.is_production = false;
+
+                for domain in deployment.get_domains() {
+                    deployments.remove(&domain);
+                }
+
+                let unpromoted_deployment = Arc::new(unpromoted_deployment);
+                register_deployment(&deployments, &unpromoted_deployment);
+            }
+
+            let deployment = Arc::new(deployment);
+            register_deployment(&deployments, &deployment);
+
+            drain_deployment_cache(
+                previous_id.to_string(),
+                workers,
+                String::from("promotion"),
+                config.undeploy_drain_timeout,
+            );
+            events.emit(&deployment.id, DeploymentEventKind::Promoted);
+
+            let mut cronjob = cronjob.lock().await;
+
+            if let Err(error) = cronjob.remove(&previous_id.to_string()).await {
+                error!(deployment = deployment.id; "Failed to remove cron: {}", error);
+            }
+
+            if deployment.should_run_cron() {
+                let id = deployment.id.clone();
+
+                if let Err(error) = cronjob.add(deployment).await {
+                    error!(deployment = id; "Failed to register cron: {}", error);
+                }
             }
-            PubSubMessageKind::Promote => {
+        }
+        PubSubMessageKind::Reload => {
+            if let Err(error) =
+                download_with_timeout(&deployment, Arc::clone(&downloader), &config).await
+            {
                 increment_counter!(
-                    "lagon_promotion",
+                    "lagon_reloads",
+                    "status" => "error",
                     "deployment" => deployment.id.clone(),
                     "function" => deployment.function_id.clone(),
                 );
+                error!(deployment = deployment.id; "Failed to download deployment: {}", error);
 
-                let previous_id = value["previousDeploymentId"].as_str().unwrap();
-
-                if let Some(deployment) = deployments.get(previous_id) {
-                    let mut unpromoted_deployment = deployment.as_ref().clone();
-                    unpromoted_deployment.is_production = false;
+                return Ok(());
+            }
 
-                    for domain in deployment.get_domains() {
-                        deployments.remove(&domain);
-                    }
+            let previous = get_deployment_by_id(&deployments, &deployment.id);
+            let deployment = Arc::new(deployment);
+            register_deployment(&deployments, &deployment);
 
-                    let unpromoted_deployment = Arc::new(unpromoted_deployment);
+            match reload_isolate(&deployment, previous, &workers, &config.deployments_dir).await {
+                Ok(reloaded) => {
+                    increment_counter!(
+                        "lagon_reloads",
+                        "status" => "success",
+                        "deployment" => deployment.id.clone(),
+                        "function" => deployment.function_id.clone(),
+                    );
+                    info!(deployment = deployment.id, reloaded = reloaded; "Deployment reloaded");
+                }
+                Err(error) => {
+                    increment_counter!(
+                        "lagon_reloads",
+                        "status" => "restart",
+                        "deployment" => deployment.id.clone(),
+                        "function" => deployment.function_id.clone(),
+                    );
+                    warn!(deployment = deployment.id; "Failed to reload isolate, restarting it: {}", error);
 
-                    for domain in unpromoted_deployment.get_domains() {
-                        deployments.insert(domain, Arc::clone(&unpromoted_deployment));
-                    }
+                    // The new code is already on disk, so the next
+                    // isolate created for this deployment will use it
+                    drain_deployment_cache(
+                        deployment.id.clone(),
+                        workers,
+                        String::from("reload"),
+                        config.undeploy_drain_timeout,
+                    );
                 }
+            }
+        }
+        PubSubMessageKind::Validate => {
+            let is_deployed = deployments
+                .iter()
+                .any(|entry| entry.value().id == deployment.id);
+            let deployment = Arc::new(deployment);
 
-                let deployment = Arc::new(deployment);
-                let domains = deployment.get_domains();
+            let result = match download_with_timeout(&deployment, Arc::clone(&downloader), &config)
+                .await
+            {
+                Ok(_) => validate_deployment(Arc::clone(&deployment), Arc::clone(&config)).await,
+                Err(error) => Err(error),
+            };
 
-                for domain in &domains {
-                    deployments.insert(domain.clone(), Arc::clone(&deployment));
+            match result {
+                Ok(_) => {
+                    increment_counter!(
+                        "lagon_validations",
+                        "status" => "success",
+                        "deployment" => deployment.id.clone(),
+                        "function" => deployment.function_id.clone(),
+                    );
+                    info!(deployment = deployment.id; "Deployment validated");
+                }
+                Err(error) => {
+                    increment_counter!(
+                        "lagon_validations",
+                        "status" => "error",
+                        "deployment" => deployment.id.clone(),
+                        "function" => deployment.function_id.clone(),
+                    );
+                    error!(deployment = deployment.id; "Failed to validate deployment: {}", error);
                 }
+            };
 
-                clear_deployment_cache(previous_id.to_string(), workers, String::from("promotion"))
-                    .await;
+            // Don't keep the files of a deployment that isn't deployed yet
+            if !is_deployed {
+                if let Err(error) = rm_deployment(&config.deployments_dir, &deployment.id) {
+                    warn!(deployment = deployment.id; "Failed to delete validated deployment: {}", error);
+                }
+            }
+        }
+        _ => warn!("Unknown message kind: {:?}, {}", kind, value),
+    };
 
-                let mut cronjob = cronjob.lock().await;
+    Ok(())
+}
+
+// Event messages contain the deployment id, the name of the
+// event and its payload, which can be any JSON value
+async fn handle_event_message<D>(value: Value, context: &MessageContext<D>) -> Result<()> {
+    let deployment_id = get_str(&value, "deploymentId")?;
+    let event = FunctionEvent {
+        name: get_str(&value, "name")?,
+        payload: value["payload"].to_string(),
+    };
+
+    let deployment = get_deployment_by_id(&context.deployments, &deployment_id)
+        .filter(|deployment| !deployment.provisioning)
+        .ok_or_else(|| anyhow!("Deployment {} not found", deployment_id))?;
+
+    // Like requests, see `handle_request`
+    if deployment.suspended {
+        return Err(anyhow!("Deployment {} is suspended", deployment_id));
+    }
+
+    let result = dispatch_event(
+        &deployment,
+        event,
+        &context.deployments,
+        &context.last_requests,
+        &context.workers,
+        &context.config,
+        &context.log_sender,
+    )
+    .await;
+
+    let error = match result {
+        RunResult::Response(response, _) if response.status().is_success() => None,
+        RunResult::Response(response, _) => Some(format!(
+            "Function answered with status {}",
+            response.status()
+        )),
+        RunResult::Timeout => Some(String::from("Function timed out")),
+        RunResult::MemoryLimit => Some(String::from("Function reached its memory limit")),
+        RunResult::Error(error) => Some(error),
+        RunResult::Stream(_) => Some(String::from("Function can't answer an event with a stream")),
+    };
+
+    increment_counter!(
+        "lagon_dispatched_events",
+        "status" => match error {
+            Some(_) => "error",
+            None => "success",
+        },
+        "deployment" => deployment.id.clone(),
+        "function" => deployment.function_id.clone(),
+    );
 
-                if let Err(error) = cronjob.remove(&previous_id.to_string()).await {
-                    error!(deployment = deployment.id; "Failed to remove cron: {}", error);
+    match error {
+        Some(error) => error!(deployment = deployment.id; "Failed to dispatch event: {}", error),
+        None => info!(deployment = deployment.id; "Event dispatched"),
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run<D, P>(
+    config: Arc<ServerConfig>,
+    downloader: Arc<D>,
+    deployments: Deployments,
+    workers: Workers,
+    cronjob: Arc<Mutex<Cronjob>>,
+    pubsub: Arc<Mutex<P>>,
+    events: &DeploymentEvents,
+    last_requests: Arc<DashMap<String, Instant>>,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+) -> Result<()>
+where
+    D: Downloader + Send + Sync + 'static,
+    P: PubSubListener,
+{
+    let context = Arc::new(MessageContext {
+        config: Arc::clone(&config),
+        downloader,
+        deployments,
+        workers,
+        cronjob: Arc::clone(&cronjob),
+        events: events.clone(),
+        last_requests,
+        log_sender,
+    });
+    let semaphore = Arc::new(Semaphore::new(config.max_concurrent_messages));
+    let mut pending: HashMap<String, oneshot::Receiver<()>> = HashMap::new();
+
+    let mut pubsub = pubsub.lock().await;
+    let mut stream = pubsub.get_stream()?;
+
+    while let Some(Ok(PubSubMessage {
+        kind,
+        payload,
+        encoding,
+    })) = stream.next().await
+    {
+        // A malformed message is skipped instead of closing the
+        // subscription, which would lose the messages published meanwhile
+        let value = match parse_payload(&payload, encoding) {
+            Ok(value) => value,
+            Err(error) => {
+                error!("Failed to parse pub/sub message: {}", error);
+                continue;
+            }
+        };
+
+        if kind == PubSubMessageKind::TriggerCron {
+            let deployment_id = match get_str(&value, "deploymentId") {
+                Ok(deployment_id) => deployment_id,
+                Err(error) => {
+                    error!("Failed to trigger cron: {}", error);
+                    continue;
+                }
+            };
+            let triggered = cronjob.lock().await.trigger(&deployment_id);
+
+            match triggered {
+                true => {
+                    increment_counter!(
+                        "lagon_cron_manual_triggers",
+                        "deployment" => deployment_id.clone(),
+                    );
+                    info!(deployment = deployment_id; "Cron triggered manually")
+                }
+                false => {
+                    warn!(deployment = deployment_id; "Cron triggered for a deployment without a registered cron")
                 }
+            }
 
-                if deployment.should_run_cron() {
-                    let id = deployment.id.clone();
+            continue;
+        }
 
-                    if let Err(error) = cronjob.add(deployment).await {
-                        error!(deployment = id; "Failed to register cron: {}", error);
-                    }
+        // Events are dispatched right away, without waiting
+        // for the previous messages of their deployment
+        if kind == PubSubMessageKind::DispatchEvent {
+            let permit = Arc::clone(&semaphore).acquire_owned().await?;
+            let context = Arc::clone(&context);
+
+            tokio::spawn(async move {
+                if let Err(error) = handle_event_message(value, &context).await {
+                    error!("Failed to dispatch event: {}", error);
                 }
+
+                drop(permit);
+            });
+
+            continue;
+        }
+
+        let mut deployment_ids = match get_str(&value, "deploymentId") {
+            Ok(deployment_id) => vec![deployment_id],
+            Err(error) => {
+                error!("Failed to handle pub/sub message: {}", error);
+                continue;
             }
-            _ => warn!("Unknown message kind: {:?}, {}", kind, payload),
         };
+
+        // Promotions also update the previous deployment
+        if let Some(previous_id) = value["previousDeploymentId"].as_str() {
+            deployment_ids.push(previous_id.to_string());
+        }
+
+        // Messages of the same deployment are handled once the previous one
+        // completed, so they keep their order (e.g a deploy and its undeploy)
+        pending.retain(|_, done| matches!(done.try_recv(), Err(TryRecvError::Empty)));
+        let mut previous = Vec::new();
+        let mut done_senders = Vec::new();
+
+        for deployment_id in deployment_ids {
+            let (done_sender, done_receiver) = oneshot::channel();
+
+            if let Some(done) = pending.insert(deployment_id, done_receiver) {
+                previous.push(done);
+            }
+
+            done_senders.push(done_sender);
+        }
+
+        let permit = Arc::clone(&semaphore).acquire_owned().await?;
+        let context = Arc::clone(&context);
+
+        tokio::spawn(async move {
+            // Also resolves if the previous message's task panicked
+            for done in previous {
+                done.await.unwrap_or(());
+            }
+
+            if let Err(error) = handle_message(kind, value, &context).await {
+                error!("Failed to handle pub/sub message: {}", error);
+            }
+
+            drop(permit);
+            drop(done_senders);
+        });
     }
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn listen_pub_sub<D, P>(
+    config: Arc<ServerConfig>,
     downloader: Arc<D>,
     deployments: Deployments,
     workers: Workers,
     cronjob: Arc<Mutex<Cronjob>>,
     pubsub: Arc<Mutex<P>>,
+    last_requests: Arc<DashMap<String, Instant>>,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
 ) where
     D: Downloader + Send + Sync + 'static,
     P: PubSubListener + 'static,
 {
     let handle = Handle::current();
     std::thread::spawn(move || {
+        let events = DeploymentEvents::new(Arc::clone(&config.on_deployment_event));
+
         handle.block_on(async {
             loop {
                 if let Err(error) = run(
+                    Arc::clone(&config),
                     Arc::clone(&downloader),
                     Arc::clone(&deployments),
                     Arc::clone(&workers),
                     Arc::clone(&cronjob),
                     Arc::clone(&pubsub),
+                    &events,
+                    Arc::clone(&last_requests),
+                    log_sender.clone(),
                 )
                 .await
                 {
@@ -0,0 +1,36 @@
+use crate::serverless::Workers;
+use metrics::gauge;
+use std::{collections::HashSet, time::Duration};
+
+const QUEUE_DEPTH_TASK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sample the number of requests waiting to be picked up by each isolate
+pub fn run_queue_depth_task(workers: Workers) {
+    tokio::spawn(async move {
+        let mut sampled_deployments = HashSet::new();
+        let mut queue_depths = Vec::new();
+
+        loop {
+            tokio::time::sleep(QUEUE_DEPTH_TASK_INTERVAL).await;
+
+            // Read the depths while iterating so we never sample
+            // a worker after it has been removed from the map
+            for worker in workers.iter() {
+                queue_depths.push((worker.key().clone(), worker.sender.len()));
+            }
+
+            // Reset the gauge of workers removed since the last sample,
+            // otherwise they would report their last depth forever
+            for deployment_id in sampled_deployments.drain() {
+                if !queue_depths.iter().any(|(id, _)| id == &deployment_id) {
+                    gauge!("lagon_isolate_queue_depth", 0.0, "deployment" => deployment_id);
+                }
+            }
+
+            for (deployment_id, queue_depth) in queue_depths.drain(..) {
+                gauge!("lagon_isolate_queue_depth", queue_depth as f64, "deployment" => deployment_id.clone());
+                sampled_deployments.insert(deployment_id);
+            }
+        }
+    });
+}
@@ -0,0 +1,200 @@
+use dashmap::DashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// How often a deprioritized request checks if it can be dispatched
+const WAIT_INTERVAL: Duration = Duration::from_millis(5);
+
+struct FunctionUsage {
+    window_start: Instant,
+    cpu_time: Duration,
+}
+
+// Share the CPU time between functions under contention. Each function gets
+// a CPU time quota per window (scaled by its weight), and requests of functions
+// that overran their quota are only dispatched once no other request is running,
+// the window is over, or they waited for `max_delay`.
+pub struct FairScheduler {
+    window: Duration,
+    cpu_quota: Duration,
+    max_delay: Duration,
+    weights: HashMap<String, u32>,
+    usages: DashMap<String, FunctionUsage>,
+    prioritized: Arc<AtomicUsize>,
+}
+
+impl FairScheduler {
+    pub fn new(window: Duration, cpu_quota: Duration) -> Self {
+        Self {
+            window,
+            cpu_quota,
+            max_delay: window,
+            weights: HashMap::new(),
+            usages: DashMap::new(),
+            prioritized: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    // Functions have a weight of 1 by default
+    pub fn weight(mut self, function_id: String, weight: u32) -> Self {
+        self.weights.insert(function_id, weight);
+        self
+    }
+
+    pub fn weights(mut self, weights: HashMap<String, u32>) -> Self {
+        self.weights.extend(weights);
+        self
+    }
+
+    fn quota(&self, function_id: &str) -> Duration {
+        self.cpu_quota * self.weights.get(function_id).copied().unwrap_or(1)
+    }
+
+    pub fn record(&self, function_id: &str, cpu_time: Duration) {
+        let mut usage = self
+            .usages
+            .entry(function_id.to_owned())
+            .or_insert_with(|| FunctionUsage {
+                window_start: Instant::now(),
+                cpu_time: Duration::ZERO,
+            });
+
+        if usage.window_start.elapsed() >= self.window {
+            usage.window_start = Instant::now();
+            usage.cpu_time = Duration::ZERO;
+        }
+
+        usage.cpu_time += cpu_time;
+    }
+
+    pub fn is_over_quota(&self, function_id: &str) -> bool {
+        match self.usages.get(function_id) {
+            Some(usage) => {
+                usage.window_start.elapsed() < self.window
+                    && usage.cpu_time > self.quota(function_id)
+            }
+            None => false,
+        }
+    }
+
+    // Wait until the request can be dispatched, and return the counter to
+    // increment while it runs if it's prioritized
+    pub async fn wait_turn(&self, function_id: &str) -> Option<Arc<AtomicUsize>> {
+        let start = Instant::now();
+
+        while self.is_over_quota(function_id) {
+            if self.prioritized.load(Ordering::SeqCst) == 0 || start.elapsed() >= self.max_delay {
+                return None;
+            }
+
+            tokio::time::sleep(WAIT_INTERVAL).await;
+        }
+
+        Some(Arc::clone(&self.prioritized))
+    }
+}
+
+// Parse weights formatted as `function_id=weight`, separated by commas
+pub fn parse_weights(weights: &str) -> Option<HashMap<String, u32>> {
+    weights
+        .split(',')
+        .map(|weight| weight.trim())
+        .filter(|weight| !weight.is_empty())
+        .map(|weight| {
+            let (function_id, weight) = weight.split_once('=')?;
+            let weight = weight.trim().parse().ok().filter(|weight| *weight > 0)?;
+
+            Some((function_id.trim().to_owned(), weight))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn over_quota() {
+        let scheduler = FairScheduler::new(Duration::from_secs(60), Duration::from_millis(10));
+
+        scheduler.record("function", Duration::from_millis(5));
+        assert!(!scheduler.is_over_quota("function"));
+
+        scheduler.record("function", Duration::from_millis(10));
+        assert!(scheduler.is_over_quota("function"));
+        assert!(!scheduler.is_over_quota("other"));
+    }
+
+    #[test]
+    fn weighted_quota() {
+        let scheduler = FairScheduler::new(Duration::from_secs(60), Duration::from_millis(10))
+            .weight(String::from("function"), 2);
+
+        scheduler.record("function", Duration::from_millis(15));
+        assert!(!scheduler.is_over_quota("function"));
+
+        scheduler.record("function", Duration::from_millis(10));
+        assert!(scheduler.is_over_quota("function"));
+    }
+
+    #[test]
+    fn parse_weights_list() {
+        let weights = parse_weights("function=2, other=3,").unwrap();
+
+        assert_eq!(weights.len(), 2);
+        assert_eq!(weights.get("function"), Some(&2));
+        assert_eq!(weights.get("other"), Some(&3));
+
+        assert!(parse_weights("function").is_none());
+        assert!(parse_weights("function=0").is_none());
+        assert!(parse_weights("function=heavy").is_none());
+    }
+
+    #[test]
+    fn window_reset() {
+        let scheduler = FairScheduler::new(Duration::from_millis(10), Duration::from_millis(10));
+
+        scheduler.record("function", Duration::from_millis(20));
+        assert!(scheduler.is_over_quota("function"));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!scheduler.is_over_quota("function"));
+    }
+
+    #[tokio::test]
+    async fn wait_turn_without_contention() {
+        let scheduler = FairScheduler::new(Duration::from_secs(60), Duration::from_millis(10));
+
+        scheduler.record("function", Duration::from_millis(20));
+
+        // Nothing else is running, so there's no need to wait
+        assert!(scheduler.wait_turn("function").await.is_none());
+        assert!(scheduler.wait_turn("other").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn wait_turn_max_delay() {
+        let scheduler = FairScheduler::new(Duration::from_secs(60), Duration::from_millis(10))
+            .max_delay(Duration::from_millis(50));
+
+        scheduler.record("function", Duration::from_millis(20));
+
+        let prioritized = scheduler.wait_turn("other").await.unwrap();
+        prioritized.fetch_add(1, Ordering::SeqCst);
+
+        let start = Instant::now();
+        assert!(scheduler.wait_turn("function").await.is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
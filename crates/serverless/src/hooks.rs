@@ -0,0 +1,40 @@
+use hyper::{Body, Request, Response};
+use std::ops::ControlFlow;
+
+// Middleware running around the dispatch of requests to deployments. Hooks
+// only see requests matching a deployment (which passed the internal signature
+// check), and run before assets are served, so `before` can also protect assets.
+//
+// `before` hooks run in the order they are registered. The first one returning
+// `ControlFlow::Break` short-circuits the others and the isolate, and its response
+// is sent as-is. `after` hooks run in the reverse order on every other response,
+// including assets and errors (e.g 500 and 502 pages).
+pub trait RequestHook: Send + Sync {
+    fn before(&self, req: Request<Body>) -> ControlFlow<Response<Body>, Request<Body>> {
+        ControlFlow::Continue(req)
+    }
+
+    fn after(&self, _response: &mut Response<Body>) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitBreachKind {
+    Timeout,
+    MemoryLimit,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitBreach {
+    pub deployment_id: String,
+    pub function_id: String,
+    pub kind: LimitBreachKind,
+    pub request_id: String,
+}
+
+// Notified when an isolate hits its time or memory limits while handling a
+// request, e.g to page someone when a function keeps breaching them. Hooks
+// run on a blocking thread so they never delay the response, and do nothing
+// by default.
+pub trait LimitBreachHook: Send + Sync {
+    fn on_breach(&self, _breach: LimitBreach) {}
+}
@@ -0,0 +1,188 @@
+use hyper::HeaderMap;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+pub const X_REAL_IP: &str = "x-real-ip";
+
+// Behind a trusted proxy, the peer address is the proxy's one and the client
+// address is the rightmost entry of `X-Forwarded-For` that wasn't appended by
+// one of the trusted proxies of the chain. Entries on its left can be set by the
+// client itself, so they can't be trusted. `X-Real-IP` is used when there's no
+// `X-Forwarded-For` header.
+pub fn get_client_ip(
+    headers: &HeaderMap,
+    remote_ip: IpAddr,
+    trusted_proxy: bool,
+    trusted_proxies: &[IpNet],
+) -> IpAddr {
+    if !trusted_proxy {
+        return remote_ip;
+    }
+
+    let forwarded_for = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|entry| entry.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+
+    if forwarded_for.is_empty() {
+        return headers
+            .get(X_REAL_IP)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(remote_ip);
+    }
+
+    let mut client_ip = remote_ip;
+
+    for entry in forwarded_for.into_iter().rev() {
+        match entry {
+            Some(ip) => {
+                client_ip = ip;
+
+                if !trusted_proxies.iter().any(|net| net.contains(&ip)) {
+                    break;
+                }
+            }
+            // Stop at invalid entries, since we can't know who wrote them
+            None => break,
+        }
+    }
+
+    client_ip
+}
+
+// Entries can either be CIDRs or single addresses
+pub fn parse_ip_net(value: &str) -> Option<IpNet> {
+    value
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+// The deny list takes precedence over the allow list, and
+// an empty allow list allows every address
+pub fn is_ip_allowed(allow_list: &[IpNet], deny_list: &[IpNet], ip: IpAddr) -> bool {
+    // Compare IPv4-mapped IPv6 addresses (e.g `::ffff:127.0.0.1`)
+    // with IPv4 CIDRs as well
+    let ip = match ip {
+        IpAddr::V6(ipv6) => ipv6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    };
+
+    if deny_list.iter().any(|net| net.contains(&ip)) {
+        return false;
+    }
+
+    allow_list.is_empty() || allow_list.iter().any(|net| net.contains(&ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(values: &[&str]) -> Vec<IpNet> {
+        values
+            .iter()
+            .map(|value| parse_ip_net(value).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn client_ip_untrusted() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, "1.1.1.1".parse().unwrap());
+
+        assert_eq!(
+            get_client_ip(&headers, "10.0.0.1".parse().unwrap(), false, &[]),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn client_ip_trusted() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, "8.8.8.8, 1.1.1.1".parse().unwrap());
+
+        assert_eq!(
+            get_client_ip(&headers, "10.0.0.1".parse().unwrap(), true, &[]),
+            "1.1.1.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            get_client_ip(&HeaderMap::new(), "10.0.0.1".parse().unwrap(), true, &[]),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn client_ip_chained_proxies() {
+        let trusted_proxies = nets(&["10.0.0.0/8"]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            "8.8.8.8, 1.1.1.1, 10.0.0.2".parse().unwrap(),
+        );
+        headers.append(X_FORWARDED_FOR, "10.0.0.3".parse().unwrap());
+
+        assert_eq!(
+            get_client_ip(
+                &headers,
+                "10.0.0.1".parse().unwrap(),
+                true,
+                &trusted_proxies
+            ),
+            "1.1.1.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn client_ip_real_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_REAL_IP, "1.1.1.1".parse().unwrap());
+
+        assert_eq!(
+            get_client_ip(&headers, "10.0.0.1".parse().unwrap(), true, &[]),
+            "1.1.1.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn allow_list() {
+        let allow_list = nets(&["10.0.0.0/8", "2001:db8::/32"]);
+
+        assert!(is_ip_allowed(&allow_list, &[], "10.1.2.3".parse().unwrap()));
+        assert!(is_ip_allowed(
+            &allow_list,
+            &[],
+            "2001:db8::1".parse().unwrap()
+        ));
+        assert!(is_ip_allowed(
+            &allow_list,
+            &[],
+            "::ffff:10.1.2.3".parse().unwrap()
+        ));
+        assert!(!is_ip_allowed(&allow_list, &[], "1.1.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn deny_list() {
+        let allow_list = nets(&["10.0.0.0/8"]);
+        let deny_list = nets(&["10.0.0.1", "1.1.1.0/24"]);
+
+        assert!(is_ip_allowed(&[], &deny_list, "8.8.8.8".parse().unwrap()));
+        assert!(!is_ip_allowed(&[], &deny_list, "1.1.1.1".parse().unwrap()));
+        assert!(!is_ip_allowed(
+            &allow_list,
+            &deny_list,
+            "10.0.0.1".parse().unwrap()
+        ));
+        assert!(is_ip_allowed(
+            &allow_list,
+            &deny_list,
+            "10.0.0.2".parse().unwrap()
+        ));
+    }
+}
@@ -0,0 +1,118 @@
+use dashmap::DashMap;
+use metrics::gauge;
+use serde::Serialize;
+use std::time::Duration;
+
+// Weight of the latest request in the average by default
+pub const DEFAULT_ALPHA: f64 = 0.1;
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentLatency {
+    pub deployment_id: String,
+    pub latency_ema_ms: f64,
+}
+
+// Exponential moving average of the latency of each deployment's requests,
+// a cheap rolling signal (e.g for autoscaling and alerting) that doesn't
+// need to query ClickHouse. Each new latency is weighted by `alpha`, from
+// 0 (ignore new requests) to 1 (only keep the latest request).
+pub struct LatencyTracker {
+    alpha: f64,
+    averages: DashMap<String, f64>,
+}
+
+impl Default for LatencyTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_ALPHA)
+    }
+}
+
+impl LatencyTracker {
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha: alpha.clamp(0.0, 1.0),
+            averages: DashMap::new(),
+        }
+    }
+
+    // The first request of a deployment starts the average
+    pub fn record(&self, deployment_id: &str, function_id: &str, latency: Duration) -> f64 {
+        let latency = latency.as_secs_f64();
+        let mut average = self
+            .averages
+            .entry(deployment_id.to_owned())
+            .or_insert(latency);
+
+        *average = self.alpha * latency + (1.0 - self.alpha) * *average;
+
+        gauge!("lagon_isolate_latency_ema", *average, "deployment" => deployment_id.to_owned(), "function" => function_id.to_owned());
+
+        *average
+    }
+
+    // In seconds
+    pub fn get(&self, deployment_id: &str) -> Option<f64> {
+        self.averages.get(deployment_id).map(|average| *average)
+    }
+
+    pub fn remove(&self, deployment_id: &str) {
+        self.averages.remove(deployment_id);
+    }
+
+    pub fn summary(&self) -> Vec<DeploymentLatency> {
+        let mut summary = self
+            .averages
+            .iter()
+            .map(|entry| DeploymentLatency {
+                deployment_id: entry.key().clone(),
+                latency_ema_ms: *entry.value() * 1000.0,
+            })
+            .collect::<Vec<_>>();
+
+        summary.sort_by(|a, b| a.deployment_id.cmp(&b.deployment_id));
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moving_average() {
+        let tracker = LatencyTracker::new(0.5);
+
+        assert_eq!(
+            tracker.record("deployment", "function", Duration::from_secs(1)),
+            1.0
+        );
+        assert_eq!(
+            tracker.record("deployment", "function", Duration::from_secs(3)),
+            2.0
+        );
+        assert_eq!(
+            tracker.record("deployment", "function", Duration::from_secs(2)),
+            2.0
+        );
+        assert_eq!(tracker.get("other"), None);
+
+        tracker.record("other", "function", Duration::from_millis(500));
+        assert_eq!(
+            tracker.summary(),
+            vec![
+                DeploymentLatency {
+                    deployment_id: "deployment".into(),
+                    latency_ema_ms: 2000.0,
+                },
+                DeploymentLatency {
+                    deployment_id: "other".into(),
+                    latency_ema_ms: 500.0,
+                },
+            ]
+        );
+
+        tracker.remove("deployment");
+        assert_eq!(tracker.get("deployment"), None);
+    }
+}
@@ -1,9 +1,29 @@
 use std::{env, sync::OnceLock};
 
+pub mod admin;
+pub mod assets;
+pub mod circuit_breaker;
 pub mod clickhouse;
+pub mod cpu_budget;
 pub mod cronjob;
 pub mod deployments;
+pub mod fairness;
+pub mod hooks;
+pub mod ip;
+pub mod latency;
+pub mod otel;
+pub mod path;
+pub mod rate_limit;
+pub mod rates;
+pub mod response_cache;
+pub mod secrets;
+pub mod self_test;
 pub mod serverless;
+pub mod shadow;
+pub mod signature;
+pub mod snapshots;
+pub mod stdout_logs;
+pub mod tls;
 
 static REGION: OnceLock<String> = OnceLock::new();
 
@@ -1,9 +1,20 @@
 use anyhow::Result;
 use lagon_runtime::{options::RuntimeOptions, Runtime};
+use lagon_serverless::circuit_breaker::CircuitBreaker;
 use lagon_serverless::clickhouse::{create_client, run_migrations};
 use lagon_serverless::deployments::get_deployments;
+use lagon_serverless::fairness::{parse_weights, FairScheduler};
 use lagon_serverless::get_region;
-use lagon_serverless::serverless::start;
+use lagon_serverless::ip::parse_ip_net;
+use lagon_serverless::latency::LatencyTracker;
+use lagon_serverless::rate_limit::RateLimiter;
+use lagon_serverless::rates::RequestRates;
+use lagon_serverless::response_cache::ResponseCache;
+use lagon_serverless::secrets::SecretsKey;
+use lagon_serverless::serverless::{start_local, start_with_config, ServerConfig};
+use lagon_serverless::shadow::ShadowTraffic;
+use lagon_serverless::stdout_logs::StdoutLogs;
+use lagon_serverless::tls::{SniCertificates, TlsConfig};
 use lagon_serverless_downloader::{get_bucket, S3BucketDownloader};
 use lagon_serverless_logger::init_logger;
 use lagon_serverless_pubsub::RedisPubSub;
@@ -15,10 +26,540 @@ use mysql::{OptsBuilder, SslOpts};
 #[cfg(not(debug_assertions))]
 use std::borrow::Cow;
 use std::env;
+use std::fs;
 use std::net::SocketAddr;
 #[cfg(not(debug_assertions))]
 use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+
+fn get_config() -> ServerConfig {
+    let mut config = ServerConfig::default().addr(
+        env::var("LAGON_LISTEN_ADDR")
+            .expect("LAGON_LISTEN_ADDR must be set")
+            .parse()
+            .expect("LAGON_LISTEN_ADDR is not a valid address"),
+    );
+
+    if let Ok(deployments_dir) = env::var("LAGON_DEPLOYMENTS_DIR") {
+        if !deployments_dir.is_empty() {
+            config.deployments_dir = PathBuf::from(deployments_dir);
+        }
+    }
+
+    if let Ok(max_isolates) = env::var("LAGON_MAX_ISOLATES") {
+        if !max_isolates.is_empty() {
+            config.max_isolates = Some(
+                max_isolates
+                    .parse()
+                    .expect("LAGON_MAX_ISOLATES is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(max_creations) = env::var("LAGON_MAX_CONCURRENT_ISOLATE_CREATIONS") {
+        if !max_creations.is_empty() {
+            config = config.max_concurrent_isolate_creations(
+                max_creations
+                    .parse()
+                    .expect("LAGON_MAX_CONCURRENT_ISOLATE_CREATIONS is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(queue_timeout) = env::var("LAGON_QUEUE_TIMEOUT_MS") {
+        if !queue_timeout.is_empty() {
+            config.queue_timeout = Some(Duration::from_millis(
+                queue_timeout
+                    .parse()
+                    .expect("LAGON_QUEUE_TIMEOUT_MS is not a valid number"),
+            ));
+        }
+    }
+
+    if let Ok(admin_addr) = env::var("LAGON_ADMIN_LISTEN_ADDR") {
+        if !admin_addr.is_empty() {
+            config.admin_addr = Some(
+                admin_addr
+                    .parse()
+                    .expect("LAGON_ADMIN_LISTEN_ADDR is not a valid address"),
+            );
+        }
+    }
+
+    if let Ok(default_deployment) = env::var("LAGON_DEFAULT_DEPLOYMENT") {
+        if !default_deployment.is_empty() {
+            config.default_deployment = Some(default_deployment);
+        }
+    }
+
+    if let Ok(internal_secret) = env::var("LAGON_INTERNAL_SECRET") {
+        if !internal_secret.is_empty() {
+            config.internal_secret = Some(internal_secret);
+        }
+    }
+
+    if let Ok(request_id_header) = env::var("LAGON_REQUEST_ID_HEADER") {
+        if !request_id_header.is_empty() {
+            config.request_id_header = request_id_header
+                .parse()
+                .expect("LAGON_REQUEST_ID_HEADER is not a valid header name");
+        }
+    }
+
+    if let Ok(suspended_message) = env::var("LAGON_SUSPENDED_MESSAGE") {
+        if !suspended_message.is_empty() {
+            config.suspended_message = suspended_message;
+        }
+    }
+
+    if let Ok(merge_slashes) = env::var("LAGON_MERGE_SLASHES") {
+        if !merge_slashes.is_empty() {
+            config.path_normalization.merge_slashes = merge_slashes
+                .parse()
+                .expect("LAGON_MERGE_SLASHES is not a valid boolean");
+        }
+    }
+
+    if let Ok(resolve_dot_segments) = env::var("LAGON_RESOLVE_DOT_SEGMENTS") {
+        if !resolve_dot_segments.is_empty() {
+            config.path_normalization.resolve_dot_segments = resolve_dot_segments
+                .parse()
+                .expect("LAGON_RESOLVE_DOT_SEGMENTS is not a valid boolean");
+        }
+    }
+
+    if let Ok(decode_unreserved) = env::var("LAGON_DECODE_UNRESERVED") {
+        if !decode_unreserved.is_empty() {
+            config.path_normalization.decode_unreserved = decode_unreserved
+                .parse()
+                .expect("LAGON_DECODE_UNRESERVED is not a valid boolean");
+        }
+    }
+
+    if let Ok(trailing_slash) = env::var("LAGON_TRAILING_SLASH") {
+        if !trailing_slash.is_empty() {
+            config.path_normalization.trailing_slash = trailing_slash
+                .parse()
+                .expect("LAGON_TRAILING_SLASH is not keep, strip or append");
+        }
+    }
+
+    if let Ok(trusted_proxy) = env::var("LAGON_TRUSTED_PROXY") {
+        if !trusted_proxy.is_empty() {
+            config.trusted_proxy = trusted_proxy
+                .parse()
+                .expect("LAGON_TRUSTED_PROXY is not a valid boolean");
+        }
+    }
+
+    if let Ok(trusted_proxies) = env::var("LAGON_TRUSTED_PROXIES") {
+        config.trusted_proxies = trusted_proxies
+            .split(',')
+            .map(|proxy| proxy.trim())
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                parse_ip_net(proxy).expect("LAGON_TRUSTED_PROXIES contains an invalid IP or CIDR")
+            })
+            .collect();
+    }
+
+    if let Ok(pinned_deployments) = env::var("LAGON_PINNED_DEPLOYMENTS") {
+        config.pinned_deployments = pinned_deployments
+            .split(',')
+            .map(|deployment_id| deployment_id.trim())
+            .filter(|deployment_id| !deployment_id.is_empty())
+            .map(String::from)
+            .collect();
+    }
+
+    if let Ok(rate_limit) = env::var("LAGON_RATE_LIMIT") {
+        if !rate_limit.is_empty() {
+            let rate: f64 = rate_limit
+                .parse()
+                .ok()
+                .filter(|rate: &f64| rate.is_finite() && *rate > 0.0)
+                .expect("LAGON_RATE_LIMIT is not a valid positive number");
+            let burst = env::var("LAGON_RATE_LIMIT_BURST")
+                .ok()
+                .filter(|burst| !burst.is_empty())
+                .map_or(rate, |burst| {
+                    burst
+                        .parse()
+                        .expect("LAGON_RATE_LIMIT_BURST is not a valid number")
+                });
+
+            config.rate_limiter = Some(RateLimiter::new(rate, burst));
+        }
+    }
+
+    if let Ok(threshold) = env::var("LAGON_CIRCUIT_BREAKER_THRESHOLD") {
+        if !threshold.is_empty() {
+            let threshold = threshold
+                .parse()
+                .expect("LAGON_CIRCUIT_BREAKER_THRESHOLD is not a valid number");
+            let window = env::var("LAGON_CIRCUIT_BREAKER_WINDOW_MS")
+                .ok()
+                .filter(|window| !window.is_empty())
+                .map_or(60_000, |window| {
+                    window
+                        .parse()
+                        .expect("LAGON_CIRCUIT_BREAKER_WINDOW_MS is not a valid number")
+                });
+            let cooldown = env::var("LAGON_CIRCUIT_BREAKER_COOLDOWN_MS")
+                .ok()
+                .filter(|cooldown| !cooldown.is_empty())
+                .map_or(30_000, |cooldown| {
+                    cooldown
+                        .parse()
+                        .expect("LAGON_CIRCUIT_BREAKER_COOLDOWN_MS is not a valid number")
+                });
+
+            config.circuit_breaker = Some(CircuitBreaker::new(
+                threshold,
+                Duration::from_millis(window),
+                Duration::from_millis(cooldown),
+            ));
+        }
+    }
+
+    if let Ok(readiness_check) = env::var("LAGON_READINESS_CHECK") {
+        if !readiness_check.is_empty() {
+            config.readiness_check = readiness_check
+                .parse()
+                .expect("LAGON_READINESS_CHECK is not a valid boolean");
+        }
+    }
+
+    if let Ok(self_test) = env::var("LAGON_SELF_TEST") {
+        if !self_test.is_empty() {
+            config.self_test = self_test
+                .parse()
+                .expect("LAGON_SELF_TEST is not a valid boolean");
+        }
+    }
+
+    if let Ok(max_concurrent_messages) = env::var("LAGON_MAX_CONCURRENT_MESSAGES") {
+        if !max_concurrent_messages.is_empty() {
+            config.max_concurrent_messages = max_concurrent_messages
+                .parse()
+                .expect("LAGON_MAX_CONCURRENT_MESSAGES is not a valid number");
+        }
+    }
+
+    if let Ok(latency_ema_alpha) = env::var("LAGON_LATENCY_EMA_ALPHA") {
+        if !latency_ema_alpha.is_empty() {
+            config.latency = LatencyTracker::new(
+                latency_ema_alpha
+                    .parse()
+                    .expect("LAGON_LATENCY_EMA_ALPHA is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(rates_window) = env::var("LAGON_RATES_WINDOW_MS") {
+        if !rates_window.is_empty() {
+            config.request_rates = RequestRates::new(Duration::from_millis(
+                rates_window
+                    .parse()
+                    .expect("LAGON_RATES_WINDOW_MS is not a valid number"),
+            ));
+        }
+    }
+
+    if let Ok(response_cache_size) = env::var("LAGON_RESPONSE_CACHE_SIZE") {
+        if !response_cache_size.is_empty() {
+            config.response_cache = ResponseCache::new(
+                response_cache_size
+                    .parse()
+                    .expect("LAGON_RESPONSE_CACHE_SIZE is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(max_shadow_requests) = env::var("LAGON_MAX_SHADOW_REQUESTS") {
+        if !max_shadow_requests.is_empty() {
+            config.shadow_traffic = ShadowTraffic::new(
+                max_shadow_requests
+                    .parse()
+                    .expect("LAGON_MAX_SHADOW_REQUESTS is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(provisioning_placeholders) = env::var("LAGON_PROVISIONING_PLACEHOLDERS") {
+        if !provisioning_placeholders.is_empty() {
+            config.provisioning_placeholders = provisioning_placeholders
+                .parse()
+                .expect("LAGON_PROVISIONING_PLACEHOLDERS is not a valid boolean");
+        }
+    }
+
+    if let Ok(download_timeout) = env::var("LAGON_DOWNLOAD_TIMEOUT_MS") {
+        if !download_timeout.is_empty() {
+            config.download_timeout = Some(Duration::from_millis(
+                download_timeout
+                    .parse()
+                    .expect("LAGON_DOWNLOAD_TIMEOUT_MS is not a valid number"),
+            ));
+        }
+    }
+
+    if let Ok(undeploy_drain_timeout) = env::var("LAGON_UNDEPLOY_DRAIN_TIMEOUT_MS") {
+        if !undeploy_drain_timeout.is_empty() {
+            config.undeploy_drain_timeout = Duration::from_millis(
+                undeploy_drain_timeout
+                    .parse()
+                    .expect("LAGON_UNDEPLOY_DRAIN_TIMEOUT_MS is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(snapshots_dir) = env::var("LAGON_SNAPSHOTS_DIR") {
+        if !snapshots_dir.is_empty() {
+            config.snapshots_dir = Some(PathBuf::from(snapshots_dir));
+        }
+    }
+
+    if let Ok(json_errors) = env::var("LAGON_JSON_ERRORS") {
+        if !json_errors.is_empty() {
+            config.json_errors = json_errors
+                .parse()
+                .expect("LAGON_JSON_ERRORS is not a valid boolean");
+        }
+    }
+
+    if let Ok(preload_links) = env::var("LAGON_PRELOAD_LINKS") {
+        if !preload_links.is_empty() {
+            config.preload_links = preload_links
+                .parse()
+                .expect("LAGON_PRELOAD_LINKS is not a valid boolean");
+        }
+    }
+
+    if let Ok(stdout_logs) = env::var("LAGON_STDOUT_LOGS") {
+        if !stdout_logs.is_empty() {
+            config.stdout_logs = Some(StdoutLogs::new(
+                stdout_logs
+                    .parse()
+                    .expect("LAGON_STDOUT_LOGS is not a valid log format"),
+            ));
+        }
+    }
+
+    if let Ok(request_sample_rate) = env::var("LAGON_REQUEST_SAMPLE_RATE") {
+        if !request_sample_rate.is_empty() {
+            config.request_sample_rate = request_sample_rate
+                .parse()
+                .expect("LAGON_REQUEST_SAMPLE_RATE is not a valid number");
+        }
+    }
+
+    if let Ok(max_pending_rows) = env::var("LAGON_CLICKHOUSE_MAX_PENDING_ROWS") {
+        if !max_pending_rows.is_empty() {
+            config.clickhouse_max_pending_rows = max_pending_rows
+                .parse()
+                .expect("LAGON_CLICKHOUSE_MAX_PENDING_ROWS is not a valid number");
+        }
+    }
+
+    if let Ok(clickhouse_logs) = env::var("LAGON_CLICKHOUSE_LOGS") {
+        if !clickhouse_logs.is_empty() {
+            config.clickhouse_logs = clickhouse_logs
+                .parse()
+                .expect("LAGON_CLICKHOUSE_LOGS is not a valid boolean");
+        }
+    }
+
+    if let Ok(keep_conflicting_domains) = env::var("LAGON_KEEP_CONFLICTING_DOMAINS") {
+        if !keep_conflicting_domains.is_empty() {
+            config.keep_conflicting_domains = keep_conflicting_domains
+                .parse()
+                .expect("LAGON_KEEP_CONFLICTING_DOMAINS is not a valid boolean");
+        }
+    }
+
+    if let Ok(warmup) = env::var("LAGON_WARMUP") {
+        if !warmup.is_empty() {
+            config.warmup = warmup.parse().expect("LAGON_WARMUP is not a valid boolean");
+        }
+    }
+
+    if let Ok(max_concurrent_warmups) = env::var("LAGON_MAX_CONCURRENT_WARMUPS") {
+        if !max_concurrent_warmups.is_empty() {
+            config.max_concurrent_warmups = max_concurrent_warmups
+                .parse()
+                .expect("LAGON_MAX_CONCURRENT_WARMUPS is not a valid number");
+        }
+    }
+
+    if let Ok(warmup_jitter) = env::var("LAGON_WARMUP_JITTER_MS") {
+        if !warmup_jitter.is_empty() {
+            config.warmup_jitter = Duration::from_millis(
+                warmup_jitter
+                    .parse()
+                    .expect("LAGON_WARMUP_JITTER_MS is not a valid number"),
+            );
+        }
+    }
+
+    // Written by the node being replaced, e.g from its admin `/handoff` endpoint
+    if let Ok(handoff_file) = env::var("LAGON_HANDOFF_FILE") {
+        if !handoff_file.is_empty() {
+            let handoff = fs::read(handoff_file).expect("Could not read LAGON_HANDOFF_FILE");
+
+            config.handoff = Some(
+                serde_json::from_slice(&handoff).expect("LAGON_HANDOFF_FILE is not a valid state"),
+            );
+        }
+    }
+
+    if let Ok(tcp_keepalive) = env::var("LAGON_TCP_KEEPALIVE_MS") {
+        if !tcp_keepalive.is_empty() {
+            config.tcp_keepalive = Some(Duration::from_millis(
+                tcp_keepalive
+                    .parse()
+                    .expect("LAGON_TCP_KEEPALIVE_MS is not a valid number"),
+            ));
+        }
+    }
+
+    if let Ok(http1_keepalive) = env::var("LAGON_HTTP1_KEEPALIVE") {
+        if !http1_keepalive.is_empty() {
+            config.http1_keepalive = http1_keepalive
+                .parse()
+                .expect("LAGON_HTTP1_KEEPALIVE is not a valid boolean");
+        }
+    }
+
+    if let Ok(header_read_timeout) = env::var("LAGON_HEADER_READ_TIMEOUT_MS") {
+        if !header_read_timeout.is_empty() {
+            config.header_read_timeout =
+                Some(Duration::from_millis(header_read_timeout.parse().expect(
+                    "LAGON_HEADER_READ_TIMEOUT_MS is not a valid number",
+                )));
+        }
+    }
+
+    if let Ok(max_headers) = env::var("LAGON_MAX_HEADERS") {
+        if !max_headers.is_empty() {
+            config.max_headers = Some(
+                max_headers
+                    .parse()
+                    .expect("LAGON_MAX_HEADERS is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(max_header_bytes) = env::var("LAGON_MAX_HEADER_BYTES") {
+        if !max_header_bytes.is_empty() {
+            config.max_header_bytes = Some(
+                max_header_bytes
+                    .parse()
+                    .expect("LAGON_MAX_HEADER_BYTES is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(max_connections) = env::var("LAGON_MAX_CONNECTIONS") {
+        if !max_connections.is_empty() {
+            config.max_connections = Some(
+                max_connections
+                    .parse()
+                    .expect("LAGON_MAX_CONNECTIONS is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(http2) = env::var("LAGON_HTTP2") {
+        if !http2.is_empty() {
+            config.http2 = http2.parse().expect("LAGON_HTTP2 is not a valid boolean");
+        }
+    }
+
+    if let Ok(max_concurrent_streams) = env::var("LAGON_HTTP2_MAX_CONCURRENT_STREAMS") {
+        if !max_concurrent_streams.is_empty() {
+            config.http2_max_concurrent_streams = Some(
+                max_concurrent_streams
+                    .parse()
+                    .expect("LAGON_HTTP2_MAX_CONCURRENT_STREAMS is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(window_size) = env::var("LAGON_HTTP2_INITIAL_STREAM_WINDOW_SIZE") {
+        if !window_size.is_empty() {
+            config.http2_initial_stream_window_size = Some(
+                window_size
+                    .parse()
+                    .expect("LAGON_HTTP2_INITIAL_STREAM_WINDOW_SIZE is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(window_size) = env::var("LAGON_HTTP2_INITIAL_CONNECTION_WINDOW_SIZE") {
+        if !window_size.is_empty() {
+            config.http2_initial_connection_window_size = Some(
+                window_size
+                    .parse()
+                    .expect("LAGON_HTTP2_INITIAL_CONNECTION_WINDOW_SIZE is not a valid number"),
+            );
+        }
+    }
+
+    if let Ok(keep_alive_interval) = env::var("LAGON_HTTP2_KEEP_ALIVE_INTERVAL_MS") {
+        if !keep_alive_interval.is_empty() {
+            config.http2_keep_alive_interval =
+                Some(Duration::from_millis(keep_alive_interval.parse().expect(
+                    "LAGON_HTTP2_KEEP_ALIVE_INTERVAL_MS is not a valid number",
+                )));
+        }
+    }
+
+    if let Ok(tls_certs_dir) = env::var("LAGON_TLS_CERTS_DIR") {
+        if !tls_certs_dir.is_empty() {
+            config = config.tls(TlsConfig::with_certificates(
+                SniCertificates::new()
+                    .load_dir(Path::new(&tls_certs_dir))
+                    .expect("Failed to load TLS certificates from LAGON_TLS_CERTS_DIR"),
+            ));
+        }
+    }
+
+    if let Ok(fairness_cpu_quota) = env::var("LAGON_FAIRNESS_CPU_QUOTA_MS") {
+        if !fairness_cpu_quota.is_empty() {
+            let cpu_quota = fairness_cpu_quota
+                .parse()
+                .expect("LAGON_FAIRNESS_CPU_QUOTA_MS is not a valid number");
+            let window = env::var("LAGON_FAIRNESS_WINDOW_MS")
+                .ok()
+                .filter(|window| !window.is_empty())
+                .map_or(1000, |window| {
+                    window
+                        .parse()
+                        .expect("LAGON_FAIRNESS_WINDOW_MS is not a valid number")
+                });
+
+            let weights = env::var("LAGON_FAIRNESS_WEIGHTS")
+                .map(|weights| {
+                    parse_weights(&weights)
+                        .expect("LAGON_FAIRNESS_WEIGHTS is not a valid list of weights")
+                })
+                .unwrap_or_default();
+
+            config.fairness = Some(
+                FairScheduler::new(
+                    Duration::from_millis(window),
+                    Duration::from_millis(cpu_quota),
+                )
+                .weights(weights),
+            );
+        }
+    }
+
+    config
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -29,9 +570,6 @@ async fn main() -> Result<()> {
     let _flush_guard = init_logger(get_region().clone()).expect("Failed to init logger");
 
     let runtime = Runtime::new(RuntimeOptions::default());
-    let addr: SocketAddr = env::var("LAGON_LISTEN_ADDR")
-        .expect("LAGON_LISTEN_ADDR must be set")
-        .parse()?;
     let prometheus_addr: SocketAddr = env::var("PROMETHEUS_LISTEN_ADDR")
         .expect("PROMETHEUS_LISTEN_ADDR must be set")
         .parse()?;
@@ -50,6 +588,20 @@ async fn main() -> Result<()> {
 
     builder.install().expect("Failed to start metrics exporter");
 
+    // Serve the deployments of the deployments directory, without
+    // a database, a bucket and a pubsub
+    if env::var("LAGON_LOCAL").map_or(false, |local| local == "true") {
+        let client = create_client();
+        run_migrations(&client).await?;
+
+        let serverless = start_local(get_config(), client).await?;
+        tokio::spawn(serverless).await?;
+
+        runtime.dispose();
+
+        return Ok(());
+    }
+
     let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let url = url.as_str();
     let opts = Opts::from_url(url).expect("Failed to parse DATABASE_URL");
@@ -69,8 +621,22 @@ async fn main() -> Result<()> {
     let client = create_client();
     run_migrations(&client).await?;
 
-    let deployments = get_deployments(conn, Arc::clone(&downloader)).await?;
-    let serverless = start(deployments, addr, downloader, pubsub, client).await?;
+    let config = get_config();
+    // Shared with the control plane, to decrypt the secrets stored in the database
+    let secrets_key = env::var("LAGON_SECRETS_KEY")
+        .ok()
+        .filter(|secrets_key| !secrets_key.is_empty())
+        .map(|secrets_key| {
+            SecretsKey::from_hex(&secrets_key).expect("LAGON_SECRETS_KEY is not a valid key")
+        });
+    let deployments = get_deployments(
+        conn,
+        Arc::clone(&downloader),
+        &config.deployments_dir,
+        secrets_key.as_ref(),
+    )
+    .await?;
+    let serverless = start_with_config(config, deployments, downloader, pubsub, client).await?;
     tokio::spawn(serverless).await?;
 
     runtime.dispose();
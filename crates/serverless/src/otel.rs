@@ -0,0 +1,304 @@
+// OpenTelemetry spans of requests, only recorded with the `otel` feature. Each
+// request has a parent span (continuing the trace of the `traceparent` header,
+// if any) with a child span for each phase: `resolve` (finding the deployment),
+// `queue` (waiting for the isolate), `execution` (until the isolate returned the
+// response) and `response` (sending it). Without the feature, these are no-ops.
+#[cfg(feature = "otel")]
+pub use enabled::{DispatchSpan, PhaseSpan, RequestSpan};
+
+#[cfg(not(feature = "otel"))]
+pub use disabled::{DispatchSpan, PhaseSpan, RequestSpan};
+
+#[cfg(feature = "otel")]
+mod enabled {
+    use hyper::{header::HeaderName, http::HeaderValue, HeaderMap};
+    use lagon_runtime_utils::Deployment;
+    use opentelemetry::{
+        global::{self, BoxedSpan},
+        propagation::{Extractor, Injector, TextMapPropagator},
+        sdk::propagation::TraceContextPropagator,
+        trace::{Span, SpanKind, Status, TraceContextExt, Tracer},
+        Context, KeyValue,
+    };
+    use std::sync::{Arc, Mutex};
+
+    const TRACER_NAME: &str = "lagon";
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl<'a> Extractor for HeaderExtractor<'a> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+
+    struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+    impl<'a> Injector for HeaderInjector<'a> {
+        fn set(&mut self, key: &str, value: String) {
+            if let (Ok(key), Ok(value)) = (
+                HeaderName::from_bytes(key.as_bytes()),
+                HeaderValue::from_str(&value),
+            ) {
+                self.0.insert(key, value);
+            }
+        }
+    }
+
+    fn start_span(name: &'static str, parent: &Context) -> BoxedSpan {
+        let tracer = global::tracer(TRACER_NAME);
+
+        tracer
+            .span_builder(name)
+            .with_kind(SpanKind::Internal)
+            .start_with_context(&tracer, parent)
+    }
+
+    // The span is ended once every clone is dropped
+    #[derive(Clone)]
+    pub struct RequestSpan {
+        cx: Context,
+    }
+
+    impl RequestSpan {
+        pub fn start(headers: &HeaderMap, method: &str, path: &str) -> Self {
+            let parent = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+            let tracer = global::tracer(TRACER_NAME);
+            let span = tracer
+                .span_builder("request")
+                .with_kind(SpanKind::Server)
+                .with_attributes(vec![
+                    KeyValue::new("http.method", method.to_owned()),
+                    KeyValue::new("http.target", path.to_owned()),
+                ])
+                .start_with_context(&tracer, &parent);
+
+            Self {
+                cx: parent.with_span(span),
+            }
+        }
+
+        pub fn phase(&self, name: &'static str) -> PhaseSpan {
+            PhaseSpan(start_span(name, &self.cx))
+        }
+
+        pub fn set_deployment(&self, deployment: &Deployment) {
+            let span = self.cx.span();
+
+            span.set_attribute(KeyValue::new("lagon.deployment_id", deployment.id.clone()));
+            span.set_attribute(KeyValue::new(
+                "lagon.function_id",
+                deployment.function_id.clone(),
+            ));
+        }
+
+        pub fn set_response(&self, status: u16, bytes_in: u32, bytes_out: usize) {
+            let span = self.cx.span();
+
+            span.set_attribute(KeyValue::new("http.status_code", status as i64));
+            span.set_attribute(KeyValue::new(
+                "http.request_content_length",
+                bytes_in as i64,
+            ));
+            span.set_attribute(KeyValue::new(
+                "http.response_content_length",
+                bytes_out as i64,
+            ));
+
+            if status >= 500 {
+                span.set_status(Status::error(format!("Status code {}", status)));
+            }
+        }
+
+        // Continue the trace in the function, e.g when it calls other services
+        pub fn inject(&self, headers: &mut HeaderMap) {
+            TraceContextPropagator::new().inject_context(&self.cx, &mut HeaderInjector(headers));
+        }
+
+        pub fn dispatch(&self) -> DispatchSpan {
+            DispatchSpan {
+                cx: self.cx.clone(),
+                current: Arc::new(Mutex::new(Some(start_span("queue", &self.cx)))),
+            }
+        }
+    }
+
+    // Ended when dropped
+    pub struct PhaseSpan(BoxedSpan);
+
+    impl PhaseSpan {
+        pub fn end(self) {}
+    }
+
+    impl Drop for PhaseSpan {
+        fn drop(&mut self) {
+            self.0.end();
+        }
+    }
+
+    // Spans of a request sent to an isolate, which is shared with the
+    // isolate's thread (in the request's extensions) to know when it
+    // picked up the request
+    #[derive(Clone)]
+    pub struct DispatchSpan {
+        cx: Context,
+        current: Arc<Mutex<Option<BoxedSpan>>>,
+    }
+
+    impl DispatchSpan {
+        pub fn picked_up(&self) {
+            let mut current = self.current.lock().unwrap();
+
+            if let Some(mut queue) = current.take() {
+                queue.end();
+                *current = Some(start_span("execution", &self.cx));
+            }
+        }
+
+        pub fn done(&self) {
+            if let Some(mut span) = self.current.lock().unwrap().take() {
+                span.end();
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod disabled {
+    use hyper::HeaderMap;
+    use lagon_runtime_utils::Deployment;
+
+    #[derive(Clone)]
+    pub struct RequestSpan;
+
+    impl RequestSpan {
+        pub fn start(_headers: &HeaderMap, _method: &str, _path: &str) -> Self {
+            Self
+        }
+
+        pub fn phase(&self, _name: &'static str) -> PhaseSpan {
+            PhaseSpan
+        }
+
+        pub fn set_deployment(&self, _deployment: &Deployment) {}
+
+        pub fn set_response(&self, _status: u16, _bytes_in: u32, _bytes_out: usize) {}
+
+        pub fn inject(&self, _headers: &mut HeaderMap) {}
+
+        pub fn dispatch(&self) -> DispatchSpan {
+            DispatchSpan
+        }
+    }
+
+    pub struct PhaseSpan;
+
+    impl PhaseSpan {
+        pub fn end(self) {}
+    }
+
+    #[derive(Clone)]
+    pub struct DispatchSpan;
+
+    impl DispatchSpan {
+        pub fn picked_up(&self) {}
+
+        pub fn done(&self) {}
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+    use hyper::HeaderMap;
+    use opentelemetry::{
+        global,
+        sdk::{
+            export::trace::{ExportResult, SpanData, SpanExporter},
+            trace::TracerProvider,
+        },
+        trace::{SpanId, TraceId},
+    };
+    use std::{
+        sync::mpsc::{channel, Sender},
+        time::Duration,
+    };
+
+    // Send the ended spans to the test
+    #[derive(Debug)]
+    struct TestExporter(Sender<SpanData>);
+
+    impl SpanExporter for TestExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            for span in batch {
+                self.0.send(span).unwrap_or(());
+            }
+
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[test]
+    fn span_hierarchy() {
+        let (sender, spans) = channel();
+        let exporter = TestExporter(sender);
+        global::set_tracer_provider(
+            TracerProvider::builder()
+                .with_simple_exporter(exporter)
+                .build(),
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+
+        let request = RequestSpan::start(&headers, "GET", "/");
+        request.phase("resolve").end();
+
+        let dispatch = request.dispatch();
+        dispatch.picked_up();
+        dispatch.done();
+        drop(dispatch);
+
+        let mut forwarded = HeaderMap::new();
+        request.inject(&mut forwarded);
+        drop(request);
+
+        let spans = (0..4)
+            .map(|_| spans.recv_timeout(Duration::from_secs(1)).unwrap())
+            .collect::<Vec<_>>();
+        let names = spans
+            .iter()
+            .map(|span| span.name.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["resolve", "queue", "execution", "request"]);
+
+        let trace_id = TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap();
+        let request_span = &spans[3];
+        assert_eq!(request_span.span_context.trace_id(), trace_id);
+        assert_eq!(
+            request_span.parent_span_id,
+            SpanId::from_hex("b7ad6b7169203331").unwrap()
+        );
+
+        for span in &spans[..3] {
+            assert_eq!(span.span_context.trace_id(), trace_id);
+            assert_eq!(span.parent_span_id, request_span.span_context.span_id());
+        }
+
+        // The function continues the trace of the request span
+        assert!(forwarded["traceparent"]
+            .to_str()
+            .unwrap()
+            .contains(&request_span.span_context.span_id().to_string()));
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Error};
+use hyper::Uri;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    // Leave the trailing slash as sent by the client
+    #[default]
+    Keep,
+    // `/path/` is `/path`
+    Strip,
+    // `/path` is `/path/`, except for files (e.g `/index.css`)
+    Append,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = Error;
+
+    fn from_str(trailing_slash: &str) -> Result<Self, Self::Err> {
+        match trailing_slash.to_ascii_lowercase().as_str() {
+            "keep" => Ok(Self::Keep),
+            "strip" => Ok(Self::Strip),
+            "append" => Ok(Self::Append),
+            _ => Err(anyhow!(
+                "Unknown trailing slash behavior {}",
+                trailing_slash
+            )),
+        }
+    }
+}
+
+// Normalize the path of requests before routing them, so clients sending
+// `/path`, `/path/` or `//path` match the same path prefix and asset. The
+// function receives the normalized path too. Paths are left untouched by
+// default, to never change what existing functions receive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathNormalization {
+    // `//a//b` is `/a/b`
+    pub merge_slashes: bool,
+    // `/a/./b/../c` is `/a/c`, never going above the root
+    pub resolve_dot_segments: bool,
+    // Decode percent-encoded unreserved characters (letters, digits, `-`, `.`,
+    // `_` and `~`), which are equivalent to their decoded form. Other characters
+    // (e.g `%2F`) are kept encoded, since decoding them changes the path.
+    pub decode_unreserved: bool,
+    pub trailing_slash: TrailingSlash,
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let byte = bytes
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(|byte| is_unreserved(*byte));
+
+        match byte {
+            Some(byte) => {
+                decoded.push(byte as char);
+                index += 3;
+            }
+            None => {
+                // Paths are ASCII, so every byte is a char
+                decoded.push(bytes[index] as char);
+                index += 1;
+            }
+        }
+    }
+
+    decoded
+}
+
+fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+
+    for char in path.chars() {
+        if char != '/' || !merged.ends_with('/') {
+            merged.push(char);
+        }
+    }
+
+    merged
+}
+
+// See https://www.rfc-editor.org/rfc/rfc3986#section-5.2.4
+fn resolve_dot_segments(path: &str) -> String {
+    let mut segments = Vec::new();
+    let mut directory = false;
+
+    for segment in path.split('/').skip(1) {
+        directory = matches!(segment, "." | "..");
+
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    // `/a/b/..` is the `/a/` directory
+    if directory {
+        segments.push("");
+    }
+
+    format!("/{}", segments.join("/"))
+}
+
+impl PathNormalization {
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    pub fn normalize(&self, path: &str) -> String {
+        let mut path = match self.decode_unreserved {
+            true => decode_unreserved(path),
+            false => path.to_owned(),
+        };
+
+        if self.merge_slashes {
+            path = merge_slashes(&path);
+        }
+
+        if self.resolve_dot_segments && path.starts_with('/') {
+            path = resolve_dot_segments(&path);
+        }
+
+        match self.trailing_slash {
+            TrailingSlash::Keep => {}
+            TrailingSlash::Strip => {
+                let stripped = path.trim_end_matches('/');
+
+                path = match stripped.is_empty() {
+                    true => String::from("/"),
+                    false => stripped.to_owned(),
+                };
+            }
+            TrailingSlash::Append => {
+                let is_file = path
+                    .rsplit('/')
+                    .next()
+                    .map_or(false, |segment| segment.contains('.'));
+
+                if !path.ends_with('/') && !is_file {
+                    path.push('/');
+                }
+            }
+        }
+
+        path
+    }
+
+    // Normalize the path of the URI, keeping its query as is. Return
+    // `None` if the path is already normalized.
+    pub fn normalize_uri(&self, uri: &Uri) -> Option<Uri> {
+        // e.g `OPTIONS *` requests
+        if !self.is_enabled() || !uri.path().starts_with('/') {
+            return None;
+        }
+
+        let path = self.normalize(uri.path());
+
+        if path == uri.path() {
+            return None;
+        }
+
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse().ok()?);
+
+        Uri::from_parts(parts).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_keeps_path() {
+        let normalization = PathNormalization::default();
+
+        assert!(!normalization.is_enabled());
+        assert_eq!(normalization.normalize("//a/../%41/"), "//a/../%41/");
+    }
+
+    #[test]
+    fn duplicate_slashes() {
+        let normalization = PathNormalization {
+            merge_slashes: true,
+            ..PathNormalization::default()
+        };
+        assert_eq!(normalization.normalize("//a//b/"), "/a/b/");
+
+        let normalization = PathNormalization {
+            trailing_slash: TrailingSlash::Strip,
+            ..normalization
+        };
+        assert_eq!(normalization.normalize("//a//b/"), "/a/b");
+        assert_eq!(normalization.normalize("//"), "/");
+    }
+
+    #[test]
+    fn dot_segments() {
+        let normalization = PathNormalization {
+            resolve_dot_segments: true,
+            ..PathNormalization::default()
+        };
+
+        assert_eq!(normalization.normalize("/a/../b"), "/b");
+        assert_eq!(normalization.normalize("/a/./b/.."), "/a/");
+        assert_eq!(normalization.normalize("/../../a"), "/a");
+        assert_eq!(normalization.normalize("/a/..b"), "/a/..b");
+    }
+
+    #[test]
+    fn percent_decoding() {
+        let normalization = PathNormalization {
+            decode_unreserved: true,
+            resolve_dot_segments: true,
+            ..PathNormalization::default()
+        };
+
+        assert_eq!(normalization.normalize("/%7Euser/%41b"), "/~user/Ab");
+        assert_eq!(normalization.normalize("/a%2Fb%20c%"), "/a%2Fb%20c%");
+        assert_eq!(normalization.normalize("/a/%2E%2E/b"), "/b");
+    }
+
+    #[test]
+    fn append_trailing_slash() {
+        let normalization = PathNormalization {
+            trailing_slash: TrailingSlash::Append,
+            ..PathNormalization::default()
+        };
+
+        assert_eq!(normalization.normalize("/a"), "/a/");
+        assert_eq!(normalization.normalize("/a/"), "/a/");
+        assert_eq!(normalization.normalize("/index.css"), "/index.css");
+        assert_eq!(
+            "STRIP".parse::<TrailingSlash>().unwrap(),
+            TrailingSlash::Strip
+        );
+    }
+}
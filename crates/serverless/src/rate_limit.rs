@@ -0,0 +1,124 @@
+use dashmap::DashMap;
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// How often buckets are cleaned up, and after how long
+// without requests a bucket is considered stale
+pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// Token bucket rate limiter keyed by client IP. Each client can make `burst`
+// requests at once, and gets `rate` new requests per second afterwards.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    // Clients would otherwise never get new requests, and
+    // computing how long they should wait would panic
+    pub fn new(rate: f64, burst: f64) -> Self {
+        assert!(
+            rate.is_finite() && rate > 0.0,
+            "The rate of a rate limiter must be a positive number"
+        );
+
+        Self {
+            rate,
+            burst,
+            buckets: DashMap::new(),
+        }
+    }
+
+    // Take a token from the client's bucket, or return how
+    // long the client should wait before retrying
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(ip).or_insert_with(|| Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+
+    // Stale buckets would be full again by now, so removing
+    // them doesn't change the limits and bounds the memory
+    pub fn cleanup(&self) {
+        self.buckets
+            .retain(|_, bucket| bucket.last_refill.elapsed() < CLEANUP_INTERVAL);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst() {
+        let rate_limiter = RateLimiter::new(1.0, 2.0);
+        let ip = "1.1.1.1".parse().unwrap();
+
+        assert!(rate_limiter.check(ip).is_ok());
+        assert!(rate_limiter.check(ip).is_ok());
+
+        let retry_after = rate_limiter.check(ip).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+
+        // Other clients have their own bucket
+        assert!(rate_limiter.check("8.8.8.8".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn refill() {
+        let rate_limiter = RateLimiter::new(100.0, 1.0);
+        let ip = "1.1.1.1".parse().unwrap();
+
+        assert!(rate_limiter.check(ip).is_ok());
+        assert!(rate_limiter.check(ip).is_err());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(rate_limiter.check(ip).is_ok());
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_rate() {
+        RateLimiter::new(0.0, 1.0);
+    }
+
+    #[test]
+    fn cleanup() {
+        let rate_limiter = RateLimiter::new(1.0, 1.0);
+
+        rate_limiter.check("1.1.1.1".parse().unwrap()).unwrap();
+        rate_limiter.cleanup();
+
+        // The bucket was just used
+        assert_eq!(rate_limiter.len(), 1);
+    }
+}
@@ -0,0 +1,191 @@
+use dashmap::DashMap;
+use metrics::gauge;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// Window the rates are computed over by default
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+// How often the gauges are recomputed
+pub const RATES_INTERVAL: Duration = Duration::from_secs(1);
+// The window slides one bucket at a time
+const BUCKETS_PER_WINDOW: u32 = 10;
+
+struct Bucket {
+    start: Instant,
+    requests: u64,
+    errors: u64,
+}
+
+struct DeploymentRequests {
+    function_id: String,
+    buckets: VecDeque<Bucket>,
+}
+
+// Per second, over the window
+#[derive(Debug, PartialEq)]
+pub struct Rates {
+    pub requests: f64,
+    pub errors: f64,
+}
+
+// Requests and errors per second of each deployment over a sliding window, for
+// live dashboards and autoscaling that can't wait for ClickHouse. Requests are
+// counted in buckets, so the memory used doesn't depend on the traffic.
+pub struct RequestRates {
+    window: Duration,
+    deployments: DashMap<String, DeploymentRequests>,
+}
+
+impl Default for RequestRates {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW)
+    }
+}
+
+impl RequestRates {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window: window.max(Duration::from_millis(BUCKETS_PER_WINDOW as u64)),
+            deployments: DashMap::new(),
+        }
+    }
+
+    // Errors are requests too, counted in both rates
+    pub fn record(&self, deployment_id: &str, function_id: &str, is_error: bool) {
+        self.record_at(deployment_id, function_id, is_error, Instant::now());
+    }
+
+    fn record_at(&self, deployment_id: &str, function_id: &str, is_error: bool, now: Instant) {
+        let mut deployment = self
+            .deployments
+            .entry(deployment_id.to_owned())
+            .or_insert_with(|| DeploymentRequests {
+                function_id: function_id.to_owned(),
+                buckets: VecDeque::new(),
+            });
+        let bucket_duration = self.window / BUCKETS_PER_WINDOW;
+
+        match deployment.buckets.back_mut() {
+            Some(bucket) if now.duration_since(bucket.start) < bucket_duration => {
+                bucket.requests += 1;
+                bucket.errors += u64::from(is_error);
+            }
+            _ => {
+                deployment.buckets.push_back(Bucket {
+                    start: now,
+                    requests: 1,
+                    errors: u64::from(is_error),
+                });
+            }
+        }
+
+        self.expire(&mut deployment.buckets, now);
+    }
+
+    fn expire(&self, buckets: &mut VecDeque<Bucket>, now: Instant) {
+        while let Some(bucket) = buckets.front() {
+            if now.duration_since(bucket.start) < self.window {
+                break;
+            }
+
+            buckets.pop_front();
+        }
+    }
+
+    pub fn get(&self, deployment_id: &str) -> Option<Rates> {
+        self.get_at(deployment_id, Instant::now())
+    }
+
+    fn get_at(&self, deployment_id: &str, now: Instant) -> Option<Rates> {
+        let mut deployment = self.deployments.get_mut(deployment_id)?;
+        self.expire(&mut deployment.buckets, now);
+
+        Some(self.rates(&deployment.buckets))
+    }
+
+    fn rates(&self, buckets: &VecDeque<Bucket>) -> Rates {
+        let window = self.window.as_secs_f64();
+        let (requests, errors) = buckets.iter().fold((0, 0), |(requests, errors), bucket| {
+            (requests + bucket.requests, errors + bucket.errors)
+        });
+
+        Rates {
+            requests: requests as f64 / window,
+            errors: errors as f64 / window,
+        }
+    }
+
+    // Recompute the gauges of every deployment. Deployments without requests
+    // in the window are forgotten, once their gauges are reset.
+    pub fn update_gauges(&self) {
+        let now = Instant::now();
+
+        self.deployments.retain(|deployment_id, deployment| {
+            self.expire(&mut deployment.buckets, now);
+            let rates = self.rates(&deployment.buckets);
+
+            gauge!("lagon_isolate_rps", rates.requests, "deployment" => deployment_id.clone(), "function" => deployment.function_id.clone());
+            gauge!("lagon_isolate_error_rate", rates.errors, "deployment" => deployment_id.clone(), "function" => deployment.function_id.clone());
+
+            !deployment.buckets.is_empty()
+        });
+    }
+
+    // Forget the deployment, e.g once it's undeployed
+    pub fn remove(&self, deployment_id: &str) {
+        if let Some((deployment_id, deployment)) = self.deployments.remove(deployment_id) {
+            gauge!("lagon_isolate_rps", 0.0, "deployment" => deployment_id.clone(), "function" => deployment.function_id.clone());
+            gauge!("lagon_isolate_error_rate", 0.0, "deployment" => deployment_id, "function" => deployment.function_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window() {
+        let rates = RequestRates::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        for i in 0..50 {
+            let now = start + Duration::from_millis(i * 100);
+            rates.record_at("deployment", "function", i % 10 == 0, now);
+        }
+
+        let now = start + Duration::from_secs(5);
+        assert_eq!(
+            rates.get_at("deployment", now),
+            Some(Rates {
+                requests: 5.0,
+                errors: 0.5,
+            })
+        );
+        assert_eq!(rates.get_at("other", now), None);
+
+        // The requests of the first 3 seconds slid out of the window
+        let now = start + Duration::from_millis(12500);
+        assert_eq!(
+            rates.get_at("deployment", now),
+            Some(Rates {
+                requests: 2.0,
+                errors: 0.2,
+            })
+        );
+
+        let now = start + Duration::from_secs(15);
+        assert_eq!(
+            rates.get_at("deployment", now),
+            Some(Rates {
+                requests: 0.0,
+                errors: 0.0,
+            })
+        );
+
+        rates.remove("deployment");
+        assert_eq!(rates.get("deployment"), None);
+    }
+}
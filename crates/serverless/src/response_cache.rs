@@ -0,0 +1,405 @@
+use anyhow::Result;
+use hyper::{
+    body::{self, Bytes, HttpBody},
+    header::{HeaderName, AGE, AUTHORIZATION, CACHE_CONTROL, SET_COOKIE, VARY},
+    http::HeaderValue,
+    Body, HeaderMap, Method, Request, Response, StatusCode,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Maximum size of the cached responses by default, in bytes
+pub const DEFAULT_RESPONSE_CACHE_SIZE: usize = 64 * 1024 * 1024;
+// A single response can't use more than this share of the
+// cache, so caching it doesn't evict every other response
+const MAX_ENTRY_SHARE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    deployment_id: String,
+    method: Method,
+    // Path and query, once normalized (see `PathNormalization`)
+    path: String,
+}
+
+// Request whose response can be cached once the function answered it
+pub struct CacheMiss {
+    key: CacheKey,
+    // Matched against the `Vary` header of the response
+    headers: HeaderMap,
+}
+
+impl CacheMiss {
+    pub fn deployment_id(&self) -> &str {
+        &self.key.deployment_id
+    }
+}
+
+pub enum Lookup {
+    Hit(Response<Body>),
+    Miss(CacheMiss),
+    // The request can't be answered from the cache, e.g a `POST`
+    Bypass,
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    // Request headers listed in the `Vary` header of the response, with
+    // the values of the request that was answered with this response
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    stored_at: Instant,
+    expires_at: Instant,
+    size: usize,
+    // Key of the entry in `CacheState::recency`
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, Entry>,
+    // Ordered from the least to the most recently used
+    recency: BTreeMap<u64, CacheKey>,
+    size: usize,
+    tick: u64,
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+}
+
+// Answer the requests of deployments with caching enabled (see
+// `Deployment::cache_responses`) with the last response of the function to
+// the same method and path, until it expires according to its `Cache-Control`.
+// Only one variant of each response is kept: a response with a `Vary` header
+// is only served to requests with the same values for these headers. The least
+// recently used responses are evicted once the cache is full.
+pub struct ResponseCache {
+    max_size: usize,
+    state: Mutex<CacheState>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESPONSE_CACHE_SIZE)
+    }
+}
+
+impl ResponseCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn get(&self, deployment_id: &str, req: &Request<Body>) -> Lookup {
+        // Responses to authenticated requests are specific to the client
+        if !matches!(*req.method(), Method::GET | Method::HEAD)
+            || req.headers().contains_key(AUTHORIZATION)
+        {
+            return Lookup::Bypass;
+        }
+
+        let key = CacheKey {
+            deployment_id: deployment_id.to_owned(),
+            method: req.method().clone(),
+            path: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.uri().path().to_owned(), |path| path.to_string()),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let (expired, matches) = match state.entries.get(&key) {
+            Some(entry) => (
+                entry.expires_at <= now,
+                entry
+                    .vary
+                    .iter()
+                    .all(|(name, value)| req.headers().get(name) == value.as_ref()),
+            ),
+            None => (false, false),
+        };
+
+        if expired {
+            state.remove(&key);
+        }
+
+        if expired || !matches {
+            return Lookup::Miss(CacheMiss {
+                key,
+                headers: req.headers().clone(),
+            });
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.recency.insert(tick, key.clone());
+
+        let entry = state.entries.get_mut(&key).unwrap();
+        let last_used = std::mem::replace(&mut entry.last_used, tick);
+
+        let mut response = Response::new(Body::from(entry.body.clone()));
+        *response.status_mut() = entry.status;
+        *response.headers_mut() = entry.headers.clone();
+        response
+            .headers_mut()
+            .insert(AGE, now.duration_since(entry.stored_at).as_secs().into());
+
+        state.recency.remove(&last_used);
+
+        Lookup::Hit(response)
+    }
+
+    // Cache the response if it's cacheable, returning it to be sent to the client.
+    // Only fully buffered responses are cached, not streams.
+    pub async fn store(&self, miss: CacheMiss, response: Response<Body>) -> Result<Response<Body>> {
+        let max_entry_size = self.max_size / MAX_ENTRY_SHARE;
+        let size = response.body().size_hint().exact();
+
+        let (ttl, vary) = match (
+            get_ttl(response.headers()),
+            get_vary(response.headers(), &miss.headers),
+        ) {
+            (Some(ttl), Some(vary))
+                if response.status() == StatusCode::OK
+                    && !response.headers().contains_key(SET_COOKIE)
+                    && size.map_or(false, |size| size as usize <= max_entry_size) =>
+            {
+                (ttl, vary)
+            }
+            _ => return Ok(response),
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = body::to_bytes(body).await?;
+
+        let size = miss.key.path.len()
+            + body.len()
+            + parts
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>();
+
+        if size <= max_entry_size {
+            let mut state = self.state.lock().unwrap();
+            state.remove(&miss.key);
+
+            while state.size + size > self.max_size {
+                match state.recency.pop_first() {
+                    Some((_, key)) => state.remove(&key),
+                    None => break,
+                }
+            }
+
+            let now = Instant::now();
+            state.tick += 1;
+            let tick = state.tick;
+
+            state.recency.insert(tick, miss.key.clone());
+            state.size += size;
+            state.entries.insert(
+                miss.key,
+                Entry {
+                    status: parts.status,
+                    headers: parts.headers.clone(),
+                    body: body.clone(),
+                    vary,
+                    stored_at: now,
+                    expires_at: now + ttl,
+                    size,
+                    last_used: tick,
+                },
+            );
+        }
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    // Forget the responses of the deployment, e.g once it's undeployed
+    pub fn remove(&self, deployment_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        let keys = state
+            .entries
+            .keys()
+            .filter(|key| key.deployment_id == deployment_id)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            state.remove(&key);
+        }
+    }
+}
+
+// Shared caches use `s-maxage` over `max-age`. Private responses, and
+// responses that must not be stored or must be revalidated aren't cached.
+fn get_ttl(headers: &HeaderMap) -> Option<Duration> {
+    let cache_control = headers.get(CACHE_CONTROL)?.to_str().ok()?;
+    let mut max_age = None;
+    let mut s_maxage = None;
+
+    for directive in cache_control.split(',') {
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (directive.trim(), None),
+        };
+
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "max-age" => max_age = value.and_then(|value| value.parse().ok()),
+            "s-maxage" => s_maxage = value.and_then(|value| value.parse().ok()),
+            _ => {}
+        }
+    }
+
+    s_maxage
+        .or(max_age)
+        .filter(|ttl| *ttl > 0)
+        .map(Duration::from_secs)
+}
+
+// `Vary: *` responses can't be matched with any request
+fn get_vary(
+    response_headers: &HeaderMap,
+    request_headers: &HeaderMap,
+) -> Option<Vec<(HeaderName, Option<HeaderValue>)>> {
+    let mut vary = Vec::new();
+
+    for value in response_headers.get_all(VARY) {
+        for name in value.to_str().ok()?.split(',').map(str::trim) {
+            if name == "*" {
+                return None;
+            }
+
+            if !name.is_empty() {
+                let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+                let value = request_headers.get(&name).cloned();
+
+                vary.push((name, value));
+            }
+        }
+    }
+
+    Some(vary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::ACCEPT_ENCODING;
+
+    fn request(path: &str, accept_encoding: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder().uri(path);
+
+        if let Some(accept_encoding) = accept_encoding {
+            request = request.header(ACCEPT_ENCODING, accept_encoding);
+        }
+
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn response(cache_control: &str, body: &'static str) -> Response<Body> {
+        Response::builder()
+            .header(CACHE_CONTROL, cache_control)
+            .header(VARY, "Accept-Encoding")
+            .body(body.into())
+            .unwrap()
+    }
+
+    async fn get(cache: &ResponseCache, request: &Request<Body>) -> Option<String> {
+        match cache.get("deployment", request) {
+            Lookup::Hit(response) => Some(
+                String::from_utf8(body::to_bytes(response.into_body()).await.unwrap().to_vec())
+                    .unwrap(),
+            ),
+            _ => None,
+        }
+    }
+
+    async fn store(cache: &ResponseCache, request: &Request<Body>, response: Response<Body>) {
+        match cache.get("deployment", request) {
+            Lookup::Miss(miss) => {
+                cache.store(miss, response).await.unwrap();
+            }
+            _ => panic!("Expected a cache miss"),
+        }
+    }
+
+    #[test]
+    fn ttl() {
+        let ttl = |cache_control: &str| {
+            get_ttl(&HeaderMap::from_iter([(
+                CACHE_CONTROL,
+                cache_control.parse().unwrap(),
+            )]))
+        };
+
+        assert_eq!(ttl("public, max-age=60"), Some(Duration::from_secs(60)));
+        assert_eq!(
+            ttl("max-age=60, s-maxage=\"10\""),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(ttl("private, max-age=60"), None);
+        assert_eq!(ttl("public, no-store"), None);
+        assert_eq!(ttl("max-age=0"), None);
+        assert_eq!(get_ttl(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn vary() {
+        let cache = ResponseCache::default();
+
+        let gzip = request("/", Some("gzip"));
+        store(&cache, &gzip, response("max-age=60", "gzip")).await;
+
+        assert_eq!(get(&cache, &gzip).await, Some(String::from("gzip")));
+        assert_eq!(get(&cache, &request("/", None)).await, None);
+        assert_eq!(get(&cache, &request("/?page=2", Some("gzip"))).await, None);
+
+        let post = Request::post("/").body(Body::empty()).unwrap();
+        assert!(matches!(cache.get("deployment", &post), Lookup::Bypass));
+    }
+
+    #[tokio::test]
+    async fn lru_eviction() {
+        let cache = ResponseCache::new(1024);
+
+        store(&cache, &request("/a", None), response("max-age=60", "a")).await;
+        store(&cache, &request("/b", None), response("max-age=60", "b")).await;
+        assert_eq!(
+            get(&cache, &request("/a", None)).await,
+            Some(String::from("a"))
+        );
+
+        // Fill the cache until `/b` is evicted, since `/a` was used more recently
+        for i in 0..24 {
+            let path = format!("/{}", i);
+            store(&cache, &request(&path, None), response("max-age=60", "0")).await;
+            get(&cache, &request("/a", None)).await;
+        }
+
+        assert_eq!(
+            get(&cache, &request("/a", None)).await,
+            Some(String::from("a"))
+        );
+        assert_eq!(get(&cache, &request("/b", None)).await, None);
+        assert!(cache.state.lock().unwrap().size <= 1024);
+
+        cache.remove("deployment");
+        assert_eq!(get(&cache, &request("/a", None)).await, None);
+    }
+}
@@ -0,0 +1,79 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, OsRng},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use anyhow::{anyhow, Result};
+
+const NONCE_LENGTH: usize = 12;
+
+// Key encrypting the secrets of deployments stored in the database, shared
+// with the control plane. Values are stored as the hex of the random nonce
+// followed by the AES-256-GCM ciphertext, so the plain values never reach
+// the database.
+#[derive(Clone)]
+pub struct SecretsKey(Aes256Gcm);
+
+impl SecretsKey {
+    // The key is 32 bytes encoded in hex, e.g `openssl rand -hex 32`
+    pub fn from_hex(key: &str) -> Result<Self> {
+        let key = hex::decode(key)?;
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| anyhow!("Invalid key length"))?;
+
+        Ok(Self(cipher))
+    }
+
+    pub fn encrypt(&self, value: &str) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, value.as_bytes())
+            .map_err(|_| anyhow!("Could not encrypt secret"))?;
+
+        Ok(hex::encode([nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<String> {
+        let value = hex::decode(value)?;
+
+        if value.len() < NONCE_LENGTH {
+            return Err(anyhow!("Encrypted secret is too short"));
+        }
+
+        let (nonce, ciphertext) = value.split_at(NONCE_LENGTH);
+        let plaintext = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Could not decrypt secret"))?;
+
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn round_trip() {
+        let key = SecretsKey::from_hex(KEY).unwrap();
+        let encrypted = key.encrypt("secret value").unwrap();
+
+        assert!(!encrypted.contains("secret value"));
+        assert_eq!(key.decrypt(&encrypted).unwrap(), "secret value");
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(SecretsKey::from_hex("0001").is_err());
+
+        let key = SecretsKey::from_hex(KEY).unwrap();
+        let other_key = SecretsKey::from_hex(&KEY.replace("00", "ff")).unwrap();
+        let encrypted = key.encrypt("secret value").unwrap();
+
+        assert!(other_key.decrypt(&encrypted).is_err());
+        assert!(key.decrypt("not hex").is_err());
+        assert!(key.decrypt("0001").is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use hyper::{body, Request};
+use lagon_runtime_http::RunResult;
+use lagon_runtime_isolate::{options::IsolateOptions, Isolate, IsolateEvent, IsolateRequest};
+use std::time::Duration;
+use tokio::runtime::Handle;
+
+const SELF_TEST_BODY: &str = "Lagon self-test";
+// Maximum time for the isolate to be created and answer
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Invoke a trivial function in a new isolate created from the snapshot, to
+// catch a broken snapshot (or isolate pipeline) before serving any traffic.
+// The isolate runs in its own thread, so a panic only fails the self-test.
+pub async fn run_self_test(snapshot_blob: &'static [u8]) -> Result<()> {
+    let handle = Handle::current();
+    let (isolate_sender, isolate_receiver) = flume::unbounded();
+
+    std::thread::Builder::new()
+        .name(String::from("self-test"))
+        .spawn(move || {
+            handle.block_on(async move {
+                let options = IsolateOptions::new(format!(
+                    "export function handler() {{ return new Response('{}') }}",
+                    SELF_TEST_BODY
+                ))
+                .snapshot_blob(snapshot_blob);
+
+                let mut isolate = Isolate::new(options, isolate_receiver);
+                isolate.evaluate();
+                isolate.run_event_loop().await;
+            });
+        })?;
+
+    let (sender, receiver) = flume::unbounded();
+
+    isolate_sender
+        .send_async(IsolateEvent::Request(IsolateRequest {
+            sender,
+            request: Request::new(Bytes::new()).into_parts(),
+            total_timeout: None,
+        }))
+        .await
+        .unwrap_or(());
+
+    let result = tokio::time::timeout(SELF_TEST_TIMEOUT, receiver.recv_async()).await;
+
+    isolate_sender
+        .send_async(IsolateEvent::Terminate(String::from("Self-test completed")))
+        .await
+        .unwrap_or(());
+
+    match result {
+        Ok(Ok(RunResult::Response(response, _))) => {
+            let status = response.status();
+            let body = body::to_bytes(response.into_body()).await?;
+
+            if status != 200 || body != SELF_TEST_BODY {
+                return Err(anyhow!(
+                    "Unexpected response: {} {}",
+                    status,
+                    String::from_utf8_lossy(&body)
+                ));
+            }
+
+            Ok(())
+        }
+        Ok(Ok(RunResult::Error(error))) => Err(anyhow!(error)),
+        Ok(Ok(_)) => Err(anyhow!("Unexpected result")),
+        Ok(Err(_)) => Err(anyhow!("Isolate stopped without answering")),
+        Err(_) => Err(anyhow!("Isolate didn't answer in time")),
+    }
+}
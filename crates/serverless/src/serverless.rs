@@ -1,55 +1,669 @@
 use crate::{
-    clickhouse::{LogRow, RequestRow},
+    admin::start_admin,
+    assets::{
+        AssetNotFound, AssetStore, AssetStream, CoalescingAssetStore, FilesystemAssetStore,
+        RangeNotSatisfiable,
+    },
+    circuit_breaker::CircuitBreaker,
+    clickhouse::{
+        create_inserters, sample_weight, Flusher, Inserters, LogRow, RequestRow,
+        DEFAULT_MAX_PENDING_ROWS,
+    },
+    cpu_budget::CpuBudgets,
     cronjob::Cronjob,
-    deployments::{cache::run_cache_clear_task, pubsub::listen_pub_sub, Deployments},
-    get_region, SNAPSHOT_BLOB,
+    deployments::{
+        cache::run_cache_clear_task,
+        events::{DeploymentEvent, DeploymentEventCallback},
+        get_canary, get_deployment, get_deployment_by_id, get_local_deployments,
+        handoff::{import_state, HandoffState},
+        is_deployment_registered, normalize_hostname,
+        pubsub::{clear_deployment_cache, drain_worker, listen_pub_sub},
+        queue::run_queue_depth_task,
+        Deployments,
+    },
+    fairness::FairScheduler,
+    get_region,
+    hooks::{LimitBreach, LimitBreachHook, LimitBreachKind, RequestHook},
+    ip::{get_client_ip, is_ip_allowed},
+    latency::LatencyTracker,
+    otel::{DispatchSpan, RequestSpan},
+    path::PathNormalization,
+    rate_limit::{RateLimiter, CLEANUP_INTERVAL},
+    rates::{RequestRates, RATES_INTERVAL},
+    response_cache::{Lookup, ResponseCache},
+    self_test::run_self_test,
+    shadow::{self, ShadowTraffic},
+    signature::{is_internal_request, verify_request},
+    snapshots::SnapshotRegistry,
+    stdout_logs::StdoutLogs,
+    tls::{tls_incoming, Connection, TlsConfig},
 };
 use anyhow::Result;
-use clickhouse::{inserter::Inserter, Client};
-use dashmap::DashMap;
-use futures::lock::Mutex;
+use clickhouse::Client;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::{stream, StreamExt};
 use hyper::{
-    header::HOST,
-    http::response::Builder,
-    server::conn::AddrStream,
+    body::{Bytes, HttpBody},
+    header::{
+        ACCEPT, ACCEPT_ENCODING, ACCEPT_RANGES, ALLOW, CONTENT_ENCODING, CONTENT_LENGTH,
+        CONTENT_RANGE, CONTENT_TYPE, COOKIE, HOST, LINK, RANGE, RETRY_AFTER,
+    },
+    http::{request::Parts, response::Builder, HeaderName, HeaderValue},
+    server::{
+        accept::{self, Accept},
+        conn::AddrIncoming,
+        Builder as ServerBuilder,
+    },
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Body, HeaderMap, Request, Response, Server, StatusCode,
 };
-use lagon_runtime_http::{RunResult, X_LAGON_ID};
+use ipnet::IpNet;
+use lagon_runtime_http::{RunResult, X_LAGON_ID, X_LAGON_TOTAL_TIMEOUT};
 use lagon_runtime_isolate::{
     options::{IsolateOptions, Metadata},
-    Isolate, IsolateEvent, IsolateRequest,
+    FunctionEvent, Isolate, IsolateEvent, IsolateRequest,
 };
 use lagon_runtime_utils::{
-    assets::{find_asset, handle_asset},
-    response::{handle_response, ResponseEvent, FAVICON_URL, PAGE_403, PAGE_404},
-    DEPLOYMENTS_DIR,
+    assets::{
+        content_range, find_asset, get_content_type, preload_link, unsatisfied_content_range,
+        RangeRequest,
+    },
+    compression::{ContentEncoding, MAX_DECOMPRESSED_SIZE},
+    response::{
+        accepts_json, find_static_response, handle_response, inject_response_headers,
+        ResponseEvent, ResponseOptions, StaticResponse, FAVICON_URL, PAGE_403, PAGE_404, PAGE_503,
+    },
+    Deployment, DEPLOYMENTS_DIR,
 };
-use lagon_serverless_downloader::Downloader;
-use lagon_serverless_pubsub::PubSubListener;
-use log::{as_debug, error, info, warn};
-use metrics::{decrement_gauge, histogram, increment_counter, increment_gauge};
+use lagon_serverless_downloader::{Downloader, FakeDownloader};
+use lagon_serverless_pubsub::{FakePubSub, PubSubListener};
+use log::{as_debug, debug, error, info, warn};
+use metrics::{counter, decrement_gauge, histogram, increment_counter, increment_gauge};
+use serde_json::json;
 use std::{
     collections::HashSet,
-    convert::Infallible,
-    env,
     future::Future,
-    net::SocketAddr,
-    path::Path,
-    sync::Arc,
+    net::{IpAddr, SocketAddr},
+    ops::ControlFlow,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant, UNIX_EPOCH},
 };
-use tokio::{runtime::Handle, sync::Mutex as TokioMutex};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    runtime::Handle,
+    sync::{
+        oneshot, watch, Mutex as TokioMutex, Notify, OwnedSemaphorePermit, Semaphore,
+        TryAcquireError,
+    },
+};
+use tokio_rustls::TlsAcceptor;
+
+pub struct Worker {
+    pub sender: flume::Sender<IsolateEvent>,
+    pub in_flight: Arc<AtomicUsize>,
+    // Pinned isolates are never evicted, see `ServerConfig::is_pinned`
+    pub pinned: bool,
+    // Set once the code of the deployment has been evaluated
+    pub ready: watch::Receiver<bool>,
+}
+
+pub type Workers = Arc<DashMap<String, Worker>>;
+
+pub struct ServerConfig {
+    // Address the server listens on
+    pub addr: SocketAddr,
+    // Where the deployments code and assets are stored,
+    // relative to the current directory if not absolute
+    pub deployments_dir: PathBuf,
+    // Maximum number of live isolates, unlimited if not set
+    pub max_isolates: Option<usize>,
+    // Limit the number of isolates being created (evaluating their code) at
+    // once, to avoid a storm of cold starts when many deployments receive
+    // their first requests together. Unlimited if not set, see
+    // `ServerConfig::max_concurrent_isolate_creations`
+    pub isolate_creations: Option<Semaphore>,
+    // Maximum time a request waits for its isolate to pick it up (e.g
+    // when the isolate is busy or starting), answered with a 503 once
+    // exceeded. Unlimited if not set.
+    pub queue_timeout: Option<Duration>,
+    // When set, requests with internal headers (e.g `X-Lagon-Id`)
+    // must be signed with this secret
+    pub internal_secret: Option<String>,
+    // Header correlating requests, read from requests and echoed in responses,
+    // e.g `X-Request-Id` to reuse the header of an existing tracing system
+    pub request_id_header: HeaderName,
+    // Address of the admin server, disabled if not set
+    pub admin_addr: Option<SocketAddr>,
+    // Where assets are served from, the deployments directory if not set
+    pub asset_store: Option<Box<dyn AssetStore>>,
+    // How often requests and logs are inserted in ClickHouse
+    pub insertion_interval: Duration,
+    // Id of the deployment serving requests that don't match any
+    // other deployment, e.g without a Host header
+    pub default_deployment: Option<String>,
+    // Share the CPU time between functions under contention,
+    // disabled if not set
+    pub fairness: Option<FairScheduler>,
+    // Deployments whose isolates are never evicted, in addition to
+    // the ones pinned by the control plane (`Deployment::pinned`)
+    pub pinned_deployments: HashSet<String>,
+    // CPU time used by deployments with a budget, see `Deployment::cpu_budget`
+    pub cpu_budgets: CpuBudgets,
+    // Moving average of the latency of each deployment, see `LatencyTracker`
+    pub latency: LatencyTracker,
+    // Requests and errors per second of each deployment, see `RequestRates`
+    pub request_rates: RequestRates,
+    // Responses of the deployments with caching enabled, see `ResponseCache`
+    pub response_cache: ResponseCache,
+    // Middleware running around the dispatch of requests, see `RequestHook`
+    pub hooks: Vec<Box<dyn RequestHook>>,
+    // Notified when isolates hit their limits, see `LimitBreachHook`
+    pub limit_breach_hook: Option<Arc<dyn LimitBreachHook>>,
+    // Maximum number of pub/sub messages handled at once. Messages of
+    // the same deployment are always handled one after the other.
+    pub max_concurrent_messages: usize,
+    // Answer requests to the domains of deployments being downloaded with
+    // a 503 until they're ready, instead of a 404
+    pub provisioning_placeholders: bool,
+    // Deployments whose download (code and assets) doesn't complete in
+    // time fail to deploy, unlimited if not set
+    pub download_timeout: Option<Duration>,
+    // Maximum time waited for the in-flight requests of drained isolates
+    // (e.g undeployed, promoted or reloaded deployments) to complete
+    // before terminating them
+    pub undeploy_drain_timeout: Duration,
+    // Evaluate the code of production deployments before making
+    // them routable, failing the deployment if it throws
+    pub readiness_check: bool,
+    // Invoke a trivial function when starting, failing to start if it doesn't
+    // answer as expected, e.g when the runtime snapshot is broken
+    pub self_test: bool,
+    // Whether the server is behind a trusted proxy, in which case the
+    // client address is read from the `X-Forwarded-For` header
+    pub trusted_proxy: bool,
+    // Proxies in front of the trusted proxy (e.g a CDN), whose
+    // entries are skipped when reading `X-Forwarded-For`
+    pub trusted_proxies: Vec<IpNet>,
+    // Limit the number of requests per client IP, disabled if not set
+    pub rate_limiter: Option<RateLimiter>,
+    // Answer requests to deployments that keep failing with a 503,
+    // disabled if not set
+    pub circuit_breaker: Option<CircuitBreaker>,
+    // Body of the 403 answered to requests of suspended deployments
+    pub suspended_message: String,
+    // Create the isolates of production deployments when starting,
+    // instead of on their first request
+    pub warmup: bool,
+    // Maximum number of isolates created at once during the warmup
+    pub max_concurrent_warmups: usize,
+    // Maximum random delay before creating each isolate during
+    // the warmup, to spread the load when starting
+    pub warmup_jitter: Duration,
+    // State exported by the node this one replaces (see `export_state`), whose
+    // warm deployments are warmed up when starting, once loaded from the database
+    pub handoff: Option<HandoffState>,
+    // Called when deployments are added, removed, promoted or
+    // fail to deploy, e.g to notify a sidecar. Does nothing by default.
+    pub on_deployment_event: DeploymentEventCallback,
+    // Snapshots isolates are created from, see `SnapshotRegistry`
+    pub snapshots: SnapshotRegistry,
+    // Directory of `<runtime version>.bin` snapshots added to
+    // `snapshots` when starting
+    pub snapshots_dir: Option<PathBuf>,
+    // Normalize the path of requests before routing them, see `PathNormalization`
+    pub path_normalization: PathNormalization,
+    // Answer errors (e.g timeouts) with a JSON body to clients accepting
+    // it, instead of an HTML page. Can also be enabled per deployment.
+    pub json_errors: bool,
+    // Preload the deployments' critical assets (see `Deployment::preload_assets`)
+    // with a `Link` header on HTML responses. Hyper can't send `103 Early Hints`,
+    // but CDNs in front of us can turn these headers into early hints.
+    pub preload_links: bool,
+    // When a deployment claims a domain already served by a deployment of
+    // another function, keep the current one instead of replacing it
+    pub keep_conflicting_domains: bool,
+    // Commit the pending requests and logs on demand, see `Flusher`
+    pub flusher: Flusher,
+    // Also write the logs of functions to stdout, disabled if not set
+    pub stdout_logs: Option<StdoutLogs>,
+    // Write the logs of functions to ClickHouse, which can be
+    // disabled when they're only written to stdout
+    pub clickhouse_logs: bool,
+    // Share of the successful requests (from 0 to 1) written to ClickHouse,
+    // can be overridden per deployment. Errors are always written.
+    pub request_sample_rate: f64,
+    // Rows of each table kept until the next commit, the oldest
+    // ones are dropped when ClickHouse can't keep up
+    pub clickhouse_max_pending_rows: usize,
+    // Interval of the TCP keep-alive probes, disabled if not set
+    pub tcp_keepalive: Option<Duration>,
+    // Keep HTTP/1 connections open between requests
+    pub http1_keepalive: bool,
+    // Close connections that don't send the headers of
+    // a request in time, unlimited if not set
+    pub header_read_timeout: Option<Duration>,
+    // Requests with more headers, or whose headers (names and values) are
+    // larger than this in total, are answered with a 431. Unlimited if not set.
+    pub max_headers: Option<usize>,
+    pub max_header_bytes: Option<usize>,
+    // Maximum number of open connections, unlimited if not set.
+    // Connections over the limit are closed right away.
+    pub max_connections: Option<usize>,
+    // Accept HTTP/2 connections (h2c with prior knowledge) besides HTTP/1.
+    // Each stream is a request handled like any other: a connection can have
+    // `http2_max_concurrent_streams` requests in flight, which all count in
+    // the requests of the deployment (e.g for the fairness and the rate limit)
+    pub http2: bool,
+    // HTTP/2 settings, hyper's defaults when not set
+    pub http2_max_concurrent_streams: Option<u32>,
+    pub http2_initial_stream_window_size: Option<u32>,
+    pub http2_initial_connection_window_size: Option<u32>,
+    // Interval of the HTTP/2 pings, disabled if not set
+    pub http2_keep_alive_interval: Option<Duration>,
+    // Terminate TLS instead of listening for plain HTTP, see `TlsConfig`
+    pub tls: Option<TlsConfig>,
+    // Requests mirrored to shadow deployments, see `ShadowTraffic`
+    pub shadow_traffic: ShadowTraffic,
+    // Region written in the requests and logs, and matched against the
+    // region of crons. `LAGON_REGION` (see `get_region`) if not set.
+    pub region: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([0, 0, 0, 0], 4000)),
+            deployments_dir: PathBuf::from(DEPLOYMENTS_DIR),
+            max_isolates: None,
+            isolate_creations: None,
+            queue_timeout: None,
+            internal_secret: None,
+            request_id_header: HeaderName::from_static(X_LAGON_ID),
+            admin_addr: None,
+            asset_store: None,
+            insertion_interval: Duration::from_secs(1),
+            default_deployment: None,
+            fairness: None,
+            pinned_deployments: HashSet::new(),
+            cpu_budgets: CpuBudgets::new(),
+            latency: LatencyTracker::default(),
+            request_rates: RequestRates::default(),
+            response_cache: ResponseCache::default(),
+            hooks: Vec::new(),
+            limit_breach_hook: None,
+            max_concurrent_messages: 16,
+            provisioning_placeholders: false,
+            download_timeout: Some(DEFAULT_DOWNLOAD_TIMEOUT),
+            undeploy_drain_timeout: DEFAULT_UNDEPLOY_DRAIN_TIMEOUT,
+            readiness_check: false,
+            self_test: false,
+            trusted_proxy: false,
+            trusted_proxies: Vec::new(),
+            rate_limiter: None,
+            circuit_breaker: None,
+            suspended_message: String::from(DEFAULT_SUSPENDED_MESSAGE),
+            warmup: false,
+            max_concurrent_warmups: 4,
+            warmup_jitter: Duration::from_millis(100),
+            handoff: None,
+            on_deployment_event: Arc::new(|_| {}),
+            snapshots: SnapshotRegistry::default(),
+            snapshots_dir: None,
+            path_normalization: PathNormalization::default(),
+            json_errors: false,
+            preload_links: false,
+            keep_conflicting_domains: false,
+            flusher: Flusher::default(),
+            stdout_logs: None,
+            clickhouse_logs: true,
+            request_sample_rate: 1.0,
+            clickhouse_max_pending_rows: DEFAULT_MAX_PENDING_ROWS,
+            tcp_keepalive: None,
+            http1_keepalive: true,
+            header_read_timeout: None,
+            max_headers: None,
+            max_header_bytes: None,
+            max_connections: None,
+            http2: false,
+            http2_max_concurrent_streams: None,
+            http2_initial_stream_window_size: None,
+            http2_initial_connection_window_size: None,
+            http2_keep_alive_interval: None,
+            tls: None,
+            shadow_traffic: ShadowTraffic::default(),
+            region: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    pub fn deployments_dir(mut self, deployments_dir: PathBuf) -> Self {
+        self.deployments_dir = deployments_dir;
+        self
+    }
+
+    pub fn max_isolates(mut self, max_isolates: usize) -> Self {
+        self.max_isolates = Some(max_isolates);
+        self
+    }
+
+    // Isolates over the limit wait for a slot before being created, up to the
+    // total timeout of their deployment, after which their requests time out
+    pub fn max_concurrent_isolate_creations(mut self, max_creations: usize) -> Self {
+        self.isolate_creations = Some(Semaphore::new(max_creations));
+        self
+    }
+
+    pub fn queue_timeout(mut self, queue_timeout: Duration) -> Self {
+        self.queue_timeout = Some(queue_timeout);
+        self
+    }
+
+    pub fn internal_secret(mut self, internal_secret: String) -> Self {
+        self.internal_secret = Some(internal_secret);
+        self
+    }
+
+    pub fn admin_addr(mut self, admin_addr: SocketAddr) -> Self {
+        self.admin_addr = Some(admin_addr);
+        self
+    }
+
+    pub fn asset_store(mut self, asset_store: impl AssetStore + 'static) -> Self {
+        self.asset_store = Some(Box::new(asset_store));
+        self
+    }
+
+    pub fn insertion_interval(mut self, insertion_interval: Duration) -> Self {
+        self.insertion_interval = insertion_interval;
+        self
+    }
+
+    pub fn default_deployment(mut self, default_deployment: String) -> Self {
+        self.default_deployment = Some(default_deployment);
+        self
+    }
+
+    pub fn fairness(mut self, fairness: FairScheduler) -> Self {
+        self.fairness = Some(fairness);
+        self
+    }
+
+    // Hooks run in the order they are added, see `RequestHook`
+    pub fn hook(mut self, hook: impl RequestHook + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    pub fn limit_breach_hook(mut self, hook: impl LimitBreachHook + 'static) -> Self {
+        self.limit_breach_hook = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    pub fn circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    pub fn on_deployment_event(
+        mut self,
+        callback: impl Fn(DeploymentEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_deployment_event = Arc::new(callback);
+        self
+    }
+
+    pub fn snapshots(mut self, snapshots: SnapshotRegistry) -> Self {
+        self.snapshots = snapshots;
+        self
+    }
+
+    pub fn flusher(mut self, flusher: Flusher) -> Self {
+        self.flusher = flusher;
+        self
+    }
+
+    pub fn stdout_logs(mut self, stdout_logs: StdoutLogs) -> Self {
+        self.stdout_logs = Some(stdout_logs);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn pin_deployment(mut self, deployment_id: String) -> Self {
+        self.pinned_deployments.insert(deployment_id);
+        self
+    }
+
+    pub fn is_pinned(&self, deployment: &Deployment) -> bool {
+        deployment.pinned || self.pinned_deployments.contains(&deployment.id)
+    }
+
+    pub fn region(&self) -> &str {
+        match &self.region {
+            Some(region) => region,
+            None => get_region(),
+        }
+    }
+}
+
+// Paths are truncated to bound the size and cardinality of the requests table
+const MAX_REQUEST_PATH_LENGTH: usize = 128;
+
+// Seconds clients should wait before retrying a deployment in maintenance
+const MAINTENANCE_RETRY_AFTER: &str = "60";
+
+// Seconds clients should wait before retrying a deployment being downloaded
+const PROVISIONING_RETRY_AFTER: &str = "1";
+
+// Clients are routed to canaries based on the value of this cookie
+// if it's set (e.g a session id), or their IP address otherwise
+const CANARY_COOKIE: &str = "lagon-canary";
 
-pub type Workers = Arc<DashMap<String, flume::Sender<IsolateEvent>>>;
+fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|cookie| {
+            let (key, value) = cookie.trim().split_once('=')?;
+
+            (key == name).then_some(value)
+        })
+}
+
+// Read the whole body, unless it grows over `max_size` bytes in
+// which case we stop reading it (e.g for chunked requests)
+async fn read_body(mut body: Body, max_size: Option<usize>) -> Result<Option<Bytes>> {
+    let max_size = match max_size {
+        Some(max_size) => max_size,
+        None => return Ok(Some(hyper::body::to_bytes(body).await?)),
+    };
+
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+
+        if bytes.len() + chunk.len() > max_size {
+            return Ok(None);
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(bytes.into()))
+}
+
+// Decompress bodies sent with a supported `Content-Encoding`, returning
+// `None` if the decompressed body is larger than `max_size`. This is CPU-bound,
+// so it runs on the blocking threads instead of stalling the other requests.
+async fn decompress_body(parts: &mut Parts, body: Bytes, max_size: usize) -> Result<Option<Bytes>> {
+    let encoding = match parts
+        .headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ContentEncoding::from_header)
+    {
+        Some(encoding) => encoding,
+        None => return Ok(Some(body)),
+    };
+
+    let decompressed =
+        tokio::task::spawn_blocking(move || encoding.decompress(&body, max_size)).await??;
+
+    let body = match decompressed {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+
+    Ok(Some(body.into()))
+}
+
+fn body_too_large(deployment: &Deployment) -> Result<Response<Body>> {
+    increment_counter!(
+        "lagon_body_too_large",
+        "deployment" => deployment.id.clone(),
+        "function" => deployment.function_id.clone(),
+    );
+
+    Ok(Builder::new()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(Body::empty())?)
+}
+
+fn headers_too_large(headers: &HeaderMap, config: &ServerConfig) -> bool {
+    if config
+        .max_headers
+        .map_or(false, |max_headers| headers.len() > max_headers)
+    {
+        return true;
+    }
+
+    config.max_header_bytes.map_or(false, |max_header_bytes| {
+        let header_bytes = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+
+        header_bytes > max_header_bytes
+    })
+}
+
+pub const DEFAULT_SUSPENDED_MESSAGE: &str = "This function has been suspended.";
+
+pub const DEFAULT_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub const DEFAULT_UNDEPLOY_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Inserted in the request's extensions when sending it to an isolate,
+// to measure how long it waits before being picked up
+struct EnqueuedAt(Instant);
+
+// Inserted in the request's extensions when there's a queue
+// timeout, notified once an isolate picked up the request
+struct PickedUp(Arc<Notify>);
+
+// Keep track of a request being processed by a worker. The count is
+// decremented once the response is done, or when dropped (e.g if the
+// client disconnected before)
+struct InFlightRequest {
+    in_flight: Arc<AtomicUsize>,
+    done: AtomicBool,
+}
+
+impl InFlightRequest {
+    fn new(in_flight: Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+
+        Self {
+            in_flight,
+            done: AtomicBool::new(false),
+        }
+    }
+
+    fn done(&self) {
+        if !self.done.swap(true, Ordering::SeqCst) {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+// Keep track of an open connection, holding its slot
+// when the number of connections is limited
+struct ActiveConnection {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ActiveConnection {
+    fn new(permit: Option<OwnedSemaphorePermit>) -> Self {
+        increment_gauge!("lagon_connections_active", 1.0);
+
+        Self { _permit: permit }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        decrement_gauge!("lagon_connections_active", 1.0);
+    }
+}
 
 async fn handle_error(
     result: RunResult,
     function_id: String,
     deployment_id: String,
     request_id: &String,
-    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
+    region: String,
+    inserters: Inserters,
+    limit_breach_hook: Option<&Arc<dyn LimitBreachHook>>,
 ) {
+    let breach_kind = match result {
+        RunResult::Timeout => Some(LimitBreachKind::Timeout),
+        RunResult::MemoryLimit => Some(LimitBreachKind::MemoryLimit),
+        _ => None,
+    };
+
+    if let (Some(kind), Some(hook)) = (breach_kind, limit_breach_hook) {
+        let hook = Arc::clone(hook);
+        let breach = LimitBreach {
+            deployment_id: deployment_id.clone(),
+            function_id: function_id.clone(),
+            kind,
+            request_id: request_id.clone(),
+        };
+
+        tokio::task::spawn_blocking(move || hook.on_breach(breach));
+    }
+
     let (level, message) = match result {
         RunResult::Timeout => {
             increment_counter!("lagon_isolate_timeouts", "deployment" => deployment_id.clone(), "function" => function_id.clone());
@@ -78,215 +692,1550 @@ async fn handle_error(
         _ => ("warn", "Unknown result".into()),
     };
 
-    if let Err(error) = inserters
-        .lock()
-        .await
-        .1
-        .write(&LogRow {
-            function_id,
-            deployment_id,
-            level: level.to_string(),
-            message,
-            region: get_region().clone(),
-            timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs() as u32,
+    inserters.1.write(LogRow {
+        function_id,
+        deployment_id,
+        level: level.to_string(),
+        message,
+        region,
+        timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs() as u32,
+        fields: None,
+    });
+}
+
+// Find the deployment with a live isolate that received a request the
+// longest time ago, without considering the deployment currently served
+fn find_lru_deployment(
+    workers: &Workers,
+    last_requests: &DashMap<String, Instant>,
+    deployment_id: &str,
+) -> Option<String> {
+    workers
+        .iter()
+        .filter(|worker| !worker.pinned)
+        .map(|worker| worker.key().clone())
+        .filter(|id| id != deployment_id)
+        .min_by_key(|id| last_requests.get(id).map(|last_request| *last_request))
+}
+
+async fn evict_lru_isolates(
+    max_isolates: usize,
+    deployment_id: &str,
+    last_requests: &Arc<DashMap<String, Instant>>,
+    workers: &Workers,
+) {
+    while !workers.contains_key(deployment_id) && workers.len() >= max_isolates {
+        let lru_deployment_id = match find_lru_deployment(workers, last_requests, deployment_id) {
+            Some(lru_deployment_id) => lru_deployment_id,
+            None => break,
+        };
+
+        increment_counter!("lagon_isolate_lru_evictions", "deployment" => lru_deployment_id.clone());
+        info!(deployment = lru_deployment_id, max_isolates = max_isolates; "Evicting least recently used isolate");
+
+        last_requests.remove(&lru_deployment_id);
+        clear_deployment_cache(
+            lru_deployment_id,
+            Arc::clone(workers),
+            String::from("eviction"),
+        )
+        .await;
+    }
+}
+
+// Send the request to the deployment's isolate, creating it with `create_worker`
+// if needed. The isolate's thread can exit (e.g after reaching its limits) right
+// before its worker is removed, in which case we remove the stale worker and retry
+// once with a new isolate. The request is given back if it still couldn't be sent.
+// Workers are only created for registered deployments: the deployment can be
+// undeployed (and its worker removed) after the request was routed to it, and
+// the isolate of a new worker would then never be terminated.
+async fn send_request(
+    deployment_id: &str,
+    workers: &Workers,
+    deployments: &Deployments,
+    mut request: IsolateRequest,
+    create_worker: impl Fn() -> Worker,
+) -> Result<Arc<InFlightRequest>, IsolateRequest> {
+    for attempt in 0..2 {
+        // Undeployments unregister the deployment before removing its worker,
+        // so checking while holding the entry can't miss an undeployment
+        let worker = match workers.entry(deployment_id.to_owned()) {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => {
+                if !is_deployment_registered(deployments, deployment_id) {
+                    warn!(deployment = deployment_id; "Deployment isn't registered anymore, not creating an isolate");
+                    break;
+                }
+
+                entry.insert(create_worker())
+            }
+        };
+
+        // The in-flight count has to be incremented while holding the worker's
+        // entry, so a concurrent drain can't miss this request
+        let in_flight_request = Arc::new(InFlightRequest::new(Arc::clone(&worker.in_flight)));
+        let isolate_sender = worker.sender.clone();
+        drop(worker);
+
+        match isolate_sender
+            .send_async(IsolateEvent::Request(request))
+            .await
+        {
+            Ok(()) => return Ok(in_flight_request),
+            Err(flume::SendError(event)) => {
+                increment_counter!("lagon_dead_worker_sends", "deployment" => deployment_id.to_owned());
+                warn!(deployment = deployment_id, attempt = attempt; "Isolate exited before receiving the request");
+
+                workers.remove_if(deployment_id, |_, worker| {
+                    Arc::ptr_eq(&worker.in_flight, &in_flight_request.in_flight)
+                });
+
+                request = match event {
+                    IsolateEvent::Request(request) => request,
+                    _ => unreachable!(),
+                };
+            }
+        }
+    }
+
+    Err(request)
+}
+
+// Wait until the isolate of a worker is created (or abandoned), so
+// requests don't spend their queue timeout waiting for a cold start
+async fn worker_ready(mut ready: watch::Receiver<bool>) {
+    while !*ready.borrow() {
+        if ready.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+// Remove a worker whose isolate couldn't be created, so the next request retries
+// with a new isolate. The requests already sent to it are answered with `result`.
+async fn abandon_worker(
+    deployment: &Deployment,
+    workers: &Workers,
+    in_flight: &Arc<AtomicUsize>,
+    ready: Option<oneshot::Sender<()>>,
+    receiver: flume::Receiver<IsolateEvent>,
+    result: impl Fn() -> RunResult,
+) {
+    workers.remove_if(&deployment.id, |_, worker| {
+        Arc::ptr_eq(&worker.in_flight, in_flight)
+    });
+    decrement_gauge!("lagon_isolates", 1.0, "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+
+    if let Some(ready) = ready {
+        ready.send(()).unwrap_or(());
+    }
+
+    while let Ok(event) = receiver.recv_async().await {
+        if let IsolateEvent::Request(request) = event {
+            request.sender.send_async(result()).await.unwrap_or(());
+        }
+    }
+}
+
+// Spawn the thread running the isolate of a deployment. When set, `ready`
+// is notified once the code of the deployment has been evaluated
+fn create_worker(
+    deployment: Arc<Deployment>,
+    config: Arc<ServerConfig>,
+    workers: Workers,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+    request_id: String,
+    ready: Option<oneshot::Sender<()>>,
+) -> Worker {
+    let handle = Handle::current();
+    let (sender, receiver) = flume::unbounded();
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let in_flight_handle = Arc::clone(&in_flight);
+    let pinned = config.is_pinned(&deployment);
+    let (ready_sender, ready_receiver) = watch::channel(false);
+
+    std::thread::Builder::new().name(deployment.get_isolate_thread_name()).spawn(move || {
+        handle.block_on(async move {
+            increment_gauge!("lagon_isolates", 1.0, "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+            info!(deployment = deployment.id, function = deployment.function_id, request = request_id; "Creating new isolate");
+
+            // Requests sent to the isolate wait with it, so it gives
+            // up once they would have timed out anyway
+            let creation_permit = match &config.isolate_creations {
+                Some(isolate_creations) => {
+                    let wait_start = Instant::now();
+                    let permit = tokio::time::timeout(
+                        Duration::from_millis(deployment.total_timeout as u64),
+                        isolate_creations.acquire(),
+                    )
+                    .await;
+                    histogram!("lagon_isolate_create_wait", wait_start.elapsed(), "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+
+                    match permit {
+                        Ok(Ok(permit)) => Some(permit),
+                        _ => {
+                            warn!(deployment = deployment.id, request = request_id; "Timed out waiting to create the isolate");
+
+                            return abandon_worker(&deployment, &workers, &in_flight_handle, ready, receiver, || {
+                                RunResult::Timeout
+                            })
+                            .await;
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            let code = match deployment.get_code(&config.deployments_dir) {
+                Ok(code) => code,
+                Err(error) => {
+                    increment_counter!("lagon_isolate_code_load_failures", "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+                    error!(deployment = deployment.id, request = request_id; "Error while getting deployment code: {}", error);
+
+                    // Evaluating an empty code would make every request fail with confusing
+                    // errors. Instead, the worker is removed so the next request retries with
+                    // a new isolate, and the requests already sent to this one get a 500
+                    return abandon_worker(&deployment, &workers, &in_flight_handle, ready, receiver, || {
+                        RunResult::Error(String::from("Deployment code unavailable"))
+                    })
+                    .await;
+                }
+            };
+            let options = IsolateOptions::with_code(code)
+                .environment_variables(deployment.get_environment_variables())
+                .memory(deployment.memory)
+                .tick_timeout(Duration::from_millis(deployment.tick_timeout as u64))
+                .total_timeout(Duration::from_millis(
+                    deployment.total_timeout as u64,
+                ))
+                .metadata(Some((
+                    deployment.id.clone(),
+                    deployment.function_id.clone(),
+                )))
+                .on_drop_callback(Box::new(|metadata| {
+                    if let Some(metadata) = metadata.as_ref().as_ref() {
+                        let labels = [
+                            ("deployment", metadata.0.clone()),
+                            ("function", metadata.1.clone()),
+                        ];
+
+                        decrement_gauge!("lagon_isolates", 1.0, &labels);
+                        info!(deployment = metadata.0, function = metadata.1; "Dropping isolate");
+                    }
+                }))
+                .on_statistics_callback(Box::new(|metadata, statistics| {
+                    if let Some(metadata) = metadata.as_ref().as_ref() {
+                        let labels = [
+                            ("deployment", metadata.0.clone()),
+                            ("function", metadata.1.clone()),
+                        ];
+
+                        histogram!(
+                            "lagon_isolate_memory_usage",
+                            statistics as f64,
+                            &labels
+                        );
+                    }
+                }))
+                .on_request_callback(Box::new(|metadata, parts: &Parts| {
+                    if let Some(dispatch_span) = parts.extensions.get::<DispatchSpan>() {
+                        dispatch_span.picked_up();
+                    }
+
+                    if let Some(PickedUp(picked_up)) = parts.extensions.get::<PickedUp>() {
+                        picked_up.notify_one();
+                    }
+
+                    if let (Some(metadata), Some(EnqueuedAt(enqueued_at))) =
+                        (metadata.as_ref().as_ref(), parts.extensions.get::<EnqueuedAt>())
+                    {
+                        let labels = [
+                            ("deployment", metadata.0.clone()),
+                            ("function", metadata.1.clone()),
+                        ];
+
+                        histogram!("lagon_request_queue_wait", enqueued_at.elapsed(), &labels);
+                    }
+                }))
+                .log_sender(log_sender)
+                .snapshot_blob(
+                    config
+                        .snapshots
+                        .get(deployment.runtime_version.as_deref()),
+                );
+
+            let cold_start_time = Instant::now();
+            let mut isolate = Isolate::new(options, receiver);
+            isolate.evaluate();
+            histogram!("lagon_isolate_cold_start", cold_start_time.elapsed(), "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+            drop(creation_permit);
+            ready_sender.send(true).unwrap_or(());
+
+            if let Some(ready) = ready {
+                ready.send(()).unwrap_or(());
+            }
+
+            if pinned {
+                increment_gauge!("lagon_pinned_isolates", 1.0, "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+            }
+
+            isolate.run_event_loop().await;
+
+            if pinned {
+                decrement_gauge!("lagon_pinned_isolates", 1.0, "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+            }
+
+            // When the event loop is completed, that means a) the isolate was terminate due to limits
+            // or b) the isolate was dropped because of cache expiration. In the first case, the isolate
+            // isn't removed from the workers map. We make sure to not remove a newer worker that
+            // replaced this one in the meantime, e.g after a promotion
+            workers.remove_if(&deployment.id, |_, worker| {
+                Arc::ptr_eq(&worker.in_flight, &in_flight_handle)
+            });
+        });
+    }).unwrap();
+
+    Worker {
+        sender,
+        in_flight,
+        pinned,
+        ready: ready_receiver,
+    }
+}
+
+// Create the isolates of the given deployments a few at a time, waiting for
+// their code to be evaluated, so a node with many deployments doesn't
+// saturate its CPU and memory when starting
+async fn warmup_deployments(
+    deployments: Vec<Arc<Deployment>>,
+    config: Arc<ServerConfig>,
+    last_requests: Arc<DashMap<String, Instant>>,
+    workers: Workers,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+) {
+    stream::iter(deployments)
+        .for_each_concurrent(config.max_concurrent_warmups, |deployment| {
+            let config = Arc::clone(&config);
+            let last_requests = Arc::clone(&last_requests);
+            let workers = Arc::clone(&workers);
+            let log_sender = log_sender.clone();
+
+            async move {
+                let jitter = config.warmup_jitter.mul_f64(rand::random());
+                tokio::time::sleep(jitter).await;
+
+                if let Some(max_isolates) = config.max_isolates {
+                    if workers.len() >= max_isolates {
+                        return;
+                    }
+                }
+
+                // Warm isolates are evicted like any other once idle
+                last_requests.insert(deployment.id.clone(), Instant::now());
+
+                let (ready_sender, ready) = oneshot::channel();
+                workers.entry(deployment.id.clone()).or_insert_with(|| {
+                    create_worker(
+                        Arc::clone(&deployment),
+                        Arc::clone(&config),
+                        Arc::clone(&workers),
+                        log_sender,
+                        String::new(),
+                        Some(ready_sender),
+                    )
+                });
+
+                // The sender is dropped if a request already created the isolate
+                ready.await.unwrap_or(());
+            }
+        })
+        .await;
+}
+
+// Put a result back in front of the next results of the receiver
+fn prepend_result(
+    result: RunResult,
+    receiver: flume::Receiver<RunResult>,
+) -> flume::Receiver<RunResult> {
+    let (sender, prepended) = flume::unbounded();
+    sender.send(result).unwrap_or(());
+
+    tokio::spawn(async move {
+        while let Ok(result) = receiver.recv_async().await {
+            if sender.send_async(result).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    prepended
+}
+
+// Send the request to the fallback of the deployment (see
+// `Deployment::fallback_deployment_id`) when its isolate answers with an error
+// or times out, and so on with the fallback's own fallback. Each deployment is
+// only tried once, so fallbacks pointing back to a previous deployment can't
+// loop. Return the deployment that answered with the receiver of its result,
+// and the request in flight in its isolate if it's a fallback.
+#[allow(clippy::too_many_arguments)]
+async fn invoke_fallbacks(
+    deployment: Arc<Deployment>,
+    receiver: flume::Receiver<RunResult>,
+    request: (Parts, Bytes),
+    deployments: &Deployments,
+    last_requests: &Arc<DashMap<String, Instant>>,
+    workers: &Workers,
+    config: &Arc<ServerConfig>,
+    log_sender: &flume::Sender<(String, String, Metadata, Option<String>)>,
+    request_id: &str,
+) -> (
+    Arc<Deployment>,
+    flume::Receiver<RunResult>,
+    Option<Arc<InFlightRequest>>,
+) {
+    let mut deployment = deployment;
+    let mut receiver = receiver;
+    let mut in_flight_request = None;
+    let mut tried = HashSet::from([deployment.id.clone()]);
+
+    loop {
+        // Handled by `handle_response`, like without a fallback
+        let result = match receiver.recv_async().await {
+            Ok(result) => result,
+            Err(_) => return (deployment, receiver, in_flight_request),
+        };
+
+        let fallback = match (&result, &deployment.fallback_deployment_id) {
+            (RunResult::Error(_) | RunResult::Timeout, Some(fallback_deployment_id))
+                if !tried.contains(fallback_deployment_id) =>
+            {
+                get_deployment_by_id(deployments, fallback_deployment_id)
+                    .filter(|fallback| !fallback.provisioning && fallback.cron.is_none())
+            }
+            _ => None,
+        };
+
+        let fallback = match fallback {
+            Some(fallback) => fallback,
+            None => {
+                return (
+                    deployment,
+                    prepend_result(result, receiver),
+                    in_flight_request,
+                )
+            }
+        };
+
+        increment_counter!(
+            "lagon_fallback_invoked",
+            "deployment" => deployment.id.clone(),
+            "function" => deployment.function_id.clone(),
+            "fallback" => fallback.id.clone(),
+        );
+        warn!(deployment = deployment.id, fallback = fallback.id, request = request_id; "Deployment failed, invoking its fallback");
+
+        // The fallback's response hides the error, so it's only visible in the primary's logs
+        let (level, message) = match &result {
+            RunResult::Error(error) => ("error", format!("Function execution error: {}", error)),
+            _ => ("warn", String::from("Function execution timed out")),
+        };
+
+        log_sender
+            .send_async((
+                level.to_owned(),
+                format!("{}, invoking fallback {}", message, fallback.id),
+                Some((deployment.id.clone(), deployment.function_id.clone())),
+                None,
+            ))
+            .await
+            .unwrap_or(());
+
+        // The failure isn't answered to the client, but still counts
+        if let (Some(circuit_breaker), RunResult::Error(_)) = (&config.circuit_breaker, &result) {
+            circuit_breaker.record_failure(&deployment.id, &deployment.function_id);
+        }
+
+        tried.insert(fallback.id.clone());
+        last_requests.insert(fallback.id.clone(), Instant::now());
+
+        let (sender, fallback_receiver) = flume::unbounded();
+        let isolate_request = IsolateRequest {
+            request: shadow::mirror_request(&request),
+            sender,
+            total_timeout: None,
+        };
+
+        match send_request(&fallback.id, workers, deployments, isolate_request, || {
+            create_worker(
+                Arc::clone(&fallback),
+                Arc::clone(config),
+                Arc::clone(workers),
+                log_sender.clone(),
+                request_id.to_owned(),
+                None,
+            )
         })
         .await
-    {
-        error!("Error while writing log: {}", error);
+        {
+            Ok(request) => in_flight_request = Some(request),
+            Err(_) => {
+                error!(deployment = fallback.id, request = request_id; "Could not send request to the fallback's isolate");
+
+                return (
+                    deployment,
+                    prepend_result(result, receiver),
+                    in_flight_request,
+                );
+            }
+        }
+
+        deployment = fallback;
+        receiver = fallback_receiver;
     }
 }
 
+// Send a copy of the request to the deployment's shadow in the background,
+// see `ShadowTraffic`. The shadow is looked up in the background task too,
+// so the primary's request only pays for copying the headers.
+#[allow(clippy::too_many_arguments)]
+fn mirror_to_shadow(
+    deployment: &Arc<Deployment>,
+    shadow_deployment_id: &str,
+    request: &(Parts, Bytes),
+    deployments: &Deployments,
+    last_requests: &Arc<DashMap<String, Instant>>,
+    workers: &Workers,
+    config: &Arc<ServerConfig>,
+    log_sender: &flume::Sender<(String, String, Metadata, Option<String>)>,
+    request_id: &str,
+) {
+    let permit = match config.shadow_traffic.try_acquire(deployment) {
+        Some(permit) => permit,
+        None => return,
+    };
+
+    let primary = Arc::clone(deployment);
+    let shadow_deployment_id = shadow_deployment_id.to_owned();
+    let request = shadow::mirror_request(request);
+    let deployments = Arc::clone(deployments);
+    let last_requests = Arc::clone(last_requests);
+    let workers = Arc::clone(workers);
+    let config = Arc::clone(config);
+    let log_sender = log_sender.clone();
+    let request_id = request_id.to_owned();
+
+    tokio::spawn(async move {
+        let _permit = permit;
+
+        let shadow = match get_deployment_by_id(&deployments, &shadow_deployment_id) {
+            Some(shadow) if !shadow.provisioning => shadow,
+            _ => {
+                warn!(deployment = primary.id, shadow = shadow_deployment_id; "Shadow deployment not found");
+                return shadow::record_dropped(&primary);
+            }
+        };
+
+        // Mirrored requests never evict the isolates of other deployments
+        if let Some(max_isolates) = config.max_isolates {
+            if !workers.contains_key(&shadow.id) && workers.len() >= max_isolates {
+                return shadow::record_dropped(&primary);
+            }
+        }
+
+        last_requests.insert(shadow.id.clone(), Instant::now());
+
+        let (sender, receiver) = flume::unbounded();
+        let isolate_request = IsolateRequest {
+            request,
+            sender,
+            total_timeout: None,
+        };
+
+        let in_flight_request =
+            match send_request(&shadow.id, &workers, &deployments, isolate_request, || {
+                create_worker(
+                    Arc::clone(&shadow),
+                    Arc::clone(&config),
+                    Arc::clone(&workers),
+                    log_sender.clone(),
+                    request_id.clone(),
+                    None,
+                )
+            })
+            .await
+            {
+                Ok(in_flight_request) => in_flight_request,
+                Err(_) => return shadow::record_error(&shadow, "dead_isolate"),
+            };
+
+        shadow::record_result(&shadow, receiver).await;
+        in_flight_request.done();
+    });
+}
+
+// Deliver an event (e.g from a queue) to the deployment's isolate instead of an
+// HTTP request, see `FunctionEvent`. The isolate is created if needed, and kept
+// like the isolates serving requests.
+pub(crate) async fn dispatch_event(
+    deployment: &Arc<Deployment>,
+    event: FunctionEvent,
+    deployments: &Deployments,
+    last_requests: &Arc<DashMap<String, Instant>>,
+    workers: &Workers,
+    config: &Arc<ServerConfig>,
+    log_sender: &flume::Sender<(String, String, Metadata, Option<String>)>,
+) -> RunResult {
+    last_requests.insert(deployment.id.clone(), Instant::now());
+
+    let mut request = Request::new(Bytes::new()).into_parts();
+    request.0.extensions.insert(event);
+
+    let (sender, receiver) = flume::unbounded();
+    let isolate_request = IsolateRequest {
+        request,
+        sender,
+        total_timeout: None,
+    };
+
+    let in_flight_request = match send_request(
+        &deployment.id,
+        workers,
+        deployments,
+        isolate_request,
+        || {
+            create_worker(
+                Arc::clone(deployment),
+                Arc::clone(config),
+                Arc::clone(workers),
+                log_sender.clone(),
+                String::new(),
+                None,
+            )
+        },
+    )
+    .await
+    {
+        Ok(in_flight_request) => in_flight_request,
+        Err(_) => return RunResult::Error(String::from("Could not send event to a new isolate")),
+    };
+
+    let result = receiver
+        .recv_async()
+        .await
+        .unwrap_or_else(|_| RunResult::Error(String::from("Isolate didn't answer the event")));
+    in_flight_request.done();
+
+    result
+}
+
+// Requests rejected before reaching the assets or the function
+// still get a row, written once by `handle_request`
+enum Routed {
+    Served(Response<Body>, Arc<Deployment>),
+    Rejected(Response<Body>, Option<Arc<Deployment>>),
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_request(
     req: Request<Body>,
-    ip: String,
+    remote_ip: IpAddr,
+    server_name: Option<String>,
     deployments: Deployments,
     last_requests: Arc<DashMap<String, Instant>>,
     workers: Workers,
-    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
-    log_sender: flume::Sender<(String, String, Metadata)>,
+    config: Arc<ServerConfig>,
+    inserters: Inserters,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
 ) -> Result<Response<Body>> {
-    let request_id = match req.headers().get(X_LAGON_ID) {
-        Some(x_lagon_id) => x_lagon_id.to_str().unwrap_or("").to_string(),
+    let client_ip = get_client_ip(
+        req.headers(),
+        remote_ip,
+        config.trusted_proxy,
+        &config.trusted_proxies,
+    );
+    let method = req.method().to_string();
+    let path = req
+        .uri()
+        .path()
+        .chars()
+        .take(MAX_REQUEST_PATH_LENGTH)
+        .collect::<String>();
+
+    let routed = route_request(
+        req,
+        client_ip,
+        server_name,
+        deployments,
+        last_requests,
+        workers,
+        Arc::clone(&config),
+        Arc::clone(&inserters),
+        log_sender,
+    )
+    .await?;
+
+    let (mut response, deployment) = match routed {
+        Routed::Served(response, deployment) => (response, Some(deployment)),
+        Routed::Rejected(response, deployment) => {
+            let (function_id, deployment_id) = match &deployment {
+                Some(deployment) => (deployment.function_id.clone(), deployment.id.clone()),
+                None => (String::new(), String::new()),
+            };
+
+            increment_counter!("lagon_requests", "deployment" => deployment_id.clone(), "function" => function_id.clone(), "kind" => "rejected");
+
+            let sample_rate = deployment
+                .as_ref()
+                .and_then(|deployment| deployment.request_sample_rate)
+                .unwrap_or(config.request_sample_rate);
+
+            if let Some(sample_weight) = sample_weight(sample_rate) {
+                inserters.0.write(RequestRow {
+                    function_id,
+                    deployment_id,
+                    region: config.region().to_owned(),
+                    bytes_in: 0,
+                    bytes_out: response.body().size_hint().exact().unwrap_or(0) as u32,
+                    cpu_time_micros: None,
+                    status_code: response.status().as_u16(),
+                    method,
+                    path,
+                    ip: client_ip.to_string(),
+                    timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs() as u32,
+                    peak_memory_bytes: None,
+                    sample_weight,
+                });
+            }
+
+            (response, deployment)
+        }
+    };
+
+    // Every response of a deployment gets its headers, including
+    // errors, limits and the requests rejected before the function
+    if let Some(deployment) = &deployment {
+        inject_response_headers(&mut response, deployment);
+    }
+
+    Ok(response)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn route_request(
+    mut req: Request<Body>,
+    client_ip: IpAddr,
+    server_name: Option<String>,
+    deployments: Deployments,
+    last_requests: Arc<DashMap<String, Instant>>,
+    workers: Workers,
+    config: Arc<ServerConfig>,
+    inserters: Inserters,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+) -> Result<Routed> {
+    let start_time = Instant::now();
+    let ip = client_ip.to_string();
+    let request_id = match req.headers().get(&config.request_id_header) {
+        Some(request_id) => request_id.to_str().unwrap_or("").to_string(),
         None => String::new(),
     };
 
-    let hostname = match req.headers().get(HOST) {
-        Some(hostname) => hostname.to_str()?.to_string(),
-        None => {
-            increment_counter!(
-                "lagon_ignored_requests",
-                "reason" => "No hostname",
-            );
-            warn!(req = as_debug!(req), ip = ip, request = request_id; "No Host header found in request");
+    // Checked first, so oversized headers are never processed further
+    if headers_too_large(req.headers(), &config) {
+        increment_counter!(
+            "lagon_ignored_requests",
+            "reason" => "Headers too large",
+        );
+        warn!(ip = ip, request = request_id; "Request headers too large");
+
+        return Ok(Routed::Rejected(
+            Builder::new()
+                .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+                .body(Body::empty())?,
+            None,
+        ));
+    }
+
+    if let Some(rate_limiter) = &config.rate_limiter {
+        if let Err(retry_after) = rate_limiter.check(client_ip) {
+            increment_counter!("lagon_rate_limited");
+            warn!(ip = ip, request = request_id; "Client rate limited");
+
+            return Ok(Routed::Rejected(
+                Builder::new()
+                    .status(429)
+                    .header(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())
+                    .body(Body::empty())?,
+                None,
+            ));
+        }
+    }
+
+    if let Some(internal_secret) = &config.internal_secret {
+        if is_internal_request(&req) && !verify_request(internal_secret, &req) {
+            increment_counter!(
+                "lagon_ignored_requests",
+                "reason" => "Invalid signature",
+            );
+            warn!(req = as_debug!(req), ip = ip, request = request_id; "Invalid signature for internal request");
+
+            return Ok(Routed::Rejected(
+                Builder::new().status(401).body(Body::empty())?,
+                None,
+            ));
+        }
+    }
+
+    // Only signed internal requests can override their total timeout (e.g to debug
+    // a single request), which is otherwise removed before reaching the function
+    let total_timeout = req
+        .headers_mut()
+        .remove(X_LAGON_TOTAL_TIMEOUT)
+        .filter(|_| config.internal_secret.is_some() && is_internal_request(&req))
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .map(Duration::from_millis);
+
+    // Before routing, so the path prefixes, the assets and
+    // the function all see the same path
+    if let Some(uri) = config.path_normalization.normalize_uri(req.uri()) {
+        *req.uri_mut() = uri;
+    }
+
+    let method = req.method().to_string();
+    let path = req
+        .uri()
+        .path()
+        .chars()
+        .take(MAX_REQUEST_PATH_LENGTH)
+        .collect::<String>();
+
+    let request_span = RequestSpan::start(req.headers(), &method, &path);
+    let resolve_span = request_span.phase("resolve");
+
+    // HTTP/2 requests don't need a Host header since the hostname is sent
+    // with `:authority`, and over TLS it's also known with SNI
+    let hostname = match req.headers().get(HOST) {
+        Some(hostname) => Some(hostname.to_str()?.to_string()),
+        None => req
+            .uri()
+            .authority()
+            .map(|authority| authority.as_str().to_string())
+            .or(server_name),
+    };
+
+    // Deployments are registered under bare domains, but we still try to match
+    // the host with its port first since local setups can use `host:port`
+    let deployment = hostname
+        .as_ref()
+        .and_then(|hostname| {
+            let path = req.uri().path();
+
+            get_deployment(&deployments, &hostname.to_lowercase(), path)
+                .or_else(|| get_deployment(&deployments, &normalize_hostname(hostname), path))
+        })
+        // Requests without a Host header or with an unknown one (e.g an IP
+        // address) are routed to the default deployment, if any
+        .or_else(|| {
+            config
+                .default_deployment
+                .as_ref()
+                .and_then(|deployment_id| get_deployment_by_id(&deployments, deployment_id))
+        });
+
+    let (deployment, hostname) = match (deployment, hostname) {
+        (Some(deployment), hostname) => (deployment, hostname.unwrap_or_default()),
+        (None, Some(hostname)) => {
+            increment_counter!(
+                "lagon_ignored_requests",
+                "reason" => "No deployment",
+                "hostname" => hostname.clone(),
+            );
+            warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "No deployment found for hostname");
+
+            return Ok(Routed::Rejected(
+                Response::builder().status(404).body(PAGE_404.into())?,
+                None,
+            ));
+        }
+        (None, None) => {
+            increment_counter!(
+                "lagon_ignored_requests",
+                "reason" => "No hostname",
+            );
+            warn!(req = as_debug!(req), ip = ip, request = request_id; "No Host header found in request");
+
+            return Ok(Routed::Rejected(
+                Builder::new().status(404).body(PAGE_404.into())?,
+                None,
+            ));
+        }
+    };
+
+    let canary_key = get_cookie(req.headers(), CANARY_COOKIE).unwrap_or(ip.as_str());
+    let deployment = match get_canary(&deployment, canary_key) {
+        Some(canary) => {
+            increment_counter!("lagon_canary_routed", "deployment" => canary.id.clone());
+
+            canary
+        }
+        None => deployment,
+    };
+
+    resolve_span.end();
+    request_span.set_deployment(&deployment);
+
+    // Reject bodies that are too large before reading them when we know
+    // their size, chunked bodies are checked while reading them instead
+    if let Some(max_body_size) = deployment.max_body_size {
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+
+        if content_length.map_or(false, |content_length| content_length > max_body_size) {
+            warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "Request body too large");
+
+            return Ok(Routed::Rejected(
+                body_too_large(&deployment)?,
+                Some(deployment),
+            ));
+        }
+    }
+
+    if deployment.provisioning {
+        increment_counter!(
+            "lagon_provisioning_blocked",
+            "deployment" => deployment.id.clone(),
+            "function" => deployment.function_id.clone(),
+        );
+
+        return Ok(Routed::Rejected(
+            Builder::new()
+                .status(503)
+                .header(RETRY_AFTER, PROVISIONING_RETRY_AFTER)
+                .body(PAGE_503.into())?,
+            Some(deployment),
+        ));
+    }
+
+    // Unlike maintenance, suspension isn't temporary
+    // so clients shouldn't retry
+    if deployment.suspended {
+        increment_counter!(
+            "lagon_suspended_blocked",
+            "deployment" => deployment.id.clone(),
+            "function" => deployment.function_id.clone(),
+        );
+
+        return Ok(Routed::Rejected(
+            Builder::new()
+                .status(403)
+                .body(config.suspended_message.clone().into())?,
+            Some(deployment),
+        ));
+    }
+
+    if deployment.maintenance {
+        increment_counter!(
+            "lagon_maintenance_blocked",
+            "deployment" => deployment.id.clone(),
+            "function" => deployment.function_id.clone(),
+        );
+
+        return Ok(Routed::Rejected(
+            Builder::new()
+                .status(503)
+                .header(RETRY_AFTER, MAINTENANCE_RETRY_AFTER)
+                .body(PAGE_503.into())?,
+            Some(deployment),
+        ));
+    }
+
+    if !is_ip_allowed(
+        &deployment.ip_allow_list,
+        &deployment.ip_deny_list,
+        client_ip,
+    ) {
+        increment_counter!(
+            "lagon_ip_blocked",
+            "deployment" => deployment.id.clone(),
+            "function" => deployment.function_id.clone(),
+        );
+        warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "Client IP not allowed");
+
+        return Ok(Routed::Rejected(
+            Builder::new().status(403).body(PAGE_403.into())?,
+            Some(deployment),
+        ));
+    }
+
+    if !deployment.allowed_methods.is_empty() && !deployment.allowed_methods.contains(req.method())
+    {
+        increment_counter!(
+            "lagon_method_blocked",
+            "deployment" => deployment.id.clone(),
+            "function" => deployment.function_id.clone(),
+        );
+        warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "Method not allowed");
+
+        let allow = deployment
+            .allowed_methods
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        return Ok(Routed::Rejected(
+            Builder::new()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(ALLOW, allow)
+                .body(Body::empty())?,
+            Some(deployment),
+        ));
+    }
+
+    // Requests without a body (e.g `GET`) don't have a content type to check
+    if !req.body().is_end_stream()
+        && !deployment.is_content_type_allowed(
+            req.headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok()),
+        )
+    {
+        increment_counter!(
+            "lagon_unsupported_media_type",
+            "deployment" => deployment.id.clone(),
+            "function" => deployment.function_id.clone(),
+        );
+        warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "Unsupported request body content type");
+
+        return Ok(Routed::Rejected(
+            Builder::new()
+                .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                .body(Body::empty())?,
+            Some(deployment),
+        ));
+    }
+
+    if deployment.cron.is_some() {
+        increment_counter!(
+            "lagon_ignored_requests",
+            "reason" => "Cron",
+            "hostname" => hostname.clone(),
+        );
+        warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "Cron deployment cannot be called directly");
+
+        // Tell API clients when the cron runs instead. Crons are only
+        // loaded in the region they run in, which is this one
+        let response = match req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .map_or(false, accepts_json)
+        {
+            true => Builder::new()
+                .status(403)
+                .header(CONTENT_TYPE, "application/json")
+                .body(
+                    json!({
+                        "error": "cron",
+                        "schedule": deployment.cron,
+                        "timezone": deployment.cron_timezone.as_deref().unwrap_or("UTC"),
+                        "region": config.region(),
+                    })
+                    .to_string(),
+                )?,
+            false => Builder::new().status(403).body(PAGE_403.to_string())?,
+        };
+
+        return Ok(Routed::Rejected(response.map(Body::from), Some(deployment)));
+    }
+
+    let mut req = req;
+
+    for hook in &config.hooks {
+        req = match hook.before(req) {
+            ControlFlow::Continue(req) => req,
+            ControlFlow::Break(response) => {
+                return Ok(Routed::Rejected(response, Some(deployment)))
+            }
+        };
+    }
+
+    let request_id_handle = request_id.clone();
+    let (sender, receiver) = flume::unbounded();
+    let mut bytes_in = 0;
+    let mut in_flight_request = None;
+    let mut prioritized_request = None;
+    let mut dispatch_span = None;
+    let mut fallback_request = None;
+    // What served the request, see `lagon_requests`
+    let mut request_kind = "function";
+
+    let url = req.uri().path();
+
+    // Single-page applications serve their fallback asset (e.g `index.html`)
+    // for every path that doesn't match any other asset. Deployments
+    // with assets disabled send every request to the function.
+    let asset = if deployment.disable_assets {
+        None
+    } else {
+        find_asset(url, &deployment.assets).or_else(|| {
+            if url == FAVICON_URL || find_static_response(url, &deployment).is_some() {
+                return None;
+            }
+
+            deployment
+                .spa_fallback
+                .as_ref()
+                .filter(|fallback| deployment.assets.contains(*fallback))
+        })
+    };
+
+    let options = ResponseOptions {
+        encoding: req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .and_then(ContentEncoding::negotiate),
+        json_errors: (config.json_errors || deployment.json_errors)
+            && req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|value| value.to_str().ok())
+                .map_or(false, accepts_json),
+        request_id: request_id.clone(),
+    };
+
+    // Assets are already served without invoking the isolate
+    let (cached_response, cache_miss) = match deployment.cache_responses && asset.is_none() {
+        true => match config.response_cache.get(&deployment.id, &req) {
+            Lookup::Hit(response) => (Some(response), None),
+            Lookup::Miss(cache_miss) => (None, Some(cache_miss)),
+            Lookup::Bypass => (None, None),
+        },
+        false => (None, None),
+    };
+
+    if let Some(asset) = asset {
+        request_kind = "asset";
+
+        let range = req
+            .headers()
+            .get(RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(RangeRequest::parse);
+
+        let asset_stream = match &config.asset_store {
+            Some(asset_store) => asset_store.fetch(&deployment.id, asset, range).await,
+            None => {
+                FilesystemAssetStore::new(config.deployments_dir.clone())
+                    .fetch(&deployment.id, asset, range)
+                    .await
+            }
+        };
+
+        let run_result = match asset_stream {
+            Ok(AssetStream { size, range, body }) => {
+                let response = Builder::new()
+                    .header(CONTENT_TYPE, get_content_type(asset))
+                    .header(ACCEPT_RANGES, "bytes");
+
+                let response = match range {
+                    Some(range) => response
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header(CONTENT_RANGE, content_range(&range, size))
+                        .header(CONTENT_LENGTH, range.end - range.start),
+                    None => response.header(CONTENT_LENGTH, size),
+                };
+
+                RunResult::Response(response.body(body)?, None)
+            }
+            Err(error) => match error.downcast_ref::<RangeNotSatisfiable>() {
+                Some(RangeNotSatisfiable { size }) => RunResult::Response(
+                    Builder::new()
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(CONTENT_RANGE, unsatisfied_content_range(*size))
+                        .body(Body::empty())?,
+                    None,
+                ),
+                None if error.is::<AssetNotFound>() => RunResult::Response(
+                    Builder::new()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(PAGE_404.into())?,
+                    None,
+                ),
+                None => {
+                    error!(deployment = &deployment.id, asset = asset, request = request_id_handle; "Error while handing asset: {}", error);
+
+                    RunResult::Error("Could not retrieve asset.".into())
+                }
+            },
+        };
+
+        sender.send_async(run_result).await.unwrap_or(());
+    } else if let Some(static_response) = find_static_response(url, &deployment) {
+        // Browsers and crawlers always request a favicon, robots.txt or well-known
+        // paths, which would otherwise create an isolate for nothing
+        request_kind = match url == FAVICON_URL {
+            true => "favicon",
+            false => "static",
+        };
+
+        let response = match static_response {
+            StaticResponse::Body(body) => Builder::new()
+                .header(CONTENT_TYPE, get_content_type(url))
+                .body(Body::from(body.to_owned()))?,
+            StaticResponse::NotFound => Response::builder().status(404).body(Body::empty())?,
+        };
+
+        sender
+            .send_async(RunResult::Response(response, None))
+            .await
+            .unwrap_or(());
+    } else if let Some(response) = cached_response {
+        request_kind = "cache";
+        increment_counter!("lagon_response_cache_hits", "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+
+        sender
+            .send_async(RunResult::Response(response, None))
+            .await
+            .unwrap_or(());
+    } else {
+        if let Some(cpu_budget) = &deployment.cpu_budget {
+            if let Err(retry_after) = config.cpu_budgets.check(&deployment.id, cpu_budget) {
+                increment_counter!("lagon_cpu_budget_exceeded", "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+                warn!(ip = ip, hostname = hostname, request = request_id; "Deployment exceeded its CPU time budget");
+
+                return Ok(Routed::Rejected(
+                    Builder::new()
+                        .status(429)
+                        .header(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())
+                        .body(Body::empty())?,
+                    Some(deployment),
+                ));
+            }
+        }
+
+        if let Some(circuit_breaker) = &config.circuit_breaker {
+            if let Err(retry_after) = circuit_breaker.check(&deployment.id) {
+                increment_counter!("lagon_circuit_rejected_requests", "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+                warn!(ip = ip, hostname = hostname, request = request_id; "Deployment keeps failing, circuit is open");
+
+                return Ok(Routed::Rejected(
+                    Builder::new()
+                        .status(503)
+                        .header(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())
+                        .body(PAGE_503.into())?,
+                    Some(deployment),
+                ));
+            }
+        }
+
+        last_requests.insert(deployment.id.clone(), Instant::now());
+
+        let (mut parts, body) = req.into_parts();
+        let body = match read_body(body, deployment.max_body_size).await? {
+            Some(body) => body,
+            None => {
+                warn!(ip = ip, hostname = hostname, request = request_id; "Request body too large");
+
+                return Ok(Routed::Rejected(
+                    body_too_large(&deployment)?,
+                    Some(deployment),
+                ));
+            }
+        };
+
+        // Counted as received on the wire, before being decompressed
+        bytes_in = body.len() as u32;
+        counter!("lagon_bytes_in", bytes_in as u64, "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+
+        let body = if deployment.decompress_body {
+            let max_size = deployment.max_body_size.unwrap_or(MAX_DECOMPRESSED_SIZE);
+
+            match decompress_body(&mut parts, body, max_size).await {
+                Ok(Some(body)) => body,
+                Ok(None) => {
+                    warn!(ip = ip, hostname = hostname, request = request_id; "Decompressed request body too large");
+
+                    return Ok(Routed::Rejected(
+                        body_too_large(&deployment)?,
+                        Some(deployment),
+                    ));
+                }
+                Err(error) => {
+                    warn!(ip = ip, hostname = hostname, request = request_id; "Could not decompress request body: {}", error);
+
+                    return Ok(Routed::Rejected(
+                        Builder::new()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::empty())?,
+                        Some(deployment),
+                    ));
+                }
+            }
+        } else {
+            body
+        };
 
-            return Ok(Builder::new().status(404).body(PAGE_404.into())?);
+        let mut request = (parts, body);
+
+        // Before the request is modified for the primary's isolate
+        if deployment.fallback_deployment_id.is_some() {
+            fallback_request = Some(shadow::mirror_request(&request));
         }
-    };
 
-    let deployment = match deployments.get(&hostname) {
-        Some(entry) => Arc::clone(entry.value()),
-        None => {
-            increment_counter!(
-                "lagon_ignored_requests",
-                "reason" => "No deployment",
-                "hostname" => hostname.clone(),
+        if let Some(shadow_deployment_id) = &deployment.shadow_deployment_id {
+            mirror_to_shadow(
+                &deployment,
+                shadow_deployment_id,
+                &request,
+                &deployments,
+                &last_requests,
+                &workers,
+                &config,
+                &log_sender,
+                &request_id,
             );
-            warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "No deployment found for hostname");
-
-            return Ok(Response::builder().status(404).body(PAGE_404.into())?);
         }
-    };
 
-    if deployment.cron.is_some() {
-        increment_counter!(
-            "lagon_ignored_requests",
-            "reason" => "Cron",
-            "hostname" => hostname.clone(),
-        );
-        warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "Cron deployment cannot be called directly");
+        // Make room for the new isolate if we reached the maximum
+        if let Some(max_isolates) = config.max_isolates {
+            evict_lru_isolates(max_isolates, &deployment.id, &last_requests, &workers).await;
+        }
 
-        return Ok(Response::builder().status(403).body(PAGE_403.into())?);
-    }
+        // Functions that overran their CPU time quota wait
+        // for the other functions' requests to be dispatched
+        if let Some(fairness) = &config.fairness {
+            if let Some(prioritized) = fairness.wait_turn(&deployment.function_id).await {
+                prioritized_request = Some(Arc::new(InFlightRequest::new(prioritized)));
+            } else {
+                increment_counter!("lagon_fairness_over_quota_requests", "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+            }
+        }
 
-    let request_id_handle = request_id.clone();
-    let (sender, receiver) = flume::unbounded();
-    let mut bytes_in = 0;
+        request.0.extensions.insert(EnqueuedAt(Instant::now()));
 
-    let url = req.uri().path();
+        let picked_up = config.queue_timeout.map(|queue_timeout| {
+            let picked_up = Arc::new(Notify::new());
+            request
+                .0
+                .extensions
+                .insert(PickedUp(Arc::clone(&picked_up)));
 
-    if let Some(asset) = find_asset(url, &deployment.assets) {
-        let root = Path::new(env::current_dir().unwrap().as_path())
-            .join(DEPLOYMENTS_DIR)
-            .join(&deployment.id);
+            (queue_timeout, picked_up)
+        });
 
-        let run_result = match handle_asset(root, asset) {
-            Ok(response) => RunResult::Response(response, None),
-            Err(error) => {
-                error!(deployment = &deployment.id, asset = asset, request = request_id_handle; "Error while handing asset: {}", error);
+        let span = request_span.dispatch();
+        request_span.inject(&mut request.0.headers);
+        request.0.extensions.insert(span.clone());
+        dispatch_span = Some(span);
 
-                RunResult::Error("Could not retrieve asset.".into())
-            }
+        let isolate_request = IsolateRequest {
+            request,
+            sender,
+            total_timeout,
         };
 
-        sender.send_async(run_result).await.unwrap_or(());
-    } else if url == FAVICON_URL {
-        sender
-            .send_async(RunResult::Response(
-                Response::builder().status(404).body(Body::empty())?,
-                None,
-            ))
-            .await
-            .unwrap_or(());
-    } else {
-        last_requests.insert(deployment.id.clone(), Instant::now());
-
-        let (parts, body) = req.into_parts();
-        let body = hyper::body::to_bytes(body).await?;
+        match send_request(
+            &deployment.id,
+            &workers,
+            &deployments,
+            isolate_request,
+            || {
+                create_worker(
+                    Arc::clone(&deployment),
+                    Arc::clone(&config),
+                    Arc::clone(&workers),
+                    log_sender.clone(),
+                    request_id_handle.clone(),
+                    None,
+                )
+            },
+        )
+        .await
+        {
+            Ok(request) => in_flight_request = Some(request),
+            Err(_) => {
+                error!(deployment = deployment.id, request = request_id; "Could not send request to a new isolate");
 
-        bytes_in = body.len() as u32;
-        let request = (parts, body);
+                return Ok(Routed::Rejected(
+                    Builder::new().status(503).body(PAGE_503.into())?,
+                    Some(deployment),
+                ));
+            }
+        }
 
-        let deployment = Arc::clone(&deployment);
-        let isolate_workers = Arc::clone(&workers);
+        // Dropping the receiver abandons the request, which
+        // the isolate skips once it gets to it
+        if let Some((queue_timeout, picked_up)) = picked_up {
+            // The queue timeout starts once the isolate is created
+            let ready = in_flight_request.as_ref().and_then(|request| {
+                workers
+                    .get(&deployment.id)
+                    .filter(|worker| Arc::ptr_eq(&worker.in_flight, &request.in_flight))
+                    .map(|worker| worker.ready.clone())
+            });
 
-        let isolate_sender = workers.entry(deployment.id.clone()).or_insert_with(|| {
-            let handle = Handle::current();
-            let (sender, receiver) = flume::unbounded();
+            if let Some(ready) = ready {
+                worker_ready(ready).await;
+            }
 
-            std::thread::Builder::new().name(String::from("isolate-") + deployment.id.as_str()).spawn(move || {
-                handle.block_on(async move {
-                    increment_gauge!("lagon_isolates", 1.0, "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
-                    info!(deployment = deployment.id, function = deployment.function_id, request = request_id_handle; "Creating new isolate");
+            if tokio::time::timeout(queue_timeout, picked_up.notified())
+                .await
+                .is_err()
+            {
+                increment_counter!("lagon_queue_timeouts", "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+                warn!(deployment = deployment.id, request = request_id; "Request timed out waiting for the isolate");
 
-                    let code = deployment.get_code().unwrap_or_else(|error| {
-                        error!(deployment = deployment.id, request = request_id_handle; "Error while getting deployment code: {}", error);
+                return Ok(Routed::Rejected(
+                    Builder::new().status(503).body(PAGE_503.into())?,
+                    Some(deployment),
+                ));
+            }
+        }
+    }
 
-                        "".into()
-                    });
-                    let options = IsolateOptions::new(code)
-                        .environment_variables(deployment.environment_variables.clone())
-                        .memory(deployment.memory)
-                        .tick_timeout(Duration::from_millis(deployment.tick_timeout as u64))
-                        .total_timeout(Duration::from_millis(
-                            deployment.total_timeout as u64,
-                        ))
-                        .metadata(Some((
-                            deployment.id.clone(),
-                            deployment.function_id.clone(),
-                        )))
-                        .on_drop_callback(Box::new(|metadata| {
-                            if let Some(metadata) = metadata.as_ref().as_ref() {
-                                let labels = [
-                                    ("deployment", metadata.0.clone()),
-                                    ("function", metadata.1.clone()),
-                                ];
-
-                                decrement_gauge!("lagon_isolates", 1.0, &labels);
-                                info!(deployment = metadata.0, function = metadata.1; "Dropping isolate");
-                            }
-                        }))
-                        .on_statistics_callback(Box::new(|metadata, statistics| {
-                            if let Some(metadata) = metadata.as_ref().as_ref() {
-                                let labels = [
-                                    ("deployment", metadata.0.clone()),
-                                    ("function", metadata.1.clone()),
-                                ];
-
-                                histogram!(
-                                    "lagon_isolate_memory_usage",
-                                    statistics as f64,
-                                    &labels
-                                );
-                            }
-                        }))
-                        .log_sender(log_sender)
-                        .snapshot_blob(SNAPSHOT_BLOB);
-
-                    let mut isolate = Isolate::new(options, receiver);
-                    isolate.evaluate();
-                    isolate.run_event_loop().await;
-
-                    // When the event loop is completed, that means a) the isolate was terminate due to limits
-                    // or b) the isolate was dropped because of cache expiration. In the first case, the isolate
-                    // isn't removed from the workers map
-                    isolate_workers.remove(&deployment.id);
-                });
-            }).unwrap();
+    // The response (and its metrics) is the one of the
+    // fallback deployment that served the request, if any
+    let (deployment, receiver) = match fallback_request {
+        Some(request) => {
+            let (deployment, receiver, fallback_in_flight_request) = invoke_fallbacks(
+                deployment,
+                receiver,
+                request,
+                &deployments,
+                &last_requests,
+                &workers,
+                &config,
+                &log_sender,
+                &request_id,
+            )
+            .await;
 
-            sender
-        });
+            // The primary's request is done once it answered
+            if fallback_in_flight_request.is_some() {
+                in_flight_request = fallback_in_flight_request;
+            }
 
-        isolate_sender
-            .send_async(IsolateEvent::Request(IsolateRequest { request, sender }))
-            .await
-            .unwrap_or(());
-    }
+            (deployment, receiver)
+        }
+        None => (deployment, receiver),
+    };
 
-    handle_response(receiver, Arc::clone(&deployment), move |event| {
+    let deployment_handle = Arc::clone(&deployment);
+    let response_config = Arc::clone(&config);
+    let response_workers = Arc::clone(&workers);
+    let response_request_span = request_span.clone();
+    let mut response = handle_response(receiver, Arc::clone(&deployment), options, move |event| {
+        let request_span = response_request_span.clone();
+        let config = Arc::clone(&response_config);
+        let workers = Arc::clone(&response_workers);
         let inserters = Arc::clone(&inserters);
         let request_id = request_id.clone();
         let deployment = Arc::clone(&deployment);
+        let in_flight_request = in_flight_request.clone();
+        let prioritized_request = prioritized_request.clone();
+        let method = method.clone();
+        let path = path.clone();
+        let ip = ip.clone();
 
         async move {
+            if let Some(in_flight_request) = in_flight_request {
+                in_flight_request.done();
+
+                // Don't remove a newer worker that replaced the one
+                // which handled this request
+                if deployment.ephemeral {
+                    if let Some((_, worker)) = workers.remove_if(&deployment.id, |_, worker| {
+                        Arc::ptr_eq(&worker.in_flight, &in_flight_request.in_flight)
+                    }) {
+                        drain_worker(worker, String::from("ephemeral"), None);
+                    }
+                }
+            }
+
+            if let Some(prioritized_request) = prioritized_request {
+                prioritized_request.done();
+            }
+
+            // Errors, limits and 5xx responses of the function
+            let is_error = !matches!(
+                event,
+                ResponseEvent::Bytes(_, _, status_code, _) if status_code < 500
+            );
+            config
+                .request_rates
+                .record(&deployment.id, &deployment.function_id, is_error);
+
+            let kind = match is_error {
+                true => "error",
+                false => request_kind,
+            };
+            increment_counter!("lagon_requests", "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone(), "kind" => kind);
+            debug!(deployment = deployment.id, kind = kind, request = request_id; "Request served");
+
             match event {
-                ResponseEvent::Bytes(bytes, cpu_time_micros) => {
-                    let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs() as u32;
-
-                    inserters
-                        .lock()
-                        .await
-                        .0
-                        .write(&RequestRow {
+                ResponseEvent::Bytes(bytes, cpu_time_micros, status_code, peak_memory) => {
+                    request_span.set_response(status_code, bytes_in, bytes);
+
+                    if let Some(circuit_breaker) = &config.circuit_breaker {
+                        circuit_breaker.record_success(&deployment.id);
+                    }
+
+                    counter!("lagon_bytes_out", bytes as u64, "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+
+                    if let (Some(fairness), Some(cpu_time_micros)) =
+                        (&config.fairness, cpu_time_micros)
+                    {
+                        fairness.record(
+                            &deployment.function_id,
+                            Duration::from_micros(cpu_time_micros as u64),
+                        );
+                    }
+
+                    if let (Some(cpu_budget), Some(cpu_time_micros)) =
+                        (&deployment.cpu_budget, cpu_time_micros)
+                    {
+                        config.cpu_budgets.record(
+                            &deployment.id,
+                            cpu_budget,
+                            Duration::from_micros(cpu_time_micros as u64),
+                        );
+                    }
+                    histogram!("lagon_request_duration", start_time.elapsed(), "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+                    config.latency.record(
+                        &deployment.id,
+                        &deployment.function_id,
+                        start_time.elapsed(),
+                    );
+
+                    // Metrics above are exact, only the rows are sampled
+                    let sample_rate = deployment
+                        .request_sample_rate
+                        .unwrap_or(config.request_sample_rate);
+
+                    if let Some(sample_weight) = sample_weight(sample_rate) {
+                        let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs() as u32;
+
+                        inserters.0.write(RequestRow {
                             function_id: deployment.function_id.clone(),
                             deployment_id: deployment.id.clone(),
-                            region: get_region().clone(),
+                            region: config.region().to_owned(),
                             bytes_in,
                             bytes_out: bytes as u32,
                             cpu_time_micros,
+                            status_code,
+                            method,
+                            path,
+                            ip,
                             timestamp,
-                        })
-                        .await
-                        .unwrap_or(());
+                            peak_memory_bytes: peak_memory.map(|peak_memory| peak_memory as u64),
+                            sample_weight,
+                        });
+                    }
                 }
                 ResponseEvent::StreamDoneNoDataError => {
                     handle_error(
@@ -296,7 +2245,9 @@ async fn handle_request(
                         deployment.function_id.clone(),
                         deployment.id.clone(),
                         &request_id,
+                        config.region().to_owned(),
                         inserters,
+                        config.limit_breach_hook.as_ref(),
                     )
                     .await;
                 }
@@ -306,17 +2257,59 @@ async fn handle_request(
                         deployment.function_id.clone(),
                         deployment.id.clone(),
                         &request_id,
+                        config.region().to_owned(),
                         inserters,
+                        config.limit_breach_hook.as_ref(),
                     )
                     .await;
                 }
                 ResponseEvent::LimitsReached(result) | ResponseEvent::Error(result) => {
+                    // Limits are answered with a 502, and errors with a 500
+                    let status_code = match result {
+                        RunResult::Error(_) => 500,
+                        _ => 502,
+                    };
+                    request_span.set_response(status_code, bytes_in, 0);
+
+                    // Only errors (e.g the code throwing) count as failures,
+                    // limits depend on the request
+                    if let (Some(circuit_breaker), RunResult::Error(_)) =
+                        (&config.circuit_breaker, &result)
+                    {
+                        circuit_breaker.record_failure(&deployment.id, &deployment.function_id);
+                    }
+
+                    histogram!("lagon_request_duration", start_time.elapsed(), "deployment" => deployment.id.clone(), "function" => deployment.function_id.clone());
+                    config.latency.record(
+                        &deployment.id,
+                        &deployment.function_id,
+                        start_time.elapsed(),
+                    );
+
+                    inserters.0.write(RequestRow {
+                        function_id: deployment.function_id.clone(),
+                        deployment_id: deployment.id.clone(),
+                        region: config.region().to_owned(),
+                        bytes_in,
+                        bytes_out: 0,
+                        cpu_time_micros: None,
+                        status_code,
+                        method,
+                        path,
+                        ip,
+                        timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs() as u32,
+                        peak_memory_bytes: None,
+                        sample_weight: 1.0,
+                    });
+
                     handle_error(
                         result,
                         deployment.function_id.clone(),
                         deployment.id.clone(),
                         &request_id,
+                        config.region().to_owned(),
                         inserters,
+                        config.limit_breach_hook.as_ref(),
                     )
                     .await;
                 }
@@ -325,9 +2318,151 @@ async fn handle_request(
             Ok(())
         }
     })
-    .await
+    .await?;
+
+    if let Some(dispatch_span) = dispatch_span {
+        dispatch_span.done();
+    }
+
+    let _response_span = request_span.phase("response");
+
+    // Not the responses of static routes or fallback deployments
+    if let Some(cache_miss) = cache_miss {
+        if request_kind == "function" && cache_miss.deployment_id() == deployment_handle.id {
+            response = config.response_cache.store(cache_miss, response).await?;
+        }
+    }
+
+    if config.preload_links {
+        add_preload_links(&mut response, &deployment_handle);
+    }
+
+    // Functions can answer with their own request id
+    if !request_id_handle.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&request_id_handle) {
+            response
+                .headers_mut()
+                .entry(&config.request_id_header)
+                .or_insert(value);
+        }
+    }
+
+    for hook in config.hooks.iter().rev() {
+        hook.after(&mut response);
+    }
+
+    Ok(Routed::Served(response, deployment_handle))
+}
+
+// Only successful HTML responses (from the function or assets) preload the
+// critical assets, and only those that exist
+fn add_preload_links(response: &mut Response<Body>, deployment: &Deployment) {
+    let is_html = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |content_type| content_type.starts_with("text/html"));
+
+    if !is_html || !response.status().is_success() {
+        return;
+    }
+
+    let links = deployment
+        .preload_assets
+        .iter()
+        .filter(|asset| deployment.assets.contains(asset.trim_start_matches('/')))
+        .map(|asset| preload_link(asset))
+        .collect::<Vec<_>>();
+
+    if links.is_empty() {
+        return;
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&links.join(", ")) {
+        response.headers_mut().append(LINK, value);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn serve<I>(
+    builder: ServerBuilder<I>,
+    connections: Option<Arc<Semaphore>>,
+    deployments: Deployments,
+    last_requests: Arc<DashMap<String, Instant>>,
+    workers: Workers,
+    config: Arc<ServerConfig>,
+    inserters: Inserters,
+    log_sender: flume::Sender<(String, String, Metadata, Option<String>)>,
+) -> Pin<Box<dyn Future<Output = hyper::Result<()>> + Send>>
+where
+    I: Accept + Send + 'static,
+    I::Conn: Connection + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut builder = builder
+        .http1_keepalive(config.http1_keepalive)
+        .http1_only(!config.http2)
+        .http2_keep_alive_interval(config.http2_keep_alive_interval);
+
+    if let Some(header_read_timeout) = config.header_read_timeout {
+        builder = builder.http1_header_read_timeout(header_read_timeout);
+    }
+
+    if let Some(max_concurrent_streams) = config.http2_max_concurrent_streams {
+        builder = builder.http2_max_concurrent_streams(max_concurrent_streams);
+    }
+
+    if let Some(window_size) = config.http2_initial_stream_window_size {
+        builder = builder.http2_initial_stream_window_size(window_size);
+    }
+
+    if let Some(window_size) = config.http2_initial_connection_window_size {
+        builder = builder.http2_initial_connection_window_size(window_size);
+    }
+
+    Box::pin(builder.serve(make_service_fn(move |conn: &I::Conn| {
+        let connection = match &connections {
+            Some(connections) if !conn.holds_slot() => Arc::clone(connections)
+                .try_acquire_owned()
+                .map(|permit| ActiveConnection::new(Some(permit))),
+            _ => Ok(ActiveConnection::new(None)),
+        };
+
+        if connection.is_err() {
+            increment_counter!("lagon_rejected_connections");
+        }
+
+        let deployments = Arc::clone(&deployments);
+        let last_requests = Arc::clone(&last_requests);
+        let workers = Arc::clone(&workers);
+        let config = Arc::clone(&config);
+        let inserters = Arc::clone(&inserters);
+        let log_sender = log_sender.clone();
+        let addr = conn.remote_addr();
+        let ip = addr.ip();
+        let server_name = conn.server_name().map(String::from);
+        async move {
+            let connection = connection?;
+            Ok::<_, TryAcquireError>(service_fn(move |req| {
+                // The slot is released when the service is dropped with the connection
+                let _connection = &connection;
+                handle_request(
+                    req,
+                    ip,
+                    server_name.clone(),
+                    Arc::clone(&deployments),
+                    Arc::clone(&last_requests),
+                    Arc::clone(&workers),
+                    Arc::clone(&config),
+                    Arc::clone(&inserters),
+                    log_sender.clone(),
+                )
+            }))
+        }
+    })))
 }
 
+// Start the server with the default configuration, listening on `addr`
 pub async fn start<D, P>(
     deployments: Deployments,
     addr: SocketAddr,
@@ -339,25 +2474,113 @@ where
     D: Downloader + Send + Sync + 'static,
     P: PubSubListener + Unpin + 'static,
 {
+    start_with_config(
+        ServerConfig::default().addr(addr),
+        deployments,
+        downloader,
+        pubsub,
+        client,
+    )
+    .await
+}
+
+// Serve the deployments of the deployments directory without a control plane,
+// e.g for local development. See `get_local_deployments` for its layout.
+pub async fn start_local(
+    config: ServerConfig,
+    client: Client,
+) -> Result<impl Future<Output = ()> + Send> {
+    let deployments = get_local_deployments(&config.deployments_dir)?;
+
+    // Nothing is ever published, so nothing is downloaded either
+    start_with_config(
+        config,
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await
+}
+
+pub async fn start_with_config<D, P>(
+    config: ServerConfig,
+    deployments: Deployments,
+    downloader: Arc<D>,
+    pubsub: P,
+    client: Client,
+) -> Result<impl Future<Output = ()> + Send>
+where
+    D: Downloader + Send + Sync + 'static,
+    P: PubSubListener + Unpin + 'static,
+{
+    let mut config = config;
+
+    // Share the reads of hot assets between concurrent requests
+    let asset_store = config
+        .asset_store
+        .take()
+        .unwrap_or_else(|| Box::new(FilesystemAssetStore::new(config.deployments_dir.clone())));
+    config.asset_store = Some(Box::new(CoalescingAssetStore::new(asset_store)));
+
+    if let Some(snapshots_dir) = &config.snapshots_dir {
+        config.snapshots.load_dir(snapshots_dir)?;
+    }
+
+    if config.self_test {
+        if let Err(error) = run_self_test(config.snapshots.get(None)).await {
+            error!(
+                "Self-test failed, the isolate runtime doesn't work: {}",
+                error
+            );
+
+            return Err(error.context("Self-test failed"));
+        }
+
+        info!("Self-test passed");
+    }
+
+    let config = Arc::new(config);
     let last_requests = Arc::new(DashMap::new());
     let workers = Arc::new(DashMap::new());
     let pubsub = Arc::new(TokioMutex::new(pubsub));
 
-    let insertion_interval = Duration::from_secs(1);
-    let inserters = Arc::new(Mutex::new((
-        client
-            .inserter::<RequestRow>("serverless.requests")?
-            .with_period(Some(insertion_interval)),
-        client
-            .inserter::<LogRow>("serverless.logs")?
-            .with_period(Some(insertion_interval)),
-    )));
-
-    let (log_sender, log_receiver) = flume::unbounded::<(String, String, Metadata)>();
+    let addr = config.addr;
+    let insertion_interval = config.insertion_interval;
+    let inserters = create_inserters(
+        &client,
+        Some(insertion_interval),
+        config.clickhouse_max_pending_rows,
+    )?;
+    config.flusher.bind(Arc::clone(&inserters));
+
+    let (log_sender, log_receiver) =
+        flume::unbounded::<(String, String, Metadata, Option<String>)>();
     let cronjob = Arc::new(TokioMutex::new(
-        Cronjob::new(log_sender.clone(), Arc::clone(&inserters)).await,
+        Cronjob::new(
+            log_sender.clone(),
+            Arc::clone(&inserters),
+            Arc::clone(&config),
+        )
+        .await,
     ));
 
+    if let Some(admin_addr) = config.admin_addr {
+        tokio::spawn(start_admin(
+            admin_addr,
+            Arc::clone(&deployments),
+            Arc::clone(&cronjob),
+            Arc::clone(&config),
+            Arc::clone(&workers),
+        )?);
+    }
+
+    let mut deployments_to_warmup = Vec::new();
+
+    if let Some(handoff) = &config.handoff {
+        deployments_to_warmup = import_state(handoff, &deployments);
+    }
+
     let mut cron_deployments = HashSet::new();
 
     for deployment in deployments.iter() {
@@ -377,88 +2600,160 @@ where
             if let Err(error) = cronjob.add(deployment.clone()).await {
                 error!("Failed to register cron: {}", error);
             }
+        } else if deployment.cron.is_none()
+            && (config.is_pinned(deployment) || (config.warmup && deployment.is_production))
+            && !deployments_to_warmup
+                .iter()
+                .any(|warm_deployment| warm_deployment.id == deployment.id)
+        {
+            deployments_to_warmup.push(Arc::clone(deployment));
         }
     }
 
     drop(cron_deployments);
 
+    // Pinned deployments are warmed up first, so they aren't
+    // skipped if we reach the maximum number of isolates
+    deployments_to_warmup.sort_by_key(|deployment| !config.is_pinned(deployment));
+
+    if !deployments_to_warmup.is_empty() {
+        info!("Warming up {} deployment(s)", deployments_to_warmup.len());
+
+        tokio::spawn(warmup_deployments(
+            deployments_to_warmup,
+            Arc::clone(&config),
+            Arc::clone(&last_requests),
+            Arc::clone(&workers),
+            log_sender.clone(),
+        ));
+    }
+
     listen_pub_sub(
+        Arc::clone(&config),
         Arc::clone(&downloader),
         Arc::clone(&deployments),
         Arc::clone(&workers),
         Arc::clone(&cronjob),
         pubsub,
+        Arc::clone(&last_requests),
+        log_sender.clone(),
     );
     run_cache_clear_task(Arc::clone(&last_requests), Arc::clone(&workers));
+    run_queue_depth_task(Arc::clone(&workers));
+
+    let rates_config = Arc::clone(&config);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RATES_INTERVAL).await;
+            rates_config.request_rates.update_gauges();
+        }
+    });
+
+    let cleanup_config = Arc::clone(&config);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(CLEANUP_INTERVAL).await;
+
+            if let Some(rate_limiter) = &cleanup_config.rate_limiter {
+                rate_limiter.cleanup();
+            }
+
+            cleanup_config.cpu_budgets.cleanup();
+        }
+    });
 
     let inserters_handle = Arc::clone(&inserters);
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(insertion_interval).await;
 
-            let mut inserters = inserters_handle.lock().await;
-
-            if let Err(error) = inserters.0.commit().await {
+            if let Err(error) = inserters_handle.0.commit().await {
                 error!("Error while committing requests: {}", error);
             }
 
-            if let Err(error) = inserters.1.commit().await {
+            if let Err(error) = inserters_handle.1.commit().await {
                 error!("Error while committing logs: {}", error);
             }
         }
     });
 
     let inserters_handle = Arc::clone(&inserters);
+    let logs_config = Arc::clone(&config);
+    let region = config.region().to_owned();
     tokio::spawn(async move {
         while let Ok(log) = log_receiver.recv_async().await {
-            let mut inserters = inserters_handle.lock().await;
-            if let Err(error) = inserters
-                .1
-                .write(&LogRow {
-                    function_id: log
-                        .2
-                        .as_ref()
-                        .map_or_else(String::new, |metadata| metadata.1.clone()),
-                    deployment_id: log
-                        .2
-                        .as_ref()
-                        .map_or_else(String::new, |metadata| metadata.0.clone()),
-                    level: log.0,
-                    message: log.1,
-                    region: get_region().clone(),
-                    timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs() as u32,
-                })
-                .await
-            {
-                error!("Error while writing log: {}", error);
+            if let Some(stdout_logs) = &logs_config.stdout_logs {
+                stdout_logs.write(&log, &region);
+            }
+
+            if !logs_config.clickhouse_logs {
+                continue;
             }
+
+            inserters_handle.1.write(LogRow {
+                function_id: log
+                    .2
+                    .as_ref()
+                    .map_or_else(String::new, |metadata| metadata.1.clone()),
+                deployment_id: log
+                    .2
+                    .as_ref()
+                    .map_or_else(String::new, |metadata| metadata.0.clone()),
+                level: log.0,
+                message: log.1,
+                region: region.clone(),
+                timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs() as u32,
+                fields: log.3,
+            });
         }
     });
 
-    let server = Server::bind(&addr).serve(make_service_fn(move |conn: &AddrStream| {
-        let deployments = Arc::clone(&deployments);
-        let last_requests = Arc::clone(&last_requests);
-        let workers = Arc::clone(&workers);
-        let inserters = Arc::clone(&inserters);
-        let log_sender = log_sender.clone();
+    let mut incoming = AddrIncoming::bind(&addr)?;
+    incoming.set_keepalive(config.tcp_keepalive);
 
-        let addr = conn.remote_addr();
-        let ip = addr.ip().to_string();
+    let connections = config
+        .max_connections
+        .map(|max_connections| Arc::new(Semaphore::new(max_connections)));
 
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                handle_request(
-                    req,
-                    ip.clone(),
-                    Arc::clone(&deployments),
-                    Arc::clone(&last_requests),
-                    Arc::clone(&workers),
-                    Arc::clone(&inserters),
-                    log_sender.clone(),
-                )
-            }))
+    let server = match &config.tls {
+        Some(tls) => {
+            let mut server_config = tls.server_config.as_ref().clone();
+
+            if server_config.alpn_protocols.is_empty() {
+                server_config.alpn_protocols = match config.http2 {
+                    true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                    false => vec![b"http/1.1".to_vec()],
+                };
+            }
+
+            let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+            serve(
+                Server::builder(accept::from_stream(tls_incoming(
+                    incoming,
+                    acceptor,
+                    connections.clone(),
+                ))),
+                connections,
+                deployments,
+                last_requests,
+                workers,
+                Arc::clone(&config),
+                inserters,
+                log_sender,
+            )
         }
-    }));
+        None => serve(
+            Server::builder(incoming),
+            connections,
+            deployments,
+            last_requests,
+            workers,
+            Arc::clone(&config),
+            inserters,
+            log_sender,
+        ),
+    };
 
     Ok(async move {
         if let Err(error) = server.await {
@@ -466,3 +2761,106 @@ where
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(sender: flume::Sender<IsolateEvent>) -> Worker {
+        Worker {
+            sender,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            pinned: false,
+            ready: watch::channel(true).1,
+        }
+    }
+
+    fn deployments() -> Deployments {
+        let deployments = Deployments::default();
+        deployments.insert(
+            String::from("127.0.0.1:4000"),
+            Arc::new(Deployment {
+                id: String::from("deployment"),
+                ..Deployment::default()
+            }),
+        );
+
+        deployments
+    }
+
+    fn isolate_request() -> IsolateRequest {
+        let (sender, _) = flume::unbounded();
+
+        IsolateRequest {
+            request: (Request::new(()).into_parts().0, Bytes::new()),
+            sender,
+            total_timeout: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_dead_worker() {
+        let workers: Workers = Arc::new(DashMap::new());
+
+        // The isolate's thread exited, but its worker wasn't removed yet
+        let (dead_sender, _) = flume::unbounded();
+        workers.insert(String::from("deployment"), worker(dead_sender));
+
+        let (sender, receiver) = flume::unbounded();
+        let in_flight_request = send_request(
+            "deployment",
+            &workers,
+            &deployments(),
+            isolate_request(),
+            || worker(sender.clone()),
+        )
+        .await
+        .ok()
+        .unwrap();
+
+        assert!(matches!(receiver.try_recv(), Ok(IsolateEvent::Request(_))));
+        assert_eq!(in_flight_request.in_flight.load(Ordering::SeqCst), 1);
+        assert!(Arc::ptr_eq(
+            &workers.get("deployment").unwrap().in_flight,
+            &in_flight_request.in_flight
+        ));
+    }
+
+    #[tokio::test]
+    async fn retry_dead_worker_once() {
+        let workers: Workers = Arc::new(DashMap::new());
+
+        let result = send_request(
+            "deployment",
+            &workers,
+            &deployments(),
+            isolate_request(),
+            || {
+                let (sender, _) = flume::unbounded();
+                worker(sender)
+            },
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(workers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unregistered_deployment() {
+        let workers: Workers = Arc::new(DashMap::new());
+
+        // The deployment was undeployed after the request was routed to it
+        let result = send_request(
+            "deployment",
+            &workers,
+            &Deployments::default(),
+            isolate_request(),
+            || unreachable!(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(workers.is_empty());
+    }
+}
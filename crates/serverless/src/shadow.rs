@@ -0,0 +1,173 @@
+use hyper::{body::Bytes, http::request::Parts, Request};
+use lagon_runtime_http::{RequestStats, RunResult, StreamResult};
+use lagon_runtime_utils::Deployment;
+use metrics::{histogram, increment_counter};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Maximum number of mirrored requests being handled at once by default
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 64;
+
+// Mirror the requests of deployments to their shadow deployment (see
+// `Deployment::shadow_deployment_id`), whose responses are discarded. Mirroring
+// can't slow down the primary deployment: requests are mirrored in the background,
+// and dropped when too many mirrored requests are already being handled.
+pub struct ShadowTraffic {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for ShadowTraffic {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CONCURRENT_REQUESTS)
+    }
+}
+
+impl ShadowTraffic {
+    pub fn new(max_concurrent_requests: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent_requests)),
+        }
+    }
+
+    // Take a slot for a request mirrored by the deployment, if there's one left
+    pub(crate) fn try_acquire(&self, deployment: &Deployment) -> Option<OwnedSemaphorePermit> {
+        let permit = Arc::clone(&self.permits).try_acquire_owned().ok();
+
+        if permit.is_none() {
+            record_dropped(deployment);
+        }
+
+        permit
+    }
+}
+
+// Mirrored requests are counted by the primary deployment
+// when dropped, since the shadow may not even exist
+pub(crate) fn record_dropped(deployment: &Deployment) {
+    increment_counter!(
+        "lagon_shadow_dropped",
+        "deployment" => deployment.id.clone(),
+        "function" => deployment.function_id.clone(),
+    );
+}
+
+// Copy the request sent to the primary deployment, without its extensions
+pub(crate) fn mirror_request(request: &(Parts, Bytes)) -> (Parts, Bytes) {
+    let (parts, body) = request;
+    let mut mirror = Request::new(());
+
+    *mirror.method_mut() = parts.method.clone();
+    *mirror.uri_mut() = parts.uri.clone();
+    *mirror.version_mut() = parts.version;
+    *mirror.headers_mut() = parts.headers.clone();
+
+    (mirror.into_parts().0, body.clone())
+}
+
+fn record_response(shadow: &Deployment, status: u16, stats: Option<RequestStats>) {
+    increment_counter!(
+        "lagon_shadow_requests",
+        "deployment" => shadow.id.clone(),
+        "function" => shadow.function_id.clone(),
+        "status" => status.to_string(),
+    );
+
+    if let Some(stats) = stats {
+        histogram!("lagon_shadow_cpu_time", stats.cpu_time, "deployment" => shadow.id.clone(), "function" => shadow.function_id.clone());
+    }
+}
+
+pub(crate) fn record_error(shadow: &Deployment, kind: &'static str) {
+    increment_counter!(
+        "lagon_shadow_errors",
+        "deployment" => shadow.id.clone(),
+        "function" => shadow.function_id.clone(),
+        "kind" => kind,
+    );
+}
+
+// Read the result of a mirrored request until it's complete,
+// recording its status, CPU time or error
+pub(crate) async fn record_result(shadow: &Deployment, receiver: flume::Receiver<RunResult>) {
+    let mut status = None;
+
+    while let Ok(result) = receiver.recv_async().await {
+        match result {
+            RunResult::Response(response, stats) => {
+                return record_response(shadow, response.status().as_u16(), stats);
+            }
+            RunResult::Stream(StreamResult::Start(builder)) => {
+                status = builder
+                    .body(())
+                    .ok()
+                    .map(|response| response.status().as_u16());
+            }
+            RunResult::Stream(StreamResult::Data(_)) => {}
+            RunResult::Stream(StreamResult::Done(stats)) => {
+                return record_response(shadow, status.unwrap_or(200), Some(stats));
+            }
+            RunResult::Timeout => return record_error(shadow, "timeout"),
+            RunResult::MemoryLimit => return record_error(shadow, "memory_limit"),
+            RunResult::Error(_) => return record_error(shadow, "error"),
+        }
+    }
+
+    record_error(shadow, "dropped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, Response};
+
+    #[test]
+    fn mirror() {
+        let mut request = Request::post("https://example.com/path")
+            .header("x-custom", "value")
+            .body(())
+            .unwrap();
+        request.extensions_mut().insert(1u8);
+
+        let request = (request.into_parts().0, Bytes::from("body"));
+        let (parts, body) = mirror_request(&request);
+
+        assert_eq!(parts.method, Method::POST);
+        assert_eq!(parts.uri, "https://example.com/path");
+        assert_eq!(parts.headers["x-custom"], "value");
+        assert!(parts.extensions.get::<u8>().is_none());
+        assert_eq!(body, "body");
+    }
+
+    #[test]
+    fn limit_concurrent_requests() {
+        let shadow_traffic = ShadowTraffic::new(1);
+        let shadow = Deployment::default();
+
+        let permit = shadow_traffic.try_acquire(&shadow);
+        assert!(permit.is_some());
+        assert!(shadow_traffic.try_acquire(&shadow).is_none());
+
+        drop(permit);
+        assert!(shadow_traffic.try_acquire(&shadow).is_some());
+    }
+
+    #[tokio::test]
+    async fn record_stream_result() {
+        let (sender, receiver) = flume::unbounded();
+
+        sender
+            .send(RunResult::Stream(StreamResult::Start(
+                Response::builder().status(201),
+            )))
+            .unwrap();
+        sender
+            .send(RunResult::Stream(StreamResult::Done(
+                RequestStats::default(),
+            )))
+            .unwrap();
+        drop(sender);
+
+        // Returns once the stream is done
+        record_result(&Deployment::default(), receiver).await;
+    }
+}
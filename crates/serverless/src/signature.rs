@@ -0,0 +1,127 @@
+use hmac::{Hmac, Mac};
+use hyper::Request;
+use lagon_runtime_http::{X_LAGON_ID, X_LAGON_REGION, X_LAGON_SIGNATURE, X_LAGON_TOTAL_TIMEOUT};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Headers that should only be set by other Lagon nodes or the control plane
+const INTERNAL_HEADERS: [&str; 2] = [X_LAGON_ID, X_LAGON_REGION];
+
+pub fn is_internal_request<B>(req: &Request<B>) -> bool {
+    INTERNAL_HEADERS
+        .iter()
+        .any(|header| req.headers().contains_key(*header))
+}
+
+// The signature covers the method, the path with its query and
+// the internal headers, each separated by a new line. Overrides
+// are only appended when set, to keep the previous signatures valid
+fn get_mac<B>(secret: &str, req: &Request<B>) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+
+    mac.update(req.method().as_str().as_bytes());
+    mac.update(b"\n");
+    mac.update(
+        req.uri()
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str())
+            .as_bytes(),
+    );
+
+    for header in INTERNAL_HEADERS {
+        mac.update(b"\n");
+
+        if let Some(value) = req.headers().get(header) {
+            mac.update(value.as_bytes());
+        }
+    }
+
+    if let Some(value) = req.headers().get(X_LAGON_TOTAL_TIMEOUT) {
+        mac.update(b"\n");
+        mac.update(value.as_bytes());
+    }
+
+    mac
+}
+
+pub fn sign_request<B>(secret: &str, req: &Request<B>) -> String {
+    hex::encode(get_mac(secret, req).finalize().into_bytes())
+}
+
+pub fn verify_request<B>(secret: &str, req: &Request<B>) -> bool {
+    let signature = match req
+        .headers()
+        .get(X_LAGON_SIGNATURE)
+        .and_then(|signature| hex::decode(signature.as_bytes()).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    get_mac(secret, req).verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal_request() -> Request<()> {
+        Request::builder()
+            .uri("http://hello.lagon.app/path?query=1")
+            .header(X_LAGON_ID, "123")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn internal_requests() {
+        assert!(is_internal_request(&internal_request()));
+        assert!(!is_internal_request(&Request::builder().body(()).unwrap()));
+    }
+
+    #[test]
+    fn verify_signed_request() {
+        let mut req = internal_request();
+        let signature = sign_request("secret", &req);
+        req.headers_mut()
+            .insert(X_LAGON_SIGNATURE, signature.parse().unwrap());
+
+        assert!(verify_request("secret", &req));
+        assert!(!verify_request("another secret", &req));
+    }
+
+    #[test]
+    fn verify_unsigned_request() {
+        let mut req = internal_request();
+        assert!(!verify_request("secret", &req));
+
+        req.headers_mut()
+            .insert(X_LAGON_SIGNATURE, "not hex".parse().unwrap());
+        assert!(!verify_request("secret", &req));
+    }
+
+    #[test]
+    fn verify_tampered_request() {
+        let mut req = internal_request();
+        let signature = sign_request("secret", &req);
+        req.headers_mut()
+            .insert(X_LAGON_SIGNATURE, signature.parse().unwrap());
+        req.headers_mut().insert(X_LAGON_ID, "456".parse().unwrap());
+
+        assert!(!verify_request("secret", &req));
+    }
+
+    #[test]
+    fn verify_tampered_timeout() {
+        let mut req = internal_request();
+        let signature = sign_request("secret", &req);
+        req.headers_mut()
+            .insert(X_LAGON_SIGNATURE, signature.parse().unwrap());
+        req.headers_mut()
+            .insert(X_LAGON_TOTAL_TIMEOUT, "60000".parse().unwrap());
+
+        assert!(!verify_request("secret", &req));
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::Result;
+use log::info;
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::SNAPSHOT_BLOB;
+
+// Snapshots of the runtime keyed by the version they were created with, so
+// deployments pinned to an older runtime version keep the matching snapshot
+pub struct SnapshotRegistry {
+    default: &'static [u8],
+    versions: HashMap<String, &'static [u8]>,
+}
+
+impl Default for SnapshotRegistry {
+    fn default() -> Self {
+        Self::new(SNAPSHOT_BLOB)
+    }
+}
+
+impl SnapshotRegistry {
+    pub fn new(default: &'static [u8]) -> Self {
+        Self {
+            default,
+            versions: HashMap::new(),
+        }
+    }
+
+    pub fn version(mut self, version: String, snapshot_blob: &'static [u8]) -> Self {
+        self.versions.insert(version, snapshot_blob);
+        self
+    }
+
+    // Register every `<version>.bin` file of the directory. Snapshots are
+    // used by isolates until the process exits, so they are never freed.
+    pub fn load_dir(&mut self, dir: &Path) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "bin")
+            {
+                continue;
+            }
+
+            if let Some(version) = path.file_stem().and_then(|stem| stem.to_str()) {
+                let snapshot_blob = fs::read(&path)?;
+
+                info!("Loaded snapshot for runtime version {}", version);
+                self.versions.insert(
+                    version.to_string(),
+                    Box::leak(snapshot_blob.into_boxed_slice()),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    // Deployments without a version, or with an unknown
+    // one, use the default snapshot
+    pub fn get(&self, version: Option<&str>) -> &'static [u8] {
+        version
+            .and_then(|version| self.versions.get(version))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_version() {
+        let registry = SnapshotRegistry::new(b"default").version(String::from("1.0.0"), b"1.0.0");
+
+        assert_eq!(registry.get(None), b"default");
+        assert_eq!(registry.get(Some("1.0.0")), b"1.0.0");
+        assert_eq!(registry.get(Some("2.0.0")), b"default");
+    }
+
+    #[test]
+    fn load_dir() {
+        let dir = std::env::temp_dir().join("lagon-snapshots-test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1.0.0.bin"), b"1.0.0").unwrap();
+        fs::write(dir.join("README.md"), b"ignored").unwrap();
+
+        let mut registry = SnapshotRegistry::new(b"default");
+        registry.load_dir(&dir).unwrap();
+
+        assert_eq!(registry.get(Some("1.0.0")), b"1.0.0");
+        assert_eq!(registry.get(Some("README")), b"default");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Error};
+use chrono::{SecondsFormat, Utc};
+use lagon_runtime_isolate::options::Metadata;
+use metrics::increment_counter;
+use serde_json::{json, Value};
+use std::{io::Write, str::FromStr};
+
+// Maximum number of lines waiting to be written, further
+// lines are dropped until the writer catches up
+const MAX_PENDING_LINES: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    // `<timestamp> <level> [deployment=.. function=.. region=..] <message> <fields>`
+    Plain,
+    // One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_ascii_lowercase().as_str() {
+            "plain" => Ok(Self::Plain),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("Unknown log format {}", format)),
+        }
+    }
+}
+
+// Write the logs of functions to stdout (e.g for container log collectors), in
+// addition to or instead of ClickHouse. Lines are written by a dedicated thread,
+// so a slow reader never blocks the task receiving the logs.
+pub struct StdoutLogs {
+    format: LogFormat,
+    sender: flume::Sender<String>,
+}
+
+impl StdoutLogs {
+    pub fn new(format: LogFormat) -> Self {
+        Self::with_writer(format, std::io::stdout())
+    }
+
+    pub fn with_writer(format: LogFormat, mut writer: impl Write + Send + 'static) -> Self {
+        let (sender, receiver) = flume::bounded::<String>(MAX_PENDING_LINES);
+
+        std::thread::Builder::new()
+            .name(String::from("stdout-logs"))
+            .spawn(move || {
+                while let Ok(line) = receiver.recv() {
+                    if writer
+                        .write_all(line.as_bytes())
+                        .and_then(|_| writer.flush())
+                        .is_err()
+                    {
+                        increment_counter!("lagon_stdout_logs_dropped");
+                    }
+                }
+            })
+            .unwrap();
+
+        Self { format, sender }
+    }
+
+    pub fn write(
+        &self,
+        (level, message, metadata, fields): &(String, String, Metadata, Option<String>),
+        region: &str,
+    ) {
+        let (deployment_id, function_id) = metadata
+            .as_ref()
+            .map_or(("", ""), |(deployment_id, function_id)| {
+                (deployment_id.as_str(), function_id.as_str())
+            });
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+
+        let line = match self.format {
+            LogFormat::Plain => {
+                let mut line = format!(
+                    "{} {} [deployment={} function={} region={}] {}",
+                    timestamp, level, deployment_id, function_id, region, message
+                );
+
+                if let Some(fields) = fields {
+                    line.push(' ');
+                    line.push_str(fields);
+                }
+
+                line
+            }
+            LogFormat::Json => {
+                let mut line = json!({
+                    "timestamp": timestamp,
+                    "level": level,
+                    "message": message,
+                    "deploymentId": deployment_id,
+                    "functionId": function_id,
+                    "region": region,
+                });
+
+                // Fields are already serialized as a JSON object
+                if let Some(fields) = fields {
+                    line["fields"] = serde_json::from_str(fields)
+                        .unwrap_or_else(|_| Value::String(fields.clone()));
+                }
+
+                line.to_string()
+            }
+        };
+
+        if self.sender.try_send(line + "\n").is_err() {
+            increment_counter!("lagon_stdout_logs_dropped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_line() {
+        let writer = SharedWriter::default();
+        let logs = StdoutLogs::with_writer(LogFormat::Json, writer.clone());
+
+        logs.write(
+            &(
+                String::from("info"),
+                String::from("Signed in"),
+                Some((String::from("deployment"), String::from("function"))),
+                Some(String::from(r#"{"userId":1}"#)),
+            ),
+            "local",
+        );
+
+        std::thread::sleep(Duration::from_millis(50));
+
+        let output = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+        let mut line = serde_json::from_str::<Value>(output.strip_suffix('\n').unwrap()).unwrap();
+        assert!(line["timestamp"].is_string());
+
+        line.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            line,
+            json!({
+                "level": "info",
+                "message": "Signed in",
+                "deploymentId": "deployment",
+                "functionId": "function",
+                "region": "local",
+                "fields": { "userId": 1 },
+            })
+        );
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("JSON".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("plain".parse::<LogFormat>().unwrap(), LogFormat::Plain);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -0,0 +1,325 @@
+use anyhow::{anyhow, Result};
+use futures::{future::poll_fn, stream, Stream};
+use hyper::server::{
+    accept::Accept,
+    conn::{AddrIncoming, AddrStream},
+};
+use log::{info, warn};
+use metrics::increment_counter;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, PrivateKey,
+};
+use rustls_pemfile::Item;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, IoSlice},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+// Connections that don't complete the handshake in time are closed
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Name of the certificate used when no other one matches
+// the SNI hostname, see `SniCertificates::load_dir`
+const DEFAULT_CERTIFICATE: &str = "default";
+
+// Connections accepted by the server, either plain or over TLS
+pub trait Connection {
+    fn remote_addr(&self) -> SocketAddr;
+
+    // Hostname the client asked for with SNI, if any
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+
+    // Whether the connection already holds its slot, when
+    // the number of connections is limited
+    fn holds_slot(&self) -> bool {
+        false
+    }
+}
+
+impl Connection for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
+}
+
+// TLS connection, holding the slot it took before its handshake
+pub struct TlsConnection {
+    stream: TlsStream<AddrStream>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Connection for TlsConnection {
+    fn remote_addr(&self) -> SocketAddr {
+        self.stream.get_ref().0.remote_addr()
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.stream.get_ref().1.server_name()
+    }
+
+    fn holds_slot(&self) -> bool {
+        self.permit.is_some()
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+pub struct TlsConfig {
+    pub(crate) server_config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsConfig {
+    pub fn new(server_config: Arc<rustls::ServerConfig>) -> Self {
+        Self { server_config }
+    }
+
+    // Select the certificate with the SNI hostname of each connection
+    pub fn with_certificates(certificates: SniCertificates) -> Self {
+        Self::new(Arc::new(
+            rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(certificates)),
+        ))
+    }
+}
+
+// Certificates keyed by the hostname they're served for. Wildcard
+// hostnames (e.g `*.example.com`) match a single leftmost label.
+#[derive(Default)]
+pub struct SniCertificates {
+    certificates: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniCertificates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(mut self, hostname: String, cert_path: &Path, key_path: &Path) -> Result<Self> {
+        self.certificates.insert(
+            hostname.to_lowercase(),
+            Arc::new(load_certified_key(cert_path, key_path)?),
+        );
+
+        Ok(self)
+    }
+
+    pub fn default_certificate(mut self, cert_path: &Path, key_path: &Path) -> Result<Self> {
+        self.default = Some(Arc::new(load_certified_key(cert_path, key_path)?));
+
+        Ok(self)
+    }
+
+    // Register every `<hostname>.crt` file of the directory with its `<hostname>.key`
+    // private key. `default.crt` is used when no other certificate matches.
+    pub fn load_dir(mut self, dir: &Path) -> Result<Self> {
+        for entry in fs::read_dir(dir)? {
+            let cert_path = entry?.path();
+
+            if cert_path
+                .extension()
+                .map_or(true, |extension| extension != "crt")
+            {
+                continue;
+            }
+
+            if let Some(hostname) = cert_path.file_stem().and_then(|stem| stem.to_str()) {
+                let key_path = cert_path.with_extension("key");
+
+                self = match hostname {
+                    DEFAULT_CERTIFICATE => self.default_certificate(&cert_path, &key_path)?,
+                    hostname => self.add(hostname.to_string(), &cert_path, &key_path)?,
+                };
+
+                info!("Loaded TLS certificate for {}", hostname);
+            }
+        }
+
+        Ok(self)
+    }
+
+    fn get(&self, hostname: &str) -> Option<Arc<CertifiedKey>> {
+        let hostname = hostname.to_lowercase();
+
+        self.certificates
+            .get(&hostname)
+            .or_else(|| {
+                let (_, parent) = hostname.split_once('.')?;
+                self.certificates.get(&format!("*.{parent}"))
+            })
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for SniCertificates {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        match client_hello.server_name() {
+            Some(hostname) => self.get(hostname),
+            None => self.default.clone(),
+        }
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    let mut key_reader = BufReader::new(File::open(key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_reader)? {
+            Some(Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key)) => {
+                break PrivateKey(key)
+            }
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key found in {}", key_path.display())),
+        }
+    };
+
+    Ok(CertifiedKey::new(
+        certs,
+        any_supported_type(&key).map_err(|error| anyhow!("{}", error))?,
+    ))
+}
+
+// Accept the TCP connections and perform their TLS handshake concurrently,
+// yielding the connections whose handshake succeeded. Connections take their
+// slot before the handshake, so pending handshakes count towards the limit.
+pub fn tls_incoming(
+    mut incoming: AddrIncoming,
+    acceptor: TlsAcceptor,
+    connections: Option<Arc<Semaphore>>,
+) -> impl Stream<Item = Result<TlsConnection, io::Error>> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                stream = poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)) => stream,
+                // The server stopped, release the listener
+                _ = sender.closed() => break,
+            };
+
+            let stream = match stream {
+                Some(Ok(stream)) => stream,
+                Some(Err(error)) => {
+                    warn!("Failed to accept connection: {}", error);
+                    continue;
+                }
+                None => break,
+            };
+
+            let permit = match &connections {
+                Some(connections) => match Arc::clone(connections).try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        increment_counter!("lagon_rejected_connections");
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => sender
+                        .send(Ok(TlsConnection { stream, permit }))
+                        .unwrap_or(()),
+                    _ => increment_counter!("lagon_tls_handshake_failures"),
+                }
+            });
+        }
+    });
+
+    stream::poll_fn(move |cx| receiver.poll_recv(cx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn select_certificate() {
+        let dir = PathBuf::from("tests/certs");
+        let certificates = SniCertificates::new()
+            .add(
+                String::from("*.b.test"),
+                &dir.join("b.test.crt"),
+                &dir.join("b.test.key"),
+            )
+            .unwrap()
+            .load_dir(&dir)
+            .unwrap();
+
+        let a = certificates.get("A.test").unwrap();
+        let b = certificates.get("b.test").unwrap();
+
+        assert_eq!(a.cert, certificates.certificates["a.test"].cert);
+        assert_eq!(b.cert, certificates.certificates["b.test"].cert);
+        assert_eq!(certificates.get("www.b.test").unwrap().cert, b.cert);
+        assert!(certificates.get("c.test").is_none());
+        assert!(certificates.get("www.a.test").is_none());
+    }
+}
@@ -1,13 +1,17 @@
 use anyhow::Result;
-use dashmap::DashMap;
 use lagon_runtime_utils::Deployment;
-use lagon_serverless::serverless::start;
+use lagon_serverless::{
+    deployments::Deployments,
+    serverless::{start, start_with_config, ServerConfig},
+};
 use lagon_serverless_downloader::FakeDownloader;
 use lagon_serverless_pubsub::FakePubSub;
 use serial_test::serial;
 use std::{
     collections::{HashMap, HashSet},
+    path::Path,
     sync::Arc,
+    time::Duration,
 };
 
 mod utils;
@@ -16,7 +20,7 @@ mod utils;
 #[serial]
 async fn html_assets() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -31,6 +35,7 @@ async fn html_assets() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -71,7 +76,7 @@ async fn html_assets() -> Result<()> {
 #[serial]
 async fn assets_nested() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -86,6 +91,7 @@ async fn assets_nested() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -119,7 +125,7 @@ async fn assets_nested() -> Result<()> {
 #[serial]
 async fn set_content_type() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -138,6 +144,7 @@ async fn set_content_type() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -171,3 +178,369 @@ async fn set_content_type() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn spa_fallback() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["index.html".into(), "hello.html".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            spa_fallback: Some("index.html".into()),
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/hello").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "hello asset!\n");
+
+    let response = reqwest::get("http://127.0.0.1:4000/some/deep/path").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "text/html");
+    assert_eq!(response.text().await?, "index asset!\n");
+
+    let response = reqwest::get("http://127.0.0.1:4000/favicon.ico").await?;
+    assert_eq!(response.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn custom_deployments_dir() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["hello.html".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            deployments_dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("deployments_test"),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Dynamic asset: /");
+
+    let response = reqwest::get("http://127.0.0.1:4000/hello").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "hello asset!\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn favicon_asset() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["favicon.ico".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/favicon.ico").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/x-icon");
+    assert_eq!(response.text().await?, "favicon asset!\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn range_requests() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["index.css".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/index.css").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["accept-ranges"], "bytes");
+    assert_eq!(response.headers()["content-length"], "28");
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000/index.css")
+        .header("range", "bytes=0-3")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 206);
+    assert_eq!(response.headers()["content-range"], "bytes 0-3/28");
+    assert_eq!(response.headers()["content-length"], "4");
+    assert_eq!(response.text().await?, "body");
+
+    let response = client
+        .get("http://127.0.0.1:4000/index.css")
+        .header("range", "bytes=100-200")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 416);
+    assert_eq!(response.headers()["content-range"], "bytes */28");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn response_headers() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["hello.html".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            response_headers: HashMap::from([(
+                "strict-transport-security".into(),
+                "max-age=63072000".into(),
+            )]),
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["strict-transport-security"],
+        "max-age=63072000"
+    );
+    assert_eq!(response.text().await?, "Dynamic asset: /");
+
+    let response = reqwest::get("http://127.0.0.1:4000/hello").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["strict-transport-security"],
+        "max-age=63072000"
+    );
+    assert_eq!(response.text().await?, "hello asset!\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn preload_links() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from([
+                "hello.html".into(),
+                "index.css".into(),
+                "static/app.js".into(),
+            ]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            preload_assets: vec![
+                "index.css".into(),
+                "/static/app.js".into(),
+                "missing.css".into(),
+            ],
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            preload_links: true,
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/hello").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["link"],
+        "</index.css>; rel=preload; as=style, </static/app.js>; rel=preload; as=script"
+    );
+
+    // Only HTML responses preload the assets
+    let response = reqwest::get("http://127.0.0.1:4000/index.css").await?;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("link").is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn count_requests_kind() -> Result<()> {
+    utils::record_counters();
+
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["hello.html".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/hello").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "hello asset!\n");
+
+    let response = reqwest::get("http://127.0.0.1:4000/other").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Dynamic asset: /other");
+
+    let response = reqwest::get("http://127.0.0.1:4000/favicon.ico").await?;
+    assert_eq!(response.status(), 404);
+
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000/hello")
+        .header("host", "unknown.test")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 404);
+
+    // Counted once the response is sent
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(utils::count_requests("asset"), 1);
+    assert_eq!(utils::count_requests("function"), 1);
+    assert_eq!(utils::count_requests("favicon"), 1);
+    assert_eq!(utils::count_requests("rejected"), 1);
+    assert_eq!(utils::count_requests("error"), 0);
+
+    Ok(())
+}
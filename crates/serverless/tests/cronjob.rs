@@ -0,0 +1,180 @@
+use anyhow::Result;
+use lagon_runtime_utils::Deployment;
+use lagon_serverless::{
+    clickhouse::{create_inserters, DEFAULT_MAX_PENDING_ROWS},
+    cronjob::Cronjob,
+    deployments::Deployments,
+    serverless::{start_with_config, ServerConfig},
+};
+use lagon_serverless_downloader::FakeDownloader;
+use lagon_serverless_pubsub::{FakePubSub, PubSubMessage, PubSubMessageKind};
+use serial_test::serial;
+use std::{
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+mod utils;
+
+#[tokio::test]
+#[serial]
+async fn jobs_next_run() -> Result<()> {
+    let client = utils::setup();
+    let (log_sender, _log_receiver) = flume::unbounded();
+    let inserters = create_inserters(&client, None, DEFAULT_MAX_PENDING_ROWS)?;
+    let mut cronjob = Cronjob::new(log_sender, inserters, Arc::new(ServerConfig::default())).await;
+
+    cronjob
+        .add(Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            is_production: true,
+            cron: Some("*/5 * * * *".into()),
+            ..Deployment::default()
+        }))
+        .await?;
+
+    let jobs = cronjob.jobs();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].deployment_id, "simple");
+    assert_eq!(jobs[0].schedule, "*/5 * * * *");
+    assert_eq!(jobs[0].timezone, "UTC");
+    assert_eq!(jobs[0].last_run, None);
+
+    let now = UNIX_EPOCH.elapsed()?.as_secs();
+    let next_run = jobs[0].next_run.unwrap();
+    assert!(next_run > now && next_run <= now + 5 * 60);
+    assert_eq!(next_run % (5 * 60), 0);
+
+    cronjob.remove(&"simple".into()).await?;
+    assert!(cronjob.jobs().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn trigger_once() -> Result<()> {
+    let client = utils::setup();
+    let (log_sender, log_receiver) = flume::unbounded();
+    let inserters = create_inserters(&client, None, DEFAULT_MAX_PENDING_ROWS)?;
+    let mut cronjob = Cronjob::new(log_sender, inserters, Arc::new(ServerConfig::default())).await;
+
+    assert!(!cronjob.trigger("simple"));
+
+    cronjob
+        .add(Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            is_production: true,
+            // Only runs on January 1st
+            cron: Some("0 0 1 1 *".into()),
+            ..Deployment::default()
+        }))
+        .await?;
+
+    assert!(cronjob.trigger("simple"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let logs = log_receiver.drain().collect::<Vec<_>>();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].0, "info");
+    assert_eq!(logs[0].1, "Cron execution successful: Hello world");
+
+    let last_run = cronjob.jobs()[0].last_run.clone().unwrap();
+    assert!(last_run.success);
+
+    cronjob.remove(&"simple".into()).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn trigger_missing_code() -> Result<()> {
+    let client = utils::setup();
+    let (log_sender, log_receiver) = flume::unbounded();
+    let inserters = create_inserters(&client, None, DEFAULT_MAX_PENDING_ROWS)?;
+    let mut cronjob = Cronjob::new(log_sender, inserters, Arc::new(ServerConfig::default())).await;
+
+    cronjob
+        .add(Arc::new(Deployment {
+            id: "missing".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            is_production: true,
+            // Only runs on January 1st
+            cron: Some("0 0 1 1 *".into()),
+            ..Deployment::default()
+        }))
+        .await?;
+
+    assert!(cronjob.trigger("missing"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let logs = log_receiver.drain().collect::<Vec<_>>();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].0, "error");
+    assert!(logs[0].1.starts_with("Cron execution error: "));
+
+    let last_run = cronjob.jobs()[0].last_run.clone().unwrap();
+    assert!(!last_run.success);
+
+    cronjob.remove(&"missing".into()).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn trigger_from_pubsub() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            // Only runs on January 1st
+            cron: Some("0 0 1 1 *".into()),
+            ..Deployment::default()
+        }),
+    );
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start_with_config(
+        ServerConfig {
+            admin_addr: Some("127.0.0.1:4001".parse()?),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::TriggerCron,
+        r#"{"deploymentId": "simple"}"#.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let crons = reqwest::get("http://127.0.0.1:4001/crons")
+        .await?
+        .text()
+        .await?;
+    assert!(crons.contains(r#""deploymentId":"simple""#));
+    assert!(crons.contains(r#""success":true"#));
+
+    Ok(())
+}
@@ -1,13 +1,19 @@
 use anyhow::Result;
-use dashmap::DashMap;
 use lagon_runtime_utils::Deployment;
-use lagon_serverless::serverless::start;
+use lagon_serverless::{
+    deployments::Deployments,
+    serverless::{start, start_local, start_with_config, ServerConfig},
+};
 use lagon_serverless_downloader::FakeDownloader;
 use lagon_serverless_pubsub::FakePubSub;
 use serial_test::serial;
 use std::{
     collections::{HashMap, HashSet},
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
 mod utils;
@@ -16,7 +22,7 @@ mod utils;
 #[serial]
 async fn simple() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -31,6 +37,7 @@ async fn simple() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -54,7 +61,7 @@ async fn simple() -> Result<()> {
 #[serial]
 async fn custom_domains() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     let deployment = Arc::new(Deployment {
         id: "simple".into(),
         function_id: "function_id".into(),
@@ -67,6 +74,7 @@ async fn custom_domains() -> Result<()> {
         total_timeout: 1000,
         is_production: true,
         cron: None,
+        ..Deployment::default()
     });
     deployments.insert("127.0.0.1:4000".into(), Arc::clone(&deployment));
     deployments.insert("custom.domain".into(), Arc::clone(&deployment));
@@ -100,7 +108,7 @@ async fn custom_domains() -> Result<()> {
 #[serial]
 async fn reuse_isolate() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -115,6 +123,7 @@ async fn reuse_isolate() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -142,7 +151,7 @@ async fn reuse_isolate() -> Result<()> {
 #[serial]
 async fn reuse_isolate_across_domains() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     let deployment = Arc::new(Deployment {
         id: "counter".into(),
         function_id: "function_id".into(),
@@ -155,6 +164,7 @@ async fn reuse_isolate_across_domains() -> Result<()> {
         total_timeout: 1000,
         is_production: true,
         cron: None,
+        ..Deployment::default()
     });
     deployments.insert("127.0.0.1:4000".into(), Arc::clone(&deployment));
     deployments.insert("another.domain".into(), deployment);
@@ -183,3 +193,465 @@ async fn reuse_isolate_across_domains() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn wildcard_domains() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    let wildcard_deployment = Arc::new(Deployment {
+        id: "simple".into(),
+        function_id: "function_id".into(),
+        function_name: "function_name".into(),
+        domains: HashSet::from(["*.example.com".into()]),
+        assets: HashSet::new(),
+        environment_variables: HashMap::new(),
+        memory: 128,
+        tick_timeout: 1000,
+        total_timeout: 1000,
+        is_production: true,
+        cron: None,
+        ..Deployment::default()
+    });
+    let exact_deployment = Arc::new(Deployment {
+        id: "counter".into(),
+        function_id: "function_id".into(),
+        function_name: "function_name".into(),
+        domains: HashSet::from(["exact.example.com".into()]),
+        assets: HashSet::new(),
+        environment_variables: HashMap::new(),
+        memory: 128,
+        tick_timeout: 1000,
+        total_timeout: 1000,
+        is_production: true,
+        cron: None,
+        ..Deployment::default()
+    });
+    deployments.insert("*.example.com".into(), wildcard_deployment);
+    deployments.insert("exact.example.com".into(), exact_deployment);
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "a.example.com")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "a.b.example.com")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // Exact matches take precedence over wildcards
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "exact.example.com")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    // The wildcard doesn't match the apex domain
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "example.com")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn normalize_hostname() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    let deployment = Arc::new(Deployment {
+        id: "simple".into(),
+        function_id: "function_id".into(),
+        function_name: "function_name".into(),
+        domains: HashSet::from(["example.com".into(), "[::1]".into()]),
+        assets: HashSet::new(),
+        environment_variables: HashMap::new(),
+        memory: 128,
+        tick_timeout: 1000,
+        total_timeout: 1000,
+        is_production: true,
+        cron: None,
+        ..Deployment::default()
+    });
+    deployments.insert("example.com".into(), Arc::clone(&deployment));
+    deployments.insert("[::1]".into(), deployment);
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    for host in ["example.com:443", "EXAMPLE.com", "[::1]:8080"] {
+        let response = client
+            .get("http://127.0.0.1:4000")
+            .header("host", host)
+            .send()
+            .await?;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await?, "Hello world");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn evict_lru_isolate() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "counter".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "another.domain".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["another.domain".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+
+    let serverless = start_with_config(
+        ServerConfig {
+            max_isolates: Some(1),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "another.domain")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // The counter isolate has been evicted to make room
+    // for the other deployment, so it starts again from 1
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn pinned_isolate_not_evicted() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "counter".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            pinned: true,
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "another.domain".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["another.domain".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+
+    let serverless = start_with_config(
+        ServerConfig {
+            max_isolates: Some(1),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "another.domain")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // The counter isolate is pinned, so it wasn't evicted
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "2");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn default_deployment() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    let deployment = Arc::new(Deployment {
+        id: "simple".into(),
+        function_id: "function_id".into(),
+        function_name: "function_name".into(),
+        domains: HashSet::from(["landing.page".into()]),
+        assets: HashSet::new(),
+        environment_variables: HashMap::new(),
+        memory: 128,
+        tick_timeout: 1000,
+        total_timeout: 1000,
+        is_production: true,
+        ..Deployment::default()
+    });
+    deployments.insert("landing.page".into(), Arc::clone(&deployment));
+    let serverless = start_with_config(
+        ServerConfig {
+            default_deployment: Some("simple".into()),
+            ..ServerConfig::default()
+        },
+        Arc::clone(&deployments),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // Unknown host, here an IP address
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // HTTP/1.0 requests don't require a Host header
+    let response = tokio::task::spawn_blocking(|| {
+        let mut stream = TcpStream::connect("127.0.0.1:4000")?;
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n")?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        Ok::<_, std::io::Error>(response)
+    })
+    .await??;
+    assert!(response.starts_with("HTTP/1.0 200 OK"));
+    assert!(response.ends_with("Hello world"));
+
+    // The default deployment is looked up by id on each request
+    deployments.remove("landing.page");
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+
+    deployments.insert("other.page".into(), deployment);
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn ephemeral_deployment() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "counter".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ephemeral: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    // The worker was removed after the first response,
+    // so a new isolate starts again from 1
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn warmup() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "uptime".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+
+    let serverless = start_with_config(
+        ServerConfig {
+            warmup: true,
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // The isolate was created when starting, before the first request
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert!(response.text().await?.parse::<u64>()? >= 300);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn local_deployments() -> Result<()> {
+    let client = utils::setup();
+    let serverless = start_local(
+        ServerConfig::default().deployments_dir(PathBuf::from("deployments_test/local")),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "counter.test")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "hello.lagon.dev")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
@@ -1,16 +1,26 @@
 use anyhow::Result;
-use dashmap::DashMap;
+use futures::StreamExt;
 use lagon_runtime_utils::{
-    response::{PAGE_403, PAGE_404, PAGE_500, PAGE_502},
-    Deployment,
+    response::{PAGE_403, PAGE_404, PAGE_500, PAGE_502, PAGE_503},
+    Deployment, DEPLOYMENTS_DIR,
+};
+use lagon_serverless::{
+    circuit_breaker::CircuitBreaker,
+    clickhouse::{Flusher, LogRow},
+    deployments::Deployments,
+    hooks::{LimitBreach, LimitBreachHook, LimitBreachKind},
+    serverless::{start, start_with_config, ServerConfig},
+    snapshots::SnapshotRegistry,
 };
-use lagon_serverless::serverless::start;
 use lagon_serverless_downloader::FakeDownloader;
 use lagon_serverless_pubsub::FakePubSub;
 use serial_test::serial;
 use std::{
     collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
 mod utils;
@@ -20,7 +30,7 @@ mod utils;
 async fn return_404_no_deployment_found() -> Result<()> {
     let client = utils::setup();
     let serverless = start(
-        Arc::new(DashMap::new()),
+        Deployments::default(),
         "127.0.0.1:4000".parse().unwrap(),
         Arc::new(FakeDownloader),
         FakePubSub::default(),
@@ -40,7 +50,7 @@ async fn return_404_no_deployment_found() -> Result<()> {
 #[serial]
 async fn return_403_cron_deployment() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -55,6 +65,46 @@ async fn return_403_cron_deployment() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: Some("".into()),
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 403);
+    assert_eq!(response.text().await?, PAGE_403);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_json_cron_deployment() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "id".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: Some("*/5 * * * *".into()),
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -67,6 +117,23 @@ async fn return_403_cron_deployment() -> Result<()> {
     .await?;
     tokio::spawn(serverless);
 
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .header("accept", "application/json")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 403);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&response.text().await?)?,
+        serde_json::json!({
+            "error": "cron",
+            "schedule": "*/5 * * * *",
+            "timezone": "UTC",
+            "region": "local",
+        })
+    );
+
     let response = reqwest::get("http://127.0.0.1:4000").await?;
     assert_eq!(response.status(), 403);
     assert_eq!(response.text().await?, PAGE_403);
@@ -78,7 +145,7 @@ async fn return_403_cron_deployment() -> Result<()> {
 #[serial]
 async fn return_500_unknown_code() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -93,6 +160,7 @@ async fn return_500_unknown_code() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -112,11 +180,63 @@ async fn return_500_unknown_code() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+async fn retry_missing_code() -> Result<()> {
+    let client = utils::setup();
+    let deployments_dir = std::env::temp_dir().join("lagon-missing-code-test");
+    fs::create_dir_all(&deployments_dir)?;
+    fs::remove_file(deployments_dir.join("simple.js")).unwrap_or(());
+
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig::default().deployments_dir(deployments_dir.clone()),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 500);
+    assert_eq!(response.text().await?, PAGE_500);
+
+    // The isolate isn't kept with an empty code
+    fs::copy(
+        PathBuf::from(DEPLOYMENTS_DIR).join("simple.js"),
+        deployments_dir.join("simple.js"),
+    )?;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn return_502_timeout_execution() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -131,6 +251,7 @@ async fn return_502_timeout_execution() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -150,11 +271,64 @@ async fn return_502_timeout_execution() -> Result<()> {
     Ok(())
 }
 
+struct ChannelLimitBreachHook(flume::Sender<LimitBreach>);
+
+impl LimitBreachHook for ChannelLimitBreachHook {
+    fn on_breach(&self, breach: LimitBreach) {
+        self.0.send(breach).unwrap();
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn limit_breach_hook() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "timeout-execution".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            ..Deployment::default()
+        }),
+    );
+    let (sender, receiver) = flume::unbounded();
+    let serverless = start_with_config(
+        ServerConfig::default().limit_breach_hook(ChannelLimitBreachHook(sender)),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 502);
+
+    let breach = tokio::time::timeout(Duration::from_secs(1), receiver.recv_async()).await??;
+    assert_eq!(breach.kind, LimitBreachKind::Timeout);
+    assert_eq!(breach.deployment_id, "timeout-execution");
+    assert_eq!(breach.function_id, "function_id");
+    assert!(!breach.request_id.is_empty());
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn return_502_timeout_init() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -169,6 +343,7 @@ async fn return_502_timeout_init() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -192,7 +367,7 @@ async fn return_502_timeout_init() -> Result<()> {
 #[serial]
 async fn return_500_code_invalid() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -207,6 +382,7 @@ async fn return_500_code_invalid() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -230,7 +406,312 @@ async fn return_500_code_invalid() -> Result<()> {
 #[serial]
 async fn return_500_throw_error() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "throw-error".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 500);
+    assert_eq!(response.text().await?, PAGE_500);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_503_circuit_open() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "throw-error".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig::default().circuit_breaker(CircuitBreaker::new(
+            2,
+            Duration::from_secs(60),
+            Duration::from_secs(30),
+        )),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    for _ in 0..2 {
+        let response = reqwest::get("http://127.0.0.1:4000").await?;
+        assert_eq!(response.status(), 500);
+        assert_eq!(response.text().await?, PAGE_500);
+    }
+
+    // The circuit is open, the deployment isn't invoked anymore
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["retry-after"], "30");
+    assert_eq!(response.text().await?, PAGE_503);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_503_queue_timeout() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "busy".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 5000,
+            is_production: true,
+            cron: None,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig::default().queue_timeout(Duration::from_millis(100)),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // The cold start of the isolate doesn't count in the queue timeout
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+
+    let busy = tokio::spawn(reqwest::get("http://127.0.0.1:4000/busy"));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Waits behind the busy request
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.text().await?, PAGE_503);
+
+    let response = busy.await??;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_json_timeout_execution() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "timeout-execution".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            json_errors: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .header("accept", "application/json")
+        .header("x-lagon-id", "request_id")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 502);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&response.text().await?)?,
+        serde_json::json!({
+            "error": "timeout",
+            "request_id": "request_id",
+        })
+    );
+
+    // Clients that don't accept JSON still get the HTML page
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 502);
+    assert_eq!(response.text().await?, PAGE_502);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn self_test_broken_snapshot() -> Result<()> {
+    let client = utils::setup();
+    let serverless = start_with_config(
+        ServerConfig {
+            self_test: true,
+            ..ServerConfig::default()
+        },
+        Deployments::default(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client.clone(),
+    )
+    .await;
+    assert!(serverless.is_ok());
+    drop(serverless);
+
+    // Without the runtime, isolates can't answer any request
+    let serverless = start_with_config(
+        ServerConfig {
+            self_test: true,
+            ..ServerConfig::default()
+        }
+        .snapshots(SnapshotRegistry::new(b"")),
+        Deployments::default(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await;
+    assert!(serverless.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn limit_isolate_creations() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "timeout-init".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "another.domain".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 500,
+            total_timeout: 500,
+            is_production: true,
+            cron: None,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig::default().max_concurrent_isolate_creations(1),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // Takes the only creation slot until its evaluation times out
+    let timeout_init = tokio::spawn(reqwest::get("http://127.0.0.1:4000"));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "another.domain")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 502);
+    assert_eq!(response.text().await?, PAGE_502);
+
+    assert_eq!(timeout_init.await??.status(), 502);
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "another.domain")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn fallback_deployment() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -245,6 +726,61 @@ async fn return_500_throw_error() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            fallback_deployment_id: Some("simple".into()),
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "fallback.domain".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "loop.domain".into(),
+        Arc::new(Deployment {
+            id: "throw-init".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            fallback_deployment_id: Some("code-invalid".into()),
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "another.domain".into(),
+        Arc::new(Deployment {
+            id: "code-invalid".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            fallback_deployment_id: Some("throw-init".into()),
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -258,8 +794,80 @@ async fn return_500_throw_error() -> Result<()> {
     tokio::spawn(serverless);
 
     let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // Both deployments fail and fall back to each other, but
+    // each one is only tried once
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "loop.domain")
+        .send()
+        .await?;
     assert_eq!(response.status(), 500);
     assert_eq!(response.text().await?, PAGE_500);
 
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn fallback_deployment_logs() -> Result<()> {
+    let (client, logs) = utils::setup_recording_logs();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "throw-error".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            fallback_deployment_id: Some("simple".into()),
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "fallback.domain".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let flusher = Flusher::default();
+    let serverless = start_with_config(
+        ServerConfig::default().flusher(flusher.clone()),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "Hello world");
+
+    flusher.flush().await?;
+
+    // The primary's error is logged even though the fallback answered
+    let logs = logs.collect::<Vec<LogRow>>().await;
+    let log = logs
+        .iter()
+        .find(|log| log.deployment_id == "throw-error")
+        .unwrap();
+    assert_eq!(log.level, "error");
+    assert!(log.message.starts_with("Function execution error: "));
+    assert!(log.message.ends_with(", invoking fallback simple"));
+
+    Ok(())
+}
@@ -1,11 +1,24 @@
-use anyhow::Result;
-use dashmap::DashMap;
-use lagon_runtime_utils::response::{PAGE_403, PAGE_404};
-use lagon_serverless::serverless::start;
-use lagon_serverless_downloader::FakeDownloader;
-use lagon_serverless_pubsub::{FakePubSub, PubSubMessage, PubSubMessageKind};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lagon_runtime_utils::response::{PAGE_403, PAGE_404, PAGE_503};
+use lagon_serverless::{
+    deployments::{
+        events::{DeploymentEvent, DeploymentEventKind},
+        Deployments,
+    },
+    serverless::{start, start_with_config, ServerConfig, DEFAULT_SUSPENDED_MESSAGE},
+};
+use lagon_serverless_downloader::{Downloader, FakeDownloader};
+use lagon_serverless_pubsub::{FakePubSub, PubSubEncoding, PubSubMessage, PubSubMessageKind};
+use serde_json::json;
 use serial_test::serial;
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 mod utils;
 
@@ -16,7 +29,7 @@ async fn deploy_undeploy() -> Result<()> {
     let pubsub = FakePubSub::default();
     let tx = pubsub.get_tx();
     let serverless = start(
-        Arc::new(DashMap::new()),
+        Deployments::default(),
         "127.0.0.1:4000".parse().unwrap(),
         Arc::new(FakeDownloader),
         pubsub,
@@ -89,7 +102,7 @@ async fn assign_correct_domains_prod() -> Result<()> {
     let pubsub = FakePubSub::default();
     let tx = pubsub.get_tx();
     let serverless = start(
-        Arc::new(DashMap::new()),
+        Deployments::default(),
         "127.0.0.1:4000".parse().unwrap(),
         Arc::new(FakeDownloader),
         pubsub,
@@ -143,6 +156,70 @@ async fn assign_correct_domains_prod() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+async fn normalize_domains() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let deployments = Deployments::default();
+    let serverless = start(
+        Arc::clone(&deployments),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000", "My.Domain", " my.domain", "my.domain"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut domains = deployments
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect::<Vec<_>>();
+    domains.sort();
+    assert_eq!(
+        domains,
+        vec![
+            "127.0.0.1:4000",
+            "function_name.lagon.dev",
+            "my.domain",
+            "simple.lagon.dev"
+        ]
+    );
+
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .header("host", "MY.domain")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn assign_correct_domains_dev() -> Result<()> {
@@ -150,7 +227,7 @@ async fn assign_correct_domains_dev() -> Result<()> {
     let pubsub = FakePubSub::default();
     let tx = pubsub.get_tx();
     let serverless = start(
-        Arc::new(DashMap::new()),
+        Deployments::default(),
         "127.0.0.1:4000".parse().unwrap(),
         Arc::new(FakeDownloader),
         pubsub,
@@ -211,7 +288,7 @@ async fn skip_cron_not_same_region() -> Result<()> {
     let pubsub = FakePubSub::default();
     let tx = pubsub.get_tx();
     let serverless = start(
-        Arc::new(DashMap::new()),
+        Deployments::default(),
         "127.0.0.1:4000".parse().unwrap(),
         Arc::new(FakeDownloader),
         pubsub,
@@ -255,7 +332,7 @@ async fn warn_cron_direct_access() -> Result<()> {
     let pubsub = FakePubSub::default();
     let tx = pubsub.get_tx();
     let serverless = start(
-        Arc::new(DashMap::new()),
+        Deployments::default(),
         "127.0.0.1:4000".parse().unwrap(),
         Arc::new(FakeDownloader),
         pubsub,
@@ -291,3 +368,1215 @@ async fn warn_cron_direct_access() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn promote_in_flight_request() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "sleep",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let in_flight_request = tokio::spawn(reqwest::get("http://127.0.0.1:4000"));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Promote,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "previousDeploymentId": "sleep",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    let response = in_flight_request.await??;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Slept");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn undeploy_in_flight_request() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "sleep",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let in_flight_request = tokio::spawn(reqwest::get("http://127.0.0.1:4000"));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Undeploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "sleep",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+
+    let response = in_flight_request.await??;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Slept");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn validate_does_not_deploy() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Validate,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.text().await?, PAGE_404);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn toggle_maintenance() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::SetMaintenance { enabled: true },
+        r#"{ "deploymentId": "simple" }"#.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["retry-after"], "60");
+    assert_eq!(response.text().await?, PAGE_503);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::SetMaintenance { enabled: false },
+        r#"{ "deploymentId": "simple" }"#.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn reload_code() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "counter",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "1");
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "2");
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Reload,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "counter",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The module is evaluated again, resetting its state
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn update_env() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "env",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": { "MESSAGE": "Hello" },
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "Hello");
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::UpdateEnv,
+        r#"{ "deploymentId": "env", "env": { "MESSAGE": "Updated" } }"#.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // A new isolate is created with the new environment variables
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Updated");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn deploy_wrong_code_hash() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": [],
+    "codeHash": "0000000000000000000000000000000000000000000000000000000000000000"
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.text().await?, PAGE_404);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn readiness_check_top_level_await() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start_with_config(
+        ServerConfig {
+            readiness_check: true,
+            ..ServerConfig::default()
+        },
+        Deployments::default(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "reject-init",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The module's promise is rejected after the top-level await
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.text().await?, PAGE_404);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn readiness_check() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start_with_config(
+        ServerConfig {
+            readiness_check: true,
+            ..ServerConfig::default()
+        },
+        Deployments::default(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "throw-init",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The deployment throws when evaluated, so it's never routable
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.text().await?, PAGE_404);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn toggle_suspension() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "counter",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::SetSuspended { enabled: true },
+        r#"{ "deploymentId": "counter" }"#.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 403);
+    assert_eq!(response.text().await?, DEFAULT_SUSPENDED_MESSAGE);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::SetSuspended { enabled: false },
+        r#"{ "deploymentId": "counter" }"#.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The isolate was terminated when suspending the deployment
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn deploy_message_pack() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let payload = rmp_serde::to_vec_named(&json!({
+        "functionId": "function_id",
+        "functionName": "function_name",
+        "deploymentId": "simple",
+        "domains": ["127.0.0.1:4000"],
+        "memory": 128,
+        "tickTimeout": 1000,
+        "totalTimeout": 1000,
+        "cron": null,
+        "cronRegion": "local",
+        "env": {},
+        "isProduction": true,
+        "assets": []
+    }))?;
+    assert_eq!(
+        PubSubEncoding::detect(&payload),
+        PubSubEncoding::MessagePack
+    );
+
+    tx.send_async(PubSubMessage::with_encoding(
+        PubSubMessageKind::Deploy,
+        payload,
+        PubSubEncoding::MessagePack,
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn invalid_payload_skipped() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::with_encoding(
+        PubSubMessageKind::Deploy,
+        vec![0x81, 0xc1],
+        PubSubEncoding::MessagePack,
+    ))
+    .await?;
+
+    let payload = rmp_serde::to_vec_named(&json!({
+        "functionId": "function_id",
+        "functionName": "function_name",
+        "deploymentId": "simple",
+        "domains": ["127.0.0.1:4000"],
+        "memory": 128,
+        "tickTimeout": 1000,
+        "totalTimeout": 1000,
+        "cron": null,
+        "cronRegion": "local",
+        "env": {},
+        "isProduction": true,
+        "assets": []
+    }))?;
+
+    tx.send_async(PubSubMessage::with_encoding(
+        PubSubMessageKind::Deploy,
+        payload,
+        PubSubEncoding::MessagePack,
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn deployment_events() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let (events_tx, events_rx) = flume::unbounded();
+    let serverless = start_with_config(
+        ServerConfig::default().on_deployment_event(move |event| {
+            events_tx.send(event).unwrap();
+        }),
+        Deployments::default(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let payload = r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#;
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        payload.into(),
+    ))
+    .await?;
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Undeploy,
+        payload.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(
+        events_rx.drain().collect::<Vec<_>>(),
+        vec![
+            DeploymentEvent {
+                deployment_id: "simple".into(),
+                kind: DeploymentEventKind::Deployed,
+            },
+            DeploymentEvent {
+                deployment_id: "simple".into(),
+                kind: DeploymentEventKind::Undeployed,
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+// Never completes the download of the `stalled` deployment
+struct StalledDownloader;
+
+#[async_trait]
+impl Downloader for StalledDownloader {
+    async fn download(&self, path: String) -> Result<Vec<u8>> {
+        if path.starts_with("stalled") {
+            futures::future::pending::<()>().await;
+        }
+
+        FakeDownloader.download(path).await
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn download_timeout() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let (events_tx, events_rx) = flume::unbounded();
+    let serverless = start_with_config(
+        ServerConfig {
+            download_timeout: Some(Duration::from_millis(100)),
+            ..ServerConfig::default()
+        }
+        .on_deployment_event(move |event| {
+            events_tx.send(event).unwrap();
+        }),
+        Deployments::default(),
+        Arc::new(StalledDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let payload = |deployment_id: &str| {
+        json!({
+            "functionId": "function_id",
+            "functionName": "function_name",
+            "deploymentId": deployment_id,
+            "domains": ["127.0.0.1:4000"],
+            "memory": 128,
+            "tickTimeout": 1000,
+            "totalTimeout": 1000,
+            "cron": null,
+            "cronRegion": "local",
+            "env": {},
+            "isProduction": true,
+            "assets": []
+        })
+        .to_string()
+    };
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        payload("stalled"),
+    ))
+    .await?;
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        payload("simple"),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    assert_eq!(
+        events_rx.drain().collect::<Vec<_>>(),
+        vec![
+            DeploymentEvent {
+                deployment_id: "simple".into(),
+                kind: DeploymentEventKind::Deployed,
+            },
+            DeploymentEvent {
+                deployment_id: "stalled".into(),
+                kind: DeploymentEventKind::Failed,
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+// Takes some time to download each file
+struct SlowDownloader;
+
+#[async_trait]
+impl Downloader for SlowDownloader {
+    async fn download(&self, path: String) -> Result<Vec<u8>> {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        FakeDownloader.download(path).await
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn deploy_concurrently() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let (events_tx, events_rx) = flume::unbounded();
+    let serverless = start_with_config(
+        ServerConfig::default().on_deployment_event(move |event| {
+            events_tx.send(event).unwrap();
+        }),
+        Deployments::default(),
+        Arc::new(SlowDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let payload = |deployment_id: &str| {
+        json!({
+            "functionId": deployment_id,
+            "functionName": deployment_id,
+            "deploymentId": deployment_id,
+            "domains": [format!("{}.lagon.test", deployment_id)],
+            "memory": 128,
+            "tickTimeout": 1000,
+            "totalTimeout": 1000,
+            "cron": null,
+            "cronRegion": "local",
+            "env": {},
+            "isProduction": true,
+            "assets": []
+        })
+        .to_string()
+    };
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        payload("simple"),
+    ))
+    .await?;
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        payload("json"),
+    ))
+    .await?;
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Undeploy,
+        payload("simple"),
+    ))
+    .await?;
+
+    // Both downloads run at the same time
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let mut events = events_rx.drain().collect::<Vec<_>>();
+    events.sort_by(|a, b| a.deployment_id.cmp(&b.deployment_id));
+
+    // Messages of the same deployment are still handled in order
+    assert_eq!(
+        events,
+        vec![
+            DeploymentEvent {
+                deployment_id: "json".into(),
+                kind: DeploymentEventKind::Deployed,
+            },
+            DeploymentEvent {
+                deployment_id: "simple".into(),
+                kind: DeploymentEventKind::Deployed,
+            },
+            DeploymentEvent {
+                deployment_id: "simple".into(),
+                kind: DeploymentEventKind::Undeployed,
+            },
+        ]
+    );
+
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .header("host", "json.lagon.test")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+// Fails every download once `fail` is set
+struct FlakyDownloader {
+    fail: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl Downloader for FlakyDownloader {
+    async fn download(&self, path: String) -> Result<Vec<u8>> {
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(anyhow!("Bucket unavailable"));
+        }
+
+        FakeDownloader.download(path).await
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn redeploy_download_failure() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let fail = Arc::new(AtomicBool::new(false));
+    let (events_tx, events_rx) = flume::unbounded();
+    let serverless = start_with_config(
+        ServerConfig::default().on_deployment_event(move |event| {
+            events_tx.send(event).unwrap();
+        }),
+        Deployments::default(),
+        Arc::new(FlakyDownloader {
+            fail: Arc::clone(&fail),
+        }),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let payload = r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#;
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        payload.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    fail.store(true, Ordering::SeqCst);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        payload.into(),
+    ))
+    .await?;
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Reload,
+        payload.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The previous code keeps serving
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    assert_eq!(
+        events_rx.drain().collect::<Vec<_>>(),
+        vec![
+            DeploymentEvent {
+                deployment_id: "simple".into(),
+                kind: DeploymentEventKind::Deployed,
+            },
+            DeploymentEvent {
+                deployment_id: "simple".into(),
+                kind: DeploymentEventKind::Failed,
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn provisioning_placeholder() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start_with_config(
+        ServerConfig {
+            provisioning_placeholders: true,
+            ..ServerConfig::default()
+        },
+        Deployments::default(),
+        Arc::new(SlowDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The code is still downloading
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "1");
+    assert_eq!(response.text().await?, PAGE_503);
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn dispatch_event() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Deployments::default(),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "event",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "null");
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::DispatchEvent,
+        json!({
+            "deploymentId": "event",
+            "name": "user.created",
+            "payload": { "id": 1, "tags": ["new"] },
+        })
+        .to_string(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The same isolate served the request and received the event
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(
+        response.text().await?,
+        r#"{"name":"user.created","payload":{"id":1,"tags":["new"]}}"#
+    );
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::SetSuspended { enabled: true },
+        r#"{ "deploymentId": "event" }"#.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::DispatchEvent,
+        json!({
+            "deploymentId": "event",
+            "name": "user.deleted",
+            "payload": { "id": 1 },
+        })
+        .to_string(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::SetSuspended { enabled: false },
+        r#"{ "deploymentId": "event" }"#.into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Events aren't dispatched to suspended deployments
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "null");
+
+    Ok(())
+}
@@ -1,15 +1,30 @@
 use anyhow::Result;
-use dashmap::DashMap;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use futures::StreamExt;
-use hyper::body::Bytes;
-use lagon_runtime_utils::Deployment;
-use lagon_serverless::serverless::start;
+use hyper::{body::Bytes, header::HeaderName, Method};
+use lagon_runtime_utils::{response::PAGE_403, Canary, CpuBudget, Deployment};
+use lagon_serverless::{
+    clickhouse::{Flusher, LogRow, RequestRow},
+    deployments::Deployments,
+    hooks::RequestHook,
+    path::{PathNormalization, TrailingSlash},
+    rate_limit::RateLimiter,
+    serverless::{start, start_with_config, ServerConfig},
+    signature::sign_request,
+    tls::{SniCertificates, TlsConfig},
+};
 use lagon_serverless_downloader::FakeDownloader;
 use lagon_serverless_pubsub::FakePubSub;
+use reqwest::Certificate;
 use serial_test::serial;
 use std::{
     collections::{HashMap, HashSet},
+    io::{Read, Write},
+    net::TcpStream,
+    ops::ControlFlow,
+    path::Path,
     sync::Arc,
+    time::Duration,
 };
 
 mod utils;
@@ -18,7 +33,7 @@ mod utils;
 #[serial]
 async fn returns_correct_http() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -33,6 +48,7 @@ async fn returns_correct_http() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -59,7 +75,7 @@ async fn returns_correct_http() -> Result<()> {
 #[serial]
 async fn returns_correct_path() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -74,6 +90,7 @@ async fn returns_correct_path() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -104,11 +121,70 @@ async fn returns_correct_path() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+async fn normalize_path() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "path-query".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            path_prefix: Some("/api".into()),
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            path_normalization: PathNormalization {
+                merge_slashes: true,
+                resolve_dot_segments: true,
+                decode_unreserved: true,
+                trailing_slash: TrailingSlash::Strip,
+            },
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // Matches the path prefix once normalized
+    let response = reqwest::get("http://127.0.0.1:4000//api//users/?page=1").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.text().await?,
+        "https://127.0.0.1:4000/api/users?page=1"
+    );
+
+    let response = reqwest::get("http://127.0.0.1:4000/api/%7Euser/%41b%20c").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.text().await?,
+        "https://127.0.0.1:4000/api/~user/Ab%20c"
+    );
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn forwards_headers() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -123,6 +199,7 @@ async fn forwards_headers() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -149,7 +226,7 @@ async fn forwards_headers() -> Result<()> {
 #[serial]
 async fn stream_sequentially() -> Result<()> {
     let client = utils::setup();
-    let deployments = Arc::new(DashMap::new());
+    let deployments = Deployments::default();
     deployments.insert(
         "127.0.0.1:4000".into(),
         Arc::new(Deployment {
@@ -164,6 +241,7 @@ async fn stream_sequentially() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            ..Deployment::default()
         }),
     );
     let serverless = start(
@@ -187,3 +265,1806 @@ async fn stream_sequentially() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn verify_internal_requests() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            internal_secret: Some("secret".into()),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-lagon-id", "internal")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 401);
+
+    let signature = sign_request(
+        "secret",
+        &hyper::Request::builder()
+            .uri("/")
+            .header("x-lagon-id", "internal")
+            .body(())?,
+    );
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-lagon-id", "internal")
+        .header("x-lagon-signature", signature)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn override_total_timeout() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "sleep".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 100,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            internal_secret: Some("secret".into()),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-lagon-total-timeout", "2000")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 502);
+
+    let signature = sign_request(
+        "secret",
+        &hyper::Request::builder()
+            .uri("/")
+            .header("x-lagon-id", "internal")
+            .header("x-lagon-total-timeout", "2000")
+            .body(())?,
+    );
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-lagon-id", "internal")
+        .header("x-lagon-total-timeout", "2000")
+        .header("x-lagon-signature", signature)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Slept");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn request_id_header() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            request_id_header: HeaderName::from_static("x-request-id"),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .header("x-request-id", "request_id")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-request-id"], "request_id");
+    assert!(response.headers().get("x-lagon-id").is_none());
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn function_favicon() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            function_favicon: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/favicon.ico").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn disable_assets() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["favicon.ico".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            disable_assets: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/favicon.ico").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn static_responses() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            static_responses: HashMap::from([(
+                "/.well-known/security.txt".into(),
+                "Contact: mailto:security@lagon.app".into(),
+            )]),
+            builtin_static_routes: true,
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "other.test".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/robots.txt").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "text/plain"
+    );
+    assert_eq!(response.text().await?, "User-agent: *\nAllow: /\n");
+
+    let response = reqwest::get("http://127.0.0.1:4000/.well-known/security.txt").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Contact: mailto:security@lagon.app");
+
+    let response = reqwest::get("http://127.0.0.1:4000/.well-known/assetlinks.json").await?;
+    assert_eq!(response.status(), 404);
+
+    // Other paths still invoke the isolate
+    let response = reqwest::get("http://127.0.0.1:4000/hello").await?;
+    assert_eq!(response.text().await?, "Hello world");
+
+    // Without the built-in routes, crawler paths are sent to the function
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000/robots.txt")
+        .header("host", "other.test")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    let response = client
+        .get("http://127.0.0.1:4000/.well-known/assetlinks.json")
+        .header("host", "other.test")
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn ip_allow_list() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ip_allow_list: vec!["127.0.0.0/8".parse()?, "10.0.0.0/8".parse()?],
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn allowed_methods() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            allowed_methods: vec![Method::GET, Method::POST],
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let response = client.get("http://127.0.0.1:4000").send().await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    let response = client.delete("http://127.0.0.1:4000").send().await?;
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "GET, POST");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn response_cache() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "cached".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cache_responses: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let get = |path: &'static str| async move {
+        let response = reqwest::get(format!("http://127.0.0.1:4000{}", path)).await?;
+        assert_eq!(response.status(), 200);
+
+        response.text().await
+    };
+
+    // Miss, then hit
+    assert_eq!(get("/").await?, "1");
+    assert_eq!(get("/").await?, "1");
+
+    // Private responses are never cached
+    assert_eq!(get("/private").await?, "2");
+    assert_eq!(get("/private").await?, "3");
+
+    // The function is invoked again once the response expired
+    assert_eq!(get("/short").await?, "4");
+    assert_eq!(get("/short").await?, "4");
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(get("/short").await?, "5");
+
+    let response = reqwest::Client::new()
+        .post("http://127.0.0.1:4000")
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "6");
+    assert_eq!(get("/").await?, "1");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn allowed_content_types() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            allowed_content_types: vec!["application/json".into()],
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("content-type", "text/plain")
+        .body("Hello")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 415);
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("content-type", "application/json; charset=utf-8")
+        .body(r#"{"hello":"world"}"#)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // Requests without a body aren't checked
+    let response = client.get("http://127.0.0.1:4000").send().await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn ip_deny_list() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ip_deny_list: vec!["127.0.0.1/32".parse()?],
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 403);
+    assert_eq!(response.text().await?, PAGE_403);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn response_headers() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            allowed_methods: vec!["GET".parse()?],
+            response_headers: HashMap::from([("x-frame-options".into(), "DENY".into())]),
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let response = client.get("http://127.0.0.1:4000").send().await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-frame-options"], "DENY");
+
+    // Rejected before reaching the function
+    let response = client.post("http://127.0.0.1:4000").send().await?;
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["x-frame-options"], "DENY");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn forwarded_for_single() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ip_deny_list: vec!["1.1.1.1/32".parse()?],
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            trusted_proxy: true,
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-forwarded-for", "1.1.1.1")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 403);
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-forwarded-for", "8.8.8.8")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn forwarded_for_chained() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ip_deny_list: vec!["1.1.1.1/32".parse()?],
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            trusted_proxy: true,
+            trusted_proxies: vec!["10.0.0.0/8".parse()?],
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-forwarded-for", "8.8.8.8, 1.1.1.1, 10.0.0.2")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 403);
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-forwarded-for", "1.1.1.1, 8.8.8.8, 10.0.0.2")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn rate_limit() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            rate_limiter: Some(RateLimiter::new(0.1, 2.0)),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    for _ in 0..2 {
+        let response = reqwest::get("http://127.0.0.1:4000").await?;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await?, "Hello world");
+    }
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers()["retry-after"], "10");
+
+    Ok(())
+}
+
+struct AuthHook;
+
+impl RequestHook for AuthHook {
+    fn before(
+        &self,
+        req: hyper::Request<hyper::Body>,
+    ) -> ControlFlow<hyper::Response<hyper::Body>, hyper::Request<hyper::Body>> {
+        match req.headers().contains_key("authorization") {
+            true => ControlFlow::Continue(req),
+            false => ControlFlow::Break(
+                hyper::Response::builder()
+                    .status(403)
+                    .body("Forbidden".into())
+                    .unwrap(),
+            ),
+        }
+    }
+
+    fn after(&self, response: &mut hyper::Response<hyper::Body>) {
+        response
+            .headers_mut()
+            .insert("x-hook", "after".parse().unwrap());
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn request_hooks() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            hooks: vec![Box::new(AuthHook)],
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 403);
+    assert!(response.headers().get("x-hook").is_none());
+    assert_eq!(response.text().await?, "Forbidden");
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("authorization", "token")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-hook"], "after");
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn compress_response() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "json".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            compress: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let response = client.get("http://127.0.0.1:4000").send().await?;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("content-encoding").is_none());
+    assert_eq!(response.headers()["vary"], "accept-encoding");
+    let body = response.text().await?;
+    assert!(body.starts_with(r#"[{"id":0,"name":"Item 0"}"#));
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("accept-encoding", "gzip")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["vary"], "accept-encoding");
+
+    let compressed = response.bytes().await?;
+    assert!(compressed.len() < body.len());
+
+    let mut decompressed = String::new();
+    GzDecoder::new(compressed.as_ref()).read_to_string(&mut decompressed)?;
+    assert_eq!(decompressed, body);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn canary_split() -> Result<()> {
+    let client = utils::setup();
+    let canary = Arc::new(Deployment {
+        id: "counter".into(),
+        function_id: "function_id".into(),
+        function_name: "function_name".into(),
+        memory: 128,
+        tick_timeout: 1000,
+        total_timeout: 1000,
+        is_production: true,
+        ..Deployment::default()
+    });
+    let stable = Deployment {
+        id: "simple".into(),
+        function_id: "function_id".into(),
+        function_name: "function_name".into(),
+        memory: 128,
+        tick_timeout: 1000,
+        total_timeout: 1000,
+        is_production: true,
+        canary: Some(Canary {
+            deployment: Arc::clone(&canary),
+            percentage: 0,
+        }),
+        ..Deployment::default()
+    };
+    let deployments = Deployments::default();
+    deployments.insert("127.0.0.1:4000".into(), Arc::new(stable.clone()));
+    let serverless = start(
+        Arc::clone(&deployments),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    for _ in 0..3 {
+        let response = reqwest::get("http://127.0.0.1:4000").await?;
+        assert_eq!(response.text().await?, "Hello world");
+    }
+
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            canary: Some(Canary {
+                deployment: canary,
+                percentage: 100,
+            }),
+            ..stable
+        }),
+    );
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "1");
+
+    // The cookie replaces the IP address as the routing key
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .header("cookie", "lagon-canary=session")
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "2");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn shadow_traffic() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            shadow_deployment_id: Some("counter".into()),
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "shadow.test".into(),
+        Arc::new(Deployment {
+            id: "counter".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // Clients only see the response of the primary deployment
+    for _ in 0..2 {
+        let response = reqwest::get("http://127.0.0.1:4000").await?;
+        assert_eq!(response.text().await?, "Hello world");
+    }
+
+    // Mirrored requests are handled in the background
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .header("host", "shadow.test")
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "3");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn flush_requests() -> Result<()> {
+    let (client, requests) = utils::setup_recording();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let flusher = Flusher::default();
+    let serverless = start_with_config(
+        ServerConfig {
+            // Only flushing commits the requests
+            insertion_interval: Duration::from_secs(60),
+            ..ServerConfig::default()
+        }
+        .flusher(flusher.clone()),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/hello").await?;
+    assert_eq!(response.status(), 200);
+
+    flusher.flush().await?;
+
+    let requests = requests.collect::<Vec<RequestRow>>().await;
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].deployment_id, "simple");
+    assert_eq!(requests[0].path, "/hello");
+    assert_eq!(requests[0].status_code, 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn record_rejected_requests() -> Result<()> {
+    let (client, requests) = utils::setup_recording();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            allowed_methods: vec![Method::GET],
+            ..Deployment::default()
+        }),
+    );
+    let flusher = Flusher::default();
+    let serverless = start_with_config(
+        ServerConfig::default().flusher(flusher.clone()),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let response = client.delete("http://127.0.0.1:4000/hello").send().await?;
+    assert_eq!(response.status(), 405);
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "unknown.test")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 404);
+
+    flusher.flush().await?;
+
+    let requests = requests.collect::<Vec<RequestRow>>().await;
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].deployment_id, "simple");
+    assert_eq!(requests[0].method, "DELETE");
+    assert_eq!(requests[0].path, "/hello");
+    assert_eq!(requests[0].status_code, 405);
+    assert_eq!(requests[1].deployment_id, "");
+    assert_eq!(requests[1].status_code, 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn configured_region() -> Result<()> {
+    let (client, requests) = utils::setup_recording();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let flusher = Flusher::default();
+    let serverless = start_with_config(
+        ServerConfig {
+            region: Some("configured-region".into()),
+            ..ServerConfig::default()
+        }
+        .flusher(flusher.clone()),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+
+    flusher.flush().await?;
+
+    let requests = requests.collect::<Vec<RequestRow>>().await;
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].region, "configured-region");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn sample_requests() -> Result<()> {
+    let (client, requests) = utils::setup_recording();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "sampled.test".into(),
+        Arc::new(Deployment {
+            id: "json".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            request_sample_rate: Some(1.0),
+            ..Deployment::default()
+        }),
+    );
+    let flusher = Flusher::default();
+    let serverless = start_with_config(
+        ServerConfig {
+            request_sample_rate: 0.0,
+            admin_addr: Some("127.0.0.1:4001".parse()?),
+            ..ServerConfig::default()
+        }
+        .flusher(flusher.clone()),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    for _ in 0..3 {
+        let response = client.get("http://127.0.0.1:4000").send().await?;
+        assert_eq!(response.status(), 200);
+    }
+
+    // The deployment's sample rate overrides the server's one
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "sampled.test")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    flusher.flush().await?;
+
+    let requests = requests.collect::<Vec<RequestRow>>().await;
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].deployment_id, "json");
+    assert_eq!(requests[0].sample_weight, 1.0);
+
+    // Requests sampled out are still measured
+    let latency = reqwest::get("http://127.0.0.1:4001/latency")
+        .await?
+        .text()
+        .await?;
+    assert!(latency.contains(r#""deploymentId":"simple""#));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn structured_logs() -> Result<()> {
+    let (client, logs) = utils::setup_recording_logs();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "structured-log".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let flusher = Flusher::default();
+    let serverless = start_with_config(
+        ServerConfig::default().flusher(flusher.clone()),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "Logged");
+
+    flusher.flush().await?;
+
+    let logs = logs.collect::<Vec<LogRow>>().await;
+    let log = logs
+        .iter()
+        .find(|log| log.deployment_id == "structured-log")
+        .unwrap();
+    assert_eq!(log.level, "log");
+    assert_eq!(log.message, r#"Signed in {"userId":42,"admin":false}"#);
+    assert_eq!(
+        log.fields.as_deref(),
+        Some(r#"{"userId":42,"admin":false}"#)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn peak_memory() -> Result<()> {
+    let (client, requests) = utils::setup_recording();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "allocate".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let flusher = Flusher::default();
+    let serverless = start_with_config(
+        ServerConfig::default().flusher(flusher.clone()),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Allocated");
+
+    flusher.flush().await?;
+
+    let requests = requests.collect::<Vec<RequestRow>>().await;
+    assert_eq!(requests.len(), 1);
+
+    // The function allocates 16MB
+    let peak_memory_bytes = requests[0].peak_memory_bytes.unwrap();
+    assert!(peak_memory_bytes >= 16 * 1024 * 1024);
+    assert!(peak_memory_bytes < 128 * 1024 * 1024);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn max_connections() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            max_connections: Some(1),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // Hold the only connection without sending anything
+    let stream = TcpStream::connect("127.0.0.1:4000")?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(reqwest::get("http://127.0.0.1:4000").await.is_err());
+
+    drop(stream);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn http2() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            http2: true,
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // Cleartext HTTP/2 (h2c), where the hostname is only sent with `:authority`
+    let response = reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()?
+        .get("http://127.0.0.1:4000")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // HTTP/1 is still accepted
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), reqwest::Version::HTTP_11);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn tls_sni() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "a.test".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    deployments.insert(
+        "b.test".into(),
+        Arc::new(Deployment {
+            id: "counter".into(),
+            function_id: "function_id_2".into(),
+            function_name: "function_name_2".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            http2: true,
+            ..ServerConfig::default()
+        }
+        .tls(TlsConfig::with_certificates(
+            SniCertificates::new().load_dir(Path::new("tests/certs"))?,
+        )),
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let addr = "127.0.0.1:4000".parse()?;
+    let client = reqwest::Client::builder()
+        .add_root_certificate(Certificate::from_pem(&std::fs::read(
+            "tests/certs/ca.pem",
+        )?)?)
+        .resolve("a.test", addr)
+        .resolve("b.test", addr)
+        .build()?;
+
+    // HTTP/2 requests don't have a Host header, so the
+    // deployment is found with the SNI hostname
+    let response = client.get("https://a.test:4000").send().await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.text().await?, "Hello world");
+
+    let response = client.get("https://b.test:4000").send().await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "1");
+
+    // Plain HTTP isn't accepted anymore
+    assert!(reqwest::get("http://127.0.0.1:4000").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn max_body_size() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            max_body_size: Some(1024),
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .body(vec![b'a'; 1024])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // Rejected with its Content-Length, before reading the body
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .body(vec![b'a'; 1025])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 413);
+
+    // Chunked bodies are rejected once they grow over the limit
+    let chunks = (0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![b'a'; 512])));
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 413);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn decompress_body() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "echo-body".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            max_body_size: Some(64 * 1024),
+            decompress_body: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"Hello world")?;
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("content-encoding", "gzip")
+        .body(encoder.finish()?)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-content-encoding"], "");
+    assert_eq!(response.text().await?, "Hello world");
+
+    // A small body expanding over the maximum size once decompressed
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&vec![0; 1024 * 1024])?;
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("content-encoding", "gzip")
+        .body(encoder.finish()?)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 413);
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("content-encoding", "gzip")
+        .body("invalid")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn header_limits() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start_with_config(
+        ServerConfig {
+            max_headers: Some(16),
+            max_header_bytes: Some(1024),
+            ..ServerConfig::default()
+        },
+        deployments,
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-custom", "a".repeat(512))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-custom", "a".repeat(1024))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 431);
+
+    let mut request = client.get("http://127.0.0.1:4000");
+    for index in 0..16 {
+        request = request.header(format!("x-custom-{index}"), "value");
+    }
+    let response = request.send().await?;
+    assert_eq!(response.status(), 431);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn cpu_budget() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Deployments::default();
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cpu_budget: Some(CpuBudget {
+                cpu_time: Duration::from_micros(1),
+                window: Duration::from_secs(60),
+            }),
+            ..Deployment::default()
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // The first request used the whole budget
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers()["retry-after"], "60");
+
+    Ok(())
+}
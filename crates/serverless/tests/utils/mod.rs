@@ -1,7 +1,11 @@
-use clickhouse::{test::handlers, Client};
+use clickhouse::{
+    test::handlers::{self, RecordControl},
+    Client,
+};
 use lagon_runtime::{options::RuntimeOptions, Runtime};
 use lagon_serverless::clickhouse::{LogRow, RequestRow};
-use std::sync::Once;
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Recorder, SharedString, Unit};
+use std::sync::{Mutex, Once};
 
 use crate::utils::mock::Mock;
 
@@ -21,3 +25,86 @@ pub fn setup() -> Client {
     mock.add(handlers::record::<LogRow>());
     Client::default().with_url(mock.url())
 }
+
+// Same as `setup`, but keep the requests inserted in ClickHouse
+#[allow(dead_code)]
+pub fn setup_recording() -> (Client, RecordControl<RequestRow>) {
+    let client = setup();
+
+    let mock = Mock::new();
+    let requests = mock.add(handlers::record::<RequestRow>());
+    mock.add(handlers::record::<LogRow>());
+
+    (client.with_url(mock.url()), requests)
+}
+
+// Same as `setup`, but keep the logs inserted in ClickHouse
+#[allow(dead_code)]
+pub fn setup_recording_logs() -> (Client, RecordControl<LogRow>) {
+    let client = setup();
+
+    let mock = Mock::new();
+    mock.add(handlers::record::<RequestRow>());
+    let logs = mock.add(handlers::record::<LogRow>());
+
+    (client.with_url(mock.url()), logs)
+}
+
+// Counters recorded, with their labels
+static COUNTERS: Mutex<Vec<(String, Vec<(String, String)>)>> = Mutex::new(Vec::new());
+
+struct TestRecorder;
+
+impl Recorder for TestRecorder {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key) -> Counter {
+        let labels = key
+            .labels()
+            .map(|label| (label.key().to_owned(), label.value().to_owned()))
+            .collect();
+
+        COUNTERS
+            .lock()
+            .unwrap()
+            .push((key.name().to_owned(), labels));
+        Counter::noop()
+    }
+
+    fn register_gauge(&self, _: &Key) -> Gauge {
+        Gauge::noop()
+    }
+
+    fn register_histogram(&self, _: &Key) -> Histogram {
+        Histogram::noop()
+    }
+}
+
+// Record the counters incremented from now on, see `count_requests`
+#[allow(dead_code)]
+pub fn record_counters() {
+    static START: Once = Once::new();
+
+    START.call_once(|| {
+        metrics::set_recorder(&TestRecorder).unwrap();
+    });
+
+    COUNTERS.lock().unwrap().clear();
+}
+
+// Number of requests counted in `lagon_requests` with the given kind
+#[allow(dead_code)]
+pub fn count_requests(kind: &str) -> usize {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, labels)| {
+            name == "lagon_requests" && labels.contains(&(String::from("kind"), String::from(kind)))
+        })
+        .count()
+}
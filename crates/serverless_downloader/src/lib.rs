@@ -37,4 +37,10 @@ pub fn get_bucket() -> Result<Bucket> {
 #[async_trait]
 pub trait Downloader {
     async fn download(&self, path: String) -> Result<Vec<u8>>;
+
+    // Whether the object is served from a local cache instead of
+    // being fetched, for downloaders that have one
+    fn is_cached(&self, _path: &str) -> bool {
+        false
+    }
 }
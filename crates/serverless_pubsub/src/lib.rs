@@ -13,17 +13,54 @@ pub enum PubSubMessageKind {
     Deploy,
     Undeploy,
     Promote,
+    Validate,
+    Reload,
+    SetMaintenance { enabled: bool },
+    SetSuspended { enabled: bool },
+    UpdateEnv,
+    TriggerCron,
+    DispatchEvent,
     Unknown,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PubSubEncoding {
+    Json,
+    MessagePack,
+}
+
+impl PubSubEncoding {
+    // Payloads are always objects, so a MessagePack payload starts
+    // with a map marker, which can't be the first byte of a JSON one
+    pub fn detect(payload: &[u8]) -> Self {
+        match payload.first() {
+            Some(0x80..=0x8f | 0xde | 0xdf) => Self::MessagePack,
+            _ => Self::Json,
+        }
+    }
+}
+
 pub struct PubSubMessage {
     pub kind: PubSubMessageKind,
-    pub payload: String,
+    pub payload: Vec<u8>,
+    pub encoding: PubSubEncoding,
 }
 
 impl PubSubMessage {
     pub fn new(kind: PubSubMessageKind, payload: String) -> Self {
-        Self { kind, payload }
+        Self::with_encoding(kind, payload.into_bytes(), PubSubEncoding::Json)
+    }
+
+    pub fn with_encoding(
+        kind: PubSubMessageKind,
+        payload: Vec<u8>,
+        encoding: PubSubEncoding,
+    ) -> Self {
+        Self {
+            kind,
+            payload,
+            encoding,
+        }
     }
 }
 
@@ -33,6 +70,15 @@ impl From<String> for PubSubMessageKind {
             "deploy" => Self::Deploy,
             "undeploy" => Self::Undeploy,
             "promote" => Self::Promote,
+            "validate" => Self::Validate,
+            "reload" => Self::Reload,
+            "enable-maintenance" => Self::SetMaintenance { enabled: true },
+            "disable-maintenance" => Self::SetMaintenance { enabled: false },
+            "suspend" => Self::SetSuspended { enabled: true },
+            "unsuspend" => Self::SetSuspended { enabled: false },
+            "update-env" => Self::UpdateEnv,
+            "trigger-cron" => Self::TriggerCron,
+            "dispatch-event" => Self::DispatchEvent,
             _ => Self::Unknown,
         }
     }
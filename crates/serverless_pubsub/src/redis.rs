@@ -1,4 +1,4 @@
-use super::{PubSubListener, PubSubMessage};
+use super::{PubSubEncoding, PubSubListener, PubSubMessage};
 use anyhow::Result;
 use futures::Stream;
 use log::info;
@@ -30,13 +30,23 @@ impl PubSubListener for RedisPubSub {
             pubsub.subscribe("deploy")?;
             pubsub.subscribe("undeploy")?;
             pubsub.subscribe("promote")?;
+            pubsub.subscribe("validate")?;
+            pubsub.subscribe("reload")?;
+            pubsub.subscribe("enable-maintenance")?;
+            pubsub.subscribe("disable-maintenance")?;
+            pubsub.subscribe("suspend")?;
+            pubsub.subscribe("unsuspend")?;
+            pubsub.subscribe("update-env")?;
+            pubsub.subscribe("trigger-cron")?;
+            pubsub.subscribe("dispatch-event")?;
 
             loop {
                 let msg = pubsub.get_message()?;
                 let kind = msg.get_channel_name().to_string().into();
-                let payload = msg.get_payload::<String>()?;
+                let payload = msg.get_payload::<Vec<u8>>()?;
+                let encoding = PubSubEncoding::detect(&payload);
 
-                yield Ok(PubSubMessage { kind, payload });
+                yield Ok(PubSubMessage::with_encoding(kind, payload, encoding));
             }
         };
 
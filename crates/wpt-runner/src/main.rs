@@ -162,6 +162,7 @@ export function handler() {{
     tx.send_async(IsolateEvent::Request(IsolateRequest {
         request,
         sender: request_tx,
+        total_timeout: None,
     }))
     .await
     .unwrap();
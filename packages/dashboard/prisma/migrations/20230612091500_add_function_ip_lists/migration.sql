@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `ipAllowList` JSON NOT NULL,
+    ADD COLUMN `ipDenyList` JSON NOT NULL;
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `suspended` BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `responseHeaders` JSON NOT NULL;
@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `cpuBudgetMs` INTEGER NULL,
+    ADD COLUMN `cpuBudgetWindowMs` INTEGER NULL;
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `fallbackDeploymentId` VARCHAR(191) NULL;
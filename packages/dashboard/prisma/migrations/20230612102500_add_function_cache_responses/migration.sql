@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `cacheResponses` BOOLEAN NOT NULL DEFAULT false;
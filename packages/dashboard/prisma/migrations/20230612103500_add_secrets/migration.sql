@@ -0,0 +1,12 @@
+-- CreateTable
+CREATE TABLE `Secret` (
+    `id` VARCHAR(191) NOT NULL,
+    `createdAt` DATETIME(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+    `updatedAt` DATETIME(3) NOT NULL,
+    `key` VARCHAR(64) NOT NULL,
+    `value` VARCHAR(10300) NOT NULL,
+    `functionId` VARCHAR(191) NOT NULL,
+
+    INDEX `Secret_functionId_idx`(`functionId`),
+    PRIMARY KEY (`id`)
+) DEFAULT CHARACTER SET utf8mb4 COLLATE utf8mb4_unicode_ci;
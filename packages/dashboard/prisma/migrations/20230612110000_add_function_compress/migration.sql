@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `compress` BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `jsonErrors` BOOLEAN NOT NULL DEFAULT false;
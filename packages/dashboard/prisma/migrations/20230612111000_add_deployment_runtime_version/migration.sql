@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE `Deployment` ADD COLUMN `runtimeVersion` VARCHAR(191) NULL;
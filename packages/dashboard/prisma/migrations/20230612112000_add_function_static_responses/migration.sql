@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `staticResponses` JSON NOT NULL,
+    ADD COLUMN `builtinStaticRoutes` BOOLEAN NOT NULL DEFAULT false;
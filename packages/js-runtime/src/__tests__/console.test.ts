@@ -110,7 +110,7 @@ describe('Console', () => {
     console.log('Hello', {
       value: 'World',
     });
-    expect(LagonSync.log).toHaveBeenLastCalledWith('log', 'Hello {"value":"World"}');
+    expect(LagonSync.log).toHaveBeenLastCalledWith('log', 'Hello {"value":"World"}', '{"value":"World"}');
 
     console.log(
       'Hello',
@@ -123,6 +123,28 @@ describe('Console', () => {
     expect(LagonSync.log).toHaveBeenLastCalledWith('log', 'Hello {"value":"World"} 42 undefined');
   });
 
+  it('should send the fields of structured logs', () => {
+    console.log('Signed in', { userId: 42, admin: false });
+    expect(LagonSync.log).toHaveBeenLastCalledWith(
+      'log',
+      'Signed in {"userId":42,"admin":false}',
+      '{"userId":42,"admin":false}',
+    );
+
+    console.log('Signed in %s', 'user', { userId: 42 });
+    expect(LagonSync.log).toHaveBeenLastCalledWith('log', 'Signed in user {"userId":42}', '{"userId":42}');
+
+    // Objects used by a placeholder, and objects that aren't plain, aren't fields
+    console.log('Signed in %j', { userId: 42 });
+    expect(LagonSync.log).toHaveBeenLastCalledWith('log', 'Signed in {"userId":42}');
+
+    console.log('Signed in', new Error('Hello World'));
+    expect(LagonSync.log).toHaveBeenLastCalledWith('log', 'Signed in Error: Hello World');
+
+    console.log({ userId: 42 });
+    expect(LagonSync.log).toHaveBeenLastCalledWith('log', '{"userId":42}');
+  });
+
   it('should format printf like string', () => {
     console.log('Hello %s', 'World');
 
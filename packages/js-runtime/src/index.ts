@@ -53,8 +53,8 @@ declare global {
   var AsyncLocalStorage: AsyncLocalStorageConstructor;
 
   var LagonSync: {
-    log: (level: string, message: string) => void;
-    pullStream: (id: number, done: boolean, chunk?: Uint8Array) => void;
+    log: (level: string, message: string, fields?: string) => void;
+    pullStream: (id: number, done: boolean, chunk?: Uint8Array) => boolean;
     uuid: () => `${string}-${string}-${string}-${string}-${string}`;
     randomValues: <T extends ArrayBufferView | null>(array: T) => void;
     getKeyValue: () => ArrayBuffer;
@@ -105,6 +105,7 @@ declare global {
       algorithm: RsaHashedKeyGenParams | EcKeyGenParams | HmacKeyGenParams | AesKeyGenParams,
     ): Promise<ArrayBuffer>;
     sleep: (ms: number) => Promise<void>;
+    streamReady: (id: number) => Promise<void>;
   };
   var __lagon__: {
     isIterable: (value: unknown) => value is ArrayBuffer;
@@ -127,6 +128,15 @@ declare global {
     h: ResponseInit['headers'];
     s: ResponseInit['status'];
   }>;
+  var masterEventHandler: (
+    id: number,
+    handler: (event: { name: string; payload: unknown }) => unknown,
+    event: { name: string; payload: unknown },
+  ) => Promise<{
+    b?: string;
+    h: ResponseInit['headers'];
+    s: ResponseInit['status'];
+  }>;
 
   interface Response {
     readonly isStream: boolean;
@@ -170,8 +180,11 @@ globalThis.masterHandler = async (id, handler, request) => {
           return;
         }
 
-        if (value.byteLength !== 0) {
-          LagonSync.pullStream(id, done, value);
+        // Wait for the receiver to catch up before reading the next
+        // chunk, so slow clients don't make us buffer the whole stream
+        if (value.byteLength !== 0 && LagonSync.pullStream(id, done, value)) {
+          LagonAsync.streamReady(id).then(read);
+          return;
         }
 
         read();
@@ -192,3 +205,21 @@ globalThis.masterHandler = async (id, handler, request) => {
     };
   }
 };
+
+// Events (e.g from a queue) are passed to the handler instead of a `Request`. The
+// handler can return a `Response` (e.g to report a failure with its status), or
+// any other value, which is sent back as JSON.
+globalThis.masterEventHandler = async (id, handler, event) => {
+  if (typeof handler !== 'function') {
+    throw new Error('Handler function is not defined or is not a function');
+  }
+
+  const result = await handler(event);
+  const response = result instanceof Response ? result : Response.json(result ?? null);
+
+  return {
+    b: await response.text(),
+    h: response.headers,
+    s: response.status,
+  };
+};
@@ -73,11 +73,46 @@
     return result.join(' ');
   };
 
+  const isPlainObject = (value: unknown): value is Record<string, unknown> => {
+    if (typeof value !== 'object' || value === null) {
+      return false;
+    }
+
+    const prototype = Object.getPrototypeOf(value);
+
+    return prototype === Object.prototype || prototype === null;
+  };
+
+  // Structured logs end with a plain object of custom fields that isn't used by
+  // a placeholder, e.g `console.log('Signed in', { userId })`. These fields are
+  // also sent as JSON so they can be queried.
+  const getFields = (input: unknown, args: unknown[]): string | undefined => {
+    if (typeof input !== 'string' || args.length === 0) {
+      return undefined;
+    }
+
+    const placeholders = input.match(/%[sdifjoOc%]/g)?.length ?? 0;
+    const fields = args[args.length - 1];
+
+    if (args.length <= placeholders || !isPlainObject(fields)) {
+      return undefined;
+    }
+
+    return JSON.stringify(fields);
+  };
+
   const types = ['log', 'info', 'debug', 'error', 'warn'] as const;
 
   types.forEach(type => {
     globalThis.console[type] = (input, ...args) => {
-      LagonSync.log(type, format(input, ...args));
+      const message = format(input, ...args);
+      const fields = getFields(input, args);
+
+      if (fields === undefined) {
+        LagonSync.log(type, message);
+      } else {
+        LagonSync.log(type, message, fields);
+      }
     };
   });
 })(globalThis);